@@ -0,0 +1,44 @@
+use dns_server_tutorial_rust::parser::dissect;
+use std::{env, error::Error, fs, process};
+
+const USAGE: &str =
+    "Usage: dns_dissect <file containing a raw DNS message>\n       dns_dissect --hex <hex string>";
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let bytes = match args.as_slice() {
+        [flag, hex] if flag == "--hex" => parse_hex(hex)?,
+        [path] if !path.starts_with('-') => fs::read(path)?,
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let dissection = dissect(&bytes);
+    print!("{}", dissection);
+
+    if dissection.error.is_some() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// Accepts the usual ways of writing hex: "bbd80120", "bb d8 01 20", "bb:d8:01:20".
+fn parse_hex(input: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let digits: Vec<char> = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("Hex input has an odd number of digits.".into());
+    }
+
+    let mut bytes = Vec::with_capacity(digits.len() / 2);
+    for pair in digits.chunks(2) {
+        let text: String = pair.iter().collect();
+        bytes.push(u8::from_str_radix(&text, 16)?);
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,7 @@
+mod capture_reader;
+mod dns_capture;
+mod frame_decoder;
+mod tcp_reassembler;
+
+pub use capture_reader::{CaptureReader, CapturedFrame, LinkType};
+pub use dns_capture::{CapturedDnsMessage, DnsCapture, Transport};
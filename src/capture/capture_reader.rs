@@ -0,0 +1,512 @@
+use std::{
+    error::Error,
+    io::{ErrorKind, Read},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const PCAP_MICROSECOND_MAGIC: u32 = 0xA1B2_C3D4;
+const PCAP_NANOSECOND_MAGIC: u32 = 0xA1B2_3C4D;
+const PCAP_GLOBAL_HEADER_REMAINDER: usize = 20; // Everything after the 4-byte magic number.
+const PCAP_RECORD_HEADER_LENGTH: usize = 16;
+
+const PCAPNG_SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const PCAPNG_INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const PCAPNG_SIMPLE_PACKET_BLOCK: u32 = 3;
+const PCAPNG_ENHANCED_PACKET_BLOCK: u32 = 6;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const PCAPNG_OPTION_END: u16 = 0;
+const PCAPNG_OPTION_TIMESTAMP_RESOLUTION: u16 = 9;
+const PCAPNG_DEFAULT_TIMESTAMP_RESOLUTION: u8 = 6; // Microseconds.
+
+// Anything bigger than this is almost certainly a corrupt length field rather than a real frame.
+const MAX_BLOCK_LENGTH: usize = 16 * 1024 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LinkType {
+    Ethernet,
+    LinuxSll,
+    LinuxSll2,
+    Unknown(u32),
+}
+
+impl LinkType {
+    pub fn from_u32(val: u32) -> LinkType {
+        match val {
+            1 => LinkType::Ethernet,
+            113 => LinkType::LinuxSll,
+            276 => LinkType::LinuxSll2,
+            _ => LinkType::Unknown(val),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CapturedFrame {
+    pub timestamp: SystemTime,
+    pub link_type: LinkType,
+    pub data: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn u16(self, bytes: &[u8]) -> u16 {
+        let raw = [bytes[0], bytes[1]];
+        match self {
+            ByteOrder::Little => u16::from_le_bytes(raw),
+            ByteOrder::Big => u16::from_be_bytes(raw),
+        }
+    }
+
+    fn u32(self, bytes: &[u8]) -> u32 {
+        let raw = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self {
+            ByteOrder::Little => u32::from_le_bytes(raw),
+            ByteOrder::Big => u32::from_be_bytes(raw),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Interface {
+    link_type: LinkType,
+    // Raw if_tsresol value: the low 7 bits are an exponent, the high bit selects base 2 rather than base 10.
+    timestamp_resolution: u8,
+}
+
+enum Format {
+    Pcap {
+        byte_order: ByteOrder,
+        nanosecond_timestamps: bool,
+        link_type: LinkType,
+    },
+    PcapNg {
+        byte_order: ByteOrder,
+        interfaces: Vec<Interface>,
+    },
+}
+
+/// Reads link-layer frames out of a classic pcap or a pcapng capture, as written by tcpdump/Wireshark.
+pub struct CaptureReader<R: Read> {
+    reader: R,
+    format: Format,
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> Result<CaptureReader<R>, Box<dyn Error>> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        if u32::from_be_bytes(magic) == PCAPNG_SECTION_HEADER_BLOCK {
+            let mut capture = CaptureReader {
+                reader,
+                format: Format::PcapNg {
+                    byte_order: ByteOrder::Little,
+                    interfaces: Vec::new(),
+                },
+            };
+            capture.read_section_header()?;
+            return Ok(capture);
+        }
+
+        let (byte_order, nanosecond_timestamps) =
+            match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                (PCAP_MICROSECOND_MAGIC, _) => (ByteOrder::Little, false),
+                (PCAP_NANOSECOND_MAGIC, _) => (ByteOrder::Little, true),
+                (_, PCAP_MICROSECOND_MAGIC) => (ByteOrder::Big, false),
+                (_, PCAP_NANOSECOND_MAGIC) => (ByteOrder::Big, true),
+                _ => return Err("Not a pcap or pcapng capture (unrecognised magic number).".into()),
+            };
+
+        let mut header = [0; PCAP_GLOBAL_HEADER_REMAINDER];
+        reader.read_exact(&mut header)?;
+        let link_type = LinkType::from_u32(byte_order.u32(&header[16..20]));
+
+        Ok(CaptureReader {
+            reader,
+            format: Format::Pcap {
+                byte_order,
+                nanosecond_timestamps,
+                link_type,
+            },
+        })
+    }
+
+    /// Returns the next captured frame, or `None` once the end of the capture has been reached.
+    pub fn next_frame(&mut self) -> Result<Option<CapturedFrame>, Box<dyn Error>> {
+        match self.format {
+            Format::Pcap {
+                byte_order,
+                nanosecond_timestamps,
+                link_type,
+            } => self.next_pcap_frame(byte_order, nanosecond_timestamps, link_type),
+            Format::PcapNg { .. } => self.next_pcapng_frame(),
+        }
+    }
+
+    fn next_pcap_frame(
+        &mut self,
+        byte_order: ByteOrder,
+        nanosecond_timestamps: bool,
+        link_type: LinkType,
+    ) -> Result<Option<CapturedFrame>, Box<dyn Error>> {
+        let mut header = [0; PCAP_RECORD_HEADER_LENGTH];
+        if !read_exact_or_eof(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+
+        let seconds = byte_order.u32(&header[0..4]) as u64;
+        let fraction = byte_order.u32(&header[4..8]);
+        let captured_length = byte_order.u32(&header[8..12]) as usize;
+        if captured_length > MAX_BLOCK_LENGTH {
+            return Err(format!("Captured frame length too large ({}).", captured_length).into());
+        }
+
+        let mut data = vec![0; captured_length];
+        self.reader.read_exact(&mut data)?;
+
+        // Out of range fractions are left as they are; they can't make the time overflow.
+        let nanos = if nanosecond_timestamps {
+            fraction as u64
+        } else {
+            fraction as u64 * 1_000
+        };
+
+        Ok(Some(CapturedFrame {
+            timestamp: UNIX_EPOCH + Duration::new(seconds, 0) + Duration::from_nanos(nanos),
+            link_type,
+            data,
+        }))
+    }
+
+    fn next_pcapng_frame(&mut self) -> Result<Option<CapturedFrame>, Box<dyn Error>> {
+        loop {
+            let mut block_type = [0; 4];
+            if !read_exact_or_eof(&mut self.reader, &mut block_type)? {
+                return Ok(None);
+            }
+            // The section header block type is a palindrome, so we can recognise it before we know the byte order.
+            if u32::from_be_bytes(block_type) == PCAPNG_SECTION_HEADER_BLOCK {
+                self.read_section_header()?;
+                continue;
+            }
+
+            let byte_order = self.byte_order();
+            let block_type = byte_order.u32(&block_type);
+            let body = self.read_block_body()?;
+
+            match block_type {
+                PCAPNG_INTERFACE_DESCRIPTION_BLOCK => {
+                    let interface = parse_interface_description(byte_order, &body)?;
+                    if let Format::PcapNg { interfaces, .. } = &mut self.format {
+                        interfaces.push(interface);
+                    }
+                }
+                PCAPNG_ENHANCED_PACKET_BLOCK => {
+                    if body.len() < 20 {
+                        return Err("Truncated pcapng enhanced packet block.".into());
+                    }
+                    let interface = self.interface(byte_order.u32(&body[0..4]) as usize)?;
+                    let timestamp = ((byte_order.u32(&body[4..8]) as u64) << 32)
+                        | byte_order.u32(&body[8..12]) as u64;
+                    let captured_length = byte_order.u32(&body[12..16]) as usize;
+                    let data = body
+                        .get(20..20 + captured_length)
+                        .ok_or("Enhanced packet block is shorter than its captured length.")?;
+
+                    return Ok(Some(CapturedFrame {
+                        timestamp: to_system_time(timestamp, interface.timestamp_resolution)?,
+                        link_type: interface.link_type,
+                        data: data.to_vec(),
+                    }));
+                }
+                PCAPNG_SIMPLE_PACKET_BLOCK => {
+                    if body.len() < 4 {
+                        return Err("Truncated pcapng simple packet block.".into());
+                    }
+                    let interface = self.interface(0)?;
+                    let original_length = byte_order.u32(&body[0..4]) as usize;
+                    let data = &body[4..(4 + original_length).min(body.len())];
+
+                    // Simple packet blocks carry no timestamp at all.
+                    return Ok(Some(CapturedFrame {
+                        timestamp: UNIX_EPOCH,
+                        link_type: interface.link_type,
+                        data: data.to_vec(),
+                    }));
+                }
+                _ => (), // Statistics, name resolution, custom blocks etc. are of no interest here.
+            }
+        }
+    }
+
+    // Expects the block type to have already been consumed.
+    fn read_section_header(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut length_and_magic = [0; 8];
+        self.reader.read_exact(&mut length_and_magic)?;
+
+        let byte_order = if u32::from_le_bytes([
+            length_and_magic[4],
+            length_and_magic[5],
+            length_and_magic[6],
+            length_and_magic[7],
+        ]) == PCAPNG_BYTE_ORDER_MAGIC
+        {
+            ByteOrder::Little
+        } else if u32::from_be_bytes([
+            length_and_magic[4],
+            length_and_magic[5],
+            length_and_magic[6],
+            length_and_magic[7],
+        ]) == PCAPNG_BYTE_ORDER_MAGIC
+        {
+            ByteOrder::Big
+        } else {
+            return Err("Invalid pcapng byte-order magic.".into());
+        };
+
+        let block_length = byte_order.u32(&length_and_magic[0..4]) as usize;
+        if !(16..=MAX_BLOCK_LENGTH).contains(&block_length) {
+            return Err(format!("Invalid pcapng section header length ({}).", block_length).into());
+        }
+        // Skip the rest of the header (versions, section length, options) and the trailing length.
+        let mut remainder = vec![0; block_length - 12];
+        self.reader.read_exact(&mut remainder)?;
+
+        // Interface IDs are scoped to a section, so a new section starts from scratch.
+        self.format = Format::PcapNg {
+            byte_order,
+            interfaces: Vec::new(),
+        };
+        Ok(())
+    }
+
+    // Expects the block type to have already been consumed. Returns the body without the trailing length.
+    fn read_block_body(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let byte_order = self.byte_order();
+        let mut length = [0; 4];
+        self.reader.read_exact(&mut length)?;
+
+        let block_length = byte_order.u32(&length) as usize;
+        if !(12..=MAX_BLOCK_LENGTH).contains(&block_length) {
+            return Err(format!("Invalid pcapng block length ({}).", block_length).into());
+        }
+
+        let mut body = vec![0; block_length - 8];
+        self.reader.read_exact(&mut body)?;
+        body.truncate(block_length - 12);
+        Ok(body)
+    }
+
+    fn byte_order(&self) -> ByteOrder {
+        match self.format {
+            Format::Pcap { byte_order, .. } | Format::PcapNg { byte_order, .. } => byte_order,
+        }
+    }
+
+    fn interface(&self, id: usize) -> Result<Interface, Box<dyn Error>> {
+        match &self.format {
+            Format::PcapNg { interfaces, .. } => match interfaces.get(id) {
+                Some(interface) => Ok(*interface),
+                None => Err(format!("Packet refers to undeclared interface {}.", id).into()),
+            },
+            Format::Pcap { .. } => Err("Classic pcap captures have no interfaces.".into()),
+        }
+    }
+}
+
+fn parse_interface_description(byte_order: ByteOrder, body: &[u8]) -> Result<Interface, String> {
+    if body.len() < 8 {
+        return Err("Truncated pcapng interface description block.".into());
+    }
+    let mut interface = Interface {
+        link_type: LinkType::from_u32(byte_order.u16(&body[0..2]) as u32),
+        timestamp_resolution: PCAPNG_DEFAULT_TIMESTAMP_RESOLUTION,
+    };
+
+    let mut pos = 8;
+    while pos + 4 <= body.len() {
+        let code = byte_order.u16(&body[pos..pos + 2]);
+        let length = byte_order.u16(&body[pos + 2..pos + 4]) as usize;
+        if code == PCAPNG_OPTION_END {
+            break;
+        }
+        if code == PCAPNG_OPTION_TIMESTAMP_RESOLUTION && length >= 1 && pos + 4 < body.len() {
+            interface.timestamp_resolution = body[pos + 4];
+        }
+        pos += 4 + ((length + 3) & !3); // Option values are padded to 32 bits.
+    }
+    Ok(interface)
+}
+
+// Fails for resolutions too fine to count in, and times too far off to represent.
+fn to_system_time(ticks: u64, resolution: u8) -> Result<SystemTime, String> {
+    let exponent = (resolution & 0x7F) as u32;
+    let nanos = if resolution & 0x80 == 0 {
+        let ticks_per_second = 10u128.checked_pow(exponent).ok_or_else(|| {
+            format!(
+                "Unsupported timestamp resolution (10^-{} seconds).",
+                exponent
+            )
+        })?;
+        ticks as u128 * 1_000_000_000 / ticks_per_second
+    } else {
+        (ticks as u128 * 1_000_000_000) >> exponent
+    };
+    let nanos = u64::try_from(nanos).map_err(|_| String::from("Timestamp out of range."))?;
+    Ok(UNIX_EPOCH + Duration::from_nanos(nanos))
+}
+
+// Like read_exact, but a clean end of file before the first byte is reported as Ok(false) rather than an error.
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, Box<dyn Error>> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err("Capture file ends part-way through a record.".into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_system_time, CaptureReader, LinkType};
+    use crate::parser::test_helpers::{
+        open_test_file, GOOGLE_QUERY_TCP_CAPTURE, GOOGLE_QUERY_UDP_CAPTURE,
+    };
+    use std::{
+        error::Error,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn reads_every_frame_from_pcap() -> Result<(), Box<dyn Error>> {
+        let mut capture =
+            CaptureReader::new(open_test_file(String::from(GOOGLE_QUERY_UDP_CAPTURE))?)?;
+        let mut frame_count = 0;
+        while let Some(frame) = capture.next_frame()? {
+            assert_eq!(frame.link_type, LinkType::Ethernet);
+            frame_count += 1;
+        }
+        assert_eq!(frame_count, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_microsecond_pcap_timestamps() -> Result<(), Box<dyn Error>> {
+        let mut capture =
+            CaptureReader::new(open_test_file(String::from(GOOGLE_QUERY_UDP_CAPTURE))?)?;
+        capture.next_frame()?;
+        let frame = capture.next_frame()?.expect("Expected a second frame.");
+        assert_eq!(
+            frame.timestamp,
+            UNIX_EPOCH + Duration::new(1_650_000_000, 275_500_000)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reads_big_endian_nanosecond_pcap() -> Result<(), Box<dyn Error>> {
+        let mut bytes = vec![0xA1, 0xB2, 0x3C, 0x4D, 0, 2, 0, 4];
+        bytes.extend_from_slice(&[0; 12]); // thiszone, sigfigs, snaplen
+        bytes.extend_from_slice(&113u32.to_be_bytes());
+        bytes.extend_from_slice(&7u32.to_be_bytes());
+        bytes.extend_from_slice(&42u32.to_be_bytes());
+        bytes.extend_from_slice(&3u32.to_be_bytes());
+        bytes.extend_from_slice(&3u32.to_be_bytes());
+        bytes.extend_from_slice(&[1, 2, 3]);
+
+        let mut capture = CaptureReader::new(bytes.as_slice())?;
+        let frame = capture.next_frame()?.expect("Expected a frame.");
+        assert_eq!(frame.link_type, LinkType::LinuxSll);
+        assert_eq!(frame.timestamp, UNIX_EPOCH + Duration::new(7, 42));
+        assert_eq!(frame.data, vec![1, 2, 3]);
+        assert!(capture.next_frame()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn reads_every_frame_from_pcapng() -> Result<(), Box<dyn Error>> {
+        let mut capture =
+            CaptureReader::new(open_test_file(String::from(GOOGLE_QUERY_TCP_CAPTURE))?)?;
+        let first = capture.next_frame()?.expect("Expected a frame.");
+        assert_eq!(first.link_type, LinkType::LinuxSll);
+        assert_eq!(
+            first.timestamp,
+            UNIX_EPOCH + Duration::new(1_650_000_000, 123)
+        );
+
+        let mut frame_count = 1;
+        while capture.next_frame()?.is_some() {
+            frame_count += 1;
+        }
+        assert_eq!(frame_count, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unrecognised_file_format() {
+        let bytes: &[u8] = b"GIF89a, definitely not a capture";
+        assert!(CaptureReader::new(bytes).is_err());
+    }
+
+    #[test]
+    fn fails_on_capture_truncated_mid_record() -> Result<(), Box<dyn Error>> {
+        let mut bytes = vec![0xD4, 0xC3, 0xB2, 0xA1];
+        bytes.extend_from_slice(&[0; 16]);
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&[0; 10]); // Half of a record header.
+
+        let mut capture = CaptureReader::new(bytes.as_slice())?;
+        assert!(capture.next_frame().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn converts_binary_timestamp_resolution() {
+        // 2^-10 second units: 1536 ticks is one and a half seconds.
+        assert_eq!(
+            to_system_time(1536, 0x80 | 10),
+            Ok(UNIX_EPOCH + Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn rejects_timestamps_it_cannot_represent() {
+        assert_eq!(
+            to_system_time(1, 38),
+            Ok(UNIX_EPOCH + Duration::from_nanos(0))
+        );
+        assert!(to_system_time(1, 39).is_err());
+        assert!(to_system_time(1, 127).is_err());
+        assert!(to_system_time(u64::MAX, 0).is_err());
+        assert!(to_system_time(u64::MAX, 0x80 | 127).is_ok());
+    }
+
+    #[test]
+    fn reads_out_of_range_microseconds() -> Result<(), Box<dyn Error>> {
+        let mut bytes = vec![0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0];
+        bytes.extend_from_slice(&[0; 12]); // thiszone, sigfigs, snaplen
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+
+        let mut capture = CaptureReader::new(bytes.as_slice())?;
+        let frame = capture.next_frame()?.expect("Expected a frame.");
+        assert_eq!(
+            frame.timestamp,
+            UNIX_EPOCH + Duration::from_micros(u32::MAX as u64)
+        );
+        Ok(())
+    }
+}
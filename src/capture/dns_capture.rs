@@ -0,0 +1,227 @@
+use super::{
+    capture_reader::CaptureReader,
+    frame_decoder::{decode_frame, TransportPayload},
+    tcp_reassembler::TcpReassembler,
+};
+use crate::parser::DnsPacket;
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fs::File,
+    io::{BufReader, Read},
+    net::SocketAddr,
+    path::Path,
+    time::SystemTime,
+};
+
+const DNS_PORT: u16 = 53;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+#[derive(Clone, Debug)]
+pub struct CapturedDnsMessage {
+    // For TCP, this is the time of the segment which completed the message.
+    pub timestamp: SystemTime,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub transport: Transport,
+    pub packet: DnsPacket,
+}
+
+/// Iterates over the DNS messages in a pcap/pcapng capture.
+/// Frames which aren't DNS are skipped; frames which look like DNS but can't be parsed are yielded as errors,
+/// after which iteration carries on with the next frame.
+pub struct DnsCapture<R: Read> {
+    frames: CaptureReader<R>,
+    reassembler: TcpReassembler,
+    ready: VecDeque<Result<CapturedDnsMessage, Box<dyn Error>>>,
+    finished: bool,
+}
+
+impl DnsCapture<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DnsCapture<BufReader<File>>, Box<dyn Error>> {
+        DnsCapture::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> DnsCapture<R> {
+    pub fn new(reader: R) -> Result<DnsCapture<R>, Box<dyn Error>> {
+        Ok(DnsCapture {
+            frames: CaptureReader::new(reader)?,
+            reassembler: TcpReassembler::new(),
+            ready: VecDeque::new(),
+            finished: false,
+        })
+    }
+
+    // Reads frames until at least one result is ready or the capture runs out.
+    fn fill(&mut self) {
+        while self.ready.is_empty() && !self.finished {
+            let frame = match self.frames.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    self.finished = true;
+                    return;
+                }
+                Err(e) => {
+                    // The container itself is broken, so there's no way to find the next frame.
+                    self.finished = true;
+                    self.ready.push_back(Err(e));
+                    return;
+                }
+            };
+
+            let decoded = match decode_frame(frame.link_type, &frame.data) {
+                Ok(Some(decoded)) => decoded,
+                Ok(None) => continue,
+                Err(e) => {
+                    self.ready.push_back(Err(e.into()));
+                    continue;
+                }
+            };
+            if decoded.source.port() != DNS_PORT && decoded.destination.port() != DNS_PORT {
+                continue;
+            }
+
+            let (transport, messages) = match decoded.payload {
+                TransportPayload::Udp(payload) => (Transport::Udp, vec![payload.to_vec()]),
+                TransportPayload::Tcp(segment) => (
+                    Transport::Tcp,
+                    self.reassembler
+                        .push(decoded.source, decoded.destination, &segment),
+                ),
+            };
+
+            for message in messages {
+                let result = DnsPacket::from_bytes(&message).map(|packet| CapturedDnsMessage {
+                    timestamp: frame.timestamp,
+                    source: decoded.source,
+                    destination: decoded.destination,
+                    transport,
+                    packet,
+                });
+                self.ready.push_back(result);
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for DnsCapture<R> {
+    type Item = Result<CapturedDnsMessage, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.fill();
+        self.ready.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CapturedDnsMessage, DnsCapture, Transport};
+    use crate::parser::{
+        test_helpers::{open_test_file, GOOGLE_QUERY_TCP_CAPTURE, GOOGLE_QUERY_UDP_CAPTURE},
+        DnsRecord, QueryType,
+    };
+    use std::{
+        error::Error,
+        net::{Ipv4Addr, SocketAddr},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    fn read_capture(filename: &str) -> Result<Vec<CapturedDnsMessage>, Box<dyn Error>> {
+        DnsCapture::new(open_test_file(String::from(filename))?)?.collect()
+    }
+
+    #[test]
+    fn reads_query_and_response_over_udp() -> Result<(), Box<dyn Error>> {
+        let messages = read_capture(GOOGLE_QUERY_UDP_CAPTURE)?;
+        assert_eq!(messages.len(), 2);
+
+        let (query, response) = (&messages[0], &messages[1]);
+        assert_eq!(query.transport, Transport::Udp);
+        assert_eq!(query.source, "192.168.1.20:51334".parse::<SocketAddr>()?);
+        assert_eq!(query.destination, "8.8.8.8:53".parse::<SocketAddr>()?);
+        assert_eq!(query.packet.questions[0].name, "google.com");
+        assert_eq!(query.packet.questions[0].query_type, QueryType::A);
+        assert!(!query.packet.header.response);
+
+        assert_eq!(response.source, query.destination);
+        assert_eq!(response.destination, query.source);
+        assert_eq!(response.packet.header.id, query.packet.header.id);
+        assert_eq!(
+            response.timestamp,
+            UNIX_EPOCH + Duration::new(1_650_000_000, 275_500_000)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reads_query_and_response_over_reassembled_tcp() -> Result<(), Box<dyn Error>> {
+        let messages = read_capture(GOOGLE_QUERY_TCP_CAPTURE)?;
+        assert_eq!(messages.len(), 2);
+
+        let (query, response) = (&messages[0], &messages[1]);
+        assert_eq!(query.transport, Transport::Tcp);
+        assert_eq!(
+            query.destination,
+            "[2001:4860:4860::8888]:53".parse::<SocketAddr>()?
+        );
+        assert_eq!(query.packet.questions[0].name, "google.com");
+
+        // The response arrived in two segments, out of order, and with a retransmission.
+        assert!(response.packet.header.response);
+        match response.packet.answers.first() {
+            Some(DnsRecord::A { address, .. }) => {
+                assert_eq!(*address, Ipv4Addr::new(142, 250, 71, 78))
+            }
+            _ => panic!("Expected the reassembled response to contain an A record."),
+        }
+        // Timestamped with the segment which completed it (5ms after the SYN).
+        assert_eq!(
+            response.timestamp,
+            UNIX_EPOCH + Duration::new(1_650_000_000, 5_000_123)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reports_unparseable_dns_payload_and_carries_on() -> Result<(), Box<dyn Error>> {
+        let mut bytes = vec![0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0];
+        bytes.extend_from_slice(&[0; 12]);
+        bytes.extend_from_slice(&1u32.to_le_bytes()); // Ethernet
+
+        for payload in [&b""[..], &b"\xbb\xd8\x01\x20\0\0\0\0\0\0\0\0"[..]] {
+            let mut frame = vec![0; 12];
+            frame.extend_from_slice(&[0x08, 0x00, 0x45, 0]);
+            frame.extend_from_slice(&(28 + payload.len() as u16).to_be_bytes());
+            frame.extend_from_slice(&[0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+            frame.extend_from_slice(&[0x30, 0x39, 0, 53]);
+            frame.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+            frame.extend_from_slice(&[0, 0]);
+            frame.extend_from_slice(payload);
+
+            bytes.extend_from_slice(&[0; 8]);
+            bytes.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&frame);
+        }
+
+        let results: Vec<_> = DnsCapture::new(bytes.as_slice())?.collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+        assert_eq!(
+            results[1]
+                .as_ref()
+                .map_err(|e| e.to_string())?
+                .packet
+                .header
+                .id,
+            0xBBD8
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+use super::capture_reader::LinkType;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88A8;
+
+const ETHERNET_HEADER_LENGTH: usize = 14;
+const LINUX_SLL_HEADER_LENGTH: usize = 16;
+const LINUX_SLL2_HEADER_LENGTH: usize = 20;
+const IPV6_HEADER_LENGTH: usize = 40;
+const UDP_HEADER_LENGTH: usize = 8;
+
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AUTHENTICATION: u8 = 51;
+const IPV6_DESTINATION_OPTIONS: u8 = 60;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransportPayload<'a> {
+    Udp(&'a [u8]),
+    Tcp(TcpSegment<'a>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TcpSegment<'a> {
+    pub sequence_number: u32,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub payload: &'a [u8],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DecodedFrame<'a> {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: TransportPayload<'a>,
+}
+
+/// Peels the link, network and transport layers off a captured frame.
+/// Returns `Ok(None)` for anything which isn't UDP or TCP over IPv4/IPv6 (ARP, ICMP, IP fragments, ...).
+pub fn decode_frame(link_type: LinkType, data: &[u8]) -> Result<Option<DecodedFrame<'_>>, String> {
+    let (ethertype, network_layer) = match link_type {
+        LinkType::Ethernet => decode_ethernet(data)?,
+        LinkType::LinuxSll => (
+            read_u16(data, 14)?,
+            slice_from(data, LINUX_SLL_HEADER_LENGTH)?,
+        ),
+        LinkType::LinuxSll2 => (
+            read_u16(data, 0)?,
+            slice_from(data, LINUX_SLL2_HEADER_LENGTH)?,
+        ),
+        LinkType::Unknown(_) => return Ok(None),
+    };
+
+    let (source_ip, destination_ip, protocol, transport_layer) = match ethertype {
+        ETHERTYPE_IPV4 => match decode_ipv4(network_layer)? {
+            Some(decoded) => decoded,
+            None => return Ok(None),
+        },
+        ETHERTYPE_IPV6 => match decode_ipv6(network_layer)? {
+            Some(decoded) => decoded,
+            None => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+
+    let source_port = read_u16(transport_layer, 0)?;
+    let destination_port = read_u16(transport_layer, 2)?;
+
+    let payload = match protocol {
+        IP_PROTOCOL_UDP => {
+            let length = read_u16(transport_layer, 4)? as usize;
+            if length < UDP_HEADER_LENGTH {
+                return Err(format!("Invalid UDP length ({}).", length));
+            }
+            // Tolerate frames cut short by the capture's snap length; the DNS parser will complain if it matters.
+            let end = length.min(transport_layer.len());
+            TransportPayload::Udp(&transport_layer[UDP_HEADER_LENGTH..end])
+        }
+        IP_PROTOCOL_TCP => {
+            let header_length = ((read_u8(transport_layer, 12)? >> 4) as usize) * 4;
+            let flags = read_u8(transport_layer, 13)?;
+            TransportPayload::Tcp(TcpSegment {
+                sequence_number: read_u32(transport_layer, 4)?,
+                syn: flags & TCP_SYN != 0,
+                fin: flags & TCP_FIN != 0,
+                rst: flags & TCP_RST != 0,
+                payload: slice_from(transport_layer, header_length)?,
+            })
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(DecodedFrame {
+        source: SocketAddr::new(source_ip, source_port),
+        destination: SocketAddr::new(destination_ip, destination_port),
+        payload,
+    }))
+}
+
+fn decode_ethernet(data: &[u8]) -> Result<(u16, &[u8]), String> {
+    let mut ethertype_pos = ETHERNET_HEADER_LENGTH - 2;
+    let mut ethertype = read_u16(data, ethertype_pos)?;
+
+    // Skip over any (possibly stacked) 802.1Q VLAN tags.
+    while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+        ethertype_pos += 4;
+        ethertype = read_u16(data, ethertype_pos)?;
+    }
+    Ok((ethertype, slice_from(data, ethertype_pos + 2)?))
+}
+
+type NetworkLayer<'a> = (IpAddr, IpAddr, u8, &'a [u8]);
+
+fn decode_ipv4(data: &[u8]) -> Result<Option<NetworkLayer<'_>>, String> {
+    let header_length = ((read_u8(data, 0)? & 0x0F) as usize) * 4;
+    let total_length = read_u16(data, 2)? as usize;
+    let fragment_info = read_u16(data, 6)?;
+    let protocol = read_u8(data, 9)?;
+
+    let more_fragments = fragment_info & 0x2000 != 0;
+    let fragment_offset = fragment_info & 0x1FFF;
+    if more_fragments || fragment_offset != 0 {
+        return Ok(None);
+    }
+
+    let source = Ipv4Addr::from(read_u32(data, 12)?);
+    let destination = Ipv4Addr::from(read_u32(data, 16)?);
+    if total_length < header_length {
+        return Err(format!("Invalid IPv4 total length ({}).", total_length));
+    }
+    let end = total_length.min(data.len());
+    let payload = data
+        .get(header_length..end)
+        .ok_or("Truncated IPv4 header.")?;
+
+    Ok(Some((source.into(), destination.into(), protocol, payload)))
+}
+
+fn decode_ipv6(data: &[u8]) -> Result<Option<NetworkLayer<'_>>, String> {
+    let payload_length = read_u16(data, 4)? as usize;
+    let mut next_header = read_u8(data, 6)?;
+    let source = Ipv6Addr::from(read_u128(data, 8)?);
+    let destination = Ipv6Addr::from(read_u128(data, 24)?);
+
+    let end = (IPV6_HEADER_LENGTH + payload_length).min(data.len());
+    let mut payload = data
+        .get(IPV6_HEADER_LENGTH..end)
+        .ok_or("Truncated IPv6 header.")?;
+
+    loop {
+        let extension_length = match next_header {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION_OPTIONS => {
+                (read_u8(payload, 1)? as usize + 1) * 8
+            }
+            IPV6_AUTHENTICATION => (read_u8(payload, 1)? as usize + 2) * 4,
+            IPV6_FRAGMENT => return Ok(None),
+            _ => break,
+        };
+        next_header = read_u8(payload, 0)?;
+        payload = slice_from(payload, extension_length)?;
+    }
+
+    Ok(Some((
+        source.into(),
+        destination.into(),
+        next_header,
+        payload,
+    )))
+}
+
+fn slice_from(data: &[u8], pos: usize) -> Result<&[u8], String> {
+    data.get(pos..).ok_or_else(|| {
+        format!(
+            "Frame too short ({} bytes, expected at least {}).",
+            data.len(),
+            pos
+        )
+    })
+}
+
+fn read_u8(data: &[u8], pos: usize) -> Result<u8, String> {
+    Ok(slice_from(data, pos)?
+        .first()
+        .copied()
+        .ok_or("Frame ends part-way through a header.")?)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, String> {
+    Ok((read_u8(data, pos)? as u16) << 8 | read_u8(data, pos + 1)? as u16)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, String> {
+    Ok((read_u16(data, pos)? as u32) << 16 | read_u16(data, pos + 2)? as u32)
+}
+
+fn read_u128(data: &[u8], pos: usize) -> Result<u128, String> {
+    let mut result = 0u128;
+    for i in 0..4 {
+        result = result << 32 | read_u32(data, pos + i * 4)? as u128;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_frame, TcpSegment, TransportPayload};
+    use crate::capture::LinkType;
+    use std::{error::Error, net::SocketAddr};
+
+    fn ipv4_udp_packet(flags_and_fragment_offset: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&(28 + payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 1]);
+        packet.extend_from_slice(&flags_and_fragment_offset.to_be_bytes());
+        packet.extend_from_slice(&[64, 17, 0, 0]);
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(&5353u16.to_be_bytes());
+        packet.extend_from_slice(&53u16.to_be_bytes());
+        packet.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn ethernet_frame(tags: &[u16], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        for tag in tags {
+            frame.extend_from_slice(&tag.to_be_bytes());
+            frame.extend_from_slice(&[0, 7]);
+        }
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn decodes_udp_over_ipv4_over_ethernet() -> Result<(), Box<dyn Error>> {
+        let frame = ethernet_frame(&[], 0x0800, &ipv4_udp_packet(0x4000, b"hello"));
+        let decoded = decode_frame(LinkType::Ethernet, &frame)?.expect("Expected a UDP datagram.");
+
+        assert_eq!(decoded.source, "10.0.0.1:5353".parse::<SocketAddr>()?);
+        assert_eq!(decoded.destination, "10.0.0.2:53".parse::<SocketAddr>()?);
+        assert_eq!(decoded.payload, TransportPayload::Udp(b"hello"));
+        Ok(())
+    }
+
+    #[test]
+    fn skips_vlan_tags() -> Result<(), Box<dyn Error>> {
+        let frame = ethernet_frame(&[0x88A8, 0x8100], 0x0800, &ipv4_udp_packet(0, b"hi"));
+        let decoded = decode_frame(LinkType::Ethernet, &frame)?.expect("Expected a UDP datagram.");
+        assert_eq!(decoded.payload, TransportPayload::Udp(b"hi"));
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_ip_fragments() -> Result<(), Box<dyn Error>> {
+        let first_fragment = ethernet_frame(&[], 0x0800, &ipv4_udp_packet(0x2000, b"part"));
+        assert!(decode_frame(LinkType::Ethernet, &first_fragment)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_non_ip_frames() -> Result<(), Box<dyn Error>> {
+        let arp = ethernet_frame(&[], 0x0806, &[0; 28]);
+        assert!(decode_frame(LinkType::Ethernet, &arp)?.is_none());
+        assert!(decode_frame(LinkType::Unknown(147), &arp)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn decodes_tcp_over_ipv6_with_extension_header() -> Result<(), Box<dyn Error>> {
+        let mut tcp = Vec::new();
+        tcp.extend_from_slice(&40000u16.to_be_bytes());
+        tcp.extend_from_slice(&53u16.to_be_bytes());
+        tcp.extend_from_slice(&1234u32.to_be_bytes());
+        tcp.extend_from_slice(&0u32.to_be_bytes());
+        tcp.extend_from_slice(&[0x50, 0x03, 0, 0, 0, 0, 0, 0]); // SYN + FIN
+        tcp.extend_from_slice(b"data");
+
+        // A hop-by-hop options header (8 bytes) sits between the IPv6 header and TCP.
+        let mut ipv6 = vec![0x60, 0, 0, 0];
+        ipv6.extend_from_slice(&(8 + tcp.len() as u16).to_be_bytes());
+        ipv6.extend_from_slice(&[0, 64]);
+        ipv6.extend_from_slice(&[0; 15]);
+        ipv6.push(1); // ::1
+        ipv6.extend_from_slice(&[0; 15]);
+        ipv6.push(2); // ::2
+        ipv6.extend_from_slice(&[6, 0, 0, 0, 0, 0, 0, 0]);
+        ipv6.extend_from_slice(&tcp);
+
+        let mut sll2 = vec![0x86, 0xDD];
+        sll2.extend_from_slice(&[0; 18]);
+        sll2.extend_from_slice(&ipv6);
+
+        let decoded = decode_frame(LinkType::LinuxSll2, &sll2)?.expect("Expected a TCP segment.");
+        assert_eq!(decoded.source, "[::1]:40000".parse::<SocketAddr>()?);
+        assert_eq!(decoded.destination, "[::2]:53".parse::<SocketAddr>()?);
+        assert_eq!(
+            decoded.payload,
+            TransportPayload::Tcp(TcpSegment {
+                sequence_number: 1234,
+                syn: true,
+                fin: true,
+                rst: false,
+                payload: b"data",
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fails_on_truncated_header() {
+        let frame = ethernet_frame(&[], 0x0800, &[0x45, 0, 0]);
+        assert!(decode_frame(LinkType::Ethernet, &frame).is_err());
+    }
+}
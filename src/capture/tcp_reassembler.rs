@@ -0,0 +1,233 @@
+use super::frame_decoder::TcpSegment;
+use std::{collections::HashMap, net::SocketAddr};
+
+// Stop holding on to out-of-order data for a stream once this much has piled up behind a gap.
+const MAX_PENDING_BYTES: usize = 1024 * 1024;
+
+#[derive(Default)]
+struct StreamState {
+    next_sequence_number: Option<u32>,
+    // Segments which arrived ahead of a gap, keyed by their starting sequence number.
+    out_of_order: Vec<(u32, Vec<u8>)>,
+    // In-order bytes which don't yet make up a complete length-prefixed message.
+    unframed: Vec<u8>,
+}
+
+/// Puts TCP byte streams back together and splits them into DNS messages using the two-byte length prefix.
+/// Each direction of a connection is tracked separately.
+#[derive(Default)]
+pub struct TcpReassembler {
+    streams: HashMap<(SocketAddr, SocketAddr), StreamState>,
+}
+
+impl TcpReassembler {
+    pub fn new() -> TcpReassembler {
+        TcpReassembler::default()
+    }
+
+    /// Feeds in one segment and returns any DNS messages (without their length prefix) which it completed.
+    pub fn push(
+        &mut self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        segment: &TcpSegment,
+    ) -> Vec<Vec<u8>> {
+        let key = (source, destination);
+        if segment.rst {
+            self.streams.remove(&key);
+            return Vec::new();
+        }
+
+        let stream = self.streams.entry(key).or_default();
+
+        // The SYN occupies one sequence number of its own.
+        let data_sequence_number = if segment.syn {
+            *stream = StreamState::default();
+            segment.sequence_number.wrapping_add(1)
+        } else {
+            segment.sequence_number
+        };
+        // If the capture started part-way through a connection, start from whatever we see first.
+        stream
+            .next_sequence_number
+            .get_or_insert(data_sequence_number);
+
+        if !segment.payload.is_empty() {
+            stream
+                .out_of_order
+                .push((data_sequence_number, segment.payload.to_vec()));
+        }
+        stream.consume_contiguous_segments();
+
+        let messages = stream.take_complete_messages();
+        let pending_bytes: usize = stream.out_of_order.iter().map(|(_, data)| data.len()).sum();
+
+        if (segment.fin && stream.out_of_order.is_empty()) || pending_bytes > MAX_PENDING_BYTES {
+            self.streams.remove(&key);
+        }
+        messages
+    }
+}
+
+impl StreamState {
+    fn consume_contiguous_segments(&mut self) {
+        let mut next = match self.next_sequence_number {
+            Some(next) => next,
+            None => return,
+        };
+
+        // Sequence numbers wrap, so "starts at or before next" has to be worked out with wrapping arithmetic.
+        while let Some(index) = self
+            .out_of_order
+            .iter()
+            .position(|(start, _)| (next.wrapping_sub(*start) as i32) >= 0)
+        {
+            let (start, data) = self.out_of_order.swap_remove(index);
+            let already_seen = next.wrapping_sub(start) as usize;
+
+            // Anything entirely behind `next` is a retransmission of data we already have.
+            if already_seen < data.len() {
+                self.unframed.extend_from_slice(&data[already_seen..]);
+                next = next.wrapping_add((data.len() - already_seen) as u32);
+            }
+        }
+        self.next_sequence_number = Some(next);
+    }
+
+    fn take_complete_messages(&mut self) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        let mut pos = 0;
+
+        while self.unframed.len() >= pos + 2 {
+            let length = (self.unframed[pos] as usize) << 8 | self.unframed[pos + 1] as usize;
+            if self.unframed.len() < pos + 2 + length {
+                break;
+            }
+            messages.push(self.unframed[pos + 2..pos + 2 + length].to_vec());
+            pos += 2 + length;
+        }
+        self.unframed.drain(..pos);
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TcpReassembler;
+    use crate::capture::frame_decoder::TcpSegment;
+    use std::net::SocketAddr;
+
+    fn segment(sequence_number: u32, payload: &[u8]) -> TcpSegment<'_> {
+        TcpSegment {
+            sequence_number,
+            syn: false,
+            fin: false,
+            rst: false,
+            payload,
+        }
+    }
+
+    fn endpoints() -> (SocketAddr, SocketAddr) {
+        (
+            "10.0.0.1:40000".parse().unwrap(),
+            "10.0.0.2:53".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn splits_pipelined_messages_in_one_segment() {
+        let (client, server) = endpoints();
+        let mut reassembler = TcpReassembler::new();
+
+        let messages =
+            reassembler.push(client, server, &segment(1, &[0, 2, b'a', b'b', 0, 1, b'c']));
+        assert_eq!(messages, vec![b"ab".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn joins_message_split_across_segments() {
+        let (client, server) = endpoints();
+        let mut reassembler = TcpReassembler::new();
+
+        assert!(reassembler
+            .push(client, server, &segment(100, &[0, 3, b'x']))
+            .is_empty());
+        let messages = reassembler.push(client, server, &segment(103, b"yz"));
+        assert_eq!(messages, vec![b"xyz".to_vec()]);
+    }
+
+    #[test]
+    fn reorders_out_of_order_segments_and_drops_retransmissions() {
+        let (client, server) = endpoints();
+        let mut reassembler = TcpReassembler::new();
+        let syn = TcpSegment {
+            syn: true,
+            ..segment(99, &[])
+        };
+
+        assert!(reassembler.push(client, server, &syn).is_empty());
+        assert!(reassembler
+            .push(client, server, &segment(102, b"cd"))
+            .is_empty());
+        assert_eq!(
+            reassembler.push(client, server, &segment(100, &[0, 2])),
+            vec![b"cd".to_vec()]
+        );
+        // Same bytes again: should not produce a second copy or corrupt the stream.
+        assert!(reassembler
+            .push(client, server, &segment(100, &[0, 2]))
+            .is_empty());
+        assert_eq!(
+            reassembler.push(client, server, &segment(104, &[0, 1, b'e'])),
+            vec![b"e".to_vec()]
+        );
+    }
+
+    #[test]
+    fn handles_sequence_number_wraparound() {
+        let (client, server) = endpoints();
+        let mut reassembler = TcpReassembler::new();
+
+        assert!(reassembler
+            .push(client, server, &segment(u32::MAX - 1, &[0, 3]))
+            .is_empty());
+        let messages = reassembler.push(client, server, &segment(0, b"abc"));
+        assert_eq!(messages, vec![b"abc".to_vec()]);
+    }
+
+    #[test]
+    fn keeps_directions_separate() {
+        let (client, server) = endpoints();
+        let mut reassembler = TcpReassembler::new();
+
+        assert!(reassembler
+            .push(client, server, &segment(1, &[0, 2, b'q']))
+            .is_empty());
+        assert!(reassembler
+            .push(server, client, &segment(1, &[0, 2, b'r']))
+            .is_empty());
+        assert_eq!(
+            reassembler.push(server, client, &segment(4, b"s")),
+            vec![b"rs".to_vec()]
+        );
+    }
+
+    #[test]
+    fn forgets_stream_on_reset() {
+        let (client, server) = endpoints();
+        let mut reassembler = TcpReassembler::new();
+        let reset = TcpSegment {
+            rst: true,
+            ..segment(3, &[])
+        };
+
+        assert!(reassembler
+            .push(client, server, &segment(1, &[0, 5, b'a']))
+            .is_empty());
+        reassembler.push(client, server, &reset);
+        assert_eq!(
+            reassembler.push(client, server, &segment(50, &[0, 1, b'b'])),
+            vec![b"b".to_vec()]
+        );
+    }
+}
@@ -0,0 +1,415 @@
+use crate::resolver::{
+    CacheLimits, Strategy, TcpLimits, DEFAULT_UPSTREAM, DEFAULT_UPSTREAM_RETRIES,
+    DEFAULT_UPSTREAM_TIMEOUT, DNS_OVER_HTTPS_PORT, DNS_OVER_TLS_PORT, ROOT_SERVERS,
+};
+use std::{
+    error::Error,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+const DEFAULT_PORT: u16 = 8000;
+const DNS_PORT: u16 = 53;
+
+/// How the server is set up, from a config file and command line flags. The file has one `key = value` setting
+/// per line, with `#` starting a comment:
+///
+/// ```text
+/// port = 8000
+/// upstream = 192.0.2.53
+/// upstream = [2001:db8::53]:5353
+/// strategy = round-robin
+/// dnssec = true
+/// ```
+///
+/// With `recursive = true` queries are resolved from the root servers instead of forwarded upstream, and
+/// `root_hint` lines replace the IANA root servers with others.
+///
+/// Each server is given `timeout_ms` milliseconds to answer before the next is tried, and when they've all been
+/// tried they're gone round again `retries` more times, waiting twice as long each round.
+///
+/// Queries are answered `workers` at a time, by default several for each core.
+///
+/// Queries are taken over TCP as well as UDP, from up to `tcp_connections` clients at once, with up to `tcp_pipeline`
+/// queries in flight on each connection. A connection with nothing in flight is closed after `tcp_idle_timeout_ms`
+/// milliseconds.
+///
+/// Given a `tls_certificate` chain and its `tls_key`, both PEM files, queries are also taken over TLS on `tls_port`
+/// (853 unless set), with the same limits as TCP. With `https = true` as well, they're also taken over HTTPS at
+/// /dns-query on `https_port` (443 unless set).
+///
+/// Answers are cached, both positive and negative, holding up to `cache_entries` of them in about `cache_memory`
+/// bytes, with TTLs clamped to between `min_ttl` and `max_ttl` seconds.
+///
+/// The same settings can be given as `--port`, `--upstream` (repeated for each server), `--strategy`, `--dnssec`,
+/// `--recursive`, `--root-hint` (also repeated), `--timeout-ms`, `--retries`, `--workers`, `--tcp-connections`,
+/// `--tcp-pipeline`, `--tcp-idle-timeout-ms`, `--tls-port`, `--tls-certificate`, `--tls-key`, `--https`,
+/// `--https-port`, `--cache-entries`, `--cache-memory`, `--min-ttl` and `--max-ttl`, and `--config` names the file to
+/// start from. Upstreams and root hints given on the command line replace the file's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub port: u16,
+    pub upstreams: Vec<SocketAddr>,
+    pub strategy: Strategy,
+    pub dnssec: bool,
+    pub recursive: bool,
+    pub root_hints: Vec<SocketAddr>,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub workers: Option<usize>,
+    pub tcp: TcpLimits,
+    pub tls_port: u16,
+    pub tls_certificate: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub https: bool,
+    pub https_port: u16,
+    pub cache: CacheLimits,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            port: DEFAULT_PORT,
+            upstreams: vec![DEFAULT_UPSTREAM],
+            strategy: Strategy::default(),
+            dnssec: false,
+            recursive: false,
+            root_hints: ROOT_SERVERS
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, DNS_PORT))
+                .collect(),
+            timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            retries: DEFAULT_UPSTREAM_RETRIES,
+            workers: None,
+            tcp: TcpLimits::default(),
+            tls_port: DNS_OVER_TLS_PORT,
+            tls_certificate: None,
+            tls_key: None,
+            https: false,
+            https_port: DNS_OVER_HTTPS_PORT,
+            cache: CacheLimits::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+        Ok(Config::parse(&text).map_err(|error| format!("{}: {}", path, error))?)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut upstreams = Vec::new();
+        let mut root_hints = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected `key = value`.", number + 1))?;
+            let (key, value) = (key.trim(), value.trim());
+            let result = match key {
+                "upstream" | "upstreams" => value
+                    .split(',')
+                    .map(|address| parse_upstream(address.trim()))
+                    .collect::<Result<Vec<_>, String>>()
+                    .map(|addresses| upstreams.extend(addresses)),
+                "root_hint" | "root_hints" => value
+                    .split(',')
+                    .map(|address| parse_upstream(address.trim()))
+                    .collect::<Result<Vec<_>, String>>()
+                    .map(|addresses| root_hints.extend(addresses)),
+                _ => config.set(key, value),
+            };
+            result.map_err(|error| format!("line {}: {}", number + 1, error))?;
+        }
+        if !upstreams.is_empty() {
+            config.upstreams = upstreams;
+        }
+        if !root_hints.is_empty() {
+            config.root_hints = root_hints;
+        }
+        Ok(config)
+    }
+
+    // Settings from command line arguments (without the program name), on top of the `--config` file if given.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, Box<dyn Error>> {
+        let args: Vec<String> = args.into_iter().collect();
+        let mut config = match args.iter().position(|arg| arg == "--config") {
+            Some(index) => Config::load(args.get(index + 1).ok_or("--config needs a file name.")?)?,
+            None => Config::default(),
+        };
+
+        let mut upstreams = Vec::new();
+        let mut root_hints = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                return Err(format!("Unexpected argument {:?}.", arg).into());
+            };
+            match flag {
+                "dnssec" => {
+                    config.dnssec = true;
+                    continue;
+                }
+                "recursive" => {
+                    config.recursive = true;
+                    continue;
+                }
+                "https" => {
+                    config.https = true;
+                    continue;
+                }
+                _ => {}
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("--{} needs a value.", flag))?;
+            match flag {
+                "config" => {}
+                "upstream" => upstreams.push(parse_upstream(value)?),
+                "root-hint" => root_hints.push(parse_upstream(value)?),
+                _ => config.set(&flag.replace('-', "_"), value)?,
+            }
+        }
+        if !upstreams.is_empty() {
+            config.upstreams = upstreams;
+        }
+        if !root_hints.is_empty() {
+            config.root_hints = root_hints;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "port" => {
+                self.port = value
+                    .parse()
+                    .map_err(|_| format!("Invalid port {:?}.", value))?
+            }
+            "tls_port" => {
+                self.tls_port = value
+                    .parse()
+                    .map_err(|_| format!("Invalid TLS port {:?}.", value))?
+            }
+            "https" => {
+                self.https = value
+                    .parse()
+                    .map_err(|_| format!("https should be true or false, not {:?}.", value))?
+            }
+            "https_port" => {
+                self.https_port = value
+                    .parse()
+                    .map_err(|_| format!("Invalid HTTPS port {:?}.", value))?
+            }
+            "tls_certificate" => self.tls_certificate = Some(PathBuf::from(value)),
+            "tls_key" => self.tls_key = Some(PathBuf::from(value)),
+            "strategy" => self.strategy = value.parse()?,
+            "dnssec" => {
+                self.dnssec = value
+                    .parse()
+                    .map_err(|_| format!("dnssec should be true or false, not {:?}.", value))?
+            }
+            "recursive" => {
+                self.recursive = value
+                    .parse()
+                    .map_err(|_| format!("recursive should be true or false, not {:?}.", value))?
+            }
+            "timeout_ms" => match number(key, value)? {
+                0 => return Err(String::from("timeout_ms should be at least 1.")),
+                millis => self.timeout = Duration::from_millis(millis),
+            },
+            "retries" => self.retries = number(key, value)?,
+            "workers" => match number(key, value)? {
+                0 => return Err(String::from("workers should be at least 1.")),
+                workers => self.workers = Some(workers),
+            },
+            "tcp_connections" => self.tcp.max_connections = number(key, value)?,
+            "tcp_pipeline" => match number(key, value)? {
+                0 => return Err(String::from("tcp_pipeline should be at least 1.")),
+                queries => self.tcp.max_pipelined = queries,
+            },
+            "tcp_idle_timeout_ms" => match number(key, value)? {
+                0 => return Err(String::from("tcp_idle_timeout_ms should be at least 1.")),
+                millis => self.tcp.idle_timeout = Duration::from_millis(millis),
+            },
+            "cache_entries" => self.cache.max_entries = number(key, value)?,
+            "cache_memory" => self.cache.max_bytes = number(key, value)?,
+            "min_ttl" => self.cache.min_ttl = number(key, value)?,
+            "max_ttl" => self.cache.max_ttl = number(key, value)?,
+            _ => return Err(format!("Unknown setting {:?}.", key)),
+        }
+        Ok(())
+    }
+}
+
+fn number<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} should be a whole number, not {:?}.", key, value))
+}
+
+// An upstream server's (or root hint's) address, on port 53 unless another is given: "192.0.2.1", "192.0.2.1:5353", "2001:db8::1"
+// or "[2001:db8::1]:5353".
+fn parse_upstream(text: &str) -> Result<SocketAddr, String> {
+    text.parse::<SocketAddr>()
+        .or_else(|_| {
+            text.parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        })
+        .map_err(|_| format!("Invalid upstream server address {:?}.", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_upstream, Config};
+    use crate::resolver::{CacheLimits, Strategy, TcpLimits, DEFAULT_UPSTREAM, ROOT_SERVERS};
+    use std::{error::Error, fs, path::PathBuf, time::Duration};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_upstream_addresses() -> Result<(), Box<dyn Error>> {
+        assert_eq!(parse_upstream("192.0.2.1")?, "192.0.2.1:53".parse()?);
+        assert_eq!(parse_upstream("192.0.2.1:5353")?, "192.0.2.1:5353".parse()?);
+        assert_eq!(parse_upstream("2001:db8::1")?, "[2001:db8::1]:53".parse()?);
+        assert_eq!(
+            parse_upstream("[2001:db8::1]:5353")?,
+            "[2001:db8::1]:5353".parse()?
+        );
+        assert!(parse_upstream("dns.example").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn defaults_to_public_upstream() -> Result<(), Box<dyn Error>> {
+        let config = Config::from_args(Vec::new())?;
+        assert_eq!(config, Config::default());
+        assert_eq!(config.upstreams, vec![DEFAULT_UPSTREAM]);
+        assert!(!config.recursive);
+        assert_eq!(config.root_hints.len(), ROOT_SERVERS.len());
+        assert_eq!(config.root_hints[0], "198.41.0.4:53".parse()?);
+        Ok(())
+    }
+
+    #[test]
+    fn parses_config_file() -> Result<(), Box<dyn Error>> {
+        let config = Config::parse(
+            "# Air-gapped resolvers\n\
+             port = 5300\n\
+             upstream = 10.0.0.53, 10.0.1.53:5353  # two sites\n\
+             upstream = [fd00::53]:53\n\
+             \n\
+             strategy = round-robin\n\
+             dnssec = true\n\
+             recursive = true\n\
+             root_hint = 10.0.0.1, fd00::1\n\
+             timeout_ms = 500\n\
+             retries = 0\n\
+             workers = 16\n\
+             tcp_connections = 32\n\
+             tcp_idle_timeout_ms = 5000\n\
+             tls_certificate = /etc/dns/chain.pem\n\
+             tls_key = /etc/dns/key.pem\n\
+             https = true\n\
+             cache_entries = 500\n\
+             max_ttl = 3600\n",
+        )?;
+        assert_eq!(config.port, 5300);
+        assert_eq!(
+            config.upstreams,
+            vec![
+                "10.0.0.53:53".parse()?,
+                "10.0.1.53:5353".parse()?,
+                "[fd00::53]:53".parse()?
+            ]
+        );
+        assert_eq!(config.strategy, Strategy::RoundRobin);
+        assert!(config.dnssec);
+        assert!(config.recursive);
+        assert_eq!(
+            config.root_hints,
+            vec!["10.0.0.1:53".parse()?, "[fd00::1]:53".parse()?]
+        );
+        assert_eq!(config.timeout, Duration::from_millis(500));
+        assert_eq!(config.retries, 0);
+        assert_eq!(config.workers, Some(16));
+        assert_eq!(config.tcp.max_connections, 32);
+        assert_eq!(config.tcp.idle_timeout, Duration::from_millis(5000));
+        assert_eq!(config.tcp.max_pipelined, TcpLimits::default().max_pipelined);
+        assert_eq!(config.tls_port, 853);
+        assert_eq!(
+            config.tls_certificate,
+            Some(PathBuf::from("/etc/dns/chain.pem"))
+        );
+        assert_eq!(config.tls_key, Some(PathBuf::from("/etc/dns/key.pem")));
+        assert!(config.https);
+        assert_eq!(config.https_port, 443);
+        assert_eq!(config.cache.max_entries, 500);
+        assert_eq!(config.cache.max_ttl, 3600);
+        assert_eq!(config.cache.min_ttl, CacheLimits::default().min_ttl);
+
+        assert!(Config::parse("port 53").is_err());
+        assert!(Config::parse("colour = blue").is_err());
+        assert!(Config::parse("strategy = fastest").is_err());
+        assert!(Config::parse("upstream = 10.0.0.300").is_err());
+        assert!(Config::parse("min_ttl = -1").is_err());
+        assert!(Config::parse("workers = 0").is_err());
+        assert!(Config::parse("timeout_ms = 0").is_err());
+        assert!(Config::parse("tcp_pipeline = 0").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() -> Result<(), Box<dyn Error>> {
+        let path =
+            std::env::temp_dir().join(format!("dns-config-test-{}.conf", std::process::id()));
+        fs::write(
+            &path,
+            "port = 5300\nupstream = 10.0.0.53\nstrategy = random\n",
+        )?;
+
+        let config = Config::from_args(args(&format!(
+            "--upstream 192.0.2.1 --config {} --upstream [2001:db8::1]:5353 --strategy round-robin --dnssec",
+            path.display()
+        )));
+        fs::remove_file(&path)?;
+        let config = config?;
+
+        assert_eq!(config.port, 5300);
+        assert_eq!(
+            config.upstreams,
+            vec!["192.0.2.1:53".parse()?, "[2001:db8::1]:5353".parse()?]
+        );
+        assert_eq!(config.strategy, Strategy::RoundRobin);
+        assert!(config.dnssec);
+        assert!(!config.recursive);
+
+        let config = Config::from_args(args(
+            "--recursive --root-hint 127.0.0.2:5353 --min-ttl 30 --cache-memory 1048576 --retries 5 \
+             --tls-port 8853 --https --https-port 8443",
+        ))?;
+        assert!(config.recursive);
+        assert_eq!(config.cache.min_ttl, 30);
+        assert_eq!(config.cache.max_bytes, 1048576);
+        assert_eq!(config.retries, 5);
+        assert_eq!(config.tls_port, 8853);
+        assert!(config.https);
+        assert_eq!(config.https_port, 8443);
+        assert_eq!(config.root_hints, vec!["127.0.0.2:5353".parse()?]);
+
+        assert!(Config::from_args(args("--port")).is_err());
+        assert!(Config::from_args(args("--port 70000")).is_err());
+        assert!(Config::from_args(args("8000")).is_err());
+        Ok(())
+    }
+}
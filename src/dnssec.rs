@@ -0,0 +1,12 @@
+mod canonical;
+mod denial;
+mod signature;
+#[cfg(test)]
+pub(crate) mod test_zones;
+mod trust_anchor;
+mod validator;
+
+pub use denial::{nsec3_hash, prove_denial, Denial};
+pub use signature::{ds_digest, ds_matches, verify_rrsig};
+pub use trust_anchor::TrustAnchor;
+pub use validator::{RecordSource, Security, Validator};
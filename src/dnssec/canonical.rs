@@ -0,0 +1,95 @@
+pub(crate) use crate::parser::labels;
+pub use crate::parser::{compare_names, is_subdomain, name_to_wire};
+use crate::parser::{DnsRecord, RRSet};
+
+pub fn label_count(name: &str) -> usize {
+    labels(name).count()
+}
+
+pub fn parent(name: &str) -> Option<&str> {
+    let name = name.trim_end_matches('.');
+    match name.split_once('.') {
+        Some((_, rest)) => Some(rest),
+        None if !name.is_empty() => Some(""),
+        None => None,
+    }
+}
+
+// The last `count` labels of `name`, e.g. suffix("a.b.example", 2) == "b.example".
+pub fn suffix(name: &str, count: usize) -> String {
+    let all: Vec<&str> = labels(name).collect();
+    all[all.len().saturating_sub(count)..].join(".")
+}
+
+// The data an RRSIG's signature covers (RFC 4034 section 3.1.8.1): the RRSIG's own fields up to the signature,
+// followed by every record of the RRset in canonical form and order.
+pub fn signed_data(rrsig: &DnsRecord, rrset: &[DnsRecord]) -> Result<Vec<u8>, String> {
+    let DnsRecord::RRSIG {
+        type_covered,
+        algorithm,
+        labels: rrsig_labels,
+        original_ttl,
+        signature_expiration,
+        signature_inception,
+        key_tag,
+        signer_name,
+        ..
+    } = rrsig
+    else {
+        return Err("Expected an RRSIG record.".into());
+    };
+
+    let mut data = Vec::new();
+    data.extend(type_covered.to_u16().to_be_bytes());
+    data.push(*algorithm);
+    data.push(*rrsig_labels);
+    data.extend(original_ttl.to_be_bytes());
+    data.extend(signature_expiration.to_be_bytes());
+    data.extend(signature_inception.to_be_bytes());
+    data.extend(key_tag.to_be_bytes());
+    data.extend(name_to_wire(signer_name));
+
+    let Some(first) = rrset.first() else {
+        return Err("Can't sign an empty RRset.".into());
+    };
+    // Records expanded from a wildcard are signed under the wildcard's name.
+    let owner = first.domain();
+    let owner = match label_count(owner) > *rrsig_labels as usize {
+        true => format!("*.{}", suffix(owner, *rrsig_labels as usize)),
+        false => owner.to_string(),
+    };
+
+    let mut set = RRSet::new(first.clone());
+    for record in &rrset[1..] {
+        set.add(record.clone())?;
+    }
+    if set.query_type() != *type_covered {
+        return Err(format!(
+            "RRSIG covers {}, not {}.",
+            type_covered,
+            set.query_type()
+        ));
+    }
+    data.extend(set.to_canonical_wire_as(&owner, *original_ttl)?);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_subdomain, parent, suffix};
+
+    #[test]
+    fn relates_names() {
+        assert!(is_subdomain("www.Example.com", "example.com"));
+        assert!(is_subdomain("example.com", "example.com"));
+        assert!(is_subdomain("example.com", ""));
+        assert!(!is_subdomain("badexample.com", "example.com"));
+        assert!(!is_subdomain("com", "example.com"));
+
+        assert_eq!(parent("www.example.com"), Some("example.com"));
+        assert_eq!(parent("com"), Some(""));
+        assert_eq!(parent(""), None);
+        assert_eq!(suffix("a.b.example", 2), "b.example");
+        assert_eq!(suffix("a.b.example", 0), "");
+    }
+}
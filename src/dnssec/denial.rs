@@ -0,0 +1,507 @@
+use super::canonical::{compare_names, is_subdomain, label_count, labels, name_to_wire, suffix};
+use crate::parser::{DnsRecord, QueryType};
+use data_encoding::BASE32HEX_NOPAD;
+use ring::digest;
+use std::cmp::Ordering;
+
+// SHA-1 is the only NSEC3 hash algorithm defined.
+const NSEC3_SHA1: u8 = 1;
+const NSEC3_OPT_OUT: u8 = 0x01;
+// RFC 9276 lets validators refuse to chase long hash chains.
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+const TYPE_DNAME: u16 = 39;
+
+// What an authenticated denial of existence proves.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Denial {
+    // The name doesn't exist.
+    NameError,
+    // The name exists, but not with the queried type. Holds the types it does have.
+    NoData(Vec<QueryType>),
+    // The name falls in an NSEC3 opt-out span, so there may be an unsigned delegation there.
+    OptOut,
+}
+
+// Works out what the (already validated) NSEC or NSEC3 records prove about `name` and `query_type`.
+pub fn prove_denial(
+    name: &str,
+    query_type: QueryType,
+    records: &[DnsRecord],
+) -> Result<Denial, String> {
+    let nsecs: Vec<Nsec> = records.iter().filter_map(Nsec::from_record).collect();
+    if !nsecs.is_empty() {
+        return nsec_denial(name, query_type, &nsecs);
+    }
+    let nsec3s = parse_nsec3s(name, records)?;
+    if !nsec3s.is_empty() {
+        return nsec3_denial(name, query_type, &nsec3s);
+    }
+    Err(format!(
+        "No NSEC or NSEC3 records to prove {} {} doesn't exist.",
+        name, query_type
+    ))
+}
+
+// An answer expanded from a wildcard is only valid if nothing closer to `name` exists (RFC 4035 section 5.3.4).
+pub fn prove_wildcard_expansion(
+    name: &str,
+    rrsig_labels: usize,
+    records: &[DnsRecord],
+) -> Result<(), String> {
+    let nsecs: Vec<Nsec> = records.iter().filter_map(Nsec::from_record).collect();
+    if nsecs.iter().any(|nsec| nsec.covers(name)) {
+        return Ok(());
+    }
+    let next_closer = suffix(name, rrsig_labels + 1);
+    let nsec3s = parse_nsec3s(name, records)?;
+    if nsec3s.iter().any(|nsec3| nsec3.covers(&next_closer)) {
+        return Ok(());
+    }
+    Err(format!(
+        "No proof that {} doesn't exist, so the wildcard answer is invalid.",
+        name
+    ))
+}
+
+fn no_data(name: &str, types: &[QueryType], query_type: QueryType) -> Result<Denial, String> {
+    if types.contains(&query_type) || types.contains(&QueryType::CNAME) {
+        return Err(format!(
+            "Denial record shows {} does have {} data.",
+            name, query_type
+        ));
+    }
+    Ok(Denial::NoData(types.to_vec()))
+}
+
+// Delegation points are signed by the parent, which can't speak for anything in the child zone except the DS.
+fn is_delegation(types: &[QueryType]) -> bool {
+    types.contains(&QueryType::NS) && !types.contains(&QueryType::SOA)
+}
+
+fn has_dname(types: &[QueryType]) -> bool {
+    types
+        .iter()
+        .any(|query_type| query_type.to_u16() == TYPE_DNAME)
+}
+
+fn wildcard_at(name: &str) -> String {
+    match name.is_empty() {
+        true => String::from("*"),
+        false => format!("*.{}", name),
+    }
+}
+
+struct Nsec<'a> {
+    owner: &'a str,
+    next: &'a str,
+    types: &'a [QueryType],
+}
+
+impl Nsec<'_> {
+    fn from_record(record: &DnsRecord) -> Option<Nsec<'_>> {
+        match record {
+            DnsRecord::NSEC {
+                domain,
+                next_domain,
+                types,
+                ..
+            } => Some(Nsec {
+                owner: domain,
+                next: next_domain,
+                types,
+            }),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        compare_names(self.owner, name) == Ordering::Equal
+    }
+
+    fn covers(&self, name: &str) -> bool {
+        let after_owner = compare_names(self.owner, name) == Ordering::Less;
+        let before_next = compare_names(name, self.next) == Ordering::Less;
+        match compare_names(self.owner, self.next) {
+            Ordering::Less => after_owner && before_next,
+            // The last NSEC in a zone points back to the apex.
+            _ => after_owner || before_next,
+        }
+    }
+}
+
+fn nsec_denial(name: &str, query_type: QueryType, nsecs: &[Nsec]) -> Result<Denial, String> {
+    if let Some(nsec) = nsecs.iter().find(|nsec| nsec.matches(name)) {
+        if is_delegation(nsec.types) && query_type != QueryType::DS {
+            return Err(format!(
+                "NSEC at delegation {} can't deny child data.",
+                name
+            ));
+        }
+        return no_data(name, nsec.types, query_type);
+    }
+
+    let covering = nsecs
+        .iter()
+        .find(|nsec| nsec.covers(name))
+        .ok_or_else(|| format!("No NSEC record covers {}.", name))?;
+    if is_subdomain(name, covering.owner)
+        && (is_delegation(covering.types) || has_dname(covering.types))
+    {
+        return Err(format!(
+            "NSEC at {} can't deny names below it.",
+            covering.owner
+        ));
+    }
+
+    // The closest encloser is the longest ancestor of the name which the NSEC shows to exist.
+    let closest_encloser = [covering.owner, covering.next]
+        .iter()
+        .map(|other| common_ancestor(name, other))
+        .max_by_key(|ancestor| label_count(ancestor))
+        .unwrap_or_default();
+
+    // If the wildcard there exists but lacks the type, it's a NODATA answer; if it doesn't exist at all, NXDOMAIN.
+    let wildcard = wildcard_at(&closest_encloser);
+    if let Some(nsec) = nsecs.iter().find(|nsec| nsec.matches(&wildcard)) {
+        return no_data(&wildcard, nsec.types, query_type);
+    }
+    match nsecs.iter().any(|nsec| nsec.covers(&wildcard)) {
+        true => Ok(Denial::NameError),
+        false => Err(format!("No NSEC record proves {} doesn't exist.", wildcard)),
+    }
+}
+
+fn common_ancestor(a: &str, b: &str) -> String {
+    let common: Vec<&str> = labels(a)
+        .rev()
+        .zip(labels(b).rev())
+        .take_while(|(a_label, b_label)| a_label.eq_ignore_ascii_case(b_label))
+        .map(|(a_label, _)| a_label)
+        .collect();
+    common.into_iter().rev().collect::<Vec<_>>().join(".")
+}
+
+struct Nsec3<'a> {
+    zone: String,
+    owner_hash: Vec<u8>,
+    next_hash: &'a [u8],
+    flags: u8,
+    iterations: u16,
+    salt: &'a [u8],
+    types: &'a [QueryType],
+}
+
+// NSEC3 records from the zone enclosing `name`, with their hashes decoded.
+fn parse_nsec3s<'a>(name: &str, records: &'a [DnsRecord]) -> Result<Vec<Nsec3<'a>>, String> {
+    let mut nsec3s = Vec::new();
+    for record in records {
+        let DnsRecord::NSEC3 {
+            domain,
+            hash_algorithm,
+            flags,
+            iterations,
+            salt,
+            next_hashed_owner,
+            types,
+            ..
+        } = record
+        else {
+            continue;
+        };
+        let Some((hash_label, zone)) = domain.split_once('.') else {
+            continue;
+        };
+        if !is_subdomain(name, zone) {
+            continue;
+        }
+        if *hash_algorithm != NSEC3_SHA1 {
+            return Err(format!(
+                "Unsupported NSEC3 hash algorithm {}.",
+                hash_algorithm
+            ));
+        }
+        if *iterations > MAX_NSEC3_ITERATIONS {
+            return Err(format!("NSEC3 iteration count {} is too high.", iterations));
+        }
+        let owner_hash = BASE32HEX_NOPAD
+            .decode(hash_label.to_ascii_uppercase().as_bytes())
+            .map_err(|_| format!("NSEC3 owner {} isn't a hash.", domain))?;
+
+        nsec3s.push(Nsec3 {
+            zone: zone.to_string(),
+            owner_hash,
+            next_hash: next_hashed_owner,
+            flags: *flags,
+            iterations: *iterations,
+            salt,
+            types,
+        });
+    }
+    Ok(nsec3s)
+}
+
+impl Nsec3<'_> {
+    fn matches(&self, name: &str) -> bool {
+        is_subdomain(name, &self.zone)
+            && nsec3_hash(name, self.salt, self.iterations) == self.owner_hash
+    }
+
+    fn covers(&self, name: &str) -> bool {
+        if !is_subdomain(name, &self.zone) {
+            return false;
+        }
+        let hash = nsec3_hash(name, self.salt, self.iterations);
+        let after_owner = self.owner_hash.as_slice() < hash.as_slice();
+        let before_next = hash.as_slice() < self.next_hash;
+        match self.owner_hash.as_slice() < self.next_hash {
+            true => after_owner && before_next,
+            false => after_owner || before_next,
+        }
+    }
+}
+
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut data = name_to_wire(name);
+    data.extend_from_slice(salt);
+    let mut hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data);
+    for _ in 0..iterations {
+        let mut data = hash.as_ref().to_vec();
+        data.extend_from_slice(salt);
+        hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data);
+    }
+    hash.as_ref().to_vec()
+}
+
+fn nsec3_denial(name: &str, query_type: QueryType, nsec3s: &[Nsec3]) -> Result<Denial, String> {
+    if let Some(nsec3) = nsec3s.iter().find(|nsec3| nsec3.matches(name)) {
+        if is_delegation(nsec3.types) && query_type != QueryType::DS {
+            return Err(format!(
+                "NSEC3 at delegation {} can't deny child data.",
+                name
+            ));
+        }
+        return no_data(name, nsec3.types, query_type);
+    }
+
+    // Closest encloser proof (RFC 5155 section 8.3): the longest existing ancestor, plus a covered next closer name.
+    let total_labels = label_count(name);
+    let (closest_encloser, encloser) = (0..total_labels)
+        .rev()
+        .map(|count| suffix(name, count))
+        .find_map(|candidate| {
+            nsec3s
+                .iter()
+                .find(|nsec3| nsec3.matches(&candidate))
+                .map(|nsec3| (candidate, nsec3))
+        })
+        .ok_or_else(|| format!("No NSEC3 record proves an ancestor of {} exists.", name))?;
+    if (is_delegation(encloser.types) && !closest_encloser.eq_ignore_ascii_case(&encloser.zone))
+        || has_dname(encloser.types)
+    {
+        return Err(format!(
+            "NSEC3 at {} can't deny names below it.",
+            closest_encloser
+        ));
+    }
+
+    let next_closer = suffix(name, label_count(&closest_encloser) + 1);
+    let next_closer_cover = nsec3s
+        .iter()
+        .find(|nsec3| nsec3.covers(&next_closer))
+        .ok_or_else(|| format!("No NSEC3 record covers {}.", next_closer))?;
+    if next_closer_cover.flags & NSEC3_OPT_OUT != 0 {
+        return Ok(Denial::OptOut);
+    }
+
+    let wildcard = wildcard_at(&closest_encloser);
+    if let Some(nsec3) = nsec3s.iter().find(|nsec3| nsec3.matches(&wildcard)) {
+        return no_data(&wildcard, nsec3.types, query_type);
+    }
+    match nsec3s.iter().any(|nsec3| nsec3.covers(&wildcard)) {
+        true => Ok(Denial::NameError),
+        false => Err(format!(
+            "No NSEC3 record proves {} doesn't exist.",
+            wildcard
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{nsec3_hash, prove_denial, prove_wildcard_expansion, Denial, NSEC3_OPT_OUT};
+    use crate::parser::{DnsRecord, QueryType};
+    use data_encoding::BASE32HEX_NOPAD;
+
+    const SALT: [u8; 4] = [0xAA, 0xBB, 0xCC, 0xDD];
+
+    fn nsec(owner: &str, next: &str, types: &[QueryType]) -> DnsRecord {
+        DnsRecord::NSEC {
+            domain: String::from(owner),
+            next_domain: String::from(next),
+            types: types.to_vec(),
+            ttl: 3600,
+        }
+    }
+
+    // An NSEC chain over example with names example, a.example, b.example (a delegation) and d.example.
+    fn nsec_chain() -> Vec<DnsRecord> {
+        vec![
+            nsec(
+                "example",
+                "a.example",
+                &[QueryType::NS, QueryType::SOA, QueryType::NSEC],
+            ),
+            nsec("a.example", "b.example", &[QueryType::A, QueryType::NSEC]),
+            nsec("b.example", "d.example", &[QueryType::NS, QueryType::NSEC]),
+            nsec("d.example", "example", &[QueryType::AAAA, QueryType::NSEC]),
+        ]
+    }
+
+    // An NSEC3 chain over the same kind of zone, hashed with the RFC 5155 appendix A parameters.
+    fn nsec3_chain(names: &[(&str, &[QueryType])], flags: u8) -> Vec<DnsRecord> {
+        let mut hashed: Vec<(Vec<u8>, &[QueryType])> = names
+            .iter()
+            .map(|(name, types)| (nsec3_hash(name, &SALT, 12), *types))
+            .collect();
+        hashed.sort();
+        (0..hashed.len())
+            .map(|i| {
+                let (hash, types) = &hashed[i];
+                DnsRecord::NSEC3 {
+                    domain: format!("{}.example", BASE32HEX_NOPAD.encode(hash).to_lowercase()),
+                    hash_algorithm: 1,
+                    flags,
+                    iterations: 12,
+                    salt: SALT.to_vec(),
+                    next_hashed_owner: hashed[(i + 1) % hashed.len()].0.clone(),
+                    types: types.to_vec(),
+                    ttl: 3600,
+                }
+            })
+            .collect()
+    }
+
+    fn example_nsec3_chain(flags: u8) -> Vec<DnsRecord> {
+        nsec3_chain(
+            &[
+                ("example", &[QueryType::NS, QueryType::SOA]),
+                ("a.example", &[QueryType::A]),
+                ("ns1.example", &[QueryType::A]),
+                ("w.example", &[]),
+                ("*.w.example", &[QueryType::UNKNOWN(15)]),
+            ],
+            flags,
+        )
+    }
+
+    #[test]
+    fn hashes_names_like_rfc_5155() {
+        let hash = |name| {
+            BASE32HEX_NOPAD
+                .encode(&nsec3_hash(name, &SALT, 12))
+                .to_lowercase()
+        };
+        assert_eq!(hash("example"), "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom");
+        assert_eq!(hash("A.Example"), "35mthgpgcu1qg68fab165klnsnk3dpvl");
+        assert_eq!(hash("*.w.example"), "r53bq7cc2uvmubfu5ocmm6pers9tk9en");
+    }
+
+    #[test]
+    fn nsec_proves_name_error() {
+        assert_eq!(
+            prove_denial("c.example", QueryType::A, &nsec_chain()),
+            Ok(Denial::NameError)
+        );
+        // After the last name, the chain wraps round to the apex.
+        assert_eq!(
+            prove_denial("z.example", QueryType::A, &nsec_chain()),
+            Ok(Denial::NameError)
+        );
+    }
+
+    #[test]
+    fn nsec_proves_no_data() {
+        assert_eq!(
+            prove_denial("a.example", QueryType::AAAA, &nsec_chain()),
+            Ok(Denial::NoData(vec![QueryType::A, QueryType::NSEC]))
+        );
+        assert!(prove_denial("a.example", QueryType::A, &nsec_chain()).is_err());
+    }
+
+    #[test]
+    fn nsec_at_delegation_only_denies_ds() {
+        assert!(matches!(
+            prove_denial("b.example", QueryType::DS, &nsec_chain()),
+            Ok(Denial::NoData(types)) if types.contains(&QueryType::NS)
+        ));
+        assert!(prove_denial("b.example", QueryType::A, &nsec_chain()).is_err());
+        assert!(prove_denial("x.b.example", QueryType::A, &nsec_chain()).is_err());
+    }
+
+    #[test]
+    fn nsec_without_wildcard_proof_is_rejected() {
+        // Only the NSEC covering c.example, not the one covering *.example.
+        let records = vec![nsec_chain().remove(2)];
+        assert!(prove_denial("c.example", QueryType::A, &records).is_err());
+    }
+
+    #[test]
+    fn nsec_wildcard_expansion_needs_covering_record() {
+        assert!(prove_wildcard_expansion("c.example", 1, &nsec_chain()).is_ok());
+        assert!(prove_wildcard_expansion("c.example", 1, &[]).is_err());
+    }
+
+    #[test]
+    fn nsec3_proves_name_error() {
+        assert_eq!(
+            prove_denial("b.example", QueryType::A, &example_nsec3_chain(0)),
+            Ok(Denial::NameError)
+        );
+    }
+
+    #[test]
+    fn nsec3_proves_no_data() {
+        assert_eq!(
+            prove_denial("ns1.example", QueryType::AAAA, &example_nsec3_chain(0)),
+            Ok(Denial::NoData(vec![QueryType::A]))
+        );
+        // An empty non-terminal exists with no types at all.
+        assert_eq!(
+            prove_denial("w.example", QueryType::A, &example_nsec3_chain(0)),
+            Ok(Denial::NoData(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn nsec3_wildcard_no_data() {
+        assert_eq!(
+            prove_denial("z.w.example", QueryType::A, &example_nsec3_chain(0)),
+            Ok(Denial::NoData(vec![QueryType::UNKNOWN(15)]))
+        );
+    }
+
+    #[test]
+    fn nsec3_opt_out_is_reported() {
+        assert_eq!(
+            prove_denial(
+                "unsigned.example",
+                QueryType::DS,
+                &example_nsec3_chain(NSEC3_OPT_OUT)
+            ),
+            Ok(Denial::OptOut)
+        );
+    }
+
+    #[test]
+    fn nsec3_rejects_missing_proof() {
+        let mut records = example_nsec3_chain(0);
+        // Drop whichever record covers the next closer name.
+        records.retain(|record| {
+            !super::parse_nsec3s("b.example", std::slice::from_ref(record))
+                .unwrap_or_default()
+                .iter()
+                .any(|nsec3| nsec3.covers("b.example"))
+        });
+        assert!(prove_denial("b.example", QueryType::A, &records).is_err());
+    }
+}
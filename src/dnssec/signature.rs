@@ -0,0 +1,350 @@
+use super::canonical::{is_subdomain, label_count, name_to_wire, signed_data};
+use crate::parser::DnsRecord;
+use ring::{digest, rsa, signature};
+
+// DNSSEC algorithm numbers we can verify.
+pub const RSASHA256: u8 = 8;
+pub const ECDSAP256SHA256: u8 = 13;
+pub const ECDSAP384SHA384: u8 = 14;
+pub const ED25519: u8 = 15;
+
+// DS digest types.
+pub const DIGEST_SHA1: u8 = 1;
+pub const DIGEST_SHA256: u8 = 2;
+pub const DIGEST_SHA384: u8 = 4;
+
+const DNSKEY_PROTOCOL: u8 = 3;
+const ZONE_KEY_FLAG: u16 = 0x0100;
+
+pub fn is_supported_algorithm(algorithm: u8) -> bool {
+    matches!(
+        algorithm,
+        RSASHA256 | ECDSAP256SHA256 | ECDSAP384SHA384 | ED25519
+    )
+}
+
+pub fn is_supported_digest(digest_type: u8) -> bool {
+    matches!(digest_type, DIGEST_SHA1 | DIGEST_SHA256 | DIGEST_SHA384)
+}
+
+// The digest a DS record would carry for `dnskey`, or None for digest types we don't support.
+pub fn ds_digest(dnskey: &DnsRecord, digest_type: u8) -> Option<Vec<u8>> {
+    let algorithm = match digest_type {
+        DIGEST_SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        DIGEST_SHA256 => &digest::SHA256,
+        DIGEST_SHA384 => &digest::SHA384,
+        _ => return None,
+    };
+    let DnsRecord::DNSKEY {
+        domain,
+        flags,
+        protocol,
+        algorithm: key_algorithm,
+        public_key,
+        ..
+    } = dnskey
+    else {
+        return None;
+    };
+
+    let mut data = name_to_wire(domain);
+    data.extend(flags.to_be_bytes());
+    data.push(*protocol);
+    data.push(*key_algorithm);
+    data.extend(public_key);
+    Some(digest::digest(algorithm, &data).as_ref().to_vec())
+}
+
+// Does the DS record refer to this DNSKEY? Only true for algorithms and digest types we support.
+pub fn ds_matches(ds: &DnsRecord, dnskey: &DnsRecord) -> bool {
+    let (
+        DnsRecord::DS {
+            domain: ds_domain,
+            key_tag,
+            algorithm,
+            digest_type,
+            digest,
+            ..
+        },
+        DnsRecord::DNSKEY {
+            domain: key_domain,
+            algorithm: key_algorithm,
+            ..
+        },
+    ) = (ds, dnskey)
+    else {
+        return false;
+    };
+
+    ds_domain.eq_ignore_ascii_case(key_domain)
+        && algorithm == key_algorithm
+        && is_supported_algorithm(*algorithm)
+        && dnskey.key_tag() == Some(*key_tag)
+        && ds_digest(dnskey, *digest_type).is_some_and(|expected| expected == *digest)
+}
+
+// Checks that `rrsig` is a valid signature by `dnskey` over `rrset` at time `now` (seconds since the epoch).
+pub fn verify_rrsig(
+    rrset: &[DnsRecord],
+    rrsig: &DnsRecord,
+    dnskey: &DnsRecord,
+    now: u32,
+) -> Result<(), String> {
+    let DnsRecord::RRSIG {
+        type_covered,
+        algorithm,
+        labels,
+        signature_expiration,
+        signature_inception,
+        key_tag,
+        signer_name,
+        signature,
+        ..
+    } = rrsig
+    else {
+        return Err("Expected an RRSIG record.".into());
+    };
+    let DnsRecord::DNSKEY {
+        domain: key_owner,
+        flags,
+        protocol,
+        algorithm: key_algorithm,
+        public_key,
+        ..
+    } = dnskey
+    else {
+        return Err("Expected a DNSKEY record.".into());
+    };
+    let Some(first) = rrset.first() else {
+        return Err("No records to verify.".into());
+    };
+    let owner = first.domain();
+
+    if rrset.iter().any(|record| {
+        record.query_type() != *type_covered || !record.domain().eq_ignore_ascii_case(owner)
+    }) {
+        return Err("RRSIG covers a different RRset.".into());
+    }
+    if !is_subdomain(owner, signer_name) || label_count(owner) < *labels as usize {
+        return Err(format!("RRSIG by {} can't cover {}.", signer_name, owner));
+    }
+    if !key_owner.eq_ignore_ascii_case(signer_name)
+        || flags & ZONE_KEY_FLAG == 0
+        || *protocol != DNSKEY_PROTOCOL
+        || key_algorithm != algorithm
+        || dnskey.key_tag() != Some(*key_tag)
+    {
+        return Err(format!(
+            "DNSKEY doesn't match RRSIG with key tag {}.",
+            key_tag
+        ));
+    }
+    // Validity times use serial number arithmetic (RFC 1982), so they keep working past 2106.
+    if (now.wrapping_sub(*signature_inception) as i32) < 0 {
+        return Err(format!("RRSIG for {} is not yet valid.", owner));
+    }
+    if (signature_expiration.wrapping_sub(now) as i32) < 0 {
+        return Err(format!("RRSIG for {} has expired.", owner));
+    }
+
+    let message = signed_data(rrsig, rrset)?;
+    verify_signature(*algorithm, public_key, &message, signature)
+        .map_err(|message| format!("Signature over {} {}: {}", owner, type_covered, message))
+}
+
+fn verify_signature(
+    algorithm: u8,
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), String> {
+    let verified = match algorithm {
+        RSASHA256 => {
+            let (exponent, modulus) = split_rsa_key(public_key)?;
+            rsa::PublicKeyComponents {
+                n: modulus,
+                e: exponent,
+            }
+            .verify(
+                &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                message,
+                signature,
+            )
+        }
+        ECDSAP256SHA256 | ECDSAP384SHA384 => {
+            // DNSKEYs hold the bare point; ring wants it in uncompressed SEC1 form.
+            let mut point = vec![0x04];
+            point.extend_from_slice(public_key);
+            let verification = match algorithm {
+                ECDSAP256SHA256 => &signature::ECDSA_P256_SHA256_FIXED,
+                _ => &signature::ECDSA_P384_SHA384_FIXED,
+            };
+            signature::UnparsedPublicKey::new(verification, point).verify(message, signature)
+        }
+        ED25519 => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(message, signature),
+        _ => return Err(format!("Unsupported algorithm {}.", algorithm)),
+    };
+    verified.map_err(|_| String::from("Signature is invalid."))
+}
+
+// RSA public keys are the exponent length (one byte, or zero then two bytes), the exponent, then the modulus
+// (RFC 3110 section 2).
+fn split_rsa_key(public_key: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    let (exponent_length, rest) = match public_key {
+        [0, high, low, rest @ ..] => (u16::from_be_bytes([*high, *low]) as usize, rest),
+        [length, rest @ ..] => (*length as usize, rest),
+        [] => return Err("Empty RSA public key.".into()),
+    };
+    if exponent_length == 0 || rest.len() <= exponent_length {
+        return Err("Malformed RSA public key.".into());
+    }
+    Ok(rest.split_at(exponent_length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ds_digest, ds_matches, verify_rrsig, DIGEST_SHA1, DIGEST_SHA256, ECDSAP256SHA256,
+        ECDSAP384SHA384, ED25519, RSASHA256,
+    };
+    use crate::{
+        dnssec::test_zones::{sign_rrset, TestKey},
+        parser::{DnsRecord, QueryType},
+    };
+    use data_encoding::{BASE64, HEXUPPER};
+    use std::{error::Error, net::Ipv4Addr};
+
+    const NOW: u32 = 1_760_000_000;
+
+    fn rrset() -> Vec<DnsRecord> {
+        [1, 2]
+            .into_iter()
+            .map(|i| DnsRecord::A {
+                domain: String::from("www.example.com"),
+                address: Ipv4Addr::new(192, 0, 2, i),
+                ttl: 300,
+            })
+            .collect()
+    }
+
+    fn check_algorithm(algorithm: u8) -> Result<(), Box<dyn Error>> {
+        let key = TestKey::generate(algorithm)?;
+        let dnskey = key.dnskey("example.com");
+        let records = rrset();
+        let rrsig = sign_rrset(&key, "example.com", &records, NOW)?;
+
+        verify_rrsig(&records, &rrsig, &dnskey, NOW)?;
+        // Record order doesn't matter, since signing uses canonical order.
+        let reversed: Vec<DnsRecord> = records.iter().rev().cloned().collect();
+        verify_rrsig(&reversed, &rrsig, &dnskey, NOW)?;
+
+        let mut tampered = records.clone();
+        tampered[0] = DnsRecord::A {
+            domain: String::from("www.example.com"),
+            address: Ipv4Addr::new(198, 51, 100, 1),
+            ttl: 300,
+        };
+        assert!(verify_rrsig(&tampered, &rrsig, &dnskey, NOW).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_rsa_sha256() -> Result<(), Box<dyn Error>> {
+        check_algorithm(RSASHA256)
+    }
+
+    #[test]
+    fn verifies_ecdsa_p256() -> Result<(), Box<dyn Error>> {
+        check_algorithm(ECDSAP256SHA256)
+    }
+
+    #[test]
+    fn verifies_ecdsa_p384() -> Result<(), Box<dyn Error>> {
+        check_algorithm(ECDSAP384SHA384)
+    }
+
+    #[test]
+    fn verifies_ed25519() -> Result<(), Box<dyn Error>> {
+        check_algorithm(ED25519)
+    }
+
+    #[test]
+    fn rejects_signature_outside_validity_period() -> Result<(), Box<dyn Error>> {
+        let key = TestKey::generate(ED25519)?;
+        let records = rrset();
+        let rrsig = sign_rrset(&key, "example.com", &records, NOW)?;
+        let dnskey = key.dnskey("example.com");
+
+        assert!(verify_rrsig(&records, &rrsig, &dnskey, NOW - 86400).is_err());
+        assert!(verify_rrsig(&records, &rrsig, &dnskey, NOW + 86400).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_key_from_another_zone() -> Result<(), Box<dyn Error>> {
+        let key = TestKey::generate(ED25519)?;
+        let records = rrset();
+        let rrsig = sign_rrset(&key, "example.com", &records, NOW)?;
+
+        assert!(verify_rrsig(&records, &rrsig, &key.dnskey("example.net"), NOW).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn wildcard_expansion_is_signed_under_the_wildcard() -> Result<(), Box<dyn Error>> {
+        let key = TestKey::generate(ED25519)?;
+        let wildcard = vec![DnsRecord::A {
+            domain: String::from("*.example.com"),
+            address: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        }];
+        let rrsig = sign_rrset(&key, "example.com", &wildcard, NOW)?;
+
+        let expanded = vec![DnsRecord::A {
+            domain: String::from("anything.example.com"),
+            address: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        }];
+        verify_rrsig(&expanded, &rrsig, &key.dnskey("example.com"), NOW)?;
+        Ok(())
+    }
+
+    // The DS examples in RFC 4034 section 5.4 and RFC 4509 section 2.3.
+    #[test]
+    fn computes_ds_digests() -> Result<(), Box<dyn Error>> {
+        let dnskey = DnsRecord::DNSKEY {
+            domain: String::from("dskey.example.com"),
+            flags: 256,
+            protocol: 3,
+            algorithm: 5,
+            public_key: BASE64.decode(
+                b"AQOeiiR0GOMYkDshWoSKz9XzfwJr1AYtsmx3TGkJaNXVbfi/2pHm822aJ5iI9BMzNXxeYCmZDRD99WYwYqUSdjMmmAphXdvxegXd/M5+X7OrzKBaMbCVdFLUUh6DhweJBjEVv5f2wwjM9XzcnOf+EPbtG9DMBmADjFDc2w/rljwvFw==",
+            )?,
+            ttl: 86400,
+        };
+        assert_eq!(
+            HEXUPPER.encode(&ds_digest(&dnskey, DIGEST_SHA1).unwrap_or_default()),
+            "2BB183AF5F22588179A53B0A98631FAD1A292118"
+        );
+        assert_eq!(
+            HEXUPPER.encode(&ds_digest(&dnskey, DIGEST_SHA256).unwrap_or_default()),
+            "D4B7D520E7BB5F0F67674A0CCEB1E3E0614B93C4F9E99B8383F6A1E4469DA50A"
+        );
+        assert_eq!(ds_digest(&dnskey, 99), None);
+        Ok(())
+    }
+
+    #[test]
+    fn matches_ds_to_key() -> Result<(), Box<dyn Error>> {
+        let key = TestKey::generate(ED25519)?;
+        let dnskey = key.dnskey("example.com");
+        let ds = key.ds("example.com");
+        assert!(ds_matches(&ds, &dnskey));
+
+        let other = TestKey::generate(ED25519)?.dnskey("example.com");
+        assert!(!ds_matches(&ds, &other));
+        assert_eq!(ds.query_type(), QueryType::DS);
+        Ok(())
+    }
+}
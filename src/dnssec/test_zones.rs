@@ -0,0 +1,497 @@
+// Locally signed zones served from memory, so validation can be tested without a network.
+
+use super::{
+    canonical::{compare_names, label_count, parent, signed_data},
+    signature::{ds_digest, DIGEST_SHA256, ECDSAP256SHA256, ECDSAP384SHA384, ED25519, RSASHA256},
+    trust_anchor::TrustAnchor,
+    validator::RecordSource,
+};
+use crate::parser::{
+    test_helpers::open_test_file, DnsPacket, DnsPacketBuilder, DnsRecord, QueryType, ResultCode,
+};
+use ring::{
+    rand::SystemRandom,
+    rsa::{KeyPair as RsaKeyPair, PublicKeyComponents},
+    signature::{
+        EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING,
+        ECDSA_P384_SHA384_FIXED_SIGNING, RSA_PKCS1_SHA256,
+    },
+};
+use std::{
+    cmp::Ordering,
+    error::Error,
+    io::Read,
+    net::Ipv4Addr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const RSA_SIGNING_KEY: &str = "rsa_signing_key.pk8";
+const SIGNATURE_LIFETIME: u32 = 3600;
+const KSK_FLAGS: u16 = 257;
+
+pub enum TestKey {
+    Rsa(RsaKeyPair),
+    Ecdsa(u8, EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl TestKey {
+    // ring can't generate RSA keys, so RSA uses a fixed key from test_data.
+    pub fn generate(algorithm: u8) -> Result<TestKey, Box<dyn Error>> {
+        let rng = SystemRandom::new();
+        Ok(match algorithm {
+            RSASHA256 => {
+                let mut pkcs8 = Vec::new();
+                open_test_file(String::from(RSA_SIGNING_KEY))?.read_to_end(&mut pkcs8)?;
+                TestKey::Rsa(RsaKeyPair::from_pkcs8(&pkcs8).map_err(|e| e.to_string())?)
+            }
+            ECDSAP256SHA256 | ECDSAP384SHA384 => {
+                let signing = match algorithm {
+                    ECDSAP256SHA256 => &ECDSA_P256_SHA256_FIXED_SIGNING,
+                    _ => &ECDSA_P384_SHA384_FIXED_SIGNING,
+                };
+                let pkcs8 =
+                    EcdsaKeyPair::generate_pkcs8(signing, &rng).map_err(|e| e.to_string())?;
+                let key_pair = EcdsaKeyPair::from_pkcs8(signing, pkcs8.as_ref(), &rng)
+                    .map_err(|e| e.to_string())?;
+                TestKey::Ecdsa(algorithm, key_pair)
+            }
+            ED25519 => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).map_err(|e| e.to_string())?;
+                TestKey::Ed25519(
+                    Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|e| e.to_string())?,
+                )
+            }
+            _ => return Err(format!("No test keys for algorithm {}.", algorithm).into()),
+        })
+    }
+
+    pub fn algorithm(&self) -> u8 {
+        match self {
+            TestKey::Rsa(_) => RSASHA256,
+            TestKey::Ecdsa(algorithm, _) => *algorithm,
+            TestKey::Ed25519(_) => ED25519,
+        }
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        match self {
+            TestKey::Rsa(key_pair) => {
+                let components = PublicKeyComponents::<Vec<u8>>::from(key_pair.public());
+                let mut key = vec![components.e.len() as u8];
+                key.extend(&components.e);
+                key.extend(&components.n);
+                key
+            }
+            TestKey::Ecdsa(_, key_pair) => key_pair.public_key().as_ref()[1..].to_vec(),
+            TestKey::Ed25519(key_pair) => key_pair.public_key().as_ref().to_vec(),
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        let rng = SystemRandom::new();
+        match self {
+            TestKey::Rsa(key_pair) => {
+                let mut signature = vec![0; key_pair.public().modulus_len()];
+                key_pair
+                    .sign(&RSA_PKCS1_SHA256, &rng, message, &mut signature)
+                    .expect("RSA signing failed.");
+                signature
+            }
+            TestKey::Ecdsa(_, key_pair) => key_pair
+                .sign(&rng, message)
+                .expect("ECDSA signing failed.")
+                .as_ref()
+                .to_vec(),
+            TestKey::Ed25519(key_pair) => key_pair.sign(message).as_ref().to_vec(),
+        }
+    }
+
+    pub fn dnskey(&self, zone: &str) -> DnsRecord {
+        DnsRecord::DNSKEY {
+            domain: zone.to_string(),
+            flags: KSK_FLAGS,
+            protocol: 3,
+            algorithm: self.algorithm(),
+            public_key: self.public_key(),
+            ttl: 3600,
+        }
+    }
+
+    pub fn ds(&self, zone: &str) -> DnsRecord {
+        let dnskey = self.dnskey(zone);
+        DnsRecord::DS {
+            domain: zone.to_string(),
+            key_tag: dnskey.key_tag().unwrap_or_default(),
+            algorithm: self.algorithm(),
+            digest_type: DIGEST_SHA256,
+            digest: ds_digest(&dnskey, DIGEST_SHA256).unwrap_or_default(),
+            ttl: 3600,
+        }
+    }
+}
+
+// Signs `rrset` as `signer`, valid for an hour either side of `now`.
+pub fn sign_rrset(
+    key: &TestKey,
+    signer: &str,
+    rrset: &[DnsRecord],
+    now: u32,
+) -> Result<DnsRecord, String> {
+    let first = rrset.first().ok_or("Nothing to sign.")?;
+    let owner = first.domain();
+    let labels = label_count(owner) - owner.starts_with("*.") as usize;
+
+    let mut rrsig = DnsRecord::RRSIG {
+        domain: owner.to_string(),
+        type_covered: first.query_type(),
+        algorithm: key.algorithm(),
+        labels: labels as u8,
+        original_ttl: first.ttl(),
+        signature_expiration: now + SIGNATURE_LIFETIME,
+        signature_inception: now - SIGNATURE_LIFETIME,
+        key_tag: key.dnskey(signer).key_tag().unwrap_or_default(),
+        signer_name: signer.to_string(),
+        signature: Vec::new(),
+        ttl: first.ttl(),
+    };
+    let data = signed_data(&rrsig, rrset)?;
+    if let DnsRecord::RRSIG { signature, .. } = &mut rrsig {
+        *signature = key.sign(&data);
+    }
+    Ok(rrsig)
+}
+
+pub fn a_record(domain: &str, last_octet: u8) -> DnsRecord {
+    DnsRecord::A {
+        domain: domain.to_string(),
+        address: Ipv4Addr::new(192, 0, 2, last_octet),
+        ttl: 300,
+    }
+}
+
+fn cname_record(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::CNAME {
+        domain: domain.to_string(),
+        host: host.to_string(),
+        ttl: 300,
+    }
+}
+
+pub struct TestZone {
+    pub name: String,
+    key: Option<TestKey>,
+    records: Vec<DnsRecord>,
+}
+
+impl TestZone {
+    pub fn signed(name: &str, algorithm: u8) -> Result<TestZone, Box<dyn Error>> {
+        let key = TestKey::generate(algorithm)?;
+        let mut zone = TestZone::unsigned(name);
+        zone.records.push(key.dnskey(name));
+        zone.key = Some(key);
+        Ok(zone)
+    }
+
+    pub fn unsigned(name: &str) -> TestZone {
+        TestZone {
+            name: name.to_string(),
+            key: None,
+            records: vec![DnsRecord::SOA {
+                domain: name.to_string(),
+                primary_name_server: format!("ns.{}", name),
+                mailbox: format!("hostmaster.{}", name),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+                ttl: 3600,
+            }],
+        }
+    }
+
+    pub fn add(&mut self, record: DnsRecord) {
+        self.records.push(record);
+    }
+
+    fn records_at<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a DnsRecord> + 'a {
+        self.records
+            .iter()
+            .filter(move |record| compare_names(record.domain(), name) == Ordering::Equal)
+    }
+
+    fn owner_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .records
+            .iter()
+            .map(|record| record.domain().to_string())
+            .collect();
+        names.sort_by(|a, b| compare_names(a, b));
+        names.dedup();
+        names
+    }
+
+    fn nsec_for(&self, index: usize) -> DnsRecord {
+        let names = self.owner_names();
+        let name = &names[index];
+        let mut types: Vec<QueryType> = self.records_at(name).map(DnsRecord::query_type).collect();
+        types.extend([QueryType::RRSIG, QueryType::NSEC]);
+        DnsRecord::NSEC {
+            domain: name.clone(),
+            next_domain: names[(index + 1) % names.len()].clone(),
+            types,
+            ttl: 300,
+        }
+    }
+
+    fn nsec_covering(&self, name: &str) -> Option<DnsRecord> {
+        let names = self.owner_names();
+        let index = names
+            .iter()
+            .rposition(|owner| compare_names(owner, name) != Ordering::Greater)?;
+        Some(self.nsec_for(index))
+    }
+
+    fn nsec_at(&self, name: &str) -> Option<DnsRecord> {
+        let names = self.owner_names();
+        let index = names
+            .iter()
+            .position(|owner| compare_names(owner, name) == Ordering::Equal)?;
+        Some(self.nsec_for(index))
+    }
+
+    // Adds an RRset to a section along with its signature, if the zone is signed.
+    fn add_signed(&self, section: &mut Vec<DnsRecord>, rrset: Vec<DnsRecord>, now: u32) {
+        if let (Some(key), Some(_)) = (&self.key, rrset.first()) {
+            // NS records at a delegation belong to the child, so the parent doesn't sign them.
+            let delegation = rrset[0].query_type() == QueryType::NS
+                && compare_names(rrset[0].domain(), &self.name) != Ordering::Equal;
+            if !delegation {
+                section.push(sign_rrset(key, &self.name, &rrset, now).expect("Signing failed."));
+            }
+        }
+        section.extend(rrset);
+    }
+
+    fn add_denial(&self, section: &mut Vec<DnsRecord>, nsec: Option<DnsRecord>, now: u32) {
+        if let (Some(nsec), Some(_)) = (nsec, &self.key) {
+            if !section.contains(&nsec) {
+                self.add_signed(section, vec![nsec], now);
+            }
+        }
+    }
+}
+
+/// A hierarchy of zones below a signed root, answering queries the way a recursive resolver would.
+pub struct TestHierarchy {
+    zones: Vec<TestZone>,
+    pub signing_time: u32,
+    // Answers for these names have their data changed after signing.
+    pub tampered: Vec<String>,
+    // Answers for these names have their signatures removed.
+    pub stripped: Vec<String>,
+}
+
+impl TestHierarchy {
+    pub fn new(root_algorithm: u8) -> Result<TestHierarchy, Box<dyn Error>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+        Ok(TestHierarchy {
+            zones: vec![TestZone::signed("", root_algorithm)?],
+            signing_time: now,
+            tampered: Vec::new(),
+            stripped: Vec::new(),
+        })
+    }
+
+    pub fn trust_anchor(&self) -> TrustAnchor {
+        let key = self.zones[0].key.as_ref().expect("The root is signed.");
+        TrustAnchor::new(vec![key.ds("")])
+    }
+
+    // Adds a zone, delegating to it from the closest enclosing zone (with a DS record if it's signed).
+    pub fn add_zone(&mut self, zone: TestZone) {
+        let parent_index = self.zone_index(&zone.name, true);
+        let ds = zone.key.as_ref().map(|key| key.ds(&zone.name));
+        let parent = &mut self.zones[parent_index];
+        parent.add(DnsRecord::NS {
+            domain: zone.name.clone(),
+            host: format!("ns.{}", zone.name),
+            ttl: 3600,
+        });
+        if let Some(ds) = ds {
+            parent.add(ds);
+        }
+        self.zones.push(zone);
+    }
+
+    // The deepest zone containing `name`, or the deepest strictly above it.
+    fn zone_index(&self, name: &str, strictly_above: bool) -> usize {
+        let mut best = 0;
+        for (i, zone) in self.zones.iter().enumerate() {
+            let encloses = super::canonical::is_subdomain(name, &zone.name)
+                && !(strictly_above && compare_names(name, &zone.name) == Ordering::Equal);
+            if encloses && label_count(&zone.name) >= label_count(&self.zones[best].name) {
+                best = i;
+            }
+        }
+        best
+    }
+
+    fn respond(&self, name: &str, query_type: QueryType) -> DnsPacket {
+        // DS records are served from the parent side of the cut.
+        let zone = &self.zones[self.zone_index(name, query_type == QueryType::DS)];
+        let now = self.signing_time;
+        let mut packet = DnsPacketBuilder::new()
+            .response(true)
+            .question(name, query_type)
+            .build();
+
+        let answers: Vec<DnsRecord> = zone
+            .records_at(name)
+            .filter(|record| record.query_type() == query_type)
+            .cloned()
+            .collect();
+        let wildcard = format!("*.{}", parent(name).unwrap_or_default());
+        let wildcard_answers: Vec<DnsRecord> = zone
+            .records_at(&wildcard)
+            .filter(|record| record.query_type() == query_type)
+            .cloned()
+            .collect();
+        let exists = zone.records_at(name).next().is_some();
+        let cname = zone
+            .records_at(name)
+            .find(|record| matches!(record, DnsRecord::CNAME { .. }))
+            .filter(|_| query_type != QueryType::CNAME);
+        let dname = zone
+            .records
+            .iter()
+            .find(|record| !matches!(record.synthesize_cname(name), Ok(None)));
+
+        if let Some(dname) = dname {
+            // Names below a DNAME get it, the CNAME it implies and whatever that CNAME leads to.
+            zone.add_signed(&mut packet.answers, vec![dname.clone()], now);
+            match dname.synthesize_cname(name) {
+                Ok(Some(cname)) => {
+                    if let DnsRecord::CNAME { host, .. } = &cname {
+                        let target = self.respond(host, query_type);
+                        packet.header.rescode = target.header.rescode;
+                        packet.answers.push(cname.clone());
+                        packet.answers.extend(target.answers);
+                        packet.authorities.extend(target.authorities);
+                    }
+                }
+                Err(rescode) => packet.header.rescode = rescode,
+                Ok(None) => {}
+            }
+        } else if let Some(cname) = cname {
+            // A CNAME comes with whatever its target has, or the proof that that doesn't exist.
+            zone.add_signed(&mut packet.answers, vec![cname.clone()], now);
+            if let DnsRecord::CNAME { host, .. } = cname {
+                let target = self.respond(host, query_type);
+                packet.header.rescode = target.header.rescode;
+                packet.answers.extend(target.answers);
+                packet.authorities.extend(target.authorities);
+            }
+        } else if !answers.is_empty() {
+            zone.add_signed(&mut packet.answers, answers, now);
+        } else if !exists && !wildcard_answers.is_empty() {
+            // Sign the wildcard, then rename the records to the query name.
+            let mut signed = Vec::new();
+            zone.add_signed(&mut signed, wildcard_answers, now);
+            for mut record in signed {
+                rename(&mut record, name);
+                packet.answers.push(record);
+            }
+            zone.add_denial(&mut packet.authorities, zone.nsec_covering(name), now);
+        } else {
+            let soa: Vec<DnsRecord> = zone
+                .records_at(&zone.name)
+                .filter(|record| record.query_type() == QueryType::SOA)
+                .cloned()
+                .collect();
+            zone.add_signed(&mut packet.authorities, soa, now);
+            if exists {
+                zone.add_denial(&mut packet.authorities, zone.nsec_at(name), now);
+            } else {
+                packet.header.rescode = ResultCode::NXDOMAIN;
+                zone.add_denial(&mut packet.authorities, zone.nsec_covering(name), now);
+                let closest_encloser = zone
+                    .owner_names()
+                    .into_iter()
+                    .filter(|owner| super::canonical::is_subdomain(name, owner))
+                    .max_by_key(|owner| label_count(owner))
+                    .unwrap_or_default();
+                let wildcard = match closest_encloser.is_empty() {
+                    true => String::from("*"),
+                    false => format!("*.{}", closest_encloser),
+                };
+                zone.add_denial(&mut packet.authorities, zone.nsec_covering(&wildcard), now);
+            }
+        }
+
+        if self.tampered.iter().any(|tampered| tampered == name) {
+            for record in packet.answers.iter_mut() {
+                if let DnsRecord::A { address, .. } = record {
+                    *address = Ipv4Addr::new(198, 51, 100, 66);
+                }
+            }
+        }
+        if self.stripped.iter().any(|stripped| stripped == name) {
+            packet
+                .answers
+                .retain(|record| !matches!(record, DnsRecord::RRSIG { .. }));
+        }
+        packet
+    }
+}
+
+// A signed root delegating to a signed "example", which delegates to a signed and an unsigned child. Names below
+// "old.example" are redirected to "secure.example" by a DNAME, and "alias.example" and "gone.example" are CNAMEs
+// for "www.example" and a name that doesn't exist.
+pub fn example_hierarchy() -> Result<TestHierarchy, Box<dyn Error>> {
+    let mut hierarchy = TestHierarchy::new(RSASHA256)?;
+
+    let mut example = TestZone::signed("example", ED25519)?;
+    example.add(a_record("www.example", 1));
+    example.add(cname_record("alias.example", "www.example"));
+    example.add(cname_record("gone.example", "missing.example"));
+    example.add(DnsRecord::DNAME {
+        domain: String::from("old.example"),
+        target: String::from("secure.example"),
+        ttl: 300,
+    });
+    hierarchy.add_zone(example);
+
+    let mut secure = TestZone::signed("secure.example", ECDSAP256SHA256)?;
+    secure.add(a_record("www.secure.example", 2));
+    secure.add(a_record("*.secure.example", 3));
+    // An MX record, which the parser has no variant for: preference 10, then mail.secure.example.
+    secure.add(DnsRecord::UNKNOWN {
+        domain: String::from("secure.example"),
+        query_type: 15,
+        data: b"\x00\x0a\x04mail\x06secure\x07example\x00".to_vec(),
+        ttl: 300,
+    });
+    hierarchy.add_zone(secure);
+
+    let mut insecure = TestZone::unsigned("insecure.example");
+    insecure.add(a_record("www.insecure.example", 4));
+    hierarchy.add_zone(insecure);
+    Ok(hierarchy)
+}
+
+fn rename(record: &mut DnsRecord, name: &str) {
+    match record {
+        DnsRecord::A { domain, .. }
+        | DnsRecord::AAAA { domain, .. }
+        | DnsRecord::RRSIG { domain, .. } => *domain = name.to_string(),
+        _ => {}
+    }
+}
+
+impl RecordSource for TestHierarchy {
+    fn query(&mut self, name: &str, query_type: QueryType) -> Result<DnsPacket, Box<dyn Error>> {
+        Ok(self.respond(name, query_type))
+    }
+}
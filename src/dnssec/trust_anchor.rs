@@ -0,0 +1,105 @@
+use super::signature::{ds_matches, DIGEST_SHA256, RSASHA256};
+use crate::parser::DnsRecord;
+use data_encoding::HEXUPPER;
+
+// The root zone KSKs published by IANA (https://data.iana.org/root-anchors/root-anchors.xml).
+const IANA_ROOT_ANCHORS: [(u16, &str); 2] = [
+    (
+        20326, // KSK-2017
+        "E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+    ),
+    (
+        38696, // KSK-2024
+        "683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+    ),
+];
+
+/// The keys trusted to sign the root zone's DNSKEY set, given either as DS records or as the DNSKEYs themselves.
+#[derive(Clone, Debug)]
+pub struct TrustAnchor {
+    records: Vec<DnsRecord>,
+}
+
+impl TrustAnchor {
+    pub fn new(records: Vec<DnsRecord>) -> TrustAnchor {
+        TrustAnchor { records }
+    }
+
+    pub fn iana_root() -> TrustAnchor {
+        let records = IANA_ROOT_ANCHORS
+            .iter()
+            .map(|(key_tag, digest)| DnsRecord::DS {
+                domain: String::new(),
+                key_tag: *key_tag,
+                algorithm: RSASHA256,
+                digest_type: DIGEST_SHA256,
+                digest: HEXUPPER
+                    .decode(digest.as_bytes())
+                    .expect("Root anchor digests are valid hex."),
+                ttl: 0,
+            })
+            .collect();
+        TrustAnchor::new(records)
+    }
+
+    pub fn trusts(&self, dnskey: &DnsRecord) -> bool {
+        self.records.iter().any(|anchor| match anchor {
+            DnsRecord::DS { .. } => ds_matches(anchor, dnskey),
+            DnsRecord::DNSKEY { .. } => anchor == dnskey || same_key(anchor, dnskey),
+            _ => false,
+        })
+    }
+}
+
+// DNSKEYs are compared on their data alone, since the TTL in an anchor is meaningless.
+fn same_key(a: &DnsRecord, b: &DnsRecord) -> bool {
+    matches!(
+        (a, b),
+        (
+            DnsRecord::DNSKEY { domain: a_domain, flags: a_flags, protocol: a_protocol, algorithm: a_algorithm, public_key: a_key, .. },
+            DnsRecord::DNSKEY { domain: b_domain, flags: b_flags, protocol: b_protocol, algorithm: b_algorithm, public_key: b_key, .. },
+        ) if a_domain.eq_ignore_ascii_case(b_domain)
+            && (a_flags, a_protocol, a_algorithm, a_key) == (b_flags, b_protocol, b_algorithm, b_key)
+    )
+}
+
+impl Default for TrustAnchor {
+    fn default() -> Self {
+        TrustAnchor::iana_root()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TrustAnchor;
+    use crate::{
+        dnssec::{signature::ED25519, test_zones::TestKey},
+        parser::DnsRecord,
+    };
+    use std::error::Error;
+
+    #[test]
+    fn trusts_keys_matching_ds_or_dnskey() -> Result<(), Box<dyn Error>> {
+        let key = TestKey::generate(ED25519)?;
+        let other = TestKey::generate(ED25519)?;
+
+        let by_ds = TrustAnchor::new(vec![key.ds("")]);
+        assert!(by_ds.trusts(&key.dnskey("")));
+        assert!(!by_ds.trusts(&other.dnskey("")));
+
+        let mut dnskey = key.dnskey("");
+        let by_key = TrustAnchor::new(vec![dnskey.clone()]);
+        if let DnsRecord::DNSKEY { ttl, .. } = &mut dnskey {
+            *ttl = 1;
+        }
+        assert!(by_key.trusts(&dnskey));
+        assert!(!by_key.trusts(&other.dnskey("")));
+        Ok(())
+    }
+
+    #[test]
+    fn iana_root_has_current_ksks() {
+        let anchor = TrustAnchor::iana_root();
+        assert_eq!(anchor.records.len(), 2);
+    }
+}
@@ -0,0 +1,660 @@
+use super::{
+    canonical::{compare_names, is_subdomain, label_count, parent, suffix},
+    denial::{prove_denial, prove_wildcard_expansion, Denial},
+    signature::{ds_matches, is_supported_algorithm, is_supported_digest, verify_rrsig},
+    trust_anchor::TrustAnchor,
+};
+use crate::parser::{DnsPacket, DnsRecord, QueryType, ResultCode};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    error::Error,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// How long to remember that a name is an insecure delegation or isn't a zone cut at all.
+const UNSIGNED_ZONE_CACHE_TIME: Duration = Duration::from_secs(300);
+
+/// Somewhere the validator can fetch the DS and DNSKEY records it needs to build a chain of trust.
+pub trait RecordSource {
+    fn query(&mut self, name: &str, query_type: QueryType) -> Result<DnsPacket, Box<dyn Error>>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Security {
+    // Every RRset in the response chains back to the trust anchor.
+    Secure,
+    // Some of the response comes from a zone which has provably opted out of DNSSEC.
+    Insecure,
+    // Signatures are missing, invalid or don't chain to the trust anchor. Holds the reason.
+    Bogus(String),
+}
+
+#[derive(Clone, Debug)]
+enum ZoneStatus {
+    // A zone with a validated DNSKEY set.
+    Secure(Vec<DnsRecord>),
+    // A delegation without a DS record, below which nothing can be validated.
+    Insecure,
+    // A name which isn't a zone cut.
+    NotAZone,
+}
+
+enum Verified {
+    // `wildcard_labels` is set when the RRset was expanded from a wildcard with that many labels.
+    Secure { wildcard_labels: Option<usize> },
+    Insecure,
+}
+
+type SignedRRset = (Vec<DnsRecord>, Vec<DnsRecord>);
+
+/// Validates responses against a chain of trust running from the trust anchor through DS and DNSKEY records
+/// (RFC 4035 section 5). Zone keys are cached between responses for as long as their TTLs allow.
+pub struct Validator {
+    trust_anchor: TrustAnchor,
+    zones: HashMap<String, (ZoneStatus, Instant)>,
+    in_progress: HashSet<String>,
+}
+
+impl Validator {
+    pub fn new(trust_anchor: TrustAnchor) -> Validator {
+        Validator {
+            trust_anchor,
+            zones: HashMap::new(),
+            in_progress: HashSet::new(),
+        }
+    }
+
+    pub fn validate(&mut self, source: &mut dyn RecordSource, response: &DnsPacket) -> Security {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as u32);
+
+        match self.check_response(source, response, now) {
+            Ok(security) => security,
+            Err(reason) => Security::Bogus(reason),
+        }
+    }
+
+    fn check_response(
+        &mut self,
+        source: &mut dyn RecordSource,
+        response: &DnsPacket,
+        now: u32,
+    ) -> Result<Security, String> {
+        let question = response
+            .questions
+            .first()
+            .ok_or("Response has no question.")?;
+        let mut secure = true;
+
+        // Denial of existence records back up negative answers and wildcard expansions.
+        let mut denial_records = Vec::new();
+        for (rrset, sigs) in rrsets(&response.authorities) {
+            let query_type = rrset[0].query_type();
+            if !matches!(
+                query_type,
+                QueryType::NSEC | QueryType::NSEC3 | QueryType::SOA
+            ) {
+                continue;
+            }
+            match self.verify_rrset(source, &rrset, &sigs, now)? {
+                Verified::Secure { .. } if query_type != QueryType::SOA => {
+                    denial_records.extend(rrset)
+                }
+                Verified::Secure { .. } => {}
+                Verified::Insecure => secure = false,
+            }
+        }
+
+        let answers = rrsets(&response.answers);
+        for (rrset, sigs) in &answers {
+            // A CNAME synthesized from a DNAME is unsigned, and is only as good as the DNAME (RFC 6672 section 5.3.1).
+            if sigs.is_empty() && is_synthesized(rrset, &response.answers) {
+                continue;
+            }
+            match self.verify_rrset(source, rrset, sigs, now)? {
+                Verified::Secure {
+                    wildcard_labels: Some(labels),
+                } => prove_wildcard_expansion(rrset[0].domain(), labels, &denial_records)?,
+                Verified::Secure { .. } => {}
+                Verified::Insecure => secure = false,
+            }
+        }
+
+        // A negative answer may come at the end of a CNAME chain, and then it's the chain's last name that needs
+        // proof it doesn't exist or has no records of the type asked for.
+        let unanswered = match response.header.rescode {
+            ResultCode::NOERROR | ResultCode::NXDOMAIN => {
+                unanswered(&question.name, question.query_type, &response.answers)
+            }
+            _ => None,
+        };
+        if let (Some(name), true) = (unanswered, secure) {
+            if denial_records.is_empty() {
+                return match self.is_insecure(source, &name, now)? {
+                    true => Ok(Security::Insecure),
+                    false => Err(format!(
+                        "No proof that {} {} doesn't exist.",
+                        name, question.query_type
+                    )),
+                };
+            }
+            let denial = prove_denial(&name, question.query_type, &denial_records)?;
+            match (response.header.rescode, denial) {
+                (_, Denial::OptOut) => secure = false,
+                (ResultCode::NXDOMAIN, Denial::NameError) => {}
+                (ResultCode::NOERROR, Denial::NoData(_)) => {}
+                (rescode, denial) => {
+                    return Err(format!(
+                        "Response code {:?} contradicts its proof ({:?}).",
+                        rescode, denial
+                    ))
+                }
+            }
+        }
+
+        Ok(match secure {
+            true => Security::Secure,
+            false => Security::Insecure,
+        })
+    }
+
+    fn verify_rrset(
+        &mut self,
+        source: &mut dyn RecordSource,
+        rrset: &[DnsRecord],
+        sigs: &[DnsRecord],
+        now: u32,
+    ) -> Result<Verified, String> {
+        let owner = rrset[0].domain().to_string();
+        let query_type = rrset[0].query_type();
+
+        if sigs.is_empty() {
+            return match self.is_insecure(source, &owner, now)? {
+                true => Ok(Verified::Insecure),
+                false => Err(format!(
+                    "{} {} is missing its signature.",
+                    owner, query_type
+                )),
+            };
+        }
+
+        let mut failure = format!("No usable signature over {} {}.", owner, query_type);
+        for sig in sigs {
+            let DnsRecord::RRSIG {
+                algorithm,
+                labels,
+                key_tag,
+                signer_name,
+                ..
+            } = sig
+            else {
+                continue;
+            };
+            if !is_supported_algorithm(*algorithm) {
+                failure = format!("Unsupported signature algorithm {}.", algorithm);
+                continue;
+            }
+            // DS records belong to the parent side of a zone cut, so the zone itself can't sign them.
+            if !is_subdomain(&owner, signer_name)
+                || (query_type == QueryType::DS
+                    && compare_names(&owner, signer_name) == Ordering::Equal)
+            {
+                failure = format!("{} can't sign {} {}.", signer_name, owner, query_type);
+                continue;
+            }
+
+            let keys = match self.zone_status(source, signer_name, now)? {
+                ZoneStatus::Secure(keys) => keys,
+                ZoneStatus::Insecure => return Ok(Verified::Insecure),
+                ZoneStatus::NotAZone => {
+                    failure = format!("Signer {} isn't a zone.", signer_name);
+                    continue;
+                }
+            };
+            for key in keys.iter().filter(|key| key.key_tag() == Some(*key_tag)) {
+                match verify_rrsig(rrset, sig, key, now) {
+                    Ok(()) => {
+                        let wildcard_labels =
+                            (label_count(&owner) > *labels as usize).then_some(*labels as usize);
+                        return Ok(Verified::Secure { wildcard_labels });
+                    }
+                    Err(message) => failure = message,
+                }
+            }
+        }
+        Err(failure)
+    }
+
+    // Walks down from the root looking for a delegation without a DS record, below which nothing is signed.
+    fn is_insecure(
+        &mut self,
+        source: &mut dyn RecordSource,
+        name: &str,
+        now: u32,
+    ) -> Result<bool, String> {
+        for count in 0..=label_count(name) {
+            if let ZoneStatus::Insecure = self.zone_status(source, &suffix(name, count), now)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn zone_status(
+        &mut self,
+        source: &mut dyn RecordSource,
+        zone: &str,
+        now: u32,
+    ) -> Result<ZoneStatus, String> {
+        let zone = zone.trim_end_matches('.').to_ascii_lowercase();
+        if let Some((status, expires)) = self.zones.get(&zone) {
+            if *expires > Instant::now() {
+                return Ok(status.clone());
+            }
+        }
+        if !self.in_progress.insert(zone.clone()) {
+            return Err(format!("Chain of trust for {} loops back on itself.", zone));
+        }
+
+        let status = match zone.is_empty() {
+            true => {
+                let anchor = &self.trust_anchor;
+                fetch_keys(source, &zone, now, &|key| anchor.trusts(key))
+            }
+            false => self.child_zone_status(source, &zone, now),
+        };
+        self.in_progress.remove(&zone);
+        let status = status?;
+
+        let lifetime = match &status {
+            ZoneStatus::Secure(keys) => {
+                Duration::from_secs(keys.iter().map(DnsRecord::ttl).min().unwrap_or(0) as u64)
+            }
+            _ => UNSIGNED_ZONE_CACHE_TIME,
+        };
+        self.zones
+            .insert(zone, (status.clone(), Instant::now() + lifetime));
+        Ok(status)
+    }
+
+    // Follows the parent's DS records (or its proof that there are none) down to the zone's keys.
+    fn child_zone_status(
+        &mut self,
+        source: &mut dyn RecordSource,
+        zone: &str,
+        now: u32,
+    ) -> Result<ZoneStatus, String> {
+        let parent_name = parent(zone).unwrap_or_default();
+        let response = source
+            .query(zone, QueryType::DS)
+            .map_err(|e| format!("DS lookup for {} failed: {}", zone, e))?;
+
+        let ds_set = rrsets(&response.answers).into_iter().find(|(rrset, _)| {
+            rrset[0].query_type() == QueryType::DS
+                && compare_names(rrset[0].domain(), zone) == Ordering::Equal
+        });
+        let proof: Vec<SignedRRset> = match ds_set {
+            Some(ds_set) => vec![ds_set],
+            None => rrsets(&response.authorities)
+                .into_iter()
+                .filter(|(rrset, _)| {
+                    matches!(rrset[0].query_type(), QueryType::NSEC | QueryType::NSEC3)
+                })
+                .collect(),
+        };
+
+        // Unsigned data here is only acceptable if we're already below an insecure delegation.
+        if proof.is_empty() || proof.iter().any(|(_, sigs)| sigs.is_empty()) {
+            return match self.is_insecure(source, parent_name, now)? {
+                true => Ok(ZoneStatus::Insecure),
+                false => Err(format!("Missing signed DS records or denial for {}.", zone)),
+            };
+        }
+        // Everything about the DS must come from above the cut, or the chain of trust means nothing.
+        let signed_from_above = proof.iter().flat_map(|(_, sigs)| sigs).all(|sig| {
+            matches!(sig, DnsRecord::RRSIG { signer_name, .. }
+                if is_subdomain(zone, signer_name) && compare_names(zone, signer_name) != Ordering::Equal)
+        });
+        if !signed_from_above {
+            return Err(format!("DS data for {} wasn't signed by its parent.", zone));
+        }
+
+        let mut denial_records = Vec::new();
+        for (rrset, sigs) in &proof {
+            if let Verified::Insecure = self.verify_rrset(source, rrset, sigs, now)? {
+                return Ok(ZoneStatus::Insecure);
+            }
+            denial_records.extend(rrset.iter().cloned());
+        }
+
+        if proof[0].0[0].query_type() == QueryType::DS {
+            let supported: Vec<&DnsRecord> = proof[0]
+                .0
+                .iter()
+                .filter(|ds| match ds {
+                    DnsRecord::DS {
+                        algorithm,
+                        digest_type,
+                        ..
+                    } => is_supported_algorithm(*algorithm) && is_supported_digest(*digest_type),
+                    _ => false,
+                })
+                .collect();
+            // A zone signed only with algorithms we don't know is treated as unsigned (RFC 4035 section 5.2).
+            if supported.is_empty() {
+                return Ok(ZoneStatus::Insecure);
+            }
+            return fetch_keys(source, zone, now, &|key| {
+                supported.iter().any(|ds| ds_matches(ds, key))
+            });
+        }
+
+        Ok(match prove_denial(zone, QueryType::DS, &denial_records)? {
+            Denial::NoData(types) if types.contains(&QueryType::NS) => ZoneStatus::Insecure,
+            Denial::OptOut => ZoneStatus::Insecure,
+            Denial::NoData(_) | Denial::NameError => ZoneStatus::NotAZone,
+        })
+    }
+}
+
+// Fetches a zone's DNSKEY set and checks it's self-signed by one of the keys the parent (or anchor) vouches for.
+fn fetch_keys(
+    source: &mut dyn RecordSource,
+    zone: &str,
+    now: u32,
+    trusted: &dyn Fn(&DnsRecord) -> bool,
+) -> Result<ZoneStatus, String> {
+    let response = source
+        .query(zone, QueryType::DNSKEY)
+        .map_err(|e| format!("DNSKEY lookup for {} failed: {}", zone, e))?;
+    let (keys, sigs) = rrsets(&response.answers)
+        .into_iter()
+        .find(|(rrset, _)| {
+            rrset[0].query_type() == QueryType::DNSKEY
+                && compare_names(rrset[0].domain(), zone) == Ordering::Equal
+        })
+        .ok_or_else(|| format!("No DNSKEY records for {}.", zone))?;
+
+    for sig in &sigs {
+        for key in keys.iter().filter(|key| trusted(key)) {
+            if verify_rrsig(&keys, sig, key, now).is_ok() {
+                return Ok(ZoneStatus::Secure(keys));
+            }
+        }
+    }
+    Err(format!(
+        "DNSKEY set for {} isn't signed by a trusted key.",
+        zone
+    ))
+}
+
+// Splits a section into RRsets, each paired with the RRSIGs covering it.
+fn rrsets(records: &[DnsRecord]) -> Vec<SignedRRset> {
+    let mut sets: Vec<SignedRRset> = Vec::new();
+    for record in records {
+        if matches!(record, DnsRecord::RRSIG { .. } | DnsRecord::OPT { .. }) {
+            continue;
+        }
+        match sets
+            .iter_mut()
+            .find(|(rrset, _)| same_rrset(&rrset[0], record))
+        {
+            Some((rrset, _)) => rrset.push(record.clone()),
+            None => sets.push((vec![record.clone()], Vec::new())),
+        }
+    }
+    for (rrset, sigs) in sets.iter_mut() {
+        sigs.extend(
+            records
+                .iter()
+                .filter(|record| match record {
+                    DnsRecord::RRSIG {
+                        domain,
+                        type_covered,
+                        ..
+                    } => {
+                        *type_covered == rrset[0].query_type()
+                            && compare_names(domain, rrset[0].domain()) == Ordering::Equal
+                    }
+                    _ => false,
+                })
+                .cloned(),
+        );
+    }
+    sets
+}
+
+// Is this a lone CNAME matching what one of the DNAMEs in `answers` implies for its owner?
+fn is_synthesized(rrset: &[DnsRecord], answers: &[DnsRecord]) -> bool {
+    let [DnsRecord::CNAME { domain, host, .. }] = rrset else {
+        return false;
+    };
+    answers
+        .iter()
+        .any(|record| match record.synthesize_cname(domain) {
+            Ok(Some(DnsRecord::CNAME {
+                host: synthesized, ..
+            })) => compare_names(host, &synthesized) == Ordering::Equal,
+            _ => false,
+        })
+}
+
+// The name a query for `name` ends up at by following the CNAMEs among `answers`, if there's nothing of the type
+// asked for there.
+fn unanswered(name: &str, query_type: QueryType, answers: &[DnsRecord]) -> Option<String> {
+    let mut name = name.to_string();
+    // Each step follows a different record, so this is enough to reach the end of any chain without going round a loop.
+    for _ in 0..=answers.len() {
+        let owned: Vec<&DnsRecord> = answers
+            .iter()
+            .filter(|record| compare_names(record.domain(), &name) == Ordering::Equal)
+            .collect();
+        if owned.iter().any(|record| record.query_type() == query_type) {
+            return None;
+        }
+        match owned.iter().find_map(|record| match record {
+            DnsRecord::CNAME { host, .. } => Some(host.clone()),
+            _ => None,
+        }) {
+            Some(host) => name = host,
+            None => return Some(name),
+        }
+    }
+    None
+}
+
+fn same_rrset(a: &DnsRecord, b: &DnsRecord) -> bool {
+    a.query_type() == b.query_type() && compare_names(a.domain(), b.domain()) == Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Security, Validator};
+    use crate::{
+        dnssec::{
+            signature::ED25519,
+            test_zones::{example_hierarchy, TestHierarchy, TestKey},
+            trust_anchor::TrustAnchor,
+            validator::RecordSource,
+        },
+        parser::{DnsRecord, QueryType},
+    };
+    use std::error::Error;
+
+    fn validate(
+        hierarchy: &mut TestHierarchy,
+        name: &str,
+        query_type: QueryType,
+    ) -> Result<Security, Box<dyn Error>> {
+        let mut validator = Validator::new(hierarchy.trust_anchor());
+        let response = hierarchy.query(name, query_type)?;
+        Ok(validator.validate(hierarchy, &response))
+    }
+
+    fn is_bogus(security: &Security) -> bool {
+        matches!(security, Security::Bogus(_))
+    }
+
+    #[test]
+    fn signed_answers_are_secure() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        assert_eq!(
+            validate(&mut hierarchy, "www.example", QueryType::A)?,
+            Security::Secure
+        );
+        assert_eq!(
+            validate(&mut hierarchy, "www.secure.example", QueryType::A)?,
+            Security::Secure
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn signed_answers_of_unknown_types_are_secure() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        assert_eq!(
+            validate(&mut hierarchy, "secure.example", QueryType::UNKNOWN(15))?,
+            Security::Secure
+        );
+
+        // The data is still checked against the signature.
+        let mut response = hierarchy.query("secure.example", QueryType::UNKNOWN(15))?;
+        for record in &mut response.answers {
+            if let DnsRecord::UNKNOWN { data, .. } = record {
+                data[1] = 20;
+            }
+        }
+        let mut validator = Validator::new(hierarchy.trust_anchor());
+        assert!(is_bogus(&validator.validate(&mut hierarchy, &response)));
+        Ok(())
+    }
+
+    #[test]
+    fn unsigned_delegation_is_insecure() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        assert_eq!(
+            validate(&mut hierarchy, "www.insecure.example", QueryType::A)?,
+            Security::Insecure
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn proven_denials_are_secure() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        assert_eq!(
+            validate(&mut hierarchy, "missing.example", QueryType::A)?,
+            Security::Secure
+        );
+        assert_eq!(
+            validate(&mut hierarchy, "www.example", QueryType::AAAA)?,
+            Security::Secure
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn wildcard_expansion_is_secure() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        assert_eq!(
+            validate(&mut hierarchy, "anything.secure.example", QueryType::A)?,
+            Security::Secure
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dname_redirections_are_secure() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        // The DNAME is signed by "example" and the A record by "secure.example", but the CNAME between them isn't.
+        assert_eq!(
+            validate(&mut hierarchy, "www.old.example", QueryType::A)?,
+            Security::Secure
+        );
+        assert_eq!(
+            validate(&mut hierarchy, "missing.old.example", QueryType::A)?,
+            Security::Secure
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn negative_answers_after_cnames_need_proof() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        let mut validator = Validator::new(hierarchy.trust_anchor());
+        // NXDOMAIN for the target of gone.example, and NODATA for the AAAA records of alias.example's.
+        for (name, query_type) in [
+            ("gone.example", QueryType::A),
+            ("alias.example", QueryType::AAAA),
+        ] {
+            let mut response = hierarchy.query(name, query_type)?;
+            assert!(!response.answers.is_empty());
+            assert_eq!(
+                validator.validate(&mut hierarchy, &response),
+                Security::Secure
+            );
+
+            // Without the NSEC records, but still with the signed SOA.
+            response.authorities.retain(|record| {
+                !matches!(
+                    record,
+                    DnsRecord::NSEC { .. }
+                        | DnsRecord::RRSIG {
+                            type_covered: QueryType::NSEC,
+                            ..
+                        }
+                )
+            });
+            assert!(is_bogus(&validator.validate(&mut hierarchy, &response)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_answers_are_bogus() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        hierarchy.tampered.push(String::from("www.example"));
+        assert!(is_bogus(&validate(
+            &mut hierarchy,
+            "www.example",
+            QueryType::A
+        )?));
+        Ok(())
+    }
+
+    #[test]
+    fn stripped_signatures_are_bogus() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        hierarchy.stripped.push(String::from("www.secure.example"));
+        assert!(is_bogus(&validate(
+            &mut hierarchy,
+            "www.secure.example",
+            QueryType::A
+        )?));
+        Ok(())
+    }
+
+    #[test]
+    fn expired_signatures_are_bogus() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        hierarchy.signing_time -= 86400;
+        assert!(is_bogus(&validate(
+            &mut hierarchy,
+            "www.example",
+            QueryType::A
+        )?));
+        Ok(())
+    }
+
+    #[test]
+    fn untrusted_root_is_bogus() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        let other_root = TestKey::generate(ED25519)?;
+        let mut validator = Validator::new(TrustAnchor::new(vec![other_root.ds("")]));
+        let response = hierarchy.query("www.example", QueryType::A)?;
+        assert!(is_bogus(&validator.validate(&mut hierarchy, &response)));
+        Ok(())
+    }
+}
@@ -0,0 +1,10 @@
+// DNS mnemonics (A, NXDOMAIN, SERVFAIL, ...) are spelled the way the RFCs spell them.
+#![allow(clippy::upper_case_acronyms)]
+// The parser's tests spell out flag values with `assert_eq!(..., true)`.
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
+
+pub mod capture;
+pub mod config;
+pub mod dnssec;
+pub mod parser;
+pub mod resolver;
@@ -1,13 +1,43 @@
-mod parser;
-mod resolver;
-
-use resolver::DnsResolver;
+use dns_server_tutorial_rust::{
+    config::Config,
+    dnssec::TrustAnchor,
+    resolver::{DnsResolver, Recursor},
+};
 use std::error::Error;
 
-const SERVER_PORT: u16 = 8000;
-
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut resolver = DnsResolver::new(SERVER_PORT)?;
+    let config = Config::from_args(std::env::args().skip(1))?;
+    let mut resolver = DnsResolver::new(config.port)?
+        .with_upstreams(config.upstreams, config.strategy.selector())?
+        .with_retries(config.timeout, config.retries)
+        .with_tcp_limits(config.tcp)
+        .with_cache(config.cache);
+    if let Some(workers) = config.workers {
+        resolver = resolver.with_workers(workers);
+    }
+    match (&config.tls_certificate, &config.tls_key) {
+        (Some(certificate), Some(key)) => {
+            resolver = resolver.with_tls(config.tls_port, certificate, key)?;
+            if config.https {
+                resolver = resolver.with_https(config.https_port, certificate, key)?;
+            }
+        }
+        (None, None) if config.https => {
+            return Err("HTTPS needs tls_certificate and tls_key.".into())
+        }
+        (None, None) => {}
+        _ => return Err("TLS needs both tls_certificate and tls_key.".into()),
+    }
+    if config.recursive {
+        let recursor = Recursor::new(config.root_hints)?
+            .with_timeout(config.timeout)
+            .with_retries(config.retries);
+        resolver = resolver.with_recursion(recursor);
+    }
+    // Validating against the IANA root keys is opt-in with `--dnssec` (or `dnssec = true` in the config file).
+    if config.dnssec {
+        resolver = resolver.with_dnssec_validation(TrustAnchor::iana_root());
+    }
     resolver.start_listening()?;
     Ok(())
 }
@@ -1,18 +1,38 @@
+mod dissector;
 mod dns_header;
 mod dns_packet;
+mod dns_packet_builder;
 mod dns_question;
 mod dns_record;
 
 mod bitshifting;
+mod loc;
 mod query_name_parser;
 mod query_type;
 mod result_code;
-mod test_helpers;
+mod rrset;
+mod security_records;
+mod svcb;
+pub(crate) mod test_helpers;
+mod tsig;
+mod type_bitmap;
 mod wrapped_buffer;
 
+pub use dissector::{dissect, hex_dump, DissectedField, Dissection, DissectionError};
 pub use dns_packet::DnsPacket;
+pub use dns_packet_builder::{DnsPacketBuilder, DEFAULT_EDNS_PAYLOAD_SIZE, EXTENDED_DNS_ERROR};
 pub use dns_question::DnsQuestion;
-pub use dns_record::DnsRecord;
+pub use dns_record::{absolute_name, DnsRecord, EdnsOption};
+pub use loc::{altitude as loc_altitude, coordinate as loc_coordinate, size as loc_size};
 pub use query_type::QueryType;
 pub use result_code::ResultCode;
-pub use wrapped_buffer::WrappedBuffer;
+pub(crate) use rrset::labels;
+pub use rrset::{compare_names, is_subdomain, name_to_wire, RRSet};
+pub use security_records::{
+    caa_authorizes, sshfp_matches, sshfp_records, tlsa_matches, CaaProperty,
+};
+pub use svcb::{parse_params as parse_svc_params, SvcParam, SvcbMode};
+pub use tsig::{
+    Keyring, TsigAlgorithm, TsigError, TsigKey, TsigRejection, TsigSession, TsigStatus,
+};
+pub use wrapped_buffer::{WrappedBuffer, MAX_MESSAGE_SIZE};
@@ -0,0 +1,711 @@
+use super::{
+    dns_record::DnsRecord, query_type::QueryType, result_code::ResultCode,
+    wrapped_buffer::WrappedBuffer,
+};
+use std::fmt::{Display, Write};
+
+const HEADER_LENGTH: usize = 12;
+const MAX_JUMPS: usize = 5; // Same limit as the real name parser.
+const MAX_BYTES_SHOWN: usize = 8;
+const HEX_DUMP_ROW_LENGTH: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DissectedField {
+    pub offset: usize,
+    pub length: usize,
+    pub name: String,
+    pub value: String,
+    pub children: Vec<DissectedField>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DissectionError {
+    pub offset: usize,
+    pub message: String,
+}
+
+/// An annotated, Wireshark-style breakdown of a raw DNS message.
+/// If parsing failed part-way through, `fields` holds everything up to the failure and `error` says where it happened.
+#[derive(Clone, Debug)]
+pub struct Dissection {
+    pub bytes: Vec<u8>,
+    pub fields: Vec<DissectedField>,
+    pub error: Option<DissectionError>,
+}
+
+pub fn dissect(bytes: &[u8]) -> Dissection {
+    let mut dissection = Dissection {
+        bytes: bytes.to_vec(),
+        fields: Vec::new(),
+        error: None,
+    };
+
+    let mut dissector = match WrappedBuffer::from_bytes(bytes) {
+        Ok(buffer) => Dissector { buffer },
+        Err(message) => {
+            dissection.error = Some(DissectionError { offset: 0, message });
+            return dissection;
+        }
+    };
+    if let Err(e) = dissector.message(&mut dissection.fields) {
+        dissection.error = Some(e);
+    }
+    dissection
+}
+
+/// Renders `bytes` sixteen to a line, with the byte at `highlight` (if any) wrapped in brackets.
+pub fn hex_dump(bytes: &[u8], highlight: Option<usize>) -> String {
+    let mut result = String::new();
+
+    for (row, chunk) in bytes.chunks(HEX_DUMP_ROW_LENGTH).enumerate() {
+        let row_offset = row * HEX_DUMP_ROW_LENGTH;
+        let _ = write!(result, "{:04x} ", row_offset);
+
+        for (i, byte) in chunk.iter().enumerate() {
+            let separator = match highlight {
+                Some(pos) if pos == row_offset + i => '[',
+                Some(pos) if pos + 1 == row_offset + i => ']',
+                _ => ' ',
+            };
+            let _ = write!(result, "{}{:02x}", separator, byte);
+        }
+        if highlight == Some(row_offset + chunk.len() - 1) {
+            result.push(']');
+        }
+        result.push('\n');
+    }
+    // Running off the end of the message is shown as a marker just past the last byte.
+    if highlight.is_some_and(|pos| pos >= bytes.len()) {
+        let _ = writeln!(result, "{:04x} [..]", bytes.len());
+    }
+    result
+}
+
+type Step<T> = Result<T, DissectionError>;
+
+struct Dissector {
+    buffer: WrappedBuffer,
+}
+
+impl Dissector {
+    fn message(&mut self, fields: &mut Vec<DissectedField>) -> Step<()> {
+        let counts = self.section(fields, "Header", |d, node| d.header(node))?;
+
+        let sections = [
+            ("Question", counts[0]),
+            ("Answer", counts[1]),
+            ("Authority", counts[2]),
+            ("Additional", counts[3]),
+        ];
+        for (section, count) in sections {
+            for i in 1..=count {
+                let name = format!("{} #{}", section, i);
+                if section == "Question" {
+                    self.section(fields, &name, |d, node| d.question(node))?;
+                } else {
+                    self.section(fields, &name, |d, node| d.record(node))?;
+                }
+            }
+        }
+
+        let end = self.buffer.pos();
+        if end < self.buffer.len() {
+            fields.push(DissectedField::leaf(
+                end,
+                self.buffer.len() - end,
+                "Trailing data",
+                format!(
+                    "{} bytes after the end of the message",
+                    self.buffer.len() - end
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // Dissects one composite field. The node is kept even when `body` fails, so partial results still show up.
+    fn section<T>(
+        &mut self,
+        fields: &mut Vec<DissectedField>,
+        name: &str,
+        body: impl FnOnce(&mut Dissector, &mut DissectedField) -> Step<T>,
+    ) -> Step<T> {
+        let mut node = DissectedField::leaf(self.buffer.pos(), 0, name, String::new());
+        let result = body(self, &mut node);
+        node.length = self.buffer.pos().saturating_sub(node.offset);
+        fields.push(node);
+        result
+    }
+
+    fn header(&mut self, node: &mut DissectedField) -> Step<[u16; 4]> {
+        let id = self.read_u16()?;
+        node.children.push(DissectedField::leaf(
+            0,
+            2,
+            "Transaction ID",
+            format!("0x{:04x} ({})", id, id),
+        ));
+
+        let flags = self.read_u16()?;
+        let mut flags_node = DissectedField::leaf(2, 2, "Flags", format!("0x{:04x}", flags));
+        let opcode = (flags >> 11) & 0x0F;
+        let rescode = (flags & 0x0F) as u8;
+        let flag_fields = [
+            (0x8000, "Response", yes_no(flags & 0x8000 != 0)),
+            (0x7800, "Opcode", opcode_name(opcode)),
+            (0x0400, "Authoritative", yes_no(flags & 0x0400 != 0)),
+            (0x0200, "Truncated", yes_no(flags & 0x0200 != 0)),
+            (0x0100, "Recursion desired", yes_no(flags & 0x0100 != 0)),
+            (0x0080, "Recursion available", yes_no(flags & 0x0080 != 0)),
+            (0x0040, "Z (reserved)", yes_no(flags & 0x0040 != 0)),
+            (0x0020, "Authentic data", yes_no(flags & 0x0020 != 0)),
+            (0x0010, "Checking disabled", yes_no(flags & 0x0010 != 0)),
+            (
+                0x000F,
+                "Reply code",
+                format!("{:?} ({})", ResultCode::from_number(rescode), rescode),
+            ),
+        ];
+        for (mask, name, decoded) in flag_fields {
+            flags_node.children.push(DissectedField::leaf(
+                2,
+                2,
+                name,
+                format!("{} = {}", bit_pattern(flags, mask), decoded),
+            ));
+        }
+        node.children.push(flags_node);
+
+        let mut counts = [0; 4];
+        let count_names = ["Questions", "Answer RRs", "Authority RRs", "Additional RRs"];
+        for (count, name) in counts.iter_mut().zip(count_names) {
+            let offset = self.buffer.pos();
+            *count = self.read_u16()?;
+            node.children
+                .push(DissectedField::leaf(offset, 2, name, count.to_string()));
+        }
+        node.value = format!("{} bytes", HEADER_LENGTH);
+        Ok(counts)
+    }
+
+    fn question(&mut self, node: &mut DissectedField) -> Step<()> {
+        let name = self.name("Name")?;
+        node.value = name.value.clone();
+        node.children.push(name);
+
+        let query_type = self.type_field(node)?;
+        let class = self.class_field(node)?;
+        node.value = format!("{}: type {}, class {}", node.value, query_type, class);
+        Ok(())
+    }
+
+    fn record(&mut self, node: &mut DissectedField) -> Step<()> {
+        let record_start = self.buffer.pos();
+        let name = self.name("Name")?;
+        node.value = name.value.clone();
+        node.children.push(name);
+
+        let record_type = self.type_field(node)?;
+        if record_type == "OPT" {
+            // The OPT pseudo-record reuses the class and TTL fields for EDNS parameters.
+            let offset = self.buffer.pos();
+            let payload_size = self.read_u16()?;
+            node.children.push(DissectedField::leaf(
+                offset,
+                2,
+                "UDP payload size",
+                payload_size.to_string(),
+            ));
+            let offset = self.buffer.pos();
+            let ttl = self.read_u32()?;
+            node.children.push(DissectedField::leaf(
+                offset,
+                4,
+                "Extended RCODE, version and flags",
+                format!("0x{:08x}", ttl),
+            ));
+            node.value = format!("{}: type OPT", node.value);
+        } else {
+            let class = self.class_field(node)?;
+            node.value = format!("{}: type {}, class {}", node.value, record_type, class);
+
+            let offset = self.buffer.pos();
+            let ttl = self.read_u32()?;
+            node.children.push(DissectedField::leaf(
+                offset,
+                4,
+                "Time to live",
+                ttl.to_string(),
+            ));
+        }
+
+        let offset = self.buffer.pos();
+        let data_length = self.read_u16()? as usize;
+        node.children.push(DissectedField::leaf(
+            offset,
+            2,
+            "Data length",
+            data_length.to_string(),
+        ));
+
+        // Let the real record parser decode RDATA so this always agrees with it, then check it used the advertised length.
+        let data_start = self.buffer.pos();
+        let raw_data = self.slice(data_start, data_length)?.to_vec();
+        self.seek(record_start)?;
+        let record = DnsRecord::read(&mut self.buffer).map_err(|message| DissectionError {
+            offset: data_start,
+            message,
+        })?;
+        let data_end = self.buffer.pos();
+        if data_end != data_start + data_length {
+            return Err(DissectionError {
+                offset: data_start,
+                message: format!(
+                    "Record data length is {} but {} bytes were parsed.",
+                    data_length,
+                    data_end - data_start
+                ),
+            });
+        }
+        node.children.push(DissectedField::leaf(
+            data_start,
+            data_length,
+            "Data",
+            describe_record_data(&record, &raw_data),
+        ));
+        Ok(())
+    }
+
+    fn name(&mut self, field_name: &str) -> Step<DissectedField> {
+        let start = self.buffer.pos();
+        let mut node = DissectedField::leaf(start, 0, field_name, String::new());
+        let mut labels: Vec<String> = Vec::new();
+        let mut local_pos = start;
+        let mut end_of_name = None;
+        let mut num_jumps = 0;
+
+        loop {
+            let length_byte = self.peek(local_pos)?;
+
+            if length_byte & 0xC0 == 0xC0 {
+                let destination =
+                    (((length_byte as usize) ^ 0xC0) << 8) | self.peek(local_pos + 1)? as usize;
+                node.children.push(DissectedField::leaf(
+                    local_pos,
+                    2,
+                    "Compression pointer",
+                    format!("-> 0x{:04x}", destination),
+                ));
+                end_of_name.get_or_insert(local_pos + 2);
+
+                num_jumps += 1;
+                if num_jumps > MAX_JUMPS {
+                    return Err(DissectionError {
+                        offset: local_pos,
+                        message: format!(
+                            "Too many compression pointers ({}) - the name may contain a cycle.",
+                            num_jumps
+                        ),
+                    });
+                }
+                local_pos = destination;
+            } else if length_byte == 0 {
+                node.children.push(DissectedField::leaf(
+                    local_pos,
+                    1,
+                    "Root label",
+                    String::new(),
+                ));
+                end_of_name.get_or_insert(local_pos + 1);
+                break;
+            } else {
+                let length = length_byte as usize;
+                let label = String::from_utf8_lossy(self.slice(local_pos + 1, length)?).to_string();
+                node.children.push(DissectedField::leaf(
+                    local_pos,
+                    length + 1,
+                    "Label",
+                    format!("\"{}\"", label),
+                ));
+                labels.push(label);
+                local_pos += length + 1;
+            }
+        }
+
+        let end = end_of_name.unwrap_or(local_pos);
+        self.seek(end)?;
+        node.length = end - start;
+        node.value = if labels.is_empty() {
+            String::from("<root>")
+        } else {
+            labels.join(".")
+        };
+        Ok(node)
+    }
+
+    fn type_field(&mut self, node: &mut DissectedField) -> Step<String> {
+        let offset = self.buffer.pos();
+        let value = self.read_u16()?;
+        let name = type_name(value);
+        node.children.push(DissectedField::leaf(
+            offset,
+            2,
+            "Type",
+            format!("{} ({})", name, value),
+        ));
+        Ok(name)
+    }
+
+    fn class_field(&mut self, node: &mut DissectedField) -> Step<String> {
+        let offset = self.buffer.pos();
+        let value = self.read_u16()?;
+        let name = class_name(value);
+        node.children.push(DissectedField::leaf(
+            offset,
+            2,
+            "Class",
+            format!("{} ({})", name, value),
+        ));
+        Ok(name)
+    }
+
+    fn read_u16(&mut self) -> Step<u16> {
+        let offset = self.buffer.pos();
+        self.buffer
+            .read_u16()
+            .map_err(|message| DissectionError { offset, message })
+    }
+
+    fn read_u32(&mut self) -> Step<u32> {
+        let offset = self.buffer.pos();
+        self.buffer
+            .read_u32()
+            .map_err(|message| DissectionError { offset, message })
+    }
+
+    fn peek(&self, offset: usize) -> Step<u8> {
+        self.buffer
+            .peek(offset)
+            .map_err(|message| DissectionError { offset, message })
+    }
+
+    fn slice(&self, offset: usize, length: usize) -> Step<&[u8]> {
+        self.buffer
+            .get_slice(offset, length)
+            .map_err(|message| DissectionError {
+                offset: offset.max(self.buffer.len().min(offset + length)),
+                message,
+            })
+    }
+
+    fn seek(&mut self, offset: usize) -> Step<()> {
+        self.buffer
+            .seek(offset)
+            .map_err(|message| DissectionError { offset, message })
+    }
+}
+
+impl DissectedField {
+    fn leaf(offset: usize, length: usize, name: &str, value: String) -> DissectedField {
+        DissectedField {
+            offset,
+            length,
+            name: String::from(name),
+            value,
+            children: Vec::new(),
+        }
+    }
+
+    fn render(&self, bytes: &[u8], depth: usize, output: &mut String) {
+        let end = (self.offset + self.length).min(bytes.len());
+        let start = self.offset.min(end);
+        let shown = &bytes[start..end.min(start + MAX_BYTES_SHOWN)];
+        let mut raw: Vec<String> = shown.iter().map(|byte| format!("{:02x}", byte)).collect();
+        if end - start > MAX_BYTES_SHOWN {
+            raw.push(String::from(".."));
+        }
+
+        let indent = "  ".repeat(depth);
+        if self.value.is_empty() {
+            let _ = writeln!(
+                output,
+                "{:04x}  {:<28} {}{}",
+                self.offset,
+                raw.join(" "),
+                indent,
+                self.name
+            );
+        } else {
+            let _ = writeln!(
+                output,
+                "{:04x}  {:<28} {}{}: {}",
+                self.offset,
+                raw.join(" "),
+                indent,
+                self.name,
+                self.value
+            );
+        }
+        for child in &self.children {
+            child.render(bytes, depth + 1, output);
+        }
+    }
+}
+
+impl Display for Dissection {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut output = String::new();
+        for field in &self.fields {
+            field.render(&self.bytes, 0, &mut output);
+        }
+        write!(formatter, "{}", output)?;
+
+        if let Some(error) = &self.error {
+            writeln!(
+                formatter,
+                "\n!! Parsing failed at offset 0x{:04x}: {}\n",
+                error.offset, error.message
+            )?;
+            write!(formatter, "{}", hex_dump(&self.bytes, Some(error.offset)))?;
+        }
+        Ok(())
+    }
+}
+
+fn describe_record_data(record: &DnsRecord, raw_data: &[u8]) -> String {
+    match record {
+        DnsRecord::A { address, .. } => address.to_string(),
+        DnsRecord::OPT {
+            version,
+            dnssec_ok,
+            options,
+            ..
+        } => format!(
+            "EDNS version {}, DO {}, {} option(s)",
+            version,
+            yes_no(*dnssec_ok),
+            options.len()
+        ),
+        DnsRecord::DNSKEY { .. } => format!(
+            "{} (key tag {})",
+            record.data_to_string(),
+            record.key_tag().unwrap_or_default()
+        ),
+        DnsRecord::NS { .. }
+        | DnsRecord::CNAME { .. }
+        | DnsRecord::DNAME { .. }
+        | DnsRecord::SOA { .. }
+        | DnsRecord::AAAA { .. }
+        | DnsRecord::DS { .. }
+        | DnsRecord::RRSIG { .. }
+        | DnsRecord::NSEC { .. }
+        | DnsRecord::NSEC3 { .. }
+        | DnsRecord::NSEC3PARAM { .. }
+        | DnsRecord::HINFO { .. }
+        | DnsRecord::LOC { .. }
+        | DnsRecord::NAPTR { .. }
+        | DnsRecord::URI { .. }
+        | DnsRecord::CAA { .. }
+        | DnsRecord::TLSA { .. }
+        | DnsRecord::SSHFP { .. }
+        | DnsRecord::SVCB { .. }
+        | DnsRecord::HTTPS { .. }
+        | DnsRecord::TSIG { .. } => record.data_to_string(),
+        DnsRecord::UNKNOWN { .. } => raw_data
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+fn type_name(value: u16) -> String {
+    QueryType::from_u16(value).to_string()
+}
+
+fn class_name(value: u16) -> String {
+    match value {
+        1 => String::from("IN"),
+        3 => String::from("CH"),
+        4 => String::from("HS"),
+        254 => String::from("NONE"),
+        255 => String::from("ANY"),
+        _ => format!("CLASS{}", value),
+    }
+}
+
+fn opcode_name(opcode: u16) -> String {
+    let name = match opcode {
+        0 => "QUERY",
+        1 => "IQUERY",
+        2 => "STATUS",
+        4 => "NOTIFY",
+        5 => "UPDATE",
+        _ => "unassigned",
+    };
+    format!("{} ({})", name, opcode)
+}
+
+fn yes_no(flag: bool) -> String {
+    String::from(if flag { "yes" } else { "no" })
+}
+
+// Wireshark-style rendering of which bits of a 16-bit field a flag occupies, e.g. ".... .0.. .... ....".
+fn bit_pattern(value: u16, mask: u16) -> String {
+    let mut result = String::new();
+    for bit in (0..16).rev() {
+        if bit != 15 && bit % 4 == 3 {
+            result.push(' ');
+        }
+        result.push(if mask & (1 << bit) == 0 {
+            '.'
+        } else if value & (1 << bit) == 0 {
+            '0'
+        } else {
+            '1'
+        });
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bit_pattern, dissect, hex_dump, DissectedField};
+    use crate::parser::{
+        test_helpers::{open_test_file, GOOGLE_QUERY},
+        DnsPacketBuilder, DnsRecord,
+    };
+    use std::{error::Error, io::Read};
+
+    fn google_response() -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut bytes = Vec::new();
+        open_test_file(String::from(GOOGLE_QUERY))?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn find<'a>(fields: &'a [DissectedField], name: &str) -> &'a DissectedField {
+        fields
+            .iter()
+            .find(|field| field.name == name)
+            .unwrap_or_else(|| panic!("Expected a field called {}.", name))
+    }
+
+    #[test]
+    fn dissects_complete_response() -> Result<(), Box<dyn Error>> {
+        let dissection = dissect(&google_response()?);
+        assert!(dissection.error.is_none());
+
+        let header = find(&dissection.fields, "Header");
+        assert_eq!(
+            find(&header.children, "Transaction ID").value,
+            "0xbbd8 (48088)"
+        );
+        assert_eq!(find(&header.children, "Answer RRs").value, "1");
+
+        let question = find(&dissection.fields, "Question #1");
+        assert_eq!(question.offset, 12);
+        assert_eq!(question.value, "google.com: type A, class IN");
+
+        let answer = find(&dissection.fields, "Answer #1");
+        let data = find(&answer.children, "Data");
+        assert_eq!(data.value, "142.250.71.78");
+        assert_eq!((data.offset, data.length), (40, 4));
+        Ok(())
+    }
+
+    #[test]
+    fn shows_compression_pointer_targets() -> Result<(), Box<dyn Error>> {
+        let dissection = dissect(&google_response()?);
+        let answer = find(&dissection.fields, "Answer #1");
+        let name = find(&answer.children, "Name");
+
+        assert_eq!(name.value, "google.com");
+        assert_eq!((name.offset, name.length), (28, 2));
+        let pointer = find(&name.children, "Compression pointer");
+        assert_eq!(pointer.value, "-> 0x000c");
+        // The labels the pointer leads to are shown at their real offsets.
+        assert_eq!(find(&name.children, "Label").offset, 12);
+        Ok(())
+    }
+
+    #[test]
+    fn shows_dnskey_presentation_and_key_tag() -> Result<(), Box<dyn Error>> {
+        let mut packet = DnsPacketBuilder::new()
+            .response(true)
+            .answer(DnsRecord::DNSKEY {
+                domain: String::from("example.com"),
+                flags: 257,
+                protocol: 3,
+                algorithm: 15,
+                public_key: vec![0xAB; 32],
+                ttl: 3600,
+            })
+            .build();
+        let dissection = dissect(&packet.to_bytes()?);
+        assert!(dissection.error.is_none());
+
+        let answer = find(&dissection.fields, "Answer #1");
+        let data = find(&answer.children, "Data");
+        assert!(data.value.starts_with("257 3 15 q6ur"));
+        assert!(data.value.ends_with("(key tag 48842)"));
+        Ok(())
+    }
+
+    #[test]
+    fn reports_offset_of_truncation() -> Result<(), Box<dyn Error>> {
+        let bytes = google_response()?;
+        let dissection = dissect(&bytes[..42]);
+
+        let error = dissection
+            .error
+            .as_ref()
+            .expect("Expected dissection to fail.");
+        assert_eq!(error.offset, 42);
+        // Everything before the failure is still available.
+        assert!(dissection
+            .fields
+            .iter()
+            .any(|field| field.name == "Answer #1"));
+
+        let rendered = dissection.to_string();
+        assert!(rendered.contains("Parsing failed at offset 0x002a"));
+        assert!(rendered.contains("[..]"));
+        Ok(())
+    }
+
+    #[test]
+    fn reports_compression_pointer_loop() {
+        let mut bytes = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        bytes.extend_from_slice(&[0xC0, 0x0C]); // A name which points at itself.
+
+        let dissection = dissect(&bytes);
+        let error = dissection.error.expect("Expected dissection to fail.");
+        assert_eq!(error.offset, 12);
+        assert!(error.message.contains("Too many compression pointers"));
+    }
+
+    #[test]
+    fn reports_trailing_data() -> Result<(), Box<dyn Error>> {
+        let mut bytes = google_response()?;
+        bytes.extend_from_slice(&[0xDE, 0xAD]);
+
+        let dissection = dissect(&bytes);
+        assert!(dissection.error.is_none());
+        let trailing = find(&dissection.fields, "Trailing data");
+        assert_eq!((trailing.offset, trailing.length), (44, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn highlights_byte_in_hex_dump() {
+        let dump = hex_dump(&[0x00, 0x11, 0x22], Some(1));
+        assert_eq!(dump, "0000  00[11]22\n");
+    }
+
+    #[test]
+    fn renders_bit_patterns() {
+        assert_eq!(bit_pattern(0x8180, 0x8000), "1... .... .... ....");
+        assert_eq!(bit_pattern(0x8180, 0x7800), ".000 0... .... ....");
+        assert_eq!(bit_pattern(0x8183, 0x000F), ".... .... .... 0011");
+    }
+}
@@ -107,7 +107,7 @@ impl DnsHeader {
     }
 
     fn write_id(&mut self, buffer: &mut WrappedBuffer) -> Result<(), String> {
-        Ok(buffer.write_u16(self.id)?)
+        buffer.write_u16(self.id)
     }
 
     fn write_flags(&mut self, buffer: &mut WrappedBuffer) -> Result<(), String> {
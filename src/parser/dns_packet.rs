@@ -1,14 +1,21 @@
 use std::{
     error::Error,
     fmt::Display,
-    io::{Read, Write},
+    io::{ErrorKind, Read, Write},
 };
 
 use super::{
-    dns_header::DnsHeader, dns_question::DnsQuestion, dns_record::DnsRecord,
-    wrapped_buffer::WrappedBuffer,
+    dns_header::DnsHeader,
+    dns_packet_builder::EXTENDED_DNS_ERROR,
+    dns_question::DnsQuestion,
+    dns_record::DnsRecord,
+    tsig::{self, Keyring, TsigSession, TsigStatus},
+    wrapped_buffer::{WrappedBuffer, MAX_MESSAGE_SIZE},
 };
 
+// Classic DNS-over-UDP limit, used unless the caller asks for something else.
+const UDP_MESSAGE_SIZE: usize = 512;
+
 #[derive(Clone, Debug)]
 pub struct DnsPacket {
     pub header: DnsHeader,
@@ -29,35 +36,218 @@ impl DnsPacket {
         }
     }
 
+    // Starts a response the way RFC 1035 expects: same ID, opcode and question section, with RD (and CD) copied over.
+    pub fn response_to(query: &DnsPacket) -> DnsPacket {
+        let mut response = DnsPacket::new();
+        response.header.id = query.header.id;
+        response.header.opcode = query.header.opcode;
+        response.header.recursion_desired = query.header.recursion_desired;
+        response.header.checking_disabled = query.header.checking_disabled;
+        response.header.response = true;
+        response.questions = query.questions.clone();
+        response.header.num_questions = response.questions.len() as u16;
+        response
+    }
+
+    // Parses exactly one message. Anything left over after the last record is treated as an error.
+    pub fn from_bytes(bytes: &[u8]) -> Result<DnsPacket, Box<dyn Error>> {
+        if bytes.is_empty() {
+            return Err("No data to read DNS packet from!".into());
+        }
+        let mut buffer = WrappedBuffer::from_bytes(bytes)?;
+        let packet = DnsPacket::read_from_buffer(&mut buffer)?;
+
+        if buffer.pos() != bytes.len() {
+            return Err(format!(
+                "{} bytes of trailing data after the end of the DNS message.",
+                bytes.len() - buffer.pos()
+            )
+            .into());
+        }
+        Ok(packet)
+    }
+
+    // Reads the reader to the end and parses what it gave as a single message (e.g. a file containing one message).
     pub fn read<T: Read>(reader: &mut T) -> Result<DnsPacket, Box<dyn Error>> {
-        let mut buffer = WrappedBuffer::new();
-        let mut packet = DnsPacket::new();
+        let mut bytes = Vec::new();
+        reader
+            .take(MAX_MESSAGE_SIZE as u64 + 1)
+            .read_to_end(&mut bytes)?;
+        DnsPacket::from_bytes(&bytes)
+    }
+
+    // Reads one message with a two-byte length prefix, as used over TCP/TLS (RFC 1035 4.2.2).
+    // Returns None if the stream ends cleanly before the next message starts.
+    pub fn read_framed<T: Read>(reader: &mut T) -> Result<Option<DnsPacket>, Box<dyn Error>> {
+        match read_frame(reader)? {
+            Some(bytes) => Ok(Some(DnsPacket::from_bytes(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Like `read_framed`, verifying each message against the TSIG session of the stream it belongs to.
+    pub fn read_signed_framed<T: Read>(
+        reader: &mut T,
+        session: &mut TsigSession,
+    ) -> Result<Option<DnsPacket>, Box<dyn Error>> {
+        match read_frame(reader)? {
+            Some(bytes) => Ok(Some(DnsPacket::from_signed_bytes(&bytes, session)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Parses a message which must be signed as the next message of `session`'s exchange (e.g. a response to a
+    // request we signed). TSIG failures are returned as `TsigError`s.
+    pub fn from_signed_bytes(
+        bytes: &[u8],
+        session: &mut TsigSession,
+    ) -> Result<DnsPacket, Box<dyn Error>> {
+        tsig::verify_at(bytes, session, tsig::now())
+    }
 
-        reader.read(&mut buffer.as_slice()?)?;
-        packet.header.read(&mut buffer)?;
+    // Parses a request which may be signed by any of the keys in `keyring`. Signed requests come with the session
+    // to sign responses with; rejected ones must be answered with `TsigRejection::response_to`.
+    pub fn from_bytes_with_keyring(
+        bytes: &[u8],
+        keyring: &Keyring,
+    ) -> Result<(DnsPacket, TsigStatus), Box<dyn Error>> {
+        tsig::verify_request_at(bytes, keyring, tsig::now())
+    }
 
+    fn read_from_buffer(buffer: &mut WrappedBuffer) -> Result<DnsPacket, Box<dyn Error>> {
+        let mut packet = DnsPacket::new();
+        packet.header.read(buffer)?;
         for _ in 0..packet.header.num_questions {
-            packet.questions.push(DnsQuestion::read(&mut buffer)?);
+            packet.questions.push(DnsQuestion::read(buffer)?);
         }
         for _ in 0..packet.header.num_answers {
-            packet.answers.push(DnsRecord::read(&mut buffer)?);
+            packet.answers.push(DnsRecord::read(buffer)?);
         }
         for _ in 0..packet.header.num_authorities {
-            packet.authorities.push(DnsRecord::read(&mut buffer)?);
+            packet.authorities.push(DnsRecord::read(buffer)?);
         }
         for _ in 0..packet.header.num_additional {
-            packet
-                .additional_records
-                .push(DnsRecord::read(&mut buffer)?);
+            packet.additional_records.push(DnsRecord::read(buffer)?);
         }
         Ok(packet)
     }
 
+    // Serialises the message, failing if it doesn't fit in a classic 512-byte UDP datagram.
+    pub fn to_bytes(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.serialize(UDP_MESSAGE_SIZE)
+    }
+
     pub fn write<T: Write>(&mut self, writer: &mut T) -> Result<usize, Box<dyn Error>> {
-        let mut buffer = WrappedBuffer::new();
+        let bytes = self.to_bytes()?;
+        writer.write_all(&bytes)?;
+        Ok(bytes.len())
+    }
+
+    // Serialises into at most `max_size` bytes, dropping whole RRsets from the additional, authority and then answer
+    // sections until the message fits. The OPT record is always kept, and TC is set if any answers had to go so the
+    // client knows to retry over TCP. Like `write`, this leaves the packet matching what was actually sent.
+    pub fn write_with_limit<T: Write>(
+        &mut self,
+        writer: &mut T,
+        max_size: usize,
+    ) -> Result<usize, Box<dyn Error>> {
+        let bytes = self.to_bytes_with_limit(max_size)?;
+        writer.write_all(&bytes)?;
+        Ok(bytes.len())
+    }
+
+    pub fn to_bytes_with_limit(&mut self, max_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let max_size = max_size.min(MAX_MESSAGE_SIZE);
+        loop {
+            let bytes = self.serialize(MAX_MESSAGE_SIZE)?;
+            if bytes.len() <= max_size {
+                return Ok(bytes);
+            }
+            if !self.drop_last_rrset() {
+                return Err(
+                    format!("DNS message cannot be made to fit in {} bytes.", max_size).into(),
+                );
+            }
+        }
+    }
+
+    // The UDP payload size the sender advertised in its OPT record, if it used EDNS.
+    pub fn edns_payload_size(&self) -> Option<u16> {
+        self.additional_records
+            .iter()
+            .find_map(|record| match record {
+                DnsRecord::OPT {
+                    udp_payload_size, ..
+                } => Some(*udp_payload_size),
+                _ => None,
+            })
+    }
+
+    // Whether the sender set the DO bit, asking for DNSSEC records in the response.
+    pub fn dnssec_ok(&self) -> bool {
+        self.additional_records.iter().any(|record| {
+            matches!(
+                record,
+                DnsRecord::OPT {
+                    dnssec_ok: true,
+                    ..
+                }
+            )
+        })
+    }
+
+    // The first Extended DNS Error (RFC 8914) in the OPT record, as its info-code and extra text.
+    pub fn extended_error(&self) -> Option<(u16, String)> {
+        self.additional_records
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::OPT { options, .. } => Some(options),
+                _ => None,
+            })
+            .flatten()
+            .find(|option| option.code == EXTENDED_DNS_ERROR && option.data.len() >= 2)
+            .map(|option| {
+                (
+                    u16::from_be_bytes([option.data[0], option.data[1]]),
+                    String::from_utf8_lossy(&option.data[2..]).into_owned(),
+                )
+            })
+    }
+
+    // Signs the message as the next one in `session`'s exchange by adding a TSIG record, which must come last. Sign
+    // after any other changes (including truncation) or the signature won't match.
+    pub fn sign(&mut self, session: &mut TsigSession) -> Result<(), Box<dyn Error>> {
+        tsig::sign_at(self, session, tsig::now(), 0, Vec::new())
+    }
+
+    // Writes the message with a two-byte length prefix; stream transports allow messages up to 64KiB.
+    pub fn write_framed<T: Write>(&mut self, writer: &mut T) -> Result<usize, Box<dyn Error>> {
+        let bytes = self.serialize(MAX_MESSAGE_SIZE)?;
+        let mut framed = Vec::with_capacity(bytes.len() + 2);
+        framed.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+        framed.extend_from_slice(&bytes);
+        writer.write_all(&framed)?;
+        Ok(framed.len())
+    }
+
+    fn serialize(&mut self, max_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = WrappedBuffer::with_size(max_size);
         self.write_header(&mut buffer)?;
         self.write_records(&mut buffer)?;
-        Ok(writer.write(buffer.get_slice(0, buffer.pos())?)?)
+        Ok(buffer.get_slice(0, buffer.pos())?.to_vec())
+    }
+
+    fn drop_last_rrset(&mut self) -> bool {
+        if remove_last_rrset(&mut self.additional_records)
+            || remove_last_rrset(&mut self.authorities)
+        {
+            return true;
+        }
+        if remove_last_rrset(&mut self.answers) {
+            self.header.truncated_message = true;
+            return true;
+        }
+        false
     }
 
     fn write_header(&mut self, buffer: &mut WrappedBuffer) -> Result<(), Box<dyn Error>> {
@@ -86,6 +276,45 @@ impl DnsPacket {
     }
 }
 
+// Reads a two-byte length prefix and the message after it, or None if the stream ends before the next message.
+fn read_frame<T: Read>(reader: &mut T) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut length = [0; 2];
+    match reader.read_exact(&mut length[..1]) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    reader.read_exact(&mut length[1..])?;
+
+    let mut bytes = vec![0; u16::from_be_bytes(length) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+// Removes every record sharing the owner name and type of the last record in the section (never the OPT record).
+fn remove_last_rrset(records: &mut Vec<DnsRecord>) -> bool {
+    let (domain, query_type) = match records
+        .iter()
+        .rev()
+        .find(|record| !matches!(record, DnsRecord::OPT { .. }))
+    {
+        Some(record) => (record.domain().to_string(), record.query_type()),
+        None => return false,
+    };
+    records.retain(|record| {
+        matches!(record, DnsRecord::OPT { .. })
+            || record.query_type() != query_type
+            || !record.domain().eq_ignore_ascii_case(&domain)
+    });
+    true
+}
+
+impl Default for DnsPacket {
+    fn default() -> Self {
+        DnsPacket::new()
+    }
+}
+
 impl Display for DnsPacket {
     fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(formatter, "{:#?}", self.header)?;
@@ -109,8 +338,30 @@ impl Display for DnsPacket {
 #[cfg(test)]
 mod tests {
     use super::DnsPacket;
-    use crate::parser::test_helpers::{open_test_file, GOOGLE_QUERY};
-    use std::error::Error;
+    use crate::parser::{
+        test_helpers::{open_test_file, GOOGLE_QUERY},
+        wrapped_buffer::MAX_MESSAGE_SIZE,
+        DnsPacketBuilder, DnsRecord, QueryType,
+    };
+    use std::{
+        error::Error,
+        io::{ErrorKind, Read},
+        net::Ipv4Addr,
+    };
+
+    // Hands out at most one byte per read() call, like a slow stream.
+    struct TrickleReader<'a>(&'a [u8]);
+
+    impl Read for TrickleReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
 
     #[test]
     fn actual_question_count_matches_header() -> Result<(), Box<dyn Error>> {
@@ -146,8 +397,207 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn response_copies_id_flags_and_questions_from_query() -> Result<(), Box<dyn Error>> {
+        let mut query = read_packet()?;
+        query.header.response = false;
+        query.header.opcode = 2;
+        query.header.checking_disabled = true;
+        query.header.authentic_data = true;
+
+        let response = DnsPacket::response_to(&query);
+        assert_eq!(response.header.id, query.header.id);
+        assert_eq!(response.header.opcode, 2);
+        assert!(response.header.response);
+        assert!(response.header.recursion_desired);
+        assert!(response.header.checking_disabled);
+        assert!(!response.header.authentic_data);
+        assert_eq!(response.questions, query.questions);
+        assert!(response.answers.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn bytes_survive_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut original = DnsPacket::from_bytes(&read_test_bytes()?)?;
+        let bytes = original.to_bytes()?;
+        let read_back = DnsPacket::from_bytes(&bytes)?;
+
+        assert_eq!(read_back.header.id, original.header.id);
+        assert_eq!(read_back.questions, original.questions);
+        assert_eq!(read_back.answers, original.answers);
+        Ok(())
+    }
+
+    #[test]
+    fn from_bytes_rejects_trailing_data() -> Result<(), Box<dyn Error>> {
+        let mut bytes = read_test_bytes()?;
+        bytes.extend_from_slice(&[0, 0, 0]);
+
+        let error =
+            DnsPacket::from_bytes(&bytes).expect_err("Expected trailing data to be rejected.");
+        assert!(error.to_string().contains("3 bytes of trailing data"));
+        Ok(())
+    }
+
+    #[test]
+    fn from_bytes_rejects_truncated_message() -> Result<(), Box<dyn Error>> {
+        let bytes = read_test_bytes()?;
+        assert!(DnsPacket::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(DnsPacket::from_bytes(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_copes_with_partial_reads() -> Result<(), Box<dyn Error>> {
+        let bytes = read_test_bytes()?;
+        let packet = DnsPacket::read(&mut TrickleReader(&bytes))?;
+        assert_eq!(packet.answers.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn framed_messages_survive_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut first = read_packet()?;
+        let mut second = read_packet()?;
+        second.header.id = 1234;
+
+        let mut stream = Vec::new();
+        first.write_framed(&mut stream)?;
+        second.write_framed(&mut stream)?;
+        let first_length = first.to_bytes()?.len();
+        assert_eq!(&stream[..2], &(first_length as u16).to_be_bytes());
+
+        let mut reader = TrickleReader(&stream);
+        let read_first = DnsPacket::read_framed(&mut reader)?.expect("Expected a first message.");
+        let read_second = DnsPacket::read_framed(&mut reader)?.expect("Expected a second message.");
+        assert_eq!(read_first.header.id, first.header.id);
+        assert_eq!(read_second.header.id, 1234);
+        assert!(DnsPacket::read_framed(&mut reader)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn read_framed_fails_when_stream_ends_mid_message() -> Result<(), Box<dyn Error>> {
+        let mut stream = Vec::new();
+        read_packet()?.write_framed(&mut stream)?;
+        stream.truncate(20);
+
+        let error = DnsPacket::read_framed(&mut stream.as_slice())
+            .expect_err("Expected a truncated stream to fail.");
+        let io_error = error
+            .downcast_ref::<std::io::Error>()
+            .expect("Expected an I/O error.");
+        assert_eq!(io_error.kind(), ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    #[test]
+    fn framed_messages_may_exceed_udp_limit() -> Result<(), Box<dyn Error>> {
+        let mut packet = read_packet()?;
+        for i in 0..40 {
+            packet.answers.push(DnsRecord::A {
+                domain: String::from("google.com"),
+                address: Ipv4Addr::new(10, 0, 0, i),
+                ttl: 60,
+            });
+        }
+        assert!(packet.to_bytes().is_err());
+
+        let mut stream = Vec::new();
+        packet.write_framed(&mut stream)?;
+        let read_back =
+            DnsPacket::read_framed(&mut stream.as_slice())?.expect("Expected a message.");
+        assert_eq!(read_back.answers.len(), 41);
+        Ok(())
+    }
+
+    #[test]
+    fn limited_write_leaves_small_message_alone() -> Result<(), Box<dyn Error>> {
+        let mut packet = response_with_records(2, 2, 2);
+        let unlimited = packet.serialize(MAX_MESSAGE_SIZE)?;
+
+        let limited = packet.to_bytes_with_limit(512)?;
+        assert_eq!(limited, unlimited);
+        assert!(!packet.header.truncated_message);
+        Ok(())
+    }
+
+    #[test]
+    fn limited_write_drops_additional_records_first() -> Result<(), Box<dyn Error>> {
+        let mut packet = response_with_records(10, 5, 30);
+        let bytes = packet.to_bytes_with_limit(512)?;
+
+        assert!(bytes.len() <= 512);
+        assert!(!packet.header.truncated_message);
+        assert_eq!(packet.answers.len(), 10);
+        // Whole RRsets go at once, and the OPT record stays.
+        assert!(packet.additional_records.iter().all(
+            |record| !matches!(record, DnsRecord::A { domain, .. } if domain == "extra.example.com")
+        ));
+        assert_eq!(packet.edns_payload_size(), Some(512));
+
+        let read_back = DnsPacket::from_bytes(&bytes)?;
+        assert_eq!(read_back.answers.len(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn limited_write_sets_tc_when_answers_are_dropped() -> Result<(), Box<dyn Error>> {
+        let mut packet = response_with_records(40, 5, 5);
+        let bytes = packet.to_bytes_with_limit(512)?;
+        assert!(bytes.len() <= 512);
+
+        let read_back = DnsPacket::from_bytes(&bytes)?;
+        assert!(read_back.header.truncated_message);
+        assert!(read_back.authorities.is_empty());
+        assert!(read_back.answers.len() < 40);
+        assert_eq!(read_back.edns_payload_size(), Some(512));
+        Ok(())
+    }
+
+    #[test]
+    fn limited_write_fails_when_nothing_left_to_drop() {
+        let mut packet = response_with_records(0, 0, 0);
+        assert!(packet.to_bytes_with_limit(20).is_err());
+    }
+
+    // Answers are split across two RRsets (A records for two names) so whole-RRset removal is visible.
+    fn response_with_records(answers: u8, authorities: u8, additional: u8) -> DnsPacket {
+        let record = |domain: &str, i: u8| DnsRecord::A {
+            domain: String::from(domain),
+            address: Ipv4Addr::new(192, 0, 2, i),
+            ttl: 300,
+        };
+        let mut packet = DnsPacketBuilder::new()
+            .response(true)
+            .question("example.com", QueryType::A)
+            .answers((0..answers).map(|i| {
+                record(
+                    if i % 2 == 0 {
+                        "example.com"
+                    } else {
+                        "www.example.com"
+                    },
+                    i,
+                )
+            }))
+            .authorities((0..authorities).map(|i| record("ns.example.com", i)))
+            .additional_records((0..additional).map(|i| record("extra.example.com", i)))
+            .edns(512)
+            .build();
+        packet.header.id = 7;
+        packet
+    }
+
+    fn read_test_bytes() -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut bytes = Vec::new();
+        open_test_file(String::from(GOOGLE_QUERY))?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
     fn read_packet() -> Result<DnsPacket, Box<dyn Error>> {
         let mut file = open_test_file(String::from(GOOGLE_QUERY))?;
-        Ok(DnsPacket::read(&mut file)?)
+        DnsPacket::read(&mut file)
     }
 }
@@ -0,0 +1,337 @@
+use super::{
+    dns_packet::DnsPacket,
+    dns_question::DnsQuestion,
+    dns_record::{DnsRecord, EdnsOption},
+    query_type::QueryType,
+    result_code::ResultCode,
+};
+
+// EDNS option carrying an Extended DNS Error (RFC 8914).
+pub const EXTENDED_DNS_ERROR: u16 = 15;
+
+// Conservative EDNS buffer size which avoids IP fragmentation on practically every path (DNS Flag Day 2020).
+pub const DEFAULT_EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+/// Builds up a `DnsPacket` one piece at a time, e.g.
+/// `DnsPacketBuilder::query().question("google.com", QueryType::A).build()`.
+/// Section counts in the header are filled in by `build`.
+#[derive(Clone, Debug)]
+pub struct DnsPacketBuilder {
+    packet: DnsPacket,
+}
+
+impl DnsPacketBuilder {
+    pub fn new() -> DnsPacketBuilder {
+        DnsPacketBuilder {
+            packet: DnsPacket::new(),
+        }
+    }
+
+    // A standard recursive query with a random ID.
+    pub fn query() -> DnsPacketBuilder {
+        DnsPacketBuilder::new().random_id().recursion_desired(true)
+    }
+
+    // A response which echoes the query's ID, opcode, RD/CD flags and question section.
+    pub fn response_to(query: &DnsPacket) -> DnsPacketBuilder {
+        DnsPacketBuilder {
+            packet: DnsPacket::response_to(query),
+        }
+    }
+
+    pub fn id(mut self, id: u16) -> DnsPacketBuilder {
+        self.packet.header.id = id;
+        self
+    }
+
+    pub fn random_id(self) -> DnsPacketBuilder {
+        self.id(rand::random())
+    }
+
+    pub fn opcode(mut self, opcode: u8) -> DnsPacketBuilder {
+        self.packet.header.opcode = opcode;
+        self
+    }
+
+    pub fn response(mut self, response: bool) -> DnsPacketBuilder {
+        self.packet.header.response = response;
+        self
+    }
+
+    pub fn authoritative_answer(mut self, authoritative_answer: bool) -> DnsPacketBuilder {
+        self.packet.header.authoritative_answer = authoritative_answer;
+        self
+    }
+
+    pub fn truncated_message(mut self, truncated_message: bool) -> DnsPacketBuilder {
+        self.packet.header.truncated_message = truncated_message;
+        self
+    }
+
+    pub fn recursion_desired(mut self, recursion_desired: bool) -> DnsPacketBuilder {
+        self.packet.header.recursion_desired = recursion_desired;
+        self
+    }
+
+    pub fn recursion_available(mut self, recursion_available: bool) -> DnsPacketBuilder {
+        self.packet.header.recursion_available = recursion_available;
+        self
+    }
+
+    pub fn authentic_data(mut self, authentic_data: bool) -> DnsPacketBuilder {
+        self.packet.header.authentic_data = authentic_data;
+        self
+    }
+
+    pub fn checking_disabled(mut self, checking_disabled: bool) -> DnsPacketBuilder {
+        self.packet.header.checking_disabled = checking_disabled;
+        self
+    }
+
+    pub fn rescode(mut self, rescode: ResultCode) -> DnsPacketBuilder {
+        self.packet.header.rescode = rescode;
+        self
+    }
+
+    pub fn question(mut self, name: &str, query_type: QueryType) -> DnsPacketBuilder {
+        self.packet.questions.push(DnsQuestion {
+            name: name.to_string(),
+            query_type,
+        });
+        self
+    }
+
+    pub fn answer(mut self, record: DnsRecord) -> DnsPacketBuilder {
+        self.packet.answers.push(record);
+        self
+    }
+
+    pub fn answers<I: IntoIterator<Item = DnsRecord>>(mut self, records: I) -> DnsPacketBuilder {
+        self.packet.answers.extend(records);
+        self
+    }
+
+    pub fn authority(mut self, record: DnsRecord) -> DnsPacketBuilder {
+        self.packet.authorities.push(record);
+        self
+    }
+
+    pub fn authorities<I: IntoIterator<Item = DnsRecord>>(
+        mut self,
+        records: I,
+    ) -> DnsPacketBuilder {
+        self.packet.authorities.extend(records);
+        self
+    }
+
+    pub fn additional_record(mut self, record: DnsRecord) -> DnsPacketBuilder {
+        self.packet.additional_records.push(record);
+        self
+    }
+
+    pub fn additional_records<I: IntoIterator<Item = DnsRecord>>(
+        mut self,
+        records: I,
+    ) -> DnsPacketBuilder {
+        self.packet.additional_records.extend(records);
+        self
+    }
+
+    // Adds an OPT record advertising the given UDP payload size, replacing any existing one.
+    pub fn edns(mut self, udp_payload_size: u16) -> DnsPacketBuilder {
+        let dnssec_ok = match self.opt_record() {
+            Some(DnsRecord::OPT { dnssec_ok, .. }) => *dnssec_ok,
+            _ => false,
+        };
+        self.packet
+            .additional_records
+            .retain(|record| !matches!(record, DnsRecord::OPT { .. }));
+        self.packet.additional_records.push(DnsRecord::OPT {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok,
+            options: Vec::new(),
+        });
+        self
+    }
+
+    // Sets the DNSSEC OK bit, adding an OPT record with the default payload size if there isn't one yet.
+    pub fn dnssec_ok(mut self, value: bool) -> DnsPacketBuilder {
+        if self.opt_record().is_none() {
+            self = self.edns(DEFAULT_EDNS_PAYLOAD_SIZE);
+        }
+        for record in self.packet.additional_records.iter_mut() {
+            if let DnsRecord::OPT { dnssec_ok, .. } = record {
+                *dnssec_ok = value;
+            }
+        }
+        self
+    }
+
+    // Adds an Extended DNS Error (RFC 8914) saying why the query failed, adding an OPT record if there isn't one yet.
+    pub fn extended_error(mut self, info_code: u16, extra_text: &str) -> DnsPacketBuilder {
+        if self.opt_record().is_none() {
+            self = self.edns(DEFAULT_EDNS_PAYLOAD_SIZE);
+        }
+        let mut data = info_code.to_be_bytes().to_vec();
+        data.extend_from_slice(extra_text.as_bytes());
+        for record in self.packet.additional_records.iter_mut() {
+            if let DnsRecord::OPT { options, .. } = record {
+                options.push(EdnsOption {
+                    code: EXTENDED_DNS_ERROR,
+                    data: data.clone(),
+                });
+            }
+        }
+        self
+    }
+
+    pub fn build(mut self) -> DnsPacket {
+        self.packet.header.num_questions = self.packet.questions.len() as u16;
+        self.packet.header.num_answers = self.packet.answers.len() as u16;
+        self.packet.header.num_authorities = self.packet.authorities.len() as u16;
+        self.packet.header.num_additional = self.packet.additional_records.len() as u16;
+        self.packet
+    }
+
+    fn opt_record(&self) -> Option<&DnsRecord> {
+        self.packet
+            .additional_records
+            .iter()
+            .find(|record| matches!(record, DnsRecord::OPT { .. }))
+    }
+}
+
+impl Default for DnsPacketBuilder {
+    fn default() -> Self {
+        DnsPacketBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DnsPacketBuilder, DEFAULT_EDNS_PAYLOAD_SIZE};
+    use crate::parser::{DnsPacket, DnsRecord, QueryType, ResultCode};
+    use std::{error::Error, net::Ipv4Addr};
+
+    #[test]
+    fn builds_recursive_query() {
+        let query = DnsPacketBuilder::query()
+            .question("google.com", QueryType::A)
+            .build();
+
+        assert!(query.header.recursion_desired);
+        assert!(!query.header.response);
+        assert_eq!(query.header.num_questions, 1);
+        assert_eq!(query.questions[0].name, "google.com");
+    }
+
+    #[test]
+    fn query_ids_are_random() {
+        let ids: Vec<u16> = (0..8)
+            .map(|_| DnsPacketBuilder::query().build().header.id)
+            .collect();
+        assert!(ids.iter().any(|id| *id != ids[0]));
+    }
+
+    #[test]
+    fn builds_response_with_records_in_each_section() {
+        let query = DnsPacketBuilder::query()
+            .id(4242)
+            .checking_disabled(true)
+            .question("google.com", QueryType::A)
+            .build();
+        let record = DnsRecord::A {
+            domain: String::from("google.com"),
+            address: Ipv4Addr::new(142, 250, 71, 78),
+            ttl: 300,
+        };
+
+        let response = DnsPacketBuilder::response_to(&query)
+            .recursion_available(true)
+            .rescode(ResultCode::NOERROR)
+            .answer(record.clone())
+            .authorities(vec![record.clone(), record.clone()])
+            .additional_record(record)
+            .build();
+
+        assert_eq!(response.header.id, 4242);
+        assert!(response.header.response);
+        assert!(response.header.recursion_desired);
+        assert!(response.header.checking_disabled);
+        assert!(response.header.recursion_available);
+        assert_eq!(response.questions, query.questions);
+        assert_eq!(
+            (
+                response.header.num_answers,
+                response.header.num_authorities,
+                response.header.num_additional
+            ),
+            (1, 2, 1)
+        );
+    }
+
+    #[test]
+    fn adds_single_opt_record() {
+        let query = DnsPacketBuilder::query()
+            .question("google.com", QueryType::A)
+            .dnssec_ok(true)
+            .edns(4096)
+            .build();
+
+        assert_eq!(query.additional_records.len(), 1);
+        match &query.additional_records[0] {
+            DnsRecord::OPT {
+                udp_payload_size,
+                dnssec_ok,
+                ..
+            } => {
+                assert_eq!(*udp_payload_size, 4096);
+                assert!(*dnssec_ok);
+            }
+            _ => panic!("Expected an OPT record."),
+        }
+    }
+
+    #[test]
+    fn dnssec_ok_uses_default_payload_size() {
+        let query = DnsPacketBuilder::query().dnssec_ok(true).build();
+        assert!(matches!(
+            query.additional_records.first(),
+            Some(DnsRecord::OPT { udp_payload_size, dnssec_ok: true, .. })
+                if *udp_payload_size == DEFAULT_EDNS_PAYLOAD_SIZE
+        ));
+    }
+
+    #[test]
+    fn extended_error_survives_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut response = DnsPacketBuilder::new()
+            .response(true)
+            .rescode(ResultCode::SERVFAIL)
+            .extended_error(22, "No response from 192.0.2.1:53.")
+            .build();
+        let parsed = DnsPacket::from_bytes(&response.to_bytes()?)?;
+        assert_eq!(
+            parsed.extended_error(),
+            Some((22, "No response from 192.0.2.1:53.".to_string()))
+        );
+        assert_eq!(parsed.edns_payload_size(), Some(DEFAULT_EDNS_PAYLOAD_SIZE));
+        Ok(())
+    }
+
+    #[test]
+    fn built_query_survives_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut query = DnsPacketBuilder::query()
+            .question("github.ru", QueryType::A)
+            .edns(DEFAULT_EDNS_PAYLOAD_SIZE)
+            .build();
+
+        let parsed = DnsPacket::from_bytes(&query.to_bytes()?)?;
+
+        assert_eq!(parsed.header.id, query.header.id);
+        assert_eq!(parsed.questions, query.questions);
+        assert_eq!(parsed.additional_records, query.additional_records);
+        Ok(())
+    }
+}
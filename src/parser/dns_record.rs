@@ -1,18 +1,37 @@
-use std::net::Ipv4Addr;
+use std::{
+    fmt::Display,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use data_encoding::{BASE32HEX_NOPAD, BASE64, HEXUPPER};
 
 use super::{
     bitshifting::get_nth_octal,
+    loc,
     query_name_parser::{QueryName, QueryNameParser},
     query_type::QueryType,
+    result_code::ResultCode,
+    rrset::{is_subdomain, labels, name_to_wire},
+    svcb::{self, SvcParam, SvcbMode},
+    tsig::TsigError,
+    type_bitmap,
     wrapped_buffer::WrappedBuffer,
 };
 
+// The longest a name can be in wire format (RFC 1035 section 2.3.4).
+const MAX_NAME_LENGTH: usize = 255;
+
+const CLASS_IN: u16 = 1;
+// The class used by pseudo-records which apply to the whole message rather than to one class of data.
+pub(super) const CLASS_ANY: u16 = 255;
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum DnsRecord {
+    // Kept as the raw record data, which is all RFC 3597 asks of a server passing on types it doesn't know.
     UNKNOWN {
         domain: String,
         query_type: u16,
-        data_length: u16,
+        data: Vec<u8>,
         ttl: u32,
     },
     A {
@@ -20,6 +39,193 @@ pub enum DnsRecord {
         address: Ipv4Addr,
         ttl: u32,
     },
+    NS {
+        domain: String,
+        host: String,
+        ttl: u32,
+    },
+    CNAME {
+        domain: String,
+        host: String,
+        ttl: u32,
+    },
+    // Redirects everything below the owner (but not the owner itself) to the same names below the target (RFC 6672).
+    DNAME {
+        domain: String,
+        target: String,
+        ttl: u32,
+    },
+    SOA {
+        domain: String,
+        primary_name_server: String,
+        mailbox: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    },
+    // Character strings are kept as bytes, since nothing says they're text in any particular encoding.
+    HINFO {
+        domain: String,
+        cpu: Vec<u8>,
+        os: Vec<u8>,
+        ttl: u32,
+    },
+    AAAA {
+        domain: String,
+        address: Ipv6Addr,
+        ttl: u32,
+    },
+    // Location (RFC 1876), in the fixed-point wire encoding described in the loc module. Only version 0 exists.
+    LOC {
+        domain: String,
+        size: u8,
+        horizontal_precision: u8,
+        vertical_precision: u8,
+        latitude: u32,
+        longitude: u32,
+        altitude: u32,
+        ttl: u32,
+    },
+    // Naming authority pointer (RFC 3403). The replacement name is never compressed, and is the root if unused.
+    NAPTR {
+        domain: String,
+        order: u16,
+        preference: u16,
+        flags: Vec<u8>,
+        services: Vec<u8>,
+        regexp: Vec<u8>,
+        replacement: String,
+        ttl: u32,
+    },
+    // EDNS(0) pseudo-record (RFC 6891). Always owned by the root, and repurposes the class and TTL fields.
+    OPT {
+        udp_payload_size: u16,
+        extended_rcode: u8,
+        version: u8,
+        dnssec_ok: bool,
+        options: Vec<EdnsOption>,
+    },
+    // DNSSEC records (RFC 4034 and RFC 5155). Names inside their data (signer, next owner) are never compressed.
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    },
+    RRSIG {
+        domain: String,
+        type_covered: QueryType,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        signature_expiration: u32,
+        signature_inception: u32,
+        key_tag: u16,
+        signer_name: String,
+        signature: Vec<u8>,
+        ttl: u32,
+    },
+    NSEC {
+        domain: String,
+        next_domain: String,
+        types: Vec<QueryType>,
+        ttl: u32,
+    },
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    },
+    NSEC3 {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed_owner: Vec<u8>,
+        types: Vec<QueryType>,
+        ttl: u32,
+    },
+    NSEC3PARAM {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        ttl: u32,
+    },
+    // Certificate and host key pinning: CAA (RFC 8659), TLSA (RFC 6698) and SSHFP (RFC 4255).
+    CAA {
+        domain: String,
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+        ttl: u32,
+    },
+    TLSA {
+        domain: String,
+        usage: u8,
+        selector: u8,
+        matching_type: u8,
+        certificate_data: Vec<u8>,
+        ttl: u32,
+    },
+    SSHFP {
+        domain: String,
+        algorithm: u8,
+        fingerprint_type: u8,
+        fingerprint: Vec<u8>,
+        ttl: u32,
+    },
+    // Service binding records (RFC 9460). HTTPS is SVCB for https:// origins. The target name is never compressed.
+    SVCB {
+        domain: String,
+        priority: u16,
+        target: String,
+        params: Vec<SvcParam>,
+        ttl: u32,
+    },
+    HTTPS {
+        domain: String,
+        priority: u16,
+        target: String,
+        params: Vec<SvcParam>,
+        ttl: u32,
+    },
+    // URI (RFC 7553). The target runs to the end of the record data rather than being length-prefixed.
+    URI {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        target: Vec<u8>,
+        ttl: u32,
+    },
+    // Transaction signature pseudo-record (RFC 8945). Owned by the key name, always class ANY with a TTL of 0.
+    TSIG {
+        domain: String,
+        algorithm: String,
+        // Seconds since the epoch, 48 bits on the wire.
+        time_signed: u64,
+        fudge: u16,
+        mac: Vec<u8>,
+        original_id: u16,
+        error: u16,
+        other_data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
 }
 
 impl DnsRecord {
@@ -29,12 +235,13 @@ impl DnsRecord {
 
         let query_type_num = buffer.read_u16()?;
         let query_type = QueryType::from_u16(query_type_num);
-        buffer.read_u16()?;
+        let class = buffer.read_u16()?;
 
         let ttl = buffer.read_u32()?;
         let data_length = buffer.read_u16()?;
+        let data_end = buffer.pos() + data_length as usize;
 
-        match query_type {
+        let record: Result<DnsRecord, String> = match query_type {
             QueryType::A => {
                 let raw_address = buffer.read_u32()?;
                 let address = Ipv4Addr::new(
@@ -49,16 +256,815 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::NS | QueryType::CNAME | QueryType::DNAME => {
+                let mut host = String::new();
+                QueryName::read(buffer, &mut host)?;
+                Ok(match query_type {
+                    QueryType::NS => DnsRecord::NS { domain, host, ttl },
+                    QueryType::CNAME => DnsRecord::CNAME { domain, host, ttl },
+                    _ => DnsRecord::DNAME {
+                        domain,
+                        target: host,
+                        ttl,
+                    },
+                })
+            }
+            QueryType::SOA => {
+                let mut primary_name_server = String::new();
+                QueryName::read(buffer, &mut primary_name_server)?;
+                let mut mailbox = String::new();
+                QueryName::read(buffer, &mut mailbox)?;
+                Ok(DnsRecord::SOA {
+                    domain,
+                    primary_name_server,
+                    mailbox,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl,
+                })
+            }
+            QueryType::HINFO => {
+                let cpu = read_character_string(buffer)?;
+                let os = read_character_string(buffer)?;
+                remaining_data(buffer, data_end)?;
+                Ok(DnsRecord::HINFO {
+                    domain,
+                    cpu,
+                    os,
+                    ttl,
+                })
+            }
+            QueryType::AAAA => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&buffer.read_bytes(16)?);
+                Ok(DnsRecord::AAAA {
+                    domain,
+                    address: Ipv6Addr::from(octets),
+                    ttl,
+                })
+            }
+            QueryType::LOC => {
+                let version = buffer.read_u8()?;
+                if version != loc::VERSION {
+                    return Err(format!("Unsupported LOC version {}.", version));
+                }
+                let size = buffer.read_u8()?;
+                let horizontal_precision = buffer.read_u8()?;
+                let vertical_precision = buffer.read_u8()?;
+                for encoded in [size, horizontal_precision, vertical_precision] {
+                    loc::validate_size(encoded)?;
+                }
+                let latitude = buffer.read_u32()?;
+                let longitude = buffer.read_u32()?;
+                let altitude = buffer.read_u32()?;
+                remaining_data(buffer, data_end)?;
+                Ok(DnsRecord::LOC {
+                    domain,
+                    size,
+                    horizontal_precision,
+                    vertical_precision,
+                    latitude,
+                    longitude,
+                    altitude,
+                    ttl,
+                })
+            }
+            QueryType::NAPTR => {
+                let order = buffer.read_u16()?;
+                let preference = buffer.read_u16()?;
+                let flags = read_character_string(buffer)?;
+                let services = read_character_string(buffer)?;
+                let regexp = read_character_string(buffer)?;
+                let mut replacement = String::new();
+                QueryName::read(buffer, &mut replacement)?;
+                remaining_data(buffer, data_end)?;
+                Ok(DnsRecord::NAPTR {
+                    domain,
+                    order,
+                    preference,
+                    flags,
+                    services,
+                    regexp,
+                    replacement,
+                    ttl,
+                })
+            }
+            QueryType::OPT => {
+                let mut options = Vec::new();
+                while buffer.pos() < data_end {
+                    let code = buffer.read_u16()?;
+                    let option_length = buffer.read_u16()? as usize;
+                    let data = buffer.get_slice(buffer.pos(), option_length)?.to_vec();
+                    buffer.advance(option_length)?;
+                    options.push(EdnsOption { code, data });
+                }
+                Ok(DnsRecord::OPT {
+                    udp_payload_size: class,
+                    extended_rcode: (ttl >> 24) as u8,
+                    version: (ttl >> 16) as u8,
+                    dnssec_ok: ttl & 0x8000 != 0,
+                    options,
+                })
+            }
+            QueryType::DS => {
+                let key_tag = buffer.read_u16()?;
+                let algorithm = buffer.read_u8()?;
+                let digest_type = buffer.read_u8()?;
+                let digest = buffer.read_bytes(remaining_data(buffer, data_end)?)?;
+                Ok(DnsRecord::DS {
+                    domain,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                    ttl,
+                })
+            }
+            QueryType::RRSIG => {
+                let type_covered = QueryType::from_u16(buffer.read_u16()?);
+                let algorithm = buffer.read_u8()?;
+                let labels = buffer.read_u8()?;
+                let original_ttl = buffer.read_u32()?;
+                let signature_expiration = buffer.read_u32()?;
+                let signature_inception = buffer.read_u32()?;
+                let key_tag = buffer.read_u16()?;
+                let mut signer_name = String::new();
+                QueryName::read(buffer, &mut signer_name)?;
+                let signature = buffer.read_bytes(remaining_data(buffer, data_end)?)?;
+                Ok(DnsRecord::RRSIG {
+                    domain,
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    signature_expiration,
+                    signature_inception,
+                    key_tag,
+                    signer_name,
+                    signature,
+                    ttl,
+                })
+            }
+            QueryType::NSEC => {
+                let mut next_domain = String::new();
+                QueryName::read(buffer, &mut next_domain)?;
+                let types = type_bitmap::read(buffer, remaining_data(buffer, data_end)?)?;
+                Ok(DnsRecord::NSEC {
+                    domain,
+                    next_domain,
+                    types,
+                    ttl,
+                })
+            }
+            QueryType::DNSKEY => {
+                let flags = buffer.read_u16()?;
+                let protocol = buffer.read_u8()?;
+                let algorithm = buffer.read_u8()?;
+                let public_key = buffer.read_bytes(remaining_data(buffer, data_end)?)?;
+                Ok(DnsRecord::DNSKEY {
+                    domain,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3 => {
+                let hash_algorithm = buffer.read_u8()?;
+                let flags = buffer.read_u8()?;
+                let iterations = buffer.read_u16()?;
+                let salt_length = buffer.read_u8()? as usize;
+                let salt = buffer.read_bytes(salt_length)?;
+                let hash_length = buffer.read_u8()? as usize;
+                let next_hashed_owner = buffer.read_bytes(hash_length)?;
+                let types = type_bitmap::read(buffer, remaining_data(buffer, data_end)?)?;
+                Ok(DnsRecord::NSEC3 {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed_owner,
+                    types,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3PARAM => {
+                let hash_algorithm = buffer.read_u8()?;
+                let flags = buffer.read_u8()?;
+                let iterations = buffer.read_u16()?;
+                let salt_length = buffer.read_u8()? as usize;
+                let salt = buffer.read_bytes(salt_length)?;
+                remaining_data(buffer, data_end)?;
+                Ok(DnsRecord::NSEC3PARAM {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    ttl,
+                })
+            }
+            QueryType::CAA => {
+                let flags = buffer.read_u8()?;
+                let tag_length = buffer.read_u8()? as usize;
+                let tag = buffer.read_bytes(tag_length)?;
+                check_caa_tag(&tag)?;
+                let tag = String::from_utf8(tag).map_err(|error| error.to_string())?;
+                let value = buffer.read_bytes(remaining_data(buffer, data_end)?)?;
+                Ok(DnsRecord::CAA {
+                    domain,
+                    flags,
+                    tag,
+                    value,
+                    ttl,
+                })
+            }
+            QueryType::TLSA => {
+                let usage = buffer.read_u8()?;
+                let selector = buffer.read_u8()?;
+                let matching_type = buffer.read_u8()?;
+                let certificate_data = buffer.read_bytes(remaining_data(buffer, data_end)?)?;
+                Ok(DnsRecord::TLSA {
+                    domain,
+                    usage,
+                    selector,
+                    matching_type,
+                    certificate_data,
+                    ttl,
+                })
+            }
+            QueryType::SSHFP => {
+                let algorithm = buffer.read_u8()?;
+                let fingerprint_type = buffer.read_u8()?;
+                let fingerprint = buffer.read_bytes(remaining_data(buffer, data_end)?)?;
+                Ok(DnsRecord::SSHFP {
+                    domain,
+                    algorithm,
+                    fingerprint_type,
+                    fingerprint,
+                    ttl,
+                })
+            }
+            QueryType::SVCB | QueryType::HTTPS => {
+                let priority = buffer.read_u16()?;
+                let mut target = String::new();
+                QueryName::read(buffer, &mut target)?;
+                let params = svcb::read(buffer, remaining_data(buffer, data_end)?)?;
+                Ok(match query_type {
+                    QueryType::SVCB => DnsRecord::SVCB {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        ttl,
+                    },
+                    _ => DnsRecord::HTTPS {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        ttl,
+                    },
+                })
+            }
+            QueryType::URI => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let target = buffer.read_bytes(remaining_data(buffer, data_end)?)?;
+                Ok(DnsRecord::URI {
+                    domain,
+                    priority,
+                    weight,
+                    target,
+                    ttl,
+                })
+            }
+            QueryType::TSIG => {
+                let mut algorithm = String::new();
+                QueryName::read(buffer, &mut algorithm)?;
+                let time_signed = (buffer.read_u16()? as u64) << 32 | buffer.read_u32()? as u64;
+                let fudge = buffer.read_u16()?;
+                let mac_size = buffer.read_u16()? as usize;
+                let mac = buffer.read_bytes(mac_size)?;
+                let original_id = buffer.read_u16()?;
+                let error = buffer.read_u16()?;
+                let other_length = buffer.read_u16()? as usize;
+                let other_data = buffer.read_bytes(other_length)?;
+                remaining_data(buffer, data_end)?;
+                Ok(DnsRecord::TSIG {
+                    domain,
+                    algorithm,
+                    time_signed,
+                    fudge,
+                    mac,
+                    original_id,
+                    error,
+                    other_data,
+                })
+            }
             QueryType::UNKNOWN(_) => {
-                buffer.advance(data_length.into())?;
+                // Compressed names would point at the wrong place in any other message, so they're expanded.
+                let data = match embedded_names(query_type_num) {
+                    Some((fixed_length, name_count)) => {
+                        let mut data = buffer.read_bytes(fixed_length)?;
+                        for _ in 0..name_count {
+                            let mut name = String::new();
+                            QueryName::read(buffer, &mut name)?;
+                            data.extend(name_to_wire(&name));
+                        }
+                        data.extend(buffer.read_bytes(remaining_data(buffer, data_end)?)?);
+                        data
+                    }
+                    None => buffer.read_bytes(data_length.into())?,
+                };
                 let query_type = query_type_num;
                 Ok(DnsRecord::UNKNOWN {
                     domain,
                     query_type,
-                    data_length,
+                    data,
                     ttl,
                 })
             }
+        };
+        let record = record?;
+        // Data that stops short of its length or runs past it would leave the rest of the message misread.
+        if buffer.pos() != data_end {
+            return Err(format!(
+                "{} record data doesn't match its advertised length of {}.",
+                query_type, data_length
+            ));
+        }
+        Ok(record)
+    }
+
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::DNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::HINFO { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::LOC { domain, .. }
+            | DnsRecord::NAPTR { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::NSEC3PARAM { domain, .. }
+            | DnsRecord::CAA { domain, .. }
+            | DnsRecord::TLSA { domain, .. }
+            | DnsRecord::SSHFP { domain, .. }
+            | DnsRecord::SVCB { domain, .. }
+            | DnsRecord::HTTPS { domain, .. }
+            | DnsRecord::URI { domain, .. }
+            | DnsRecord::TSIG { domain, .. } => domain,
+            DnsRecord::OPT { .. } => "",
+        }
+    }
+
+    pub fn query_type(&self) -> QueryType {
+        match self {
+            DnsRecord::UNKNOWN { query_type, .. } => QueryType::UNKNOWN(*query_type),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::DNAME { .. } => QueryType::DNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::HINFO { .. } => QueryType::HINFO,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::LOC { .. } => QueryType::LOC,
+            DnsRecord::NAPTR { .. } => QueryType::NAPTR,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::NSEC { .. } => QueryType::NSEC,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::NSEC3 { .. } => QueryType::NSEC3,
+            DnsRecord::NSEC3PARAM { .. } => QueryType::NSEC3PARAM,
+            DnsRecord::CAA { .. } => QueryType::CAA,
+            DnsRecord::TLSA { .. } => QueryType::TLSA,
+            DnsRecord::SSHFP { .. } => QueryType::SSHFP,
+            DnsRecord::SVCB { .. } => QueryType::SVCB,
+            DnsRecord::HTTPS { .. } => QueryType::HTTPS,
+            DnsRecord::URI { .. } => QueryType::URI,
+            DnsRecord::TSIG { .. } => QueryType::TSIG,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::DNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::HINFO { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::LOC { ttl, .. }
+            | DnsRecord::NAPTR { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::NSEC3PARAM { ttl, .. }
+            | DnsRecord::CAA { ttl, .. }
+            | DnsRecord::TLSA { ttl, .. }
+            | DnsRecord::SSHFP { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. }
+            | DnsRecord::HTTPS { ttl, .. }
+            | DnsRecord::URI { ttl, .. } => *ttl,
+            DnsRecord::OPT { .. } | DnsRecord::TSIG { .. } => 0,
+        }
+    }
+
+    // Pseudo-records have no TTL to set.
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::DNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::HINFO { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::LOC { ttl, .. }
+            | DnsRecord::NAPTR { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::NSEC3PARAM { ttl, .. }
+            | DnsRecord::CAA { ttl, .. }
+            | DnsRecord::TLSA { ttl, .. }
+            | DnsRecord::SSHFP { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. }
+            | DnsRecord::HTTPS { ttl, .. }
+            | DnsRecord::URI { ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } | DnsRecord::TSIG { .. } => {}
+        }
+    }
+
+    // IN for everything but the pseudo-records: TSIG is ANY, and OPT uses the class for its UDP payload size.
+    pub fn class(&self) -> u16 {
+        match self {
+            DnsRecord::OPT {
+                udp_payload_size, ..
+            } => *udp_payload_size,
+            DnsRecord::TSIG { .. } => CLASS_ANY,
+            _ => CLASS_IN,
+        }
+    }
+
+    // The key tag (RFC 4034 appendix B) which DS and RRSIG records use to refer to this key, if this is a DNSKEY.
+    pub fn key_tag(&self) -> Option<u16> {
+        let DnsRecord::DNSKEY {
+            flags,
+            protocol,
+            algorithm,
+            public_key,
+            ..
+        } = self
+        else {
+            return None;
+        };
+
+        // Algorithm 1 (RSA/MD5) is the odd one out: its tag is taken straight from the end of the modulus.
+        if *algorithm == 1 {
+            let len = public_key.len();
+            return match len {
+                0..=2 => Some(0),
+                _ => Some(u16::from_be_bytes([
+                    public_key[len - 3],
+                    public_key[len - 2],
+                ])),
+            };
+        }
+
+        let mut data = vec![(flags >> 8) as u8, *flags as u8, *protocol, *algorithm];
+        data.extend_from_slice(public_key);
+        let mut accumulator: u32 = 0;
+        for (i, byte) in data.iter().enumerate() {
+            accumulator += if i % 2 == 0 {
+                (*byte as u32) << 8
+            } else {
+                *byte as u32
+            };
+        }
+        accumulator += (accumulator >> 16) & 0xFFFF;
+        Some((accumulator & 0xFFFF) as u16)
+    }
+
+    // The latitude and longitude of a LOC record in degrees, north and east being positive.
+    pub fn coordinates(&self) -> Option<(f64, f64)> {
+        match self {
+            DnsRecord::LOC {
+                latitude,
+                longitude,
+                ..
+            } => Some((loc::degrees(*latitude), loc::degrees(*longitude))),
+            _ => None,
+        }
+    }
+
+    // The CNAME a DNAME implies for `name` (RFC 6672 section 2.2), which is `name` with the DNAME's owner swapped for
+    // its target. Nothing if this isn't a DNAME or `name` isn't below its owner, and YXDOMAIN if the new name would be
+    // too long.
+    pub fn synthesize_cname(&self, name: &str) -> Result<Option<DnsRecord>, ResultCode> {
+        let DnsRecord::DNAME {
+            domain,
+            target,
+            ttl,
+        } = self
+        else {
+            return Ok(None);
+        };
+        let name_labels: Vec<&str> = labels(name).collect();
+        let prefix_length = name_labels.len().saturating_sub(labels(domain).count());
+        if prefix_length == 0 || !is_subdomain(name, domain) {
+            return Ok(None);
+        }
+
+        let host: Vec<&str> = name_labels[..prefix_length]
+            .iter()
+            .copied()
+            .chain(labels(target))
+            .collect();
+        // Each label takes its length plus a length byte, and the root label one more byte.
+        let wire_length: usize = host.iter().map(|label| label.len() + 1).sum::<usize>() + 1;
+        if wire_length > MAX_NAME_LENGTH {
+            return Err(ResultCode::YXDOMAIN);
+        }
+        Ok(Some(DnsRecord::CNAME {
+            domain: name.to_string(),
+            host: host.join("."),
+            ttl: *ttl,
+        }))
+    }
+
+    // Whether an SVCB or HTTPS record is an alias or describes a service endpoint.
+    pub fn svcb_mode(&self) -> Option<SvcbMode> {
+        match self {
+            DnsRecord::SVCB { priority, .. } | DnsRecord::HTTPS { priority, .. } => {
+                Some(SvcbMode::from_priority(*priority))
+            }
+            _ => None,
+        }
+    }
+
+    // The record data in zone file (presentation) format, e.g. "257 3 8 AwEAAa..." for a DNSKEY.
+    pub fn data_to_string(&self) -> String {
+        match self {
+            // RFC 3597 generic form.
+            DnsRecord::UNKNOWN { data, .. } if data.is_empty() => String::from("\\# 0"),
+            DnsRecord::UNKNOWN { data, .. } => {
+                format!("\\# {} {}", data.len(), HEXUPPER.encode(data))
+            }
+            DnsRecord::A { address, .. } => address.to_string(),
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::DNAME { target: host, .. } => absolute_name(host),
+            DnsRecord::SOA {
+                primary_name_server,
+                mailbox,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {}",
+                absolute_name(primary_name_server),
+                absolute_name(mailbox),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRecord::HINFO { cpu, os, .. } => format!(
+                "{} {}",
+                format_character_string(cpu),
+                format_character_string(os)
+            ),
+            DnsRecord::AAAA { address, .. } => address.to_string(),
+            DnsRecord::LOC {
+                size,
+                horizontal_precision,
+                vertical_precision,
+                latitude,
+                longitude,
+                altitude,
+                ..
+            } => loc::format(
+                *latitude,
+                *longitude,
+                *altitude,
+                *size,
+                *horizontal_precision,
+                *vertical_precision,
+            ),
+            DnsRecord::NAPTR {
+                order,
+                preference,
+                flags,
+                services,
+                regexp,
+                replacement,
+                ..
+            } => format!(
+                "{} {} {} {} {} {}",
+                order,
+                preference,
+                format_character_string(flags),
+                format_character_string(services),
+                format_character_string(regexp),
+                absolute_name(replacement)
+            ),
+            DnsRecord::OPT {
+                udp_payload_size,
+                version,
+                dnssec_ok,
+                options,
+                ..
+            } => format!(
+                "version {} udp {} do {} options {}",
+                version,
+                udp_payload_size,
+                *dnssec_ok as u8,
+                options.len()
+            ),
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                HEXUPPER.encode(digest)
+            ),
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                signature_expiration,
+                signature_inception,
+                key_tag,
+                signer_name,
+                signature,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {} {} {}",
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                format_timestamp(*signature_expiration),
+                format_timestamp(*signature_inception),
+                key_tag,
+                absolute_name(signer_name),
+                BASE64.encode(signature)
+            ),
+            DnsRecord::NSEC {
+                next_domain, types, ..
+            } => format!("{}{}", absolute_name(next_domain), format_types(types)),
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                BASE64.encode(public_key)
+            ),
+            DnsRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed_owner,
+                types,
+                ..
+            } => format!(
+                "{} {} {} {} {}{}",
+                hash_algorithm,
+                flags,
+                iterations,
+                format_salt(salt),
+                BASE32HEX_NOPAD.encode(next_hashed_owner),
+                format_types(types)
+            ),
+            DnsRecord::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                hash_algorithm,
+                flags,
+                iterations,
+                format_salt(salt)
+            ),
+            DnsRecord::CAA {
+                flags, tag, value, ..
+            } => format!("{} {} {}", flags, tag, format_character_string(value)),
+            DnsRecord::TLSA {
+                usage,
+                selector,
+                matching_type,
+                certificate_data,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                usage,
+                selector,
+                matching_type,
+                HEXUPPER.encode(certificate_data)
+            ),
+            DnsRecord::SSHFP {
+                algorithm,
+                fingerprint_type,
+                fingerprint,
+                ..
+            } => format!(
+                "{} {} {}",
+                algorithm,
+                fingerprint_type,
+                HEXUPPER.encode(fingerprint)
+            ),
+            DnsRecord::SVCB {
+                priority,
+                target,
+                params,
+                ..
+            }
+            | DnsRecord::HTTPS {
+                priority,
+                target,
+                params,
+                ..
+            } => format!(
+                "{} {}{}",
+                priority,
+                absolute_name(target),
+                svcb::format(params)
+            ),
+            DnsRecord::URI {
+                priority,
+                weight,
+                target,
+                ..
+            } => format!(
+                "{} {} {}",
+                priority,
+                weight,
+                format_character_string(target)
+            ),
+            DnsRecord::TSIG {
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other_data,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {} {}",
+                absolute_name(algorithm),
+                time_signed,
+                fudge,
+                mac.len(),
+                BASE64.encode(mac),
+                original_id,
+                TsigError::from_u16(*error)
+                    .map_or(error.to_string(), |error| format!("{:?}", error)),
+                other_data.len()
+            ),
         }
     }
 
@@ -71,7 +1077,7 @@ impl DnsRecord {
                 ref address,
                 ttl,
             } => {
-                QueryName::write(buffer, &domain)?;
+                QueryName::write(buffer, domain)?;
                 buffer.write_u16(QueryType::A.to_u16())?;
                 buffer.write_u16(1)?; // "class" (always 1)
                 buffer.write_u32(ttl)?;
@@ -83,22 +1089,519 @@ impl DnsRecord {
                     buffer.write_u8(octet)?;
                 }
             }
-            _ => (),
+            DnsRecord::OPT {
+                udp_payload_size,
+                extended_rcode,
+                version,
+                dnssec_ok,
+                ref options,
+            } => {
+                QueryName::write(buffer, "")?;
+                buffer.write_u16(QueryType::OPT.to_u16())?;
+                buffer.write_u16(udp_payload_size)?;
+                buffer.write_u32(
+                    (extended_rcode as u32) << 24
+                        | (version as u32) << 16
+                        | (dnssec_ok as u32) << 15,
+                )?;
+
+                let data_length: usize = options.iter().map(|option| 4 + option.data.len()).sum();
+                buffer.write_u16(data_length as u16)?;
+                for option in options {
+                    buffer.write_u16(option.code)?;
+                    buffer.write_u16(option.data.len() as u16)?;
+                    for byte in &option.data {
+                        buffer.write_u8(*byte)?;
+                    }
+                }
+            }
+            DnsRecord::NS {
+                ref domain,
+                ref host,
+                ttl,
+            }
+            | DnsRecord::CNAME {
+                ref domain,
+                ref host,
+                ttl,
+            }
+            | DnsRecord::DNAME {
+                ref domain,
+                target: ref host,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, self.query_type(), ttl)?;
+                QueryName::write(buffer, host)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::SOA {
+                ref domain,
+                ref primary_name_server,
+                ref mailbox,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::SOA, ttl)?;
+                QueryName::write(buffer, primary_name_server)?;
+                QueryName::write(buffer, mailbox)?;
+                for value in [serial, refresh, retry, expire, minimum] {
+                    buffer.write_u32(value)?;
+                }
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::HINFO {
+                ref domain,
+                ref cpu,
+                ref os,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::HINFO, ttl)?;
+                write_length_prefixed(buffer, cpu)?;
+                write_length_prefixed(buffer, os)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::AAAA {
+                ref domain,
+                ref address,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::AAAA, ttl)?;
+                buffer.write_bytes(&address.octets())?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::LOC {
+                ref domain,
+                size,
+                horizontal_precision,
+                vertical_precision,
+                latitude,
+                longitude,
+                altitude,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::LOC, ttl)?;
+                for value in [loc::VERSION, size, horizontal_precision, vertical_precision] {
+                    buffer.write_u8(value)?;
+                }
+                for value in [latitude, longitude, altitude] {
+                    buffer.write_u32(value)?;
+                }
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::NAPTR {
+                ref domain,
+                order,
+                preference,
+                ref flags,
+                ref services,
+                ref regexp,
+                ref replacement,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::NAPTR, ttl)?;
+                buffer.write_u16(order)?;
+                buffer.write_u16(preference)?;
+                for field in [flags, services, regexp] {
+                    write_length_prefixed(buffer, field)?;
+                }
+                QueryName::write(buffer, replacement)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::DS {
+                ref domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::DS, ttl)?;
+                buffer.write_u16(key_tag)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(digest_type)?;
+                buffer.write_bytes(digest)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::RRSIG {
+                ref domain,
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                signature_expiration,
+                signature_inception,
+                key_tag,
+                ref signer_name,
+                ref signature,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::RRSIG, ttl)?;
+                buffer.write_u16(type_covered.to_u16())?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(labels)?;
+                buffer.write_u32(original_ttl)?;
+                buffer.write_u32(signature_expiration)?;
+                buffer.write_u32(signature_inception)?;
+                buffer.write_u16(key_tag)?;
+                QueryName::write(buffer, signer_name)?;
+                buffer.write_bytes(signature)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::NSEC {
+                ref domain,
+                ref next_domain,
+                ref types,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::NSEC, ttl)?;
+                QueryName::write(buffer, next_domain)?;
+                type_bitmap::write(buffer, types)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::DNSKEY {
+                ref domain,
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::DNSKEY, ttl)?;
+                buffer.write_u16(flags)?;
+                buffer.write_u8(protocol)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_bytes(public_key)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::NSEC3 {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed_owner,
+                ref types,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::NSEC3, ttl)?;
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                write_length_prefixed(buffer, salt)?;
+                write_length_prefixed(buffer, next_hashed_owner)?;
+                type_bitmap::write(buffer, types)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::NSEC3PARAM {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::NSEC3PARAM, ttl)?;
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                write_length_prefixed(buffer, salt)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::CAA {
+                ref domain,
+                flags,
+                ref tag,
+                ref value,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::CAA, ttl)?;
+                buffer.write_u8(flags)?;
+                check_caa_tag(tag.as_bytes())?;
+                write_length_prefixed(buffer, tag.as_bytes())?;
+                buffer.write_bytes(value)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::TLSA {
+                ref domain,
+                usage,
+                selector,
+                matching_type,
+                ref certificate_data,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::TLSA, ttl)?;
+                buffer.write_u8(usage)?;
+                buffer.write_u8(selector)?;
+                buffer.write_u8(matching_type)?;
+                buffer.write_bytes(certificate_data)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::SSHFP {
+                ref domain,
+                algorithm,
+                fingerprint_type,
+                ref fingerprint,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::SSHFP, ttl)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(fingerprint_type)?;
+                buffer.write_bytes(fingerprint)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::SVCB {
+                ref domain,
+                priority,
+                ref target,
+                ref params,
+                ttl,
+            }
+            | DnsRecord::HTTPS {
+                ref domain,
+                priority,
+                ref target,
+                ref params,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, self.query_type(), ttl)?;
+                buffer.write_u16(priority)?;
+                QueryName::write(buffer, target)?;
+                svcb::write(buffer, params)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::URI {
+                ref domain,
+                priority,
+                weight,
+                ref target,
+                ttl,
+            } => {
+                let length_position = write_preamble(buffer, domain, QueryType::URI, ttl)?;
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_bytes(target)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::TSIG {
+                ref domain,
+                ref algorithm,
+                time_signed,
+                fudge,
+                ref mac,
+                original_id,
+                error,
+                ref other_data,
+            } => {
+                QueryName::write(buffer, domain)?;
+                buffer.write_u16(QueryType::TSIG.to_u16())?;
+                buffer.write_u16(CLASS_ANY)?;
+                buffer.write_u32(0)?;
+                let length_position = buffer.pos();
+                buffer.write_u16(0)?;
+                QueryName::write(buffer, algorithm)?;
+                buffer.write_u16((time_signed >> 32) as u16)?;
+                buffer.write_u32(time_signed as u32)?;
+                buffer.write_u16(fudge)?;
+                buffer.write_u16(mac.len() as u16)?;
+                buffer.write_bytes(mac)?;
+                buffer.write_u16(original_id)?;
+                buffer.write_u16(error)?;
+                buffer.write_u16(other_data.len() as u16)?;
+                buffer.write_bytes(other_data)?;
+                write_data_length(buffer, length_position)?;
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                query_type,
+                ref data,
+                ttl,
+            } => {
+                let length_position =
+                    write_preamble(buffer, domain, QueryType::UNKNOWN(query_type), ttl)?;
+                buffer.write_bytes(data)?;
+                write_data_length(buffer, length_position)?;
+            }
         };
         Ok(buffer.pos() - start_position)
     }
 }
 
+impl Display for DnsRecord {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{} {} {} {} {}",
+            absolute_name(self.domain()),
+            self.ttl(),
+            match self {
+                DnsRecord::TSIG { .. } => "ANY",
+                _ => "IN",
+            },
+            self.query_type(),
+            self.data_to_string()
+        )
+    }
+}
+
+// For types without a variant of their own whose data may still hold compressed names (RFC 3597 section 4), the
+// length of the fixed fields in front of the names, and how many names there are.
+pub(super) fn embedded_names(query_type: u16) -> Option<(usize, usize)> {
+    match query_type {
+        3 | 4 | 7 | 8 | 9 | 12 => Some((0, 1)), // MD, MF, MB, MG, MR, PTR
+        14 | 17 => Some((0, 2)),                // MINFO, RP
+        15 | 18 | 21 | 36 => Some((2, 1)),      // MX, AFSDB, RT, KX
+        26 => Some((2, 2)),                     // PX
+        33 => Some((6, 1)),                     // SRV
+        _ => None,
+    }
+}
+
+// How much of the record data is left, failing if the fields read so far already ran past its end.
+fn remaining_data(buffer: &WrappedBuffer, data_end: usize) -> Result<usize, String> {
+    data_end
+        .checked_sub(buffer.pos())
+        .ok_or_else(|| String::from("Record data is longer than its advertised length."))
+}
+
+// Writes everything up to the record data, returning where the data length goes so it can be filled in afterwards.
+fn write_preamble(
+    buffer: &mut WrappedBuffer,
+    domain: &str,
+    query_type: QueryType,
+    ttl: u32,
+) -> Result<usize, String> {
+    QueryName::write(buffer, domain)?;
+    buffer.write_u16(query_type.to_u16())?;
+    buffer.write_u16(1)?; // "class" (always 1)
+    buffer.write_u32(ttl)?;
+    let length_position = buffer.pos();
+    buffer.write_u16(0)?;
+    Ok(length_position)
+}
+
+fn write_data_length(buffer: &mut WrappedBuffer, length_position: usize) -> Result<(), String> {
+    let end = buffer.pos();
+    let data_length = end - length_position - 2;
+    if data_length > u16::MAX as usize {
+        return Err(format!("Record data too long ({} bytes).", data_length));
+    }
+    buffer.seek(length_position)?;
+    buffer.write_u16(data_length as u16)?;
+    buffer.seek(end)
+}
+
+// CAA tags are letters and digits only (RFC 8659 section 4.1).
+fn check_caa_tag(tag: &[u8]) -> Result<(), String> {
+    if tag.is_empty() || !tag.iter().all(u8::is_ascii_alphanumeric) {
+        return Err(format!("Invalid CAA tag {}.", format_character_string(tag)));
+    }
+    Ok(())
+}
+
+fn read_character_string(buffer: &mut WrappedBuffer) -> Result<Vec<u8>, String> {
+    let length = buffer.read_u8()? as usize;
+    buffer.read_bytes(length)
+}
+
+fn write_length_prefixed(buffer: &mut WrappedBuffer, bytes: &[u8]) -> Result<(), String> {
+    if bytes.len() > u8::MAX as usize {
+        return Err(format!(
+            "Field too long ({} bytes, maximum is 255).",
+            bytes.len()
+        ));
+    }
+    buffer.write_u8(bytes.len() as u8)?;
+    buffer.write_bytes(bytes)
+}
+
+// A quoted character string (RFC 1035 section 5.1), with quotes, backslashes and unprintable bytes escaped.
+fn format_character_string(bytes: &[u8]) -> String {
+    let escaped: String = bytes
+        .iter()
+        .map(|&byte| match byte {
+            b'"' | b'\\' => format!("\\{}", byte as char),
+            0x20..=0x7E => (byte as char).to_string(),
+            _ => format!("\\{:03}", byte),
+        })
+        .collect();
+    format!("\"{}\"", escaped)
+}
+
+// `name` with the trailing dot of a fully qualified name, as zone files write it.
+pub fn absolute_name(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+fn format_types(types: &[QueryType]) -> String {
+    types
+        .iter()
+        .map(|query_type| format!(" {}", query_type))
+        .collect()
+}
+
+fn format_salt(salt: &[u8]) -> String {
+    match salt.is_empty() {
+        true => String::from("-"),
+        false => HEXUPPER.encode(salt),
+    }
+}
+
+// RRSIG validity times are shown as YYYYMMDDHHmmSS in UTC.
+fn format_timestamp(seconds: u32) -> String {
+    let days = (seconds / 86400) as i64;
+    let time_of_day = seconds % 86400;
+
+    // Days since the epoch to a civil date (Howard Hinnant's algorithm).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
+
 #[cfg(test)]
 mod tests {
-    use super::DnsRecord;
+    use super::{format_timestamp, DnsRecord, EdnsOption, SvcbMode};
     use crate::parser::{
         dns_question::DnsQuestion,
+        loc_altitude, loc_coordinate, loc_size, parse_svc_params,
+        query_type::QueryType,
+        result_code::ResultCode,
         test_helpers::{are_same_enum_variant, get_buffer_at_question_section, GOOGLE_QUERY},
         wrapped_buffer::WrappedBuffer,
     };
+    use data_encoding::HEXUPPER;
     use std::{error::Error, net::Ipv4Addr};
 
+    // Writes `record` and reads it back, which should give the same record and stop where the writing did.
+    fn assert_round_trip(record: &DnsRecord) -> Result<(), Box<dyn Error>> {
+        let mut buffer = WrappedBuffer::new();
+        let written = record.write(&mut buffer)?;
+        buffer.seek(0)?;
+        assert_eq!(&DnsRecord::read(&mut buffer)?, record);
+        assert_eq!(buffer.pos(), written);
+        Ok(())
+    }
+
     #[test]
     fn can_read_record_of_known_type() -> Result<(), Box<dyn Error>> {
         let mut buffer = get_buffer_after_question_section(String::from(GOOGLE_QUERY))?;
@@ -148,37 +1651,495 @@ mod tests {
     }
 
     #[test]
-    #[ignore = "Need to edit a packet to have an unrecognised query type"]
-    fn can_read_record_of_unknown_type() -> Result<(), Box<dyn Error>> {
-        todo!()
+    fn can_write_opt_record() -> Result<(), Box<dyn Error>> {
+        let record = DnsRecord::OPT {
+            udp_payload_size: 1232,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![EdnsOption {
+                code: 10, // COOKIE
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }],
+        };
+        assert_eq!(record.write(&mut WrappedBuffer::new())?, 11 + 12);
+        assert_round_trip(&record)
+    }
+
+    // The DNSKEY from the DS example in RFC 4034 section 5.4.
+    fn rfc_example_dnskey() -> Result<DnsRecord, Box<dyn Error>> {
+        Ok(DnsRecord::DNSKEY {
+            domain: String::from("dskey.example.com"),
+            flags: 256,
+            protocol: 3,
+            algorithm: 5,
+            public_key: data_encoding::BASE64.decode(
+                b"AQOeiiR0GOMYkDshWoSKz9XzfwJr1AYtsmx3TGkJaNXVbfi/2pHm822aJ5iI9BMzNXxeYCmZDRD99WYwYqUSdjMmmAphXdvxegXd/M5+X7OrzKBaMbCVdFLUUh6DhweJBjEVv5f2wwjM9XzcnOf+EPbtG9DMBmADjFDc2w/rljwvFw==",
+            )?,
+            ttl: 86400,
+        })
+    }
+
+    fn dnssec_records() -> Result<Vec<DnsRecord>, Box<dyn Error>> {
+        Ok(vec![
+            rfc_example_dnskey()?,
+            DnsRecord::DS {
+                domain: String::from("dskey.example.com"),
+                key_tag: 60485,
+                algorithm: 5,
+                digest_type: 1,
+                digest: vec![0x2B, 0xB1, 0x83, 0xAF, 0x5F, 0x22, 0x58, 0x81, 0x79, 0xA5],
+                ttl: 86400,
+            },
+            DnsRecord::RRSIG {
+                domain: String::from("host.example.com"),
+                type_covered: QueryType::A,
+                algorithm: 5,
+                labels: 3,
+                original_ttl: 86400,
+                signature_expiration: 1048354263,
+                signature_inception: 1045762263,
+                key_tag: 2642,
+                signer_name: String::from("example.com"),
+                signature: vec![1, 2, 3, 4, 5, 6],
+                ttl: 86400,
+            },
+            DnsRecord::NSEC {
+                domain: String::from("alfa.example.com"),
+                next_domain: String::from("host.example.com"),
+                types: vec![
+                    QueryType::A,
+                    QueryType::UNKNOWN(15),
+                    QueryType::RRSIG,
+                    QueryType::NSEC,
+                ],
+                ttl: 86400,
+            },
+            DnsRecord::NSEC3 {
+                domain: String::from("0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example"),
+                hash_algorithm: 1,
+                flags: 1,
+                iterations: 12,
+                salt: vec![0xAA, 0xBB, 0xCC, 0xDD],
+                next_hashed_owner: vec![0x16; 20],
+                types: vec![QueryType::UNKNOWN(15), QueryType::RRSIG],
+                ttl: 3600,
+            },
+            DnsRecord::NSEC3PARAM {
+                domain: String::from("example"),
+                hash_algorithm: 1,
+                flags: 0,
+                iterations: 12,
+                salt: Vec::new(),
+                ttl: 3600,
+            },
+        ])
     }
 
     #[test]
-    fn skips_write_for_record_of_unknown_type() -> Result<(), Box<dyn Error>> {
-        let unknown_record = DnsRecord::UNKNOWN {
-            domain: String::from("google.com"),
-            query_type: 10,
-            data_length: 22,
-            ttl: 8541,
+    fn common_records_survive_round_trip() -> Result<(), Box<dyn Error>> {
+        let records = vec![
+            DnsRecord::NS {
+                domain: String::from("example.com"),
+                host: String::from("ns1.example.com"),
+                ttl: 172800,
+            },
+            DnsRecord::CNAME {
+                domain: String::from("www.example.com"),
+                host: String::from("example.com"),
+                ttl: 300,
+            },
+            DnsRecord::SOA {
+                domain: String::from("example.com"),
+                primary_name_server: String::from("ns1.example.com"),
+                mailbox: String::from("hostmaster.example.com"),
+                serial: 2026101901,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+                ttl: 3600,
+            },
+            DnsRecord::AAAA {
+                domain: String::from("example.com"),
+                address: "2001:db8::1".parse()?,
+                ttl: 300,
+            },
+        ];
+        for record in records {
+            assert_round_trip(&record)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dnssec_records_survive_round_trip() -> Result<(), Box<dyn Error>> {
+        for record in dnssec_records()? {
+            assert_round_trip(&record)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn signer_name_is_written_uncompressed() -> Result<(), Box<dyn Error>> {
+        let record = &dnssec_records()?[2];
+        let mut buffer = WrappedBuffer::new();
+        let written = record.write(&mut buffer)?;
+
+        // Owner (18) + fixed fields (10) + length (2) + RRSIG fields (18) + "example.com" (13) + signature (6).
+        assert_eq!(written, 18 + 10 + 18 + 13 + 6);
+        assert_eq!(buffer.get_slice(28 + 18, 13)?, b"\x07example\x03com\x00");
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_dnssec_data_shorter_than_its_fields() -> Result<(), Box<dyn Error>> {
+        let mut buffer = WrappedBuffer::new();
+        dnssec_records()?[5].write(&mut buffer)?;
+        // Claim the NSEC3PARAM data is only 3 bytes long (the length follows the 9-byte owner and 8 fixed bytes).
+        buffer.seek(17)?;
+        buffer.write_u16(3)?;
+        buffer.seek(0)?;
+
+        assert!(DnsRecord::read(&mut buffer).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_data_of_the_wrong_length() -> Result<(), Box<dyn Error>> {
+        // Each is followed by enough bytes to read the whole type from, as the rest of a message would be.
+        let records: [&[u8]; 3] = [
+            // An AAAA record with only 4 bytes of data.
+            b"\x00\x00\x1c\x00\x01\x00\x00\x01\x2c\x00\x04\x20\x01\x0d\xb8",
+            // An A record with none, as a dynamic update deleting it would have.
+            b"\x00\x00\x01\x00\xff\x00\x00\x00\x00\x00\x00",
+            // A CNAME whose name, the root, is shorter than its data.
+            b"\x00\x00\x05\x00\x01\x00\x00\x01\x2c\x00\x03\x00",
+        ];
+        for record in records {
+            let mut bytes = record.to_vec();
+            bytes.extend_from_slice(&[0; 16]);
+            let mut buffer = WrappedBuffer::from_bytes(&bytes)?;
+            assert!(DnsRecord::read(&mut buffer).is_err(), "{:?}", record);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn computes_dnskey_key_tag() -> Result<(), Box<dyn Error>> {
+        assert_eq!(rfc_example_dnskey()?.key_tag(), Some(60485));
+        assert_eq!(dnssec_records()?[1].key_tag(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn presents_dnssec_records() -> Result<(), Box<dyn Error>> {
+        let records = dnssec_records()?;
+        assert!(records[0]
+            .to_string()
+            .starts_with("dskey.example.com. 86400 IN DNSKEY 256 3 5 AQOeiiR0GOMYkDsh"));
+        assert_eq!(
+            records[1].data_to_string(),
+            "60485 5 1 2BB183AF5F22588179A5"
+        );
+        assert_eq!(
+            records[2].data_to_string(),
+            "A 5 3 86400 20030322173103 20030220173103 2642 example.com. AQIDBAUG"
+        );
+        assert_eq!(
+            records[3].data_to_string(),
+            "host.example.com. A TYPE15 RRSIG NSEC"
+        );
+        assert_eq!(
+            records[4].data_to_string(),
+            "1 1 12 AABBCCDD 2OB1C5GM2OB1C5GM2OB1C5GM2OB1C5GM TYPE15 RRSIG"
+        );
+        assert_eq!(records[5].data_to_string(), "1 0 12 -");
+        Ok(())
+    }
+
+    #[test]
+    fn service_binding_records_survive_round_trip() -> Result<(), Box<dyn Error>> {
+        let https = DnsRecord::HTTPS {
+            domain: String::from("example.com"),
+            priority: 1,
+            target: String::new(),
+            params: parse_svc_params("alpn=h3,h2 ipv4hint=192.0.2.1 port=8443")?,
+            ttl: 300,
+        };
+        let alias = DnsRecord::SVCB {
+            domain: String::from("_8443._foo.api.example.com"),
+            priority: 0,
+            target: String::from("svc4.example.net"),
+            params: Vec::new(),
+            ttl: 7200,
+        };
+        for record in [&https, &alias] {
+            assert_round_trip(record)?;
+        }
+
+        assert_eq!(
+            https.to_string(),
+            "example.com. 300 IN HTTPS 1 . alpn=h3,h2 port=8443 ipv4hint=192.0.2.1"
+        );
+        assert_eq!(https.svcb_mode(), Some(SvcbMode::Service));
+        assert_eq!(alias.data_to_string(), "0 svc4.example.net.");
+        assert_eq!(alias.svcb_mode(), Some(SvcbMode::Alias));
+        Ok(())
+    }
+
+    #[test]
+    fn descriptive_records_survive_round_trip() -> Result<(), Box<dyn Error>> {
+        let hinfo = DnsRecord::HINFO {
+            domain: String::from("host.example.com"),
+            cpu: b"INTEL-386".to_vec(),
+            // Not UTF-8, which has to come back unchanged.
+            os: b"Windows \"3.1\" \xe9dition".to_vec(),
+            ttl: 3600,
+        };
+        let loc = DnsRecord::LOC {
+            domain: String::from("cambridge-net.kei.com"),
+            size: loc_size(3000),
+            horizontal_precision: loc_size(1_000_000),
+            vertical_precision: loc_size(1000),
+            latitude: loc_coordinate(42.365),
+            longitude: loc_coordinate(-71.105),
+            altitude: loc_altitude(-24.0),
+            ttl: 3600,
+        };
+        let naptr = DnsRecord::NAPTR {
+            domain: String::from("4.3.2.1.5.5.5.0.0.8.1.e164.arpa"),
+            order: 100,
+            preference: 10,
+            flags: b"u".to_vec(),
+            services: b"E2U+sip".to_vec(),
+            regexp: b"!^.*$!sip:information@example.com!".to_vec(),
+            replacement: String::new(),
+            ttl: 3600,
+        };
+        let uri = DnsRecord::URI {
+            domain: String::from("_ftp._tcp.example.com"),
+            priority: 10,
+            weight: 1,
+            target: b"ftp://ftp1.example.com/public".to_vec(),
+            ttl: 3600,
+        };
+        for record in [&hinfo, &loc, &naptr, &uri] {
+            assert_round_trip(record)?;
+        }
+
+        assert_eq!(
+            hinfo.data_to_string(),
+            "\"INTEL-386\" \"Windows \\\"3.1\\\" \\233dition\""
+        );
+        assert_eq!(
+            loc.to_string(),
+            "cambridge-net.kei.com. 3600 IN LOC 42 21 54.000 N 71 6 18.000 W -24m 30m 10000m 10m"
+        );
+        let (latitude, longitude) = loc.coordinates().ok_or("LOC record has no coordinates")?;
+        assert!((latitude - 42.365).abs() < 1e-9);
+        assert!((longitude + 71.105).abs() < 1e-9);
+        assert_eq!(uri.coordinates(), None);
+        assert_eq!(
+            naptr.data_to_string(),
+            "100 10 \"u\" \"E2U+sip\" \"!^.*$!sip:information@example.com!\" ."
+        );
+        assert_eq!(
+            uri.data_to_string(),
+            "10 1 \"ftp://ftp1.example.com/public\""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_loc_versions() -> Result<(), Box<dyn Error>> {
+        let loc = DnsRecord::LOC {
+            domain: String::from("example.com"),
+            size: 0x12,
+            horizontal_precision: 0x16,
+            vertical_precision: 0x13,
+            latitude: loc_coordinate(0.0),
+            longitude: loc_coordinate(0.0),
+            altitude: loc_altitude(0.0),
+            ttl: 3600,
         };
         let mut buffer = WrappedBuffer::new();
-        unknown_record.write(&mut buffer)?;
+        loc.write(&mut buffer)?;
+        // The version is the first byte of the record data, after the name (13 bytes) and the fixed fields.
+        buffer.seek(13 + 10)?;
+        buffer.write_u8(1)?;
         buffer.seek(0)?;
 
-        match DnsRecord::read(&mut buffer)? {
+        assert!(DnsRecord::read(&mut buffer).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn synthesizes_cnames_below_dname_owner() {
+        let dname = DnsRecord::DNAME {
+            domain: String::from("old.example"),
+            target: String::from("new.example.net"),
+            ttl: 600,
+        };
+        assert_eq!(
+            dname.synthesize_cname("a.b.OLD.example"),
+            Ok(Some(DnsRecord::CNAME {
+                domain: String::from("a.b.OLD.example"),
+                host: String::from("a.b.new.example.net"),
+                ttl: 600,
+            }))
+        );
+        assert_eq!(
+            dname.to_string(),
+            "old.example. 600 IN DNAME new.example.net."
+        );
+        // The owner itself isn't redirected, and nor is anything outside it.
+        assert_eq!(dname.synthesize_cname("old.example"), Ok(None));
+        assert_eq!(dname.synthesize_cname("www.bold.example"), Ok(None));
+        assert_eq!(dname.synthesize_cname("example"), Ok(None));
+
+        let long_name = format!(
+            "{0}.{0}.{0}.{1}.old.example",
+            "a".repeat(63),
+            "b".repeat(47)
+        );
+        assert_eq!(
+            dname.synthesize_cname(&long_name),
+            Err(ResultCode::YXDOMAIN)
+        );
+    }
+
+    #[test]
+    fn security_records_survive_round_trip() -> Result<(), Box<dyn Error>> {
+        let caa = DnsRecord::CAA {
+            domain: String::from("example.com"),
+            flags: 128,
+            tag: String::from("issue"),
+            value: b"ca.example.net; account=\"230\"\x01".to_vec(),
+            ttl: 3600,
+        };
+        let tlsa = DnsRecord::TLSA {
+            domain: String::from("_443._tcp.www.example.com"),
+            usage: 0,
+            selector: 0,
+            matching_type: 1,
+            certificate_data: HEXUPPER
+                .decode(b"D2ABDE240D7CD3EE6B4B28C54DF034B97983A1D16E8A410E4561CB106618E971")?,
+            ttl: 3600,
+        };
+        let sshfp = DnsRecord::SSHFP {
+            domain: String::from("host.example.com"),
+            algorithm: 2,
+            fingerprint_type: 1,
+            fingerprint: HEXUPPER.decode(b"123456789ABCDEF67890123456789ABCDEF67890")?,
+            ttl: 3600,
+        };
+        for record in [&caa, &tlsa, &sshfp] {
+            assert_round_trip(record)?;
+        }
+
+        assert_eq!(
+            caa.to_string(),
+            "example.com. 3600 IN CAA 128 issue \"ca.example.net; account=\\\"230\\\"\\001\""
+        );
+        assert_eq!(
+            tlsa.data_to_string(),
+            "0 0 1 D2ABDE240D7CD3EE6B4B28C54DF034B97983A1D16E8A410E4561CB106618E971"
+        );
+        assert_eq!(
+            sshfp.data_to_string(),
+            "2 1 123456789ABCDEF67890123456789ABCDEF67890"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_caa_tags_other_than_letters_and_digits() -> Result<(), Box<dyn Error>> {
+        let caa = |tag: &str| DnsRecord::CAA {
+            domain: String::from("example.com"),
+            flags: 0,
+            tag: tag.to_string(),
+            value: b"ca.example.net".to_vec(),
+            ttl: 3600,
+        };
+        assert!(caa("is sue").write(&mut WrappedBuffer::new()).is_err());
+        assert!(caa("").write(&mut WrappedBuffer::new()).is_err());
+
+        // A tag byte that isn't ASCII, which read leniently would come back as something else.
+        let mut buffer = WrappedBuffer::new();
+        caa("issue").write(&mut buffer)?;
+        // The tag follows the name (13 bytes), the fixed fields, the flags and the tag length.
+        buffer.seek(13 + 10 + 2)?;
+        buffer.write_u8(0xE9)?;
+        buffer.seek(0)?;
+        assert!(DnsRecord::read(&mut buffer).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tsig_record_survives_round_trip() -> Result<(), Box<dyn Error>> {
+        let record = DnsRecord::TSIG {
+            domain: String::from("transfer.example"),
+            algorithm: String::from("hmac-sha256"),
+            time_signed: 5_000_000_000,
+            fudge: 300,
+            mac: vec![1, 2, 3, 4],
+            original_id: 0x1234,
+            error: 18,
+            other_data: vec![0, 0, 0, 0, 0, 1],
+        };
+        assert_round_trip(&record)?;
+        assert_eq!(
+            record.to_string(),
+            "transfer.example. 0 ANY TSIG hmac-sha256. 5000000000 300 4 AQIDBA== 4660 BADTIME 6"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn formats_rrsig_timestamps() {
+        assert_eq!(format_timestamp(0), "19700101000000");
+        assert_eq!(format_timestamp(1048354263), "20030322173103");
+        assert_eq!(format_timestamp(u32::MAX), "21060207062815");
+    }
+
+    #[test]
+    fn can_read_record_of_unknown_type() -> Result<(), Box<dyn Error>> {
+        // "google.com", then an MX record for it whose exchange, smtp.google.com, points back at it.
+        let mut bytes = b"\x06google\x03com\x00".to_vec();
+        bytes.extend_from_slice(b"\xc0\x00\x00\x0f\x00\x01\x00\x00\x01\x2c\x00\x09");
+        bytes.extend_from_slice(b"\x00\x0a\x04smtp\xc0\x00");
+        let mut buffer = WrappedBuffer::from_bytes(&bytes)?;
+        buffer.seek(12)?;
+
+        let record = DnsRecord::read(&mut buffer)?;
+        assert_eq!(buffer.pos(), bytes.len());
+        assert_eq!(
+            record,
             DnsRecord::UNKNOWN {
-                domain,
-                query_type,
-                data_length,
-                ttl,
-            } => {
-                assert_eq!(domain, String::new());
-                assert_eq!(query_type, 0);
-                assert_eq!(data_length, 0);
-                assert_eq!(ttl, 0);
+                domain: String::from("google.com"),
+                query_type: 15,
+                data: b"\x00\x0a\x04smtp\x06google\x03com\x00".to_vec(),
+                ttl: 300,
             }
-            _ => panic!("Expected to skip writing DNS record of unknown type."),
-        }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn writes_back_record_of_unknown_type() -> Result<(), Box<dyn Error>> {
+        // An MX record: preference 10, then the exchange's name.
+        let unknown_record = DnsRecord::UNKNOWN {
+            domain: String::from("google.com"),
+            query_type: 15,
+            data: b"\x00\x0a\x04smtp\x06google\x03com\x00".to_vec(),
+            ttl: 8541,
+        };
+        assert_round_trip(&unknown_record)?;
+        assert_eq!(
+            unknown_record.data_to_string(),
+            "\\# 19 000A04736D747006676F6F676C6503636F6D00"
+        );
         Ok(())
     }
 
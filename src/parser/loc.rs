@@ -0,0 +1,156 @@
+// LOC records (RFC 1876) keep positions as fixed-point integers offset so they're never negative: latitude and
+// longitude in thousandths of an arcsecond from 2^31, and altitude in centimetres from 100,000m below the WGS 84
+// reference spheroid. Sizes and precisions are single bytes, a mantissa and power of ten in centimetres.
+
+const EQUATOR: i64 = 1 << 31;
+const ALTITUDE_BASE: i64 = 10_000_000;
+const MILLIARCSECONDS_PER_DEGREE: f64 = 3_600_000.0;
+
+// The only version of the record data there is.
+pub(super) const VERSION: u8 = 0;
+
+// Encodes a latitude or longitude in degrees, north and east being positive.
+pub fn coordinate(degrees: f64) -> u32 {
+    (EQUATOR + (degrees * MILLIARCSECONDS_PER_DEGREE).round() as i64).clamp(0, u32::MAX as i64)
+        as u32
+}
+
+// Encodes an altitude in metres.
+pub fn altitude(metres: f64) -> u32 {
+    (ALTITUDE_BASE + (metres * 100.0).round() as i64).clamp(0, u32::MAX as i64) as u32
+}
+
+// Encodes a size or precision, rounding down to the nearest representable value (at most 90,000km).
+pub fn size(centimetres: u64) -> u8 {
+    let mut mantissa = centimetres;
+    let mut exponent = 0;
+    while mantissa > 9 {
+        mantissa /= 10;
+        exponent += 1;
+    }
+    match exponent {
+        0..=9 => (mantissa as u8) << 4 | exponent,
+        _ => 0x99,
+    }
+}
+
+// Decodes a latitude or longitude to degrees.
+pub(super) fn degrees(raw: u32) -> f64 {
+    (raw as i64 - EQUATOR) as f64 / MILLIARCSECONDS_PER_DEGREE
+}
+
+pub(super) fn validate_size(encoded: u8) -> Result<(), String> {
+    match (encoded >> 4, encoded & 0x0F) {
+        (0..=9, 0..=9) => Ok(()),
+        _ => Err(format!("Invalid LOC size or precision 0x{:02X}.", encoded)),
+    }
+}
+
+// Zone file format, e.g. "42 21 54.000 N 71 6 18.000 W -24m 30m 10000m 10m".
+pub(super) fn format(
+    latitude: u32,
+    longitude: u32,
+    altitude: u32,
+    size: u8,
+    horizontal_precision: u8,
+    vertical_precision: u8,
+) -> String {
+    let altitude = altitude as i64 - ALTITUDE_BASE;
+    format!(
+        "{} {} {}{} {} {} {}",
+        format_coordinate(latitude, 'N', 'S'),
+        format_coordinate(longitude, 'E', 'W'),
+        if altitude < 0 { "-" } else { "" },
+        format_metres(altitude.unsigned_abs()),
+        format_metres(decode_size(size)),
+        format_metres(decode_size(horizontal_precision)),
+        format_metres(decode_size(vertical_precision))
+    )
+}
+
+fn decode_size(encoded: u8) -> u64 {
+    (encoded >> 4) as u64 * 10u64.pow((encoded & 0x0F) as u32)
+}
+
+// Degrees, minutes and seconds to the thousandth, then the hemisphere.
+fn format_coordinate(raw: u32, positive: char, negative: char) -> String {
+    let offset = raw as i64 - EQUATOR;
+    let milliarcseconds = offset.unsigned_abs();
+    format!(
+        "{} {} {}.{:03} {}",
+        milliarcseconds / 3_600_000,
+        milliarcseconds / 60_000 % 60,
+        milliarcseconds / 1000 % 60,
+        milliarcseconds % 1000,
+        if offset < 0 { negative } else { positive }
+    )
+}
+
+fn format_metres(centimetres: u64) -> String {
+    match centimetres % 100 {
+        0 => format!("{}m", centimetres / 100),
+        fraction => format!("{}.{:02}m", centimetres / 100, fraction),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{altitude, coordinate, degrees, format, size, validate_size};
+
+    // The RFC's defaults for when a zone file leaves them out: 1m across, 10km horizontal and 10m vertical.
+    const DEFAULT_SIZE: u8 = 0x12;
+    const DEFAULT_HORIZONTAL_PRECISION: u8 = 0x16;
+    const DEFAULT_VERTICAL_PRECISION: u8 = 0x13;
+
+    #[test]
+    fn encodes_sizes() {
+        assert_eq!(size(100), DEFAULT_SIZE);
+        assert_eq!(size(1_000_000), DEFAULT_HORIZONTAL_PRECISION);
+        assert_eq!(size(1000), DEFAULT_VERTICAL_PRECISION);
+        assert_eq!(size(0), 0x00);
+        assert_eq!(size(3000), 0x33);
+        assert_eq!(size(3456), 0x33);
+        assert_eq!(size(u64::MAX), 0x99);
+
+        assert!(validate_size(0x99).is_ok());
+        assert!(validate_size(0xA0).is_err());
+        assert!(validate_size(0x1A).is_err());
+    }
+
+    #[test]
+    fn formats_rfc_example() {
+        // The RFC's example for cambridge-net.kei.com, 42 21 54 N 71 06 18 W -24m 30m.
+        let latitude = coordinate(42.0 + 21.0 / 60.0 + 54.0 / 3600.0);
+        let longitude = coordinate(-(71.0 + 6.0 / 60.0 + 18.0 / 3600.0));
+        assert_eq!(latitude, 2299997648);
+        assert_eq!(longitude, 1891505648);
+        assert_eq!(
+            format(
+                latitude,
+                longitude,
+                altitude(-24.0),
+                size(3000),
+                DEFAULT_HORIZONTAL_PRECISION,
+                DEFAULT_VERTICAL_PRECISION
+            ),
+            "42 21 54.000 N 71 6 18.000 W -24m 30m 10000m 10m"
+        );
+        assert!((degrees(latitude) - 42.365).abs() < 1e-9);
+        assert!((degrees(longitude) + 71.105).abs() < 1e-9);
+    }
+
+    #[test]
+    fn formats_fractions_and_southern_hemisphere() {
+        assert_eq!(
+            format(
+                coordinate(-33.856_785),
+                coordinate(151.215_297),
+                altitude(5.5),
+                size(150),
+                size(20),
+                size(5)
+            ),
+            "33 51 24.426 S 151 12 55.069 E 5.50m 1m 0.20m 0.05m"
+        );
+    }
+}
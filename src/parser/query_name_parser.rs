@@ -63,7 +63,8 @@ pub trait QueryNameParser {
     }
 
     fn write(buffer: &mut WrappedBuffer, name: &str) -> Result<(), String> {
-        for segment in name.split('.') {
+        // Skipping empty segments means the root ("") and fully-qualified names ("google.com.") are written correctly.
+        for segment in name.split('.').filter(|segment| !segment.is_empty()) {
             let length = segment.len() as u8;
 
             if length > 0x3f {
@@ -119,6 +120,18 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn writes_root_name_as_single_null_byte() -> Result<(), Box<dyn Error>> {
+        let mut buffer = WrappedBuffer::new();
+        QueryName::write(&mut buffer, "")?;
+        assert_eq!(buffer.pos(), 1);
+
+        let mut fully_qualified = WrappedBuffer::new();
+        QueryName::write(&mut fully_qualified, "google.com.")?;
+        assert_eq!(fully_qualified.pos(), 12);
+        Ok(())
+    }
+
     #[test]
     #[ignore = "Need to create a packet exhibiting this scenario in a hex editor or something."]
     fn parsing_fails_for_packet_with_too_many_jumps() -> Result<(), Box<dyn Error>> {
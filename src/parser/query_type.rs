@@ -1,26 +1,145 @@
-#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+use std::{fmt::Display, str::FromStr};
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy, PartialOrd, Ord)]
 #[allow(dead_code)]
 pub enum QueryType {
     UNKNOWN(u16),
     A,
+    NS,
+    CNAME,
+    SOA,
+    HINFO,
+    AAAA,
+    LOC,
+    NAPTR,
+    DNAME,
+    OPT,
+    DS,
+    SSHFP,
+    RRSIG,
+    NSEC,
+    DNSKEY,
+    NSEC3,
+    NSEC3PARAM,
+    TLSA,
+    SVCB,
+    HTTPS,
+    TSIG,
+    URI,
+    CAA,
 }
 
 impl QueryType {
     pub fn from_u16(val: u16) -> QueryType {
         match val {
             1 => QueryType::A,
+            2 => QueryType::NS,
+            5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            13 => QueryType::HINFO,
+            28 => QueryType::AAAA,
+            29 => QueryType::LOC,
+            35 => QueryType::NAPTR,
+            39 => QueryType::DNAME,
+            41 => QueryType::OPT,
+            43 => QueryType::DS,
+            44 => QueryType::SSHFP,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            50 => QueryType::NSEC3,
+            51 => QueryType::NSEC3PARAM,
+            52 => QueryType::TLSA,
+            64 => QueryType::SVCB,
+            65 => QueryType::HTTPS,
+            250 => QueryType::TSIG,
+            256 => QueryType::URI,
+            257 => QueryType::CAA,
             _ => QueryType::UNKNOWN(val),
         }
     }
 
-    pub fn to_u16(&self) -> u16 {
-        match *self {
+    pub fn to_u16(self) -> u16 {
+        match self {
             QueryType::A => 1,
-            QueryType::UNKNOWN(_) => 0,
+            QueryType::NS => 2,
+            QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::HINFO => 13,
+            QueryType::AAAA => 28,
+            QueryType::LOC => 29,
+            QueryType::NAPTR => 35,
+            QueryType::DNAME => 39,
+            QueryType::OPT => 41,
+            QueryType::DS => 43,
+            QueryType::SSHFP => 44,
+            QueryType::RRSIG => 46,
+            QueryType::NSEC => 47,
+            QueryType::DNSKEY => 48,
+            QueryType::NSEC3 => 50,
+            QueryType::NSEC3PARAM => 51,
+            QueryType::TLSA => 52,
+            QueryType::SVCB => 64,
+            QueryType::HTTPS => 65,
+            QueryType::TSIG => 250,
+            QueryType::URI => 256,
+            QueryType::CAA => 257,
+            QueryType::UNKNOWN(val) => val,
+        }
+    }
+}
+
+// Mnemonics for known types, and the RFC 3597 "TYPEnnn" form for everything else.
+impl Display for QueryType {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryType::UNKNOWN(val) => write!(formatter, "TYPE{}", val),
+            known => write!(formatter, "{:?}", known),
         }
     }
 }
 
+// The reverse of `Display`, ignoring case.
+impl FromStr for QueryType {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<QueryType, String> {
+        let upper = text.to_ascii_uppercase();
+        if let Some(number) = upper.strip_prefix("TYPE") {
+            return number
+                .parse()
+                .map(QueryType::from_u16)
+                .map_err(|_| format!("Bad record type: {}", text));
+        }
+        Ok(match upper.as_str() {
+            "A" => QueryType::A,
+            "NS" => QueryType::NS,
+            "CNAME" => QueryType::CNAME,
+            "SOA" => QueryType::SOA,
+            "HINFO" => QueryType::HINFO,
+            "AAAA" => QueryType::AAAA,
+            "LOC" => QueryType::LOC,
+            "NAPTR" => QueryType::NAPTR,
+            "DNAME" => QueryType::DNAME,
+            "OPT" => QueryType::OPT,
+            "DS" => QueryType::DS,
+            "SSHFP" => QueryType::SSHFP,
+            "RRSIG" => QueryType::RRSIG,
+            "NSEC" => QueryType::NSEC,
+            "DNSKEY" => QueryType::DNSKEY,
+            "NSEC3" => QueryType::NSEC3,
+            "NSEC3PARAM" => QueryType::NSEC3PARAM,
+            "TLSA" => QueryType::TLSA,
+            "SVCB" => QueryType::SVCB,
+            "HTTPS" => QueryType::HTTPS,
+            "TSIG" => QueryType::TSIG,
+            "URI" => QueryType::URI,
+            "CAA" => QueryType::CAA,
+            _ => return Err(format!("Unknown record type: {}", text)),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::QueryType;
@@ -28,6 +147,7 @@ mod tests {
     #[test]
     fn gets_value_for_known_type() {
         assert_eq!(QueryType::A, QueryType::from_u16(1));
+        assert_eq!(QueryType::DNSKEY, QueryType::from_u16(48));
     }
 
     #[test]
@@ -35,4 +155,22 @@ mod tests {
         assert_eq!(QueryType::UNKNOWN(0), QueryType::from_u16(0));
         assert_eq!(QueryType::UNKNOWN(999), QueryType::from_u16(999));
     }
+
+    #[test]
+    fn unknown_type_keeps_its_value() {
+        assert_eq!(QueryType::UNKNOWN(999).to_u16(), 999);
+        assert_eq!(QueryType::UNKNOWN(999).to_string(), "TYPE999");
+        assert_eq!(QueryType::NSEC3PARAM.to_string(), "NSEC3PARAM");
+    }
+
+    #[test]
+    fn parses_what_it_displays() {
+        for value in [1, 28, 51, 65, 257, 999] {
+            let query_type = QueryType::from_u16(value);
+            assert_eq!(query_type.to_string().parse(), Ok(query_type));
+        }
+        assert_eq!("aaaa".parse(), Ok(QueryType::AAAA));
+        assert!("AAAAA".parse::<QueryType>().is_err());
+        assert!("TYPE70000".parse::<QueryType>().is_err());
+    }
 }
@@ -6,6 +6,10 @@ pub enum ResultCode {
     NXDOMAIN = 3,
     NOTIMP = 4,
     REFUSED = 5,
+    // A name that shouldn't exist does, or (with DNAME) the substituted name would be too long.
+    YXDOMAIN = 6,
+    // The server isn't authoritative for the zone, or (with TSIG) the request's signature was rejected.
+    NOTAUTH = 9,
 }
 
 impl ResultCode {
@@ -16,7 +20,9 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR,
+            6 => ResultCode::YXDOMAIN,
+            9 => ResultCode::NOTAUTH,
+            _ => ResultCode::NOERROR,
         }
     }
 }
@@ -31,8 +37,13 @@ mod tests {
     }
 
     #[test]
-    pub fn creates_noerr_result_from_num_gt_five() {
-        assert_eq!(ResultCode::from_number(6), ResultCode::NOERROR);
-        assert_eq!(ResultCode::from_number(0), ResultCode::from_number(6));
+    pub fn creates_noerr_result_from_unknown_num() {
+        assert_eq!(ResultCode::from_number(7), ResultCode::NOERROR);
+        assert_eq!(ResultCode::from_number(0), ResultCode::from_number(7));
+    }
+
+    #[test]
+    pub fn creates_yxdomain_result_from_six() {
+        assert_eq!(ResultCode::from_number(6), ResultCode::YXDOMAIN);
     }
 }
@@ -0,0 +1,371 @@
+use super::{
+    dns_record::{embedded_names, DnsRecord},
+    query_type::QueryType,
+    wrapped_buffer::{WrappedBuffer, MAX_MESSAGE_SIZE},
+};
+use std::cmp::Ordering;
+
+/// Records sharing an owner, type and class (RFC 2181 section 5), which DNS treats as a single unit. They share one
+/// TTL, the lowest of the records' own if they disagree, and a record can't appear twice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RRSet {
+    name: String,
+    query_type: QueryType,
+    class: u16,
+    ttl: u32,
+    records: Vec<DnsRecord>,
+}
+
+impl RRSet {
+    pub fn new(record: DnsRecord) -> RRSet {
+        RRSet {
+            name: record.domain().to_string(),
+            query_type: record.query_type(),
+            class: record.class(),
+            ttl: record.ttl(),
+            records: vec![record],
+        }
+    }
+
+    // Splits records into RRsets, in the order each RRset first appears.
+    pub fn group<I: IntoIterator<Item = DnsRecord>>(records: I) -> Vec<RRSet> {
+        let mut sets: Vec<RRSet> = Vec::new();
+        for record in records {
+            match sets.iter_mut().find(|set| set.belongs(&record)) {
+                Some(set) => set.insert(record),
+                None => sets.push(RRSet::new(record)),
+            }
+        }
+        sets
+    }
+
+    pub fn add(&mut self, record: DnsRecord) -> Result<(), String> {
+        if !self.belongs(&record) {
+            return Err(format!(
+                "{} {} doesn't belong in the RRset for {} {}.",
+                record.domain(),
+                record.query_type(),
+                self.name,
+                self.query_type
+            ));
+        }
+        self.insert(record);
+        Ok(())
+    }
+
+    // Owner names are compared ignoring case.
+    pub fn belongs(&self, record: &DnsRecord) -> bool {
+        record.query_type() == self.query_type
+            && record.class() == self.class
+            && compare_names(record.domain(), &self.name) == Ordering::Equal
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn query_type(&self) -> QueryType {
+        self.query_type
+    }
+
+    pub fn class(&self) -> u16 {
+        self.class
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    // The records, all carrying the RRset's TTL.
+    pub fn records(&self) -> &[DnsRecord] {
+        &self.records
+    }
+
+    pub fn into_records(self) -> Vec<DnsRecord> {
+        self.records
+    }
+
+    // Each record's data in canonical form (RFC 4034 section 6.2), sorted as unsigned bytes and without duplicates
+    // (section 6.3).
+    pub fn canonical_rdata(&self) -> Result<Vec<Vec<u8>>, String> {
+        let mut rdata = self
+            .records
+            .iter()
+            .map(canonical_rdata)
+            .collect::<Result<Vec<_>, String>>()?;
+        rdata.sort();
+        rdata.dedup();
+        Ok(rdata)
+    }
+
+    // The RRset in canonical form and order, as used for signatures and zone digests.
+    pub fn to_canonical_wire(&self) -> Result<Vec<u8>, String> {
+        self.to_canonical_wire_as(&self.name, self.ttl)
+    }
+
+    // The canonical form with a different owner and TTL, which is how RRSIGs cover wildcard expansions (signed under
+    // the wildcard's name) and records whose TTLs have counted down in a cache (signed with the original TTL).
+    pub fn to_canonical_wire_as(&self, owner: &str, ttl: u32) -> Result<Vec<u8>, String> {
+        let owner = name_to_wire(owner);
+        let mut wire = Vec::new();
+        for rdata in self.canonical_rdata()? {
+            wire.extend(&owner);
+            wire.extend(self.query_type.to_u16().to_be_bytes());
+            wire.extend(self.class.to_be_bytes());
+            wire.extend(ttl.to_be_bytes());
+            wire.extend((rdata.len() as u16).to_be_bytes());
+            wire.extend(rdata);
+        }
+        Ok(wire)
+    }
+
+    // Canonical order of RRsets: by owner name, then type, then class.
+    pub fn canonical_cmp(&self, other: &RRSet) -> Ordering {
+        compare_names(&self.name, &other.name)
+            .then(self.query_type.to_u16().cmp(&other.query_type.to_u16()))
+            .then(self.class.cmp(&other.class))
+    }
+
+    fn insert(&mut self, mut record: DnsRecord) {
+        if record.ttl() < self.ttl {
+            self.ttl = record.ttl();
+            for existing in self.records.iter_mut() {
+                existing.set_ttl(self.ttl);
+            }
+        }
+        record.set_ttl(self.ttl);
+        if !self.records.contains(&record) {
+            self.records.push(record);
+        }
+    }
+}
+
+// Canonical name order (RFC 4034 section 6.1): labels are compared right to left as lowercased bytes, and a name
+// sorts before any name below it.
+pub fn compare_names(a: &str, b: &str) -> Ordering {
+    let mut a_labels = labels(a).rev();
+    let mut b_labels = labels(b).rev();
+    loop {
+        match (a_labels.next(), b_labels.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a_label), Some(b_label)) => {
+                let order = a_label
+                    .bytes()
+                    .map(|byte| byte.to_ascii_lowercase())
+                    .cmp(b_label.bytes().map(|byte| byte.to_ascii_lowercase()));
+                if order != Ordering::Equal {
+                    return order;
+                }
+            }
+        }
+    }
+}
+
+// Is `name` the same as, or somewhere below, `ancestor`?
+pub fn is_subdomain(name: &str, ancestor: &str) -> bool {
+    let mut name_labels = labels(name).rev();
+    labels(ancestor).rev().all(|label| {
+        name_labels
+            .next()
+            .is_some_and(|other| other.eq_ignore_ascii_case(label))
+    })
+}
+
+// Uncompressed, lowercased wire form of a name.
+pub fn name_to_wire(name: &str) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in labels(name) {
+        wire.push(label.len() as u8);
+        wire.extend(label.bytes().map(|byte| byte.to_ascii_lowercase()));
+    }
+    wire.push(0);
+    wire
+}
+
+// The labels of `name`, leftmost first. The root has none.
+pub(crate) fn labels(name: &str) -> impl DoubleEndedIterator<Item = &str> {
+    name.split('.').filter(|label| !label.is_empty())
+}
+
+// RDATA as written by `DnsRecord::write`, which never compresses names, with the names that RFC 4034 section 6.2
+// (as corrected by RFC 6840 section 5.1) says to lowercase lowercased. Records of other types are left as they are
+// (RFC 3597 section 7).
+fn canonical_rdata(record: &DnsRecord) -> Result<Vec<u8>, String> {
+    let mut record = record.clone();
+    match &mut record {
+        DnsRecord::UNKNOWN {
+            query_type, data, ..
+        } => {
+            if let Some((fixed_length, _)) = embedded_names(*query_type) {
+                // Nothing but names follows the fixed fields, and label lengths are all below any letter.
+                if let Some(names) = data.get_mut(fixed_length..) {
+                    names.make_ascii_lowercase();
+                }
+            }
+        }
+        DnsRecord::NS { host: name, .. }
+        | DnsRecord::CNAME { host: name, .. }
+        | DnsRecord::DNAME { target: name, .. }
+        | DnsRecord::NAPTR {
+            replacement: name, ..
+        }
+        | DnsRecord::RRSIG {
+            signer_name: name, ..
+        } => name.make_ascii_lowercase(),
+        DnsRecord::SOA {
+            primary_name_server,
+            mailbox,
+            ..
+        } => {
+            primary_name_server.make_ascii_lowercase();
+            mailbox.make_ascii_lowercase();
+        }
+        _ => {}
+    }
+
+    let mut buffer = WrappedBuffer::with_size(MAX_MESSAGE_SIZE);
+    let written = record.write(&mut buffer)?;
+    // Skip the owner name, type, class, TTL and data length.
+    let data_start = name_to_wire(record.domain()).len() + 10;
+    Ok(buffer.get_slice(data_start, written - data_start)?.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare_names, RRSet};
+    use crate::parser::{DnsRecord, QueryType};
+    use std::{error::Error, net::Ipv4Addr};
+
+    fn a_record(domain: &str, last_octet: u8, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            address: Ipv4Addr::new(192, 0, 2, last_octet),
+            ttl,
+        }
+    }
+
+    #[test]
+    fn sorts_names_canonically() {
+        // The example ordering from RFC 4034 section 6.1.
+        let expected = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "\u{1}.z.example",
+            "*.z.example",
+            "\u{80}.z.example",
+        ];
+        let mut names = expected.to_vec();
+        names.reverse();
+        names.sort_by(|a, b| compare_names(a, b));
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn groups_records_by_owner_and_type() {
+        let records = vec![
+            a_record("www.example.com", 2, 300),
+            a_record("mail.example.com", 3, 300),
+            a_record("WWW.Example.com", 1, 60),
+            DnsRecord::CNAME {
+                domain: String::from("ftp.example.com"),
+                host: String::from("www.example.com"),
+                ttl: 300,
+            },
+            a_record("www.example.com", 2, 300),
+        ];
+
+        let sets = RRSet::group(records);
+
+        assert_eq!(sets.len(), 3);
+        assert_eq!(sets[0].name(), "www.example.com");
+        assert_eq!(sets[0].query_type(), QueryType::A);
+        assert_eq!(sets[0].class(), 1);
+        // The lowest TTL wins, and the repeated record only appears once.
+        assert_eq!(sets[0].ttl(), 60);
+        assert_eq!(sets[0].records().len(), 2);
+        assert!(sets[0].records().iter().all(|record| record.ttl() == 60));
+        assert_eq!(sets[2].query_type(), QueryType::CNAME);
+    }
+
+    #[test]
+    fn rejects_records_from_other_rrsets() -> Result<(), Box<dyn Error>> {
+        let mut set = RRSet::new(a_record("www.example.com", 1, 300));
+        assert!(set.add(a_record("mail.example.com", 1, 300)).is_err());
+        assert!(set
+            .add(DnsRecord::AAAA {
+                domain: String::from("www.example.com"),
+                address: "2001:db8::1".parse()?,
+                ttl: 300,
+            })
+            .is_err());
+        set.add(a_record("www.example.com", 2, 300))?;
+        assert_eq!(set.records().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn encodes_canonically() -> Result<(), Box<dyn Error>> {
+        let mut set = RRSet::new(DnsRecord::NS {
+            domain: String::from("Example.COM"),
+            host: String::from("NS2.Example.com"),
+            ttl: 3600,
+        });
+        set.add(DnsRecord::NS {
+            domain: String::from("example.com"),
+            host: String::from("ns1.example.com"),
+            ttl: 3600,
+        })?;
+
+        let ns1 = b"\x03ns1\x07example\x03com\x00";
+        let ns2 = b"\x03ns2\x07example\x03com\x00";
+        assert_eq!(set.canonical_rdata()?, vec![ns1.to_vec(), ns2.to_vec()]);
+
+        let mut expected = Vec::new();
+        for rdata in [ns1, ns2] {
+            expected.extend(b"\x07example\x03com\x00");
+            expected.extend([0, 2, 0, 1, 0, 0, 0x0E, 0x10, 0, rdata.len() as u8]);
+            expected.extend(rdata);
+        }
+        assert_eq!(set.to_canonical_wire()?, expected);
+
+        // Wildcard owner and original TTL, as an RRSIG would see them.
+        let as_signed = set.to_canonical_wire_as("*.com", 86400)?;
+        assert!(as_signed.starts_with(b"\x01*\x03com\x00\x00\x02\x00\x01\x00\x01\x51\x80"));
+        Ok(())
+    }
+
+    #[test]
+    fn orders_rrsets_canonically() {
+        let mut sets = RRSet::group(vec![
+            a_record("b.example", 1, 300),
+            DnsRecord::NS {
+                domain: String::from("a.example"),
+                host: String::from("ns.example"),
+                ttl: 300,
+            },
+            a_record("A.example", 1, 300),
+            a_record("example", 1, 300),
+        ]);
+        sets.sort_by(|a, b| a.canonical_cmp(b));
+
+        let order: Vec<(&str, QueryType)> = sets
+            .iter()
+            .map(|set| (set.name(), set.query_type()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("example", QueryType::A),
+                ("A.example", QueryType::A),
+                ("a.example", QueryType::NS),
+                ("b.example", QueryType::A),
+            ]
+        );
+    }
+}
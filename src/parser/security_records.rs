@@ -0,0 +1,355 @@
+use super::dns_record::DnsRecord;
+use ring::digest;
+
+// Helpers for the records which pin down who may issue certificates for a name (CAA, RFC 8659), which
+// certificates a TLS service uses (TLSA, RFC 6698) and which host keys an SSH server has (SSHFP, RFC 4255).
+
+// A CAA flag saying the property must be understood before issuing.
+const CAA_CRITICAL: u8 = 0x80;
+
+// TLSA selectors and matching types.
+const SELECTOR_CERTIFICATE: u8 = 0;
+const SELECTOR_PUBLIC_KEY: u8 = 1;
+const MATCH_EXACT: u8 = 0;
+const MATCH_SHA256: u8 = 1;
+const MATCH_SHA512: u8 = 2;
+
+// SSHFP fingerprint types.
+const FINGERPRINT_SHA1: u8 = 1;
+const FINGERPRINT_SHA256: u8 = 2;
+
+// SSH key types (as named inside the key blob) and their SSHFP algorithm numbers.
+const SSH_KEY_ALGORITHMS: [(&str, u8); 6] = [
+    ("ssh-rsa", 1),
+    ("ssh-dss", 2),
+    ("ecdsa-sha2-nistp256", 3),
+    ("ecdsa-sha2-nistp384", 3),
+    ("ecdsa-sha2-nistp521", 3),
+    ("ssh-ed25519", 4),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaaProperty {
+    // Which CA may issue certificates. No issuer means no CA may.
+    Issue {
+        issuer: Option<String>,
+        parameters: Vec<(String, String)>,
+    },
+    // As `Issue`, for wildcard certificates only.
+    IssueWild {
+        issuer: Option<String>,
+        parameters: Vec<(String, String)>,
+    },
+    // Where CAs should report requests which break the policy (a mailto: or https: URL).
+    Iodef(String),
+    Other {
+        tag: String,
+        value: Vec<u8>,
+    },
+}
+
+impl CaaProperty {
+    pub fn from_record(record: &DnsRecord) -> Option<CaaProperty> {
+        let DnsRecord::CAA { tag, value, .. } = record else {
+            return None;
+        };
+        let text = String::from_utf8_lossy(value);
+        Some(match tag.to_ascii_lowercase().as_str() {
+            "issue" => {
+                let (issuer, parameters) = parse_issuer(&text);
+                CaaProperty::Issue { issuer, parameters }
+            }
+            "issuewild" => {
+                let (issuer, parameters) = parse_issuer(&text);
+                CaaProperty::IssueWild { issuer, parameters }
+            }
+            "iodef" => CaaProperty::Iodef(text.into_owned()),
+            _ => CaaProperty::Other {
+                tag: tag.clone(),
+                value: value.clone(),
+            },
+        })
+    }
+}
+
+// Whether the CAA RRset for a name lets `issuer` (a CA's domain, e.g. "letsencrypt.org") issue a certificate for it
+// (RFC 8659 section 4). Finding the relevant RRset, by climbing towards the root until some name has CAA records,
+// is up to the caller.
+pub fn caa_authorizes(records: &[DnsRecord], issuer: &str, wildcard: bool) -> bool {
+    let properties: Vec<(u8, CaaProperty)> = records
+        .iter()
+        .filter_map(|record| match record {
+            DnsRecord::CAA { flags, .. } => Some((*flags, CaaProperty::from_record(record)?)),
+            _ => None,
+        })
+        .collect();
+    // An unknown property marked critical stops issuance altogether.
+    if properties.iter().any(|(flags, property)| {
+        flags & CAA_CRITICAL != 0 && matches!(property, CaaProperty::Other { .. })
+    }) {
+        return false;
+    }
+
+    let issuers = |wild: bool| -> Vec<&Option<String>> {
+        properties
+            .iter()
+            .filter_map(|(_, property)| match property {
+                CaaProperty::Issue { issuer, .. } if !wild => Some(issuer),
+                CaaProperty::IssueWild { issuer, .. } if wild => Some(issuer),
+                _ => None,
+            })
+            .collect()
+    };
+    // issuewild properties take over for wildcard certificates, if there are any.
+    let mut relevant = issuers(wildcard);
+    if wildcard && relevant.is_empty() {
+        relevant = issuers(false);
+    }
+
+    relevant.is_empty()
+        || relevant.iter().any(|allowed| {
+            allowed
+                .as_deref()
+                .is_some_and(|allowed| allowed.eq_ignore_ascii_case(issuer))
+        })
+}
+
+// Whether any of the TLSA records matches a certificate, given both the whole DER certificate and its DER
+// SubjectPublicKeyInfo since the selector decides which one is compared. Which certificate in the chain to
+// check depends on each record's usage: the end entity for usages 1 and 3, a CA for usages 0 and 2.
+pub fn tlsa_matches(records: &[DnsRecord], certificate: &[u8], public_key_info: &[u8]) -> bool {
+    records.iter().any(|record| {
+        let DnsRecord::TLSA {
+            selector,
+            matching_type,
+            certificate_data,
+            ..
+        } = record
+        else {
+            return false;
+        };
+        let selected = match *selector {
+            SELECTOR_CERTIFICATE => certificate,
+            SELECTOR_PUBLIC_KEY => public_key_info,
+            _ => return false,
+        };
+        match *matching_type {
+            MATCH_EXACT => selected == certificate_data.as_slice(),
+            MATCH_SHA256 => digest_matches(&digest::SHA256, selected, certificate_data),
+            MATCH_SHA512 => digest_matches(&digest::SHA512, selected, certificate_data),
+            _ => false,
+        }
+    })
+}
+
+// Whether any of the SSHFP records matches an SSH host key, given in the SSH wire format (the base64 part of a
+// known_hosts or .pub line, decoded).
+pub fn sshfp_matches(records: &[DnsRecord], host_key: &[u8]) -> bool {
+    let Some(key_algorithm) = ssh_key_algorithm(host_key) else {
+        return false;
+    };
+    records.iter().any(|record| match record {
+        DnsRecord::SSHFP {
+            algorithm,
+            fingerprint_type,
+            fingerprint,
+            ..
+        } if *algorithm == key_algorithm => match *fingerprint_type {
+            FINGERPRINT_SHA1 => {
+                digest_matches(&digest::SHA1_FOR_LEGACY_USE_ONLY, host_key, fingerprint)
+            }
+            FINGERPRINT_SHA256 => digest_matches(&digest::SHA256, host_key, fingerprint),
+            _ => false,
+        },
+        _ => false,
+    })
+}
+
+// The SSHFP records to publish for a host key, like `ssh-keygen -r`.
+pub fn sshfp_records(domain: &str, host_key: &[u8], ttl: u32) -> Option<Vec<DnsRecord>> {
+    let algorithm = ssh_key_algorithm(host_key)?;
+    Some(
+        [
+            (FINGERPRINT_SHA1, &digest::SHA1_FOR_LEGACY_USE_ONLY),
+            (FINGERPRINT_SHA256, &digest::SHA256),
+        ]
+        .into_iter()
+        .map(|(fingerprint_type, hash)| DnsRecord::SSHFP {
+            domain: domain.to_string(),
+            algorithm,
+            fingerprint_type,
+            fingerprint: digest::digest(hash, host_key).as_ref().to_vec(),
+            ttl,
+        })
+        .collect(),
+    )
+}
+
+fn digest_matches(algorithm: &'static digest::Algorithm, data: &[u8], expected: &[u8]) -> bool {
+    digest::digest(algorithm, data).as_ref() == expected
+}
+
+// SSH key blobs start with the key type as a length-prefixed string.
+fn ssh_key_algorithm(host_key: &[u8]) -> Option<u8> {
+    let length = u32::from_be_bytes(host_key.get(..4)?.try_into().ok()?) as usize;
+    let key_type = host_key.get(4..4 + length)?;
+    SSH_KEY_ALGORITHMS
+        .iter()
+        .find(|(name, _)| name.as_bytes() == key_type)
+        .map(|(_, algorithm)| *algorithm)
+}
+
+// Splits an issue value like "ca.example.net; account=230123" into the issuer and its parameters.
+fn parse_issuer(value: &str) -> (Option<String>, Vec<(String, String)>) {
+    let (issuer, parameters) = value.split_once(';').unwrap_or((value, ""));
+    let issuer = Some(issuer.trim())
+        .filter(|issuer| !issuer.is_empty())
+        .map(str::to_string);
+    let parameters = parameters
+        .split(';')
+        .filter_map(|parameter| {
+            let (tag, value) = parameter.split_once('=')?;
+            Some((tag.trim().to_string(), value.trim().to_string()))
+        })
+        .collect();
+    (issuer, parameters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{caa_authorizes, sshfp_matches, sshfp_records, tlsa_matches, CaaProperty};
+    use crate::parser::DnsRecord;
+    use data_encoding::{BASE64, HEXUPPER};
+    use ring::digest;
+    use std::error::Error;
+
+    fn caa(flags: u8, tag: &str, value: &str) -> DnsRecord {
+        DnsRecord::CAA {
+            domain: String::from("example.com"),
+            flags,
+            tag: tag.to_string(),
+            value: value.as_bytes().to_vec(),
+            ttl: 3600,
+        }
+    }
+
+    fn tlsa(selector: u8, matching_type: u8, certificate_data: Vec<u8>) -> DnsRecord {
+        DnsRecord::TLSA {
+            domain: String::from("_443._tcp.www.example.com"),
+            usage: 3,
+            selector,
+            matching_type,
+            certificate_data,
+            ttl: 3600,
+        }
+    }
+
+    // An Ed25519 key blob as it appears (base64 encoded) in an OpenSSH .pub file.
+    const ED25519_HOST_KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    #[test]
+    fn parses_caa_properties() {
+        assert_eq!(
+            CaaProperty::from_record(&caa(0, "issue", "ca.example.net; account=230123")),
+            Some(CaaProperty::Issue {
+                issuer: Some(String::from("ca.example.net")),
+                parameters: vec![(String::from("account"), String::from("230123"))],
+            })
+        );
+        assert_eq!(
+            CaaProperty::from_record(&caa(0, "issuewild", ";")),
+            Some(CaaProperty::IssueWild {
+                issuer: None,
+                parameters: Vec::new(),
+            })
+        );
+        assert_eq!(
+            CaaProperty::from_record(&caa(0, "iodef", "mailto:security@example.com")),
+            Some(CaaProperty::Iodef(String::from(
+                "mailto:security@example.com"
+            )))
+        );
+    }
+
+    #[test]
+    fn applies_caa_policy() {
+        let records = vec![
+            caa(0, "issue", "ca.example.net"),
+            caa(0, "issuewild", ";"),
+            caa(0, "iodef", "mailto:security@example.com"),
+        ];
+        assert!(caa_authorizes(&records, "ca.example.net", false));
+        assert!(caa_authorizes(&records, "CA.Example.NET", false));
+        assert!(!caa_authorizes(&records, "other-ca.example", false));
+        assert!(!caa_authorizes(&records, "ca.example.net", true));
+
+        // Without issuewild, wildcards fall back to issue.
+        assert!(caa_authorizes(&records[..1], "ca.example.net", true));
+        // No issue properties at all means anyone may issue.
+        assert!(caa_authorizes(&records[2..], "other-ca.example", false));
+        assert!(caa_authorizes(&[], "other-ca.example", false));
+        // Unless there's a critical property we don't understand.
+        assert!(!caa_authorizes(
+            &[caa(128, "tbs", "unknown")],
+            "ca.example.net",
+            false
+        ));
+    }
+
+    #[test]
+    fn matches_tlsa_records() {
+        let certificate = b"certificate".as_slice();
+        let public_key_info = b"public key info".as_slice();
+        let sha256 = |data| digest::digest(&digest::SHA256, data).as_ref().to_vec();
+        let sha512 = |data| digest::digest(&digest::SHA512, data).as_ref().to_vec();
+
+        for record in [
+            tlsa(0, 0, certificate.to_vec()),
+            tlsa(0, 1, sha256(certificate)),
+            tlsa(1, 1, sha256(public_key_info)),
+            tlsa(1, 2, sha512(public_key_info)),
+        ] {
+            assert!(tlsa_matches(&[record], certificate, public_key_info));
+        }
+        assert!(!tlsa_matches(
+            &[tlsa(1, 1, sha256(certificate))],
+            certificate,
+            public_key_info
+        ));
+        assert!(!tlsa_matches(
+            &[tlsa(0, 1, sha256(certificate))],
+            b"another certificate",
+            public_key_info
+        ));
+    }
+
+    #[test]
+    fn matches_sshfp_records() -> Result<(), Box<dyn Error>> {
+        let host_key = BASE64.decode(ED25519_HOST_KEY.as_bytes())?;
+        let records = sshfp_records("host.example.com", &host_key, 3600).unwrap_or_default();
+
+        assert_eq!(
+            records[1].data_to_string(),
+            format!(
+                "4 2 {}",
+                HEXUPPER.encode(digest::digest(&digest::SHA256, &host_key).as_ref())
+            )
+        );
+        for record in &records {
+            assert!(sshfp_matches(std::slice::from_ref(record), &host_key));
+        }
+
+        let mut other_key = host_key.clone();
+        let last = other_key.len() - 1;
+        other_key[last] ^= 1;
+        assert!(!sshfp_matches(&records, &other_key));
+        // A fingerprint only counts for the right key algorithm.
+        let mut wrong_algorithm = records[1].clone();
+        if let DnsRecord::SSHFP { algorithm, .. } = &mut wrong_algorithm {
+            *algorithm = 1;
+        }
+        assert!(!sshfp_matches(&[wrong_algorithm], &host_key));
+        Ok(())
+    }
+}
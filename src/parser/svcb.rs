@@ -0,0 +1,552 @@
+use super::wrapped_buffer::WrappedBuffer;
+use data_encoding::BASE64;
+use std::{
+    fmt::Display,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+// SvcParams (RFC 9460 section 2.1) follow the target name in SVCB and HTTPS records. Each is a key, the length of
+// its value and the value itself, and keys must appear in strictly increasing order with no repeats.
+
+const MANDATORY: u16 = 0;
+const ALPN: u16 = 1;
+const NO_DEFAULT_ALPN: u16 = 2;
+const PORT: u16 = 3;
+const IPV4HINT: u16 = 4;
+const ECH: u16 = 5;
+const IPV6HINT: u16 = 6;
+
+const KEY_NAMES: [(u16, &str); 7] = [
+    (MANDATORY, "mandatory"),
+    (ALPN, "alpn"),
+    (NO_DEFAULT_ALPN, "no-default-alpn"),
+    (PORT, "port"),
+    (IPV4HINT, "ipv4hint"),
+    (ECH, "ech"),
+    (IPV6HINT, "ipv6hint"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SvcbMode {
+    // Priority 0: the record just points at another name, like a CNAME that's allowed at the zone apex.
+    Alias,
+    // Any other priority: the record describes an endpoint, lower priorities being preferred.
+    Service,
+}
+
+impl SvcbMode {
+    pub fn from_priority(priority: u16) -> SvcbMode {
+        match priority {
+            0 => SvcbMode::Alias,
+            _ => SvcbMode::Service,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SvcParam {
+    // Keys a client must understand to use the record.
+    Mandatory(Vec<u16>),
+    // ALPN protocol IDs, e.g. "h2" and "h3".
+    Alpn(Vec<String>),
+    NoDefaultAlpn,
+    Port(u16),
+    Ipv4Hint(Vec<Ipv4Addr>),
+    // An ECHConfigList, kept opaque.
+    Ech(Vec<u8>),
+    Ipv6Hint(Vec<Ipv6Addr>),
+    Unknown { key: u16, value: Vec<u8> },
+}
+
+impl SvcParam {
+    pub fn key(&self) -> u16 {
+        match self {
+            SvcParam::Mandatory(_) => MANDATORY,
+            SvcParam::Alpn(_) => ALPN,
+            SvcParam::NoDefaultAlpn => NO_DEFAULT_ALPN,
+            SvcParam::Port(_) => PORT,
+            SvcParam::Ipv4Hint(_) => IPV4HINT,
+            SvcParam::Ech(_) => ECH,
+            SvcParam::Ipv6Hint(_) => IPV6HINT,
+            SvcParam::Unknown { key, .. } => *key,
+        }
+    }
+
+    fn from_wire(key: u16, value: Vec<u8>) -> Result<SvcParam, String> {
+        let param = match key {
+            MANDATORY => SvcParam::Mandatory(
+                fixed_size_items(&value, 2, key)?
+                    .map(|item| u16::from_be_bytes([item[0], item[1]]))
+                    .collect(),
+            ),
+            ALPN => {
+                let mut ids = Vec::new();
+                let mut rest = value.as_slice();
+                while let Some((&length, tail)) = rest.split_first() {
+                    let id = tail
+                        .get(..length as usize)
+                        .ok_or("ALPN ID runs past the end of the alpn SvcParam.")?;
+                    ids.push(String::from_utf8_lossy(id).into_owned());
+                    rest = &tail[length as usize..];
+                }
+                SvcParam::Alpn(ids)
+            }
+            NO_DEFAULT_ALPN if value.is_empty() => SvcParam::NoDefaultAlpn,
+            NO_DEFAULT_ALPN => return Err("no-default-alpn SvcParam must be empty.".into()),
+            PORT if value.len() == 2 => SvcParam::Port(u16::from_be_bytes([value[0], value[1]])),
+            PORT => return Err("port SvcParam must be 2 bytes long.".into()),
+            IPV4HINT => SvcParam::Ipv4Hint(
+                fixed_size_items(&value, 4, key)?
+                    .map(|item| Ipv4Addr::new(item[0], item[1], item[2], item[3]))
+                    .collect(),
+            ),
+            ECH => SvcParam::Ech(value),
+            IPV6HINT => SvcParam::Ipv6Hint(
+                fixed_size_items(&value, 16, key)?
+                    .map(|item| {
+                        let mut octets = [0; 16];
+                        octets.copy_from_slice(item);
+                        Ipv6Addr::from(octets)
+                    })
+                    .collect(),
+            ),
+            _ => SvcParam::Unknown { key, value },
+        };
+        Ok(param)
+    }
+
+    fn value_to_wire(&self) -> Result<Vec<u8>, String> {
+        Ok(match self {
+            SvcParam::Mandatory(keys) => keys.iter().flat_map(|key| key.to_be_bytes()).collect(),
+            SvcParam::Alpn(ids) => {
+                let mut value = Vec::new();
+                for id in ids {
+                    if id.len() > u8::MAX as usize {
+                        return Err(format!("ALPN ID {} is too long.", id));
+                    }
+                    value.push(id.len() as u8);
+                    value.extend(id.bytes());
+                }
+                value
+            }
+            SvcParam::NoDefaultAlpn => Vec::new(),
+            SvcParam::Port(port) => port.to_be_bytes().to_vec(),
+            SvcParam::Ipv4Hint(addresses) => addresses.iter().flat_map(Ipv4Addr::octets).collect(),
+            SvcParam::Ech(config) => config.clone(),
+            SvcParam::Ipv6Hint(addresses) => addresses.iter().flat_map(Ipv6Addr::octets).collect(),
+            SvcParam::Unknown { value, .. } => value.clone(),
+        })
+    }
+}
+
+// Presentation format (RFC 9460 section 2.1), e.g. "alpn=h2,h3" or "port=8443".
+impl Display for SvcParam {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = key_name(self.key());
+        let value = match self {
+            SvcParam::Mandatory(keys) => keys.iter().map(|key| key_name(*key)).collect(),
+            SvcParam::Alpn(ids) => ids.iter().map(|id| escape(id.as_bytes(), true)).collect(),
+            SvcParam::NoDefaultAlpn => return write!(formatter, "{}", name),
+            SvcParam::Port(port) => vec![port.to_string()],
+            SvcParam::Ipv4Hint(addresses) => addresses.iter().map(Ipv4Addr::to_string).collect(),
+            SvcParam::Ech(config) => vec![BASE64.encode(config)],
+            SvcParam::Ipv6Hint(addresses) => addresses.iter().map(Ipv6Addr::to_string).collect(),
+            SvcParam::Unknown { value, .. } if value.is_empty() => {
+                return write!(formatter, "{}", name)
+            }
+            SvcParam::Unknown { value, .. } => vec![escape(value, false)],
+        };
+        write!(formatter, "{}={}", name, value.join(","))
+    }
+}
+
+impl FromStr for SvcParam {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<SvcParam, String> {
+        let (name, value) = match text.split_once('=') {
+            Some((name, value)) => (name, Some(unquote(value))),
+            None => (text, None),
+        };
+        let key = key_from_name(name).ok_or_else(|| format!("Unknown SvcParam key {}.", name))?;
+        let items = || -> Result<Vec<Vec<u8>>, String> {
+            match value {
+                Some(value) if !value.is_empty() => split_list(value)
+                    .iter()
+                    .map(|item| unescape(item))
+                    .collect(),
+                _ => Err(format!("SvcParam {} needs a value.", name)),
+            }
+        };
+        let strings = || -> Result<Vec<String>, String> {
+            items()?
+                .into_iter()
+                .map(|item| String::from_utf8(item).map_err(|e| e.to_string()))
+                .collect()
+        };
+
+        Ok(match key {
+            MANDATORY => {
+                let mut keys = strings()?
+                    .iter()
+                    .map(|name| {
+                        key_from_name(name).ok_or(format!("Unknown SvcParam key {}.", name))
+                    })
+                    .collect::<Result<Vec<u16>, _>>()?;
+                keys.sort_unstable();
+                SvcParam::Mandatory(keys)
+            }
+            ALPN => SvcParam::Alpn(strings()?),
+            NO_DEFAULT_ALPN if value.is_none() => SvcParam::NoDefaultAlpn,
+            NO_DEFAULT_ALPN => return Err("no-default-alpn doesn't take a value.".into()),
+            PORT => SvcParam::Port(
+                value
+                    .unwrap_or_default()
+                    .parse()
+                    .map_err(|e| format!("Invalid port: {}", e))?,
+            ),
+            IPV4HINT => SvcParam::Ipv4Hint(parse_addresses(&strings()?)?),
+            ECH => SvcParam::Ech(
+                BASE64
+                    .decode(value.unwrap_or_default().as_bytes())
+                    .map_err(|e| format!("Invalid ech: {}", e))?,
+            ),
+            IPV6HINT => SvcParam::Ipv6Hint(parse_addresses(&strings()?)?),
+            _ => SvcParam::Unknown {
+                key,
+                value: unescape(value.unwrap_or_default())?,
+            },
+        })
+    }
+}
+
+// Parses the SvcParams part of an SVCB or HTTPS record in presentation format, e.g. `alpn=h2 port="8443"`. Unlike
+// the wire format, presentation format allows any order, so the result is sorted into key order.
+pub fn parse_params(text: &str) -> Result<Vec<SvcParam>, String> {
+    let mut params = tokens(text)?
+        .iter()
+        .map(|token| token.parse())
+        .collect::<Result<Vec<SvcParam>, String>>()?;
+    params.sort_by_key(SvcParam::key);
+    validate(&params)?;
+    Ok(params)
+}
+
+pub fn read(buffer: &mut WrappedBuffer, length: usize) -> Result<Vec<SvcParam>, String> {
+    let end = buffer.pos() + length;
+    let mut params = Vec::new();
+    while buffer.pos() < end {
+        let key = buffer.read_u16()?;
+        let value_length = buffer.read_u16()? as usize;
+        if buffer.pos() + value_length > end {
+            return Err("SvcParam runs past the end of the record data.".into());
+        }
+        params.push(SvcParam::from_wire(key, buffer.read_bytes(value_length)?)?);
+    }
+    validate(&params)?;
+    Ok(params)
+}
+
+pub fn write(buffer: &mut WrappedBuffer, params: &[SvcParam]) -> Result<(), String> {
+    validate(params)?;
+    for param in params {
+        let value = param.value_to_wire()?;
+        buffer.write_u16(param.key())?;
+        buffer.write_u16(value.len() as u16)?;
+        buffer.write_bytes(&value)?;
+    }
+    Ok(())
+}
+
+pub fn format(params: &[SvcParam]) -> String {
+    params.iter().map(|param| format!(" {}", param)).collect()
+}
+
+// The rules every set of SvcParams must follow, whichever way it's written (RFC 9460 sections 2.2 and 8).
+fn validate(params: &[SvcParam]) -> Result<(), String> {
+    let keys: Vec<u16> = params.iter().map(SvcParam::key).collect();
+    if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err("SvcParam keys must be in strictly increasing order.".into());
+    }
+
+    for param in params {
+        match param {
+            SvcParam::Mandatory(mandatory) => {
+                if mandatory.is_empty() {
+                    return Err("mandatory SvcParam can't be empty.".into());
+                }
+                if mandatory.contains(&MANDATORY) {
+                    return Err("mandatory SvcParam can't list itself.".into());
+                }
+                if mandatory.windows(2).any(|pair| pair[0] >= pair[1]) {
+                    return Err("mandatory SvcParam keys must be sorted with no repeats.".into());
+                }
+                if let Some(missing) = mandatory.iter().find(|key| !keys.contains(key)) {
+                    return Err(format!(
+                        "Mandatory SvcParam {} is missing.",
+                        key_name(*missing)
+                    ));
+                }
+            }
+            SvcParam::Alpn(ids) if ids.is_empty() || ids.iter().any(String::is_empty) => {
+                return Err("alpn SvcParam needs at least one non-empty protocol ID.".into())
+            }
+            SvcParam::Ipv4Hint(addresses) if addresses.is_empty() => {
+                return Err("ipv4hint SvcParam needs at least one address.".into())
+            }
+            SvcParam::Ipv6Hint(addresses) if addresses.is_empty() => {
+                return Err("ipv6hint SvcParam needs at least one address.".into())
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn fixed_size_items(
+    value: &[u8],
+    size: usize,
+    key: u16,
+) -> Result<std::slice::Chunks<'_, u8>, String> {
+    match value.len() % size {
+        0 => Ok(value.chunks(size)),
+        _ => Err(format!(
+            "{} SvcParam length isn't a multiple of {}.",
+            key_name(key),
+            size
+        )),
+    }
+}
+
+fn key_name(key: u16) -> String {
+    KEY_NAMES
+        .iter()
+        .find(|(known, _)| *known == key)
+        .map_or_else(|| format!("key{}", key), |(_, name)| name.to_string())
+}
+
+fn key_from_name(name: &str) -> Option<u16> {
+    KEY_NAMES
+        .iter()
+        .find(|(_, known)| known.eq_ignore_ascii_case(name))
+        .map(|(key, _)| *key)
+        .or_else(|| name.strip_prefix("key")?.parse().ok())
+}
+
+fn parse_addresses<T: FromStr>(items: &[String]) -> Result<Vec<T>, String> {
+    items
+        .iter()
+        .map(|item| {
+            item.parse()
+                .map_err(|_| format!("Invalid address {}.", item))
+        })
+        .collect()
+}
+
+// Splits on whitespace, keeping quoted strings together.
+fn tokens(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                current.push(c);
+                current.extend(chars.next());
+            }
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quoted string in SvcParams.".into());
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+// Splits a comma-separated list, leaving escaped commas (`\,`) inside their items.
+fn split_list(value: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ',' => {
+                items.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&value[start..]);
+    items
+}
+
+// Decodes `\X` and `\DDD` escapes in a character string.
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match rest {
+            [a, b, c, tail @ ..]
+                if a.is_ascii_digit() && b.is_ascii_digit() && c.is_ascii_digit() =>
+            {
+                let value = (a - b'0') as u16 * 100 + (b - b'0') as u16 * 10 + (c - b'0') as u16;
+                bytes
+                    .push(u8::try_from(value).map_err(|_| format!("Invalid escape \\{}.", value))?);
+                rest = tail;
+            }
+            [escaped, tail @ ..] => {
+                bytes.push(*escaped);
+                rest = tail;
+            }
+            [] => return Err("Character string ends with a backslash.".into()),
+        }
+    }
+    Ok(bytes)
+}
+
+// Escapes bytes for presentation, including commas when the value is an item in a list.
+fn escape(bytes: &[u8], in_list: bool) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b',' if in_list => String::from("\\,"),
+            b'\\' | b'"' => format!("\\{}", byte as char),
+            0x21..=0x7E => (byte as char).to_string(),
+            _ => format!("\\{:03}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_params, read, write, SvcParam, SvcbMode};
+    use crate::parser::wrapped_buffer::WrappedBuffer;
+    use data_encoding::HEXUPPER;
+    use std::error::Error;
+
+    fn to_wire(params: &[SvcParam]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = WrappedBuffer::new();
+        write(&mut buffer, params)?;
+        Ok(buffer.get_slice(0, buffer.pos())?.to_vec())
+    }
+
+    fn from_wire(hex: &str) -> Result<Vec<SvcParam>, Box<dyn Error>> {
+        let bytes = HEXUPPER.decode(hex.as_bytes())?;
+        let mut buffer = WrappedBuffer::from_bytes(&bytes)?;
+        Ok(read(&mut buffer, bytes.len())?)
+    }
+
+    #[test]
+    fn matches_rfc_9460_test_vectors() -> Result<(), Box<dyn Error>> {
+        // Appendix D.2, figures 4 to 7 (just the SvcParams).
+        let cases = [
+            ("port=53", "000300020035"),
+            ("key667=hello", "029B000568656C6C6F"),
+            (
+                "ipv6hint=2001:db8::1,2001:db8::53:1",
+                "0006002020010DB800000000000000000000000120010DB8000000000000000000530001",
+            ),
+            (
+                "alpn=h2,h3-19 mandatory=ipv4hint,alpn ipv4hint=192.0.2.1",
+                "000000040001000400010009026832056833 2D3139 00040004C0000201",
+            ),
+        ];
+        for (presentation, hex) in cases {
+            let params = parse_params(presentation)?;
+            let hex = hex.replace(' ', "");
+            assert_eq!(HEXUPPER.encode(&to_wire(&params)?), hex);
+            assert_eq!(from_wire(&hex)?, params);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn presentation_survives_round_trip() -> Result<(), Box<dyn Error>> {
+        let text = "mandatory=alpn,port alpn=h2,h\\,3 no-default-alpn port=8443 \
+                    ipv4hint=192.0.2.1,192.0.2.2 ech=AEX+DQ== ipv6hint=2001:db8::1 key667=\"hello\\210qoo\"";
+        let params = parse_params(text)?;
+        assert_eq!(
+            params[1],
+            SvcParam::Alpn(vec![String::from("h2"), String::from("h,3")])
+        );
+        assert_eq!(
+            params[7],
+            SvcParam::Unknown {
+                key: 667,
+                value: b"hello\xd2qoo".to_vec()
+            }
+        );
+
+        let formatted: Vec<String> = params.iter().map(SvcParam::to_string).collect();
+        assert_eq!(
+            formatted.join(" "),
+            "mandatory=alpn,port alpn=h2,h\\,3 no-default-alpn port=8443 \
+             ipv4hint=192.0.2.1,192.0.2.2 ech=AEX+DQ== ipv6hint=2001:db8::1 key667=hello\\210qoo"
+        );
+        assert_eq!(parse_params(&formatted.join(" "))?, params);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_malformed_params() {
+        // Appendix D.3 failure cases, plus a few more.
+        for text in [
+            "alpn=h2 alpn=h3",
+            "mandatory=ipv4hint",
+            "mandatory=mandatory",
+            "mandatory=port,port port=53",
+            "no-default-alpn=abc",
+            "alpn",
+            "port=abc",
+            "ipv4hint=2001:db8::1",
+        ] {
+            assert!(parse_params(text).is_err(), "{} should be rejected", text);
+        }
+    }
+
+    #[test]
+    fn rejects_keys_out_of_order_on_the_wire() -> Result<(), Box<dyn Error>> {
+        // port then alpn.
+        assert!(from_wire("00030002003500010003026832").is_err());
+        assert!(to_wire(&[SvcParam::Port(53), SvcParam::NoDefaultAlpn]).is_err());
+        // A port that's not two bytes, and a hint that's not a whole number of addresses.
+        assert!(from_wire("00030001FF").is_err());
+        assert!(from_wire("00040003C00002").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn priority_zero_is_alias_mode() {
+        assert_eq!(SvcbMode::from_priority(0), SvcbMode::Alias);
+        assert_eq!(SvcbMode::from_priority(1), SvcbMode::Service);
+    }
+}
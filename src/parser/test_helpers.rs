@@ -1,19 +1,22 @@
 #![allow(unused)]
 use super::wrapped_buffer::WrappedBuffer;
-use std::{error::Error, fs::File, io::Read};
+use std::{error::Error, fs::File, io::Read, path::PathBuf};
 
 const TEST_DATA_DIR: &str = "test_data";
 pub const GOOGLE_QUERY: &str = "google_query_response.txt";
+pub const GOOGLE_QUERY_UDP_CAPTURE: &str = "google_query_udp.pcap";
+pub const GOOGLE_QUERY_TCP_CAPTURE: &str = "google_query_tcp.pcapng";
 pub const HEADER_LENGTH_BYTES: usize = 12;
 pub const RECORD_COUNT_SIZE_BYTES: usize = 2;
 
 pub fn open_test_file(filename: String) -> std::io::Result<File> {
-    File::open(format!(
-        "{}/{}/{}",
-        std::env::var("CARGO_MANIFEST_DIR").unwrap(),
-        TEST_DATA_DIR,
-        filename
-    ))
+    File::open(test_file_path(&filename))
+}
+
+pub fn test_file_path(filename: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), TEST_DATA_DIR, filename]
+        .iter()
+        .collect()
 }
 
 pub fn get_buffer_at_question_section(input_file: String) -> Result<WrappedBuffer, Box<dyn Error>> {
@@ -25,7 +28,9 @@ pub fn get_buffer_at_question_section(input_file: String) -> Result<WrappedBuffe
 pub fn get_buffer_at_beginning(input_file: String) -> Result<WrappedBuffer, Box<dyn Error>> {
     let mut buffer = WrappedBuffer::new();
     let mut file = open_test_file(input_file)?;
-    file.read(&mut buffer.as_slice()?)?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    buffer.as_slice()?[..contents.len()].copy_from_slice(&contents);
     Ok(buffer)
 }
 
@@ -0,0 +1,773 @@
+use super::{
+    dns_packet::DnsPacket,
+    dns_packet_builder::DnsPacketBuilder,
+    dns_record::{DnsRecord, CLASS_ANY},
+    result_code::ResultCode,
+    rrset::{compare_names, name_to_wire},
+    wrapped_buffer::{WrappedBuffer, MAX_MESSAGE_SIZE},
+};
+use data_encoding::BASE64;
+use ring::hmac;
+use std::{
+    cmp::Ordering,
+    error::Error,
+    fmt::Display,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// How far apart the signer's and verifier's clocks may be, in seconds (RFC 8945 section 10).
+const DEFAULT_FUDGE: u16 = 300;
+// A stream may leave at most 99 messages in a row unsigned (RFC 8945 section 5.3.1).
+const MAX_UNSIGNED_MESSAGES: usize = 99;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TsigAlgorithm> {
+        [TsigAlgorithm::HmacSha256, TsigAlgorithm::HmacSha512]
+            .into_iter()
+            .find(|algorithm| compare_names(algorithm.name(), name) == Ordering::Equal)
+    }
+
+    fn hmac_algorithm(&self) -> hmac::Algorithm {
+        match self {
+            TsigAlgorithm::HmacSha256 => hmac::HMAC_SHA256,
+            TsigAlgorithm::HmacSha512 => hmac::HMAC_SHA512,
+        }
+    }
+}
+
+/// A shared secret, named the same way at both ends of a transaction.
+#[derive(Clone, Debug)]
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: TsigAlgorithm,
+    key: hmac::Key,
+}
+
+impl TsigKey {
+    pub fn new(name: &str, algorithm: TsigAlgorithm, secret: &[u8]) -> TsigKey {
+        TsigKey {
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            algorithm,
+            key: hmac::Key::new(algorithm.hmac_algorithm(), secret),
+        }
+    }
+
+    // Secrets are usually handed around base64 encoded, as in BIND's `key` statements.
+    pub fn from_base64(
+        name: &str,
+        algorithm: TsigAlgorithm,
+        secret: &str,
+    ) -> Result<TsigKey, String> {
+        let secret = BASE64
+            .decode(secret.as_bytes())
+            .map_err(|e| format!("Invalid TSIG secret for {}: {}", name, e))?;
+        Ok(TsigKey::new(name, algorithm, &secret))
+    }
+}
+
+/// The keys a server will accept signed requests from, looked up by name.
+#[derive(Clone, Debug, Default)]
+pub struct Keyring {
+    keys: Vec<TsigKey>,
+}
+
+impl Keyring {
+    pub fn new() -> Keyring {
+        Keyring::default()
+    }
+
+    // Adds a key, replacing any existing key with the same name.
+    pub fn add(&mut self, key: TsigKey) {
+        self.keys.retain(|existing| existing.name != key.name);
+        self.keys.push(key);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TsigKey> {
+        self.keys
+            .iter()
+            .find(|key| compare_names(&key.name, name) == Ordering::Equal)
+    }
+}
+
+// TSIG's own error codes, carried in the TSIG record of a NOTAUTH response (RFC 8945 section 3).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TsigError {
+    BADSIG = 16,
+    BADKEY = 17,
+    BADTIME = 18,
+}
+
+impl TsigError {
+    pub fn from_u16(value: u16) -> Option<TsigError> {
+        match value {
+            16 => Some(TsigError::BADSIG),
+            17 => Some(TsigError::BADKEY),
+            18 => Some(TsigError::BADTIME),
+            _ => None,
+        }
+    }
+}
+
+impl Display for TsigError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            TsigError::BADSIG => "MAC didn't match",
+            TsigError::BADKEY => "key not recognised",
+            TsigError::BADTIME => "signature time outside the allowed window",
+        };
+        write!(formatter, "TSIG {:?}: {}", self, description)
+    }
+}
+
+impl Error for TsigError {}
+
+/// Signs and verifies the messages of one exchange: a request, then one or more responses to it. Each MAC covers
+/// the one before it, so messages can't be dropped or reordered in a multi-message TCP stream (RFC 8945 section 5.3).
+#[derive(Clone, Debug)]
+pub struct TsigSession {
+    key: TsigKey,
+    fudge: u16,
+    previous_mac: Option<Vec<u8>>,
+    // Signed messages so far. Only the request and the first response sign every TSIG variable; later messages in
+    // the stream sign just the timers.
+    signed_messages: usize,
+    // Unsigned messages received since the last signed one, which the next MAC also covers.
+    unsigned: Vec<u8>,
+    unsigned_messages: usize,
+}
+
+impl TsigSession {
+    pub fn new(key: TsigKey) -> TsigSession {
+        TsigSession {
+            key,
+            fudge: DEFAULT_FUDGE,
+            previous_mac: None,
+            signed_messages: 0,
+            unsigned: Vec::new(),
+            unsigned_messages: 0,
+        }
+    }
+
+    pub fn key(&self) -> &TsigKey {
+        &self.key
+    }
+
+    fn sign(
+        &mut self,
+        message: &[u8],
+        original_id: u16,
+        time_signed: u64,
+        error: u16,
+        other_data: Vec<u8>,
+    ) -> DnsRecord {
+        let mut record = DnsRecord::TSIG {
+            domain: self.key.name.clone(),
+            algorithm: self.key.algorithm.name().to_string(),
+            time_signed,
+            fudge: self.fudge,
+            mac: Vec::new(),
+            original_id,
+            error,
+            other_data,
+        };
+        let signed = hmac::sign(&self.key.key, &self.signed_data(message, &record));
+        if let DnsRecord::TSIG { mac, .. } = &mut record {
+            *mac = signed.as_ref().to_vec();
+        }
+        self.accept(&record);
+        record
+    }
+
+    // Checks the TSIG record on a received message, given the message as it arrived.
+    fn verify(&mut self, bytes: &[u8], tsig: &DnsRecord, now: u64) -> Result<(), TsigError> {
+        let DnsRecord::TSIG {
+            domain,
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            ..
+        } = tsig
+        else {
+            return Err(TsigError::BADSIG);
+        };
+        if compare_names(domain, &self.key.name) != Ordering::Equal
+            || TsigAlgorithm::from_name(algorithm) != Some(self.key.algorithm)
+        {
+            return Err(TsigError::BADKEY);
+        }
+
+        let message = unsigned_message(bytes, tsig, *original_id).ok_or(TsigError::BADSIG)?;
+        hmac::verify(&self.key.key, &self.signed_data(&message, tsig), mac)
+            .map_err(|_| TsigError::BADSIG)?;
+        // The MAC is genuine, so even a BADTIME reply gets to build on it.
+        self.accept(tsig);
+
+        match now.abs_diff(*time_signed) > *fudge as u64 {
+            true => Err(TsigError::BADTIME),
+            false => Ok(()),
+        }
+    }
+
+    fn accept(&mut self, tsig: &DnsRecord) {
+        if let DnsRecord::TSIG { mac, .. } = tsig {
+            self.previous_mac = Some(mac.clone());
+        }
+        self.signed_messages += 1;
+        self.unsigned.clear();
+        self.unsigned_messages = 0;
+    }
+
+    // Everything the MAC covers (RFC 8945 sections 4.3 and 5.3.1).
+    fn signed_data(&self, message: &[u8], tsig: &DnsRecord) -> Vec<u8> {
+        let mut data = Vec::new();
+        if let Some(previous_mac) = &self.previous_mac {
+            data.extend_from_slice(&(previous_mac.len() as u16).to_be_bytes());
+            data.extend_from_slice(previous_mac);
+        }
+        data.extend_from_slice(&self.unsigned);
+        data.extend_from_slice(message);
+
+        let DnsRecord::TSIG {
+            domain,
+            algorithm,
+            time_signed,
+            fudge,
+            error,
+            other_data,
+            ..
+        } = tsig
+        else {
+            return data;
+        };
+        // Names in the MAC are in canonical form: lowercase and uncompressed.
+        if self.signed_messages < 2 {
+            data.extend(name_to_wire(domain));
+            data.extend_from_slice(&CLASS_ANY.to_be_bytes());
+            data.extend_from_slice(&0u32.to_be_bytes());
+            data.extend(name_to_wire(algorithm));
+        }
+        data.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+        data.extend_from_slice(&fudge.to_be_bytes());
+        if self.signed_messages < 2 {
+            data.extend_from_slice(&error.to_be_bytes());
+            data.extend_from_slice(&(other_data.len() as u16).to_be_bytes());
+            data.extend_from_slice(other_data);
+        }
+        data
+    }
+}
+
+/// What a server made of the TSIG record (or lack of one) on a request.
+#[derive(Debug)]
+pub enum TsigStatus {
+    Unsigned,
+    // Responses should be signed with this session.
+    Verified(TsigSession),
+    Rejected(TsigRejection),
+}
+
+/// A request whose signature didn't check out, which must be answered with a NOTAUTH error response.
+#[derive(Debug)]
+pub struct TsigRejection {
+    pub error: TsigError,
+    request_tsig: DnsRecord,
+    // Only BADTIME responses are signed, since only then is the request's MAC known to be genuine.
+    session: Option<TsigSession>,
+}
+
+impl TsigRejection {
+    pub fn response_to(self, query: &DnsPacket) -> Result<DnsPacket, Box<dyn Error>> {
+        self.response_at(query, now())
+    }
+
+    fn response_at(self, query: &DnsPacket, now: u64) -> Result<DnsPacket, Box<dyn Error>> {
+        let mut response = DnsPacketBuilder::response_to(query)
+            .rescode(ResultCode::NOTAUTH)
+            .build();
+        let DnsRecord::TSIG {
+            domain,
+            algorithm,
+            time_signed,
+            fudge,
+            ..
+        } = self.request_tsig
+        else {
+            return Err("Rejected request has no TSIG record.".into());
+        };
+
+        match self.session {
+            // The request's own time goes back so the client's time check passes, with ours in the other data.
+            Some(mut session) => {
+                let other_data = now.to_be_bytes()[2..].to_vec();
+                sign_at(
+                    &mut response,
+                    &mut session,
+                    time_signed,
+                    self.error as u16,
+                    other_data,
+                )?;
+            }
+            None => {
+                response.additional_records.push(DnsRecord::TSIG {
+                    domain,
+                    algorithm,
+                    time_signed: now,
+                    fudge,
+                    mac: Vec::new(),
+                    original_id: query.header.id,
+                    error: self.error as u16,
+                    other_data: Vec::new(),
+                });
+                response.header.num_additional = response.additional_records.len() as u16;
+            }
+        }
+        Ok(response)
+    }
+}
+
+pub(super) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// Appends a TSIG record signing the packet as it would currently be serialised, replacing any old one.
+pub(super) fn sign_at(
+    packet: &mut DnsPacket,
+    session: &mut TsigSession,
+    time_signed: u64,
+    error: u16,
+    other_data: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    packet
+        .additional_records
+        .retain(|record| !matches!(record, DnsRecord::TSIG { .. }));
+    let message = packet.to_bytes_with_limit(MAX_MESSAGE_SIZE)?;
+    let tsig = session.sign(&message, packet.header.id, time_signed, error, other_data);
+    packet.additional_records.push(tsig);
+    packet.header.num_additional = packet.additional_records.len() as u16;
+    Ok(())
+}
+
+// Parses and verifies the next message of an exchange. Once the first response has been verified, up to 99
+// messages in a row may be unsigned; their bytes are folded into the MAC of the next signed message.
+pub(super) fn verify_at(
+    bytes: &[u8],
+    session: &mut TsigSession,
+    now: u64,
+) -> Result<DnsPacket, Box<dyn Error>> {
+    let packet = DnsPacket::from_bytes(bytes)?;
+    let Some(tsig) = find_tsig(&packet)? else {
+        if session.signed_messages < 2 || session.unsigned_messages >= MAX_UNSIGNED_MESSAGES {
+            return Err("Expected a TSIG signed message.".into());
+        }
+        session.unsigned.extend_from_slice(bytes);
+        session.unsigned_messages += 1;
+        return Ok(packet);
+    };
+
+    // Key and signature errors come back unsigned, so all we can do is pass them on.
+    if let DnsRecord::TSIG { mac, error, .. } = tsig {
+        if mac.is_empty() && *error != 0 {
+            return Err(tsig_error(*error));
+        }
+    }
+    session.verify(bytes, tsig, now)?;
+    match tsig {
+        DnsRecord::TSIG { error, .. } if *error != 0 => Err(tsig_error(*error)),
+        _ => Ok(packet),
+    }
+}
+
+// Parses a request and checks its signature against the server's keyring.
+pub(super) fn verify_request_at(
+    bytes: &[u8],
+    keyring: &Keyring,
+    now: u64,
+) -> Result<(DnsPacket, TsigStatus), Box<dyn Error>> {
+    let packet = DnsPacket::from_bytes(bytes)?;
+    let Some(tsig) = find_tsig(&packet)? else {
+        return Ok((packet, TsigStatus::Unsigned));
+    };
+    let rejection = |error, session| {
+        TsigStatus::Rejected(TsigRejection {
+            error,
+            request_tsig: tsig.clone(),
+            session,
+        })
+    };
+
+    let key = match tsig {
+        DnsRecord::TSIG {
+            domain, algorithm, ..
+        } => keyring
+            .get(domain)
+            .filter(|key| TsigAlgorithm::from_name(algorithm) == Some(key.algorithm)),
+        _ => None,
+    };
+    let status = match key {
+        Some(key) => {
+            let mut session = TsigSession::new(key.clone());
+            match session.verify(bytes, tsig, now) {
+                Ok(()) => TsigStatus::Verified(session),
+                Err(TsigError::BADTIME) => rejection(TsigError::BADTIME, Some(session)),
+                Err(error) => rejection(error, None),
+            }
+        }
+        None => rejection(TsigError::BADKEY, None),
+    };
+    Ok((packet, status))
+}
+
+// The message's TSIG record, which must be the last record in the message if it has one.
+fn find_tsig(packet: &DnsPacket) -> Result<Option<&DnsRecord>, String> {
+    let is_tsig = |record: &&DnsRecord| matches!(record, DnsRecord::TSIG { .. });
+    let misplaced = packet
+        .answers
+        .iter()
+        .chain(&packet.authorities)
+        .any(|r| is_tsig(&r))
+        || packet
+            .additional_records
+            .iter()
+            .rev()
+            .skip(1)
+            .any(|r| is_tsig(&r));
+    if misplaced {
+        return Err(String::from(
+            "TSIG record must be the last record in a message.",
+        ));
+    }
+    Ok(packet.additional_records.last().filter(is_tsig))
+}
+
+// The message as it was before it was signed: without its TSIG record, and with the original ID. TSIG records
+// are never compressed, so the record is exactly as long as it is when we write it.
+fn unsigned_message(bytes: &[u8], tsig: &DnsRecord, original_id: u16) -> Option<Vec<u8>> {
+    let mut buffer = WrappedBuffer::with_size(MAX_MESSAGE_SIZE);
+    let tsig_length = tsig.write(&mut buffer).ok()?;
+    let mut message = bytes.get(..bytes.len().checked_sub(tsig_length)?)?.to_vec();
+    if message.len() < 12 {
+        return None;
+    }
+    let additional_count = u16::from_be_bytes([message[10], message[11]]).checked_sub(1)?;
+    message[0..2].copy_from_slice(&original_id.to_be_bytes());
+    message[10..12].copy_from_slice(&additional_count.to_be_bytes());
+    Some(message)
+}
+
+fn tsig_error(error: u16) -> Box<dyn Error> {
+    match TsigError::from_u16(error) {
+        Some(error) => error.into(),
+        None => format!("TSIG error {}.", error).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        sign_at, verify_at, verify_request_at, Keyring, TsigAlgorithm, TsigError, TsigKey,
+        TsigSession, TsigStatus,
+    };
+    use crate::parser::{DnsPacket, DnsPacketBuilder, DnsRecord, QueryType, ResultCode};
+    use data_encoding::HEXUPPER;
+    use std::{error::Error, io::Cursor, net::Ipv4Addr};
+
+    const NOW: u64 = 1700000000;
+
+    fn key(algorithm: TsigAlgorithm) -> TsigKey {
+        TsigKey::new("test-key.", algorithm, b"secret")
+    }
+
+    fn keyring() -> Keyring {
+        let mut keyring = Keyring::new();
+        keyring.add(key(TsigAlgorithm::HmacSha256));
+        keyring
+    }
+
+    fn query() -> DnsPacket {
+        DnsPacketBuilder::query()
+            .id(0x1234)
+            .question("example.com", QueryType::A)
+            .build()
+    }
+
+    fn signed_query(session: &mut TsigSession, time: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut query = query();
+        sign_at(&mut query, session, time, 0, Vec::new())?;
+        query.to_bytes()
+    }
+
+    fn response(query: &DnsPacket, last_octet: u8) -> DnsPacket {
+        DnsPacketBuilder::response_to(query)
+            .answer(DnsRecord::A {
+                domain: String::from("example.com"),
+                address: Ipv4Addr::new(192, 0, 2, last_octet),
+                ttl: 300,
+            })
+            .build()
+    }
+
+    fn rejection_error(error: Box<dyn Error>) -> Option<TsigError> {
+        error.downcast_ref::<TsigError>().copied()
+    }
+
+    #[test]
+    fn computes_mac_over_message_and_variables() -> Result<(), Box<dyn Error>> {
+        let mut query = query();
+        sign_at(
+            &mut query,
+            &mut TsigSession::new(key(TsigAlgorithm::HmacSha256)),
+            NOW,
+            0,
+            Vec::new(),
+        )?;
+
+        match query.additional_records.last() {
+            Some(DnsRecord::TSIG { mac, .. }) => assert_eq!(
+                HEXUPPER.encode(mac),
+                "10785159A4122417590EFE02E432255F88E5B62A86B02A1EF73F8D18DF44B49D"
+            ),
+            other => panic!("Expected a TSIG record, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn server_verifies_request_and_client_verifies_response() -> Result<(), Box<dyn Error>> {
+        for algorithm in [TsigAlgorithm::HmacSha256, TsigAlgorithm::HmacSha512] {
+            let mut keyring = Keyring::new();
+            keyring.add(key(algorithm));
+            let mut client = TsigSession::new(key(algorithm));
+            let bytes = signed_query(&mut client, NOW)?;
+
+            let (query, status) = verify_request_at(&bytes, &keyring, NOW + 10)?;
+            let TsigStatus::Verified(mut server) = status else {
+                panic!("Expected request to verify, got {:?}", status);
+            };
+            assert_eq!(query.questions[0].name, "example.com");
+
+            let mut response = response(&query, 1);
+            sign_at(&mut response, &mut server, NOW + 10, 0, Vec::new())?;
+            let received = verify_at(&response.to_bytes()?, &mut client, NOW + 20)?;
+            assert_eq!(received.answers, response.answers);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unsigned_requests_are_left_alone() -> Result<(), Box<dyn Error>> {
+        let bytes = query().to_bytes()?;
+        let (_, status) = verify_request_at(&bytes, &keyring(), NOW)?;
+        assert!(matches!(status, TsigStatus::Unsigned));
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_request_is_rejected_with_badsig() -> Result<(), Box<dyn Error>> {
+        let mut client = TsigSession::new(key(TsigAlgorithm::HmacSha256));
+        let mut bytes = signed_query(&mut client, NOW)?;
+        bytes[2] ^= 0x01; // Flip the RD bit.
+
+        let (query, status) = verify_request_at(&bytes, &keyring(), NOW)?;
+        let TsigStatus::Rejected(rejection) = status else {
+            panic!("Expected request to be rejected, got {:?}", status);
+        };
+        assert_eq!(rejection.error, TsigError::BADSIG);
+
+        // The error goes back unsigned, and the client reports it.
+        let response = rejection.response_at(&query, NOW)?;
+        assert_eq!(response.header.rescode, ResultCode::NOTAUTH);
+        assert!(matches!(
+            response.additional_records.last(),
+            Some(DnsRecord::TSIG { mac, error: 16, .. }) if mac.is_empty()
+        ));
+        let error = verify_at(&response.clone().to_bytes()?, &mut client, NOW).unwrap_err();
+        assert_eq!(rejection_error(error), Some(TsigError::BADSIG));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_key_is_rejected_with_badkey() -> Result<(), Box<dyn Error>> {
+        let other_key = TsigKey::new("other-key", TsigAlgorithm::HmacSha256, b"secret");
+        let bytes = signed_query(&mut TsigSession::new(other_key), NOW)?;
+        let (_, status) = verify_request_at(&bytes, &keyring(), NOW)?;
+        assert!(matches!(status, TsigStatus::Rejected(r) if r.error == TsigError::BADKEY));
+
+        // Same name, different algorithm.
+        let wrong_algorithm = key(TsigAlgorithm::HmacSha512);
+        let bytes = signed_query(&mut TsigSession::new(wrong_algorithm), NOW)?;
+        let (_, status) = verify_request_at(&bytes, &keyring(), NOW)?;
+        assert!(matches!(status, TsigStatus::Rejected(r) if r.error == TsigError::BADKEY));
+        Ok(())
+    }
+
+    #[test]
+    fn stale_request_gets_signed_badtime_response() -> Result<(), Box<dyn Error>> {
+        let mut client = TsigSession::new(key(TsigAlgorithm::HmacSha256));
+        let bytes = signed_query(&mut client, NOW - 301)?;
+
+        let (query, status) = verify_request_at(&bytes, &keyring(), NOW)?;
+        let TsigStatus::Rejected(rejection) = status else {
+            panic!("Expected request to be rejected, got {:?}", status);
+        };
+        assert_eq!(rejection.error, TsigError::BADTIME);
+
+        let mut response = rejection.response_at(&query, NOW)?;
+        match response.additional_records.last() {
+            Some(DnsRecord::TSIG {
+                time_signed,
+                mac,
+                other_data,
+                ..
+            }) => {
+                assert_eq!(*time_signed, NOW - 301);
+                assert!(!mac.is_empty());
+                assert_eq!(other_data, &NOW.to_be_bytes()[2..]);
+            }
+            other => panic!("Expected a TSIG record, got {:?}", other),
+        }
+        // The client can check the error really came from the server.
+        let error = verify_at(&response.to_bytes()?, &mut client, NOW - 301).unwrap_err();
+        assert_eq!(rejection_error(error), Some(TsigError::BADTIME));
+        Ok(())
+    }
+
+    #[test]
+    fn response_outside_fudge_is_badtime() -> Result<(), Box<dyn Error>> {
+        let mut client = TsigSession::new(key(TsigAlgorithm::HmacSha256));
+        let bytes = signed_query(&mut client, NOW)?;
+        let (query, status) = verify_request_at(&bytes, &keyring(), NOW)?;
+        let TsigStatus::Verified(mut server) = status else {
+            panic!("Expected request to verify, got {:?}", status);
+        };
+
+        let mut response = response(&query, 1);
+        sign_at(&mut response, &mut server, NOW, 0, Vec::new())?;
+        let error = verify_at(&response.to_bytes()?, &mut client, NOW + 1000).unwrap_err();
+        assert_eq!(rejection_error(error), Some(TsigError::BADTIME));
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_multi_message_stream() -> Result<(), Box<dyn Error>> {
+        let mut client = TsigSession::new(key(TsigAlgorithm::HmacSha256));
+        let bytes = signed_query(&mut client, NOW)?;
+        let (query, status) = verify_request_at(&bytes, &keyring(), NOW)?;
+        let TsigStatus::Verified(mut server) = status else {
+            panic!("Expected request to verify, got {:?}", status);
+        };
+
+        let mut messages = Vec::new();
+        for i in 0..3 {
+            let mut message = response(&query, i);
+            sign_at(&mut message, &mut server, NOW + i as u64, 0, Vec::new())?;
+            messages.push(message.to_bytes()?);
+        }
+        // An unsigned message covered by the next signed one.
+        let mut server_with_gap = server.clone();
+        let unsigned = response(&query, 3).to_bytes()?;
+        let mut last = response(&query, 4);
+        server_with_gap.unsigned.extend_from_slice(&unsigned);
+        sign_at(&mut last, &mut server_with_gap, NOW + 4, 0, Vec::new())?;
+        messages.push(unsigned);
+        messages.push(last.to_bytes()?);
+
+        let mut reordered = client.clone();
+        for message in &messages {
+            verify_at(message, &mut client, NOW)?;
+        }
+
+        // Messages which go missing or arrive out of order are caught.
+        verify_at(&messages[0], &mut reordered, NOW)?;
+        let error = verify_at(&messages[2], &mut reordered, NOW).unwrap_err();
+        assert_eq!(rejection_error(error), Some(TsigError::BADSIG));
+        Ok(())
+    }
+
+    #[test]
+    fn signed_stream_round_trips_through_framing() -> Result<(), Box<dyn Error>> {
+        let mut client = TsigSession::new(key(TsigAlgorithm::HmacSha512));
+        let mut query = query();
+        query.sign(&mut client)?;
+
+        let mut keyring = Keyring::new();
+        keyring.add(key(TsigAlgorithm::HmacSha512));
+        let (query, status) = DnsPacket::from_bytes_with_keyring(&query.to_bytes()?, &keyring)?;
+        let TsigStatus::Verified(mut server) = status else {
+            panic!("Expected request to verify, got {:?}", status);
+        };
+
+        let mut stream = Vec::new();
+        for i in 0..2 {
+            let mut message = response(&query, i);
+            message.sign(&mut server)?;
+            message.write_framed(&mut stream)?;
+        }
+
+        let mut reader = Cursor::new(stream);
+        let mut received = 0;
+        while let Some(message) = DnsPacket::read_signed_framed(&mut reader, &mut client)? {
+            assert_eq!(message.header.id, 0x1234);
+            received += 1;
+        }
+        assert_eq!(received, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn first_response_must_be_signed() -> Result<(), Box<dyn Error>> {
+        let mut client = TsigSession::new(key(TsigAlgorithm::HmacSha256));
+        signed_query(&mut client, NOW)?;
+        let unsigned = response(&query(), 1).to_bytes()?;
+        assert!(verify_at(&unsigned, &mut client, NOW).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tsig_record_must_come_last() -> Result<(), Box<dyn Error>> {
+        let mut client = TsigSession::new(key(TsigAlgorithm::HmacSha256));
+        let mut query = query();
+        sign_at(&mut query, &mut client, NOW, 0, Vec::new())?;
+        query.additional_records.push(DnsRecord::A {
+            domain: String::from("example.com"),
+            address: Ipv4Addr::LOCALHOST,
+            ttl: 0,
+        });
+        let bytes = query.to_bytes()?;
+        assert!(verify_request_at(&bytes, &keyring(), NOW).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn keyring_lookup_ignores_case_and_trailing_dot() -> Result<(), Box<dyn Error>> {
+        let mut keyring = Keyring::new();
+        keyring.add(TsigKey::from_base64(
+            "Transfer.Example.",
+            TsigAlgorithm::HmacSha512,
+            "c2VjcmV0",
+        )?);
+        assert!(keyring.get("transfer.example").is_some());
+        assert!(keyring.get("other.example").is_none());
+        assert_eq!(
+            TsigAlgorithm::from_name("HMAC-SHA256."),
+            Some(TsigAlgorithm::HmacSha256)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use super::{query_type::QueryType, wrapped_buffer::WrappedBuffer};
+
+// Type bitmaps (RFC 4034 section 4.1.2) list the record types present at a name, as used by NSEC and NSEC3.
+// Types are grouped into 256-type windows; each window is written as its number, the length of its bitmap and then
+// the bitmap itself, with the most significant bit of the first byte standing for the first type in the window.
+
+const MAX_WINDOW_LENGTH: usize = 32;
+
+pub fn read(buffer: &mut WrappedBuffer, length: usize) -> Result<Vec<QueryType>, String> {
+    let end = buffer.pos() + length;
+    let mut types = Vec::new();
+
+    while buffer.pos() < end {
+        let window = buffer.read_u8()? as u16;
+        let window_length = buffer.read_u8()? as usize;
+        if window_length == 0 || window_length > MAX_WINDOW_LENGTH {
+            return Err(format!(
+                "Invalid type bitmap window length ({}).",
+                window_length
+            ));
+        }
+        if buffer.pos() + window_length > end {
+            return Err("Type bitmap window runs past the end of the record data.".into());
+        }
+
+        for (i, byte) in buffer.read_bytes(window_length)?.into_iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(QueryType::from_u16(window << 8 | (i * 8 + bit) as u16));
+                }
+            }
+        }
+    }
+    Ok(types)
+}
+
+pub fn write(buffer: &mut WrappedBuffer, types: &[QueryType]) -> Result<(), String> {
+    let mut values: Vec<u16> = types.iter().map(|query_type| query_type.to_u16()).collect();
+    values.sort_unstable();
+    values.dedup();
+
+    for window_values in values.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let window = window_values[0] >> 8;
+        let mut bitmap = [0u8; MAX_WINDOW_LENGTH];
+        let mut window_length = 0;
+        for value in window_values {
+            let low = (value & 0xFF) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+            window_length = low / 8 + 1;
+        }
+
+        buffer.write_u8(window as u8)?;
+        buffer.write_u8(window_length as u8)?;
+        buffer.write_bytes(&bitmap[..window_length])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, write};
+    use crate::parser::{query_type::QueryType, wrapped_buffer::WrappedBuffer};
+    use std::error::Error;
+
+    // The example from RFC 4034 section 4.3: A MX RRSIG NSEC TYPE1234.
+    const EXAMPLE_TYPES: [QueryType; 5] = [
+        QueryType::A,
+        QueryType::UNKNOWN(15),
+        QueryType::RRSIG,
+        QueryType::NSEC,
+        QueryType::UNKNOWN(1234),
+    ];
+
+    fn example_bitmap() -> Vec<u8> {
+        let mut bytes = vec![0x00, 0x06, 0x40, 0x01, 0x00, 0x00, 0x00, 0x03, 0x04, 0x1b];
+        bytes.extend([0; 26]);
+        bytes.push(0x20);
+        bytes
+    }
+
+    #[test]
+    fn writes_rfc_example() -> Result<(), Box<dyn Error>> {
+        let mut buffer = WrappedBuffer::new();
+        // Order and duplicates in the input don't matter.
+        let mut types = EXAMPLE_TYPES.to_vec();
+        types.reverse();
+        types.push(QueryType::A);
+        write(&mut buffer, &types)?;
+
+        let expected = example_bitmap();
+        assert_eq!(buffer.pos(), expected.len());
+        assert_eq!(buffer.get_slice(0, expected.len())?, expected.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn reads_rfc_example() -> Result<(), Box<dyn Error>> {
+        let bytes = example_bitmap();
+        let mut buffer = WrappedBuffer::from_bytes(&bytes)?;
+        assert_eq!(read(&mut buffer, bytes.len())?, EXAMPLE_TYPES.to_vec());
+        assert_eq!(buffer.pos(), bytes.len());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_window_overrunning_record_data() -> Result<(), Box<dyn Error>> {
+        let bytes = example_bitmap();
+        let mut buffer = WrappedBuffer::from_bytes(&bytes)?;
+        assert!(read(&mut buffer, 12).is_err());
+
+        let mut buffer = WrappedBuffer::from_bytes(&[0x00, 0x00])?;
+        assert!(read(&mut buffer, 2).is_err());
+        Ok(())
+    }
+}
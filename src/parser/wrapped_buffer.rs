@@ -1,22 +1,44 @@
 use super::bitshifting::{get_lsb, get_msb, get_nth_octal};
 
+// Classic (non-EDNS) UDP message size limit.
 const BUFFER_SIZE: usize = 512;
+// The two-byte length prefix used on stream transports can't describe anything bigger than this.
+pub const MAX_MESSAGE_SIZE: usize = 65535;
 
 pub struct WrappedBuffer {
-    raw_buffer: [u8; BUFFER_SIZE],
+    raw_buffer: Vec<u8>,
     position: usize,
 }
 
 impl WrappedBuffer {
     pub fn new() -> WrappedBuffer {
+        WrappedBuffer::with_size(BUFFER_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> WrappedBuffer {
         WrappedBuffer {
-            raw_buffer: [0; BUFFER_SIZE],
+            raw_buffer: vec![0; size],
             position: 0,
         }
     }
 
+    // Wraps exactly one message, so reads past its end fail instead of returning zero padding.
+    pub fn from_bytes(bytes: &[u8]) -> Result<WrappedBuffer, String> {
+        if bytes.len() > MAX_MESSAGE_SIZE {
+            return Err(format!(
+                "Message too long ({} bytes, maximum is {}).",
+                bytes.len(),
+                MAX_MESSAGE_SIZE
+            ));
+        }
+        Ok(WrappedBuffer {
+            raw_buffer: bytes.to_vec(),
+            position: 0,
+        })
+    }
+
     pub fn read_u8(&mut self) -> Result<u8, String> {
-        if self.position >= BUFFER_SIZE {
+        if self.position >= self.raw_buffer.len() {
             return Err("End of buffer!".into());
         }
         let result: u8 = self.raw_buffer[self.position];
@@ -33,7 +55,7 @@ impl WrappedBuffer {
     }
 
     pub fn write_u8(&mut self, value: u8) -> Result<(), String> {
-        if self.position >= BUFFER_SIZE {
+        if self.position >= self.raw_buffer.len() {
             return Err("End of buffer!".into());
         }
         self.raw_buffer[self.position] = value;
@@ -54,8 +76,21 @@ impl WrappedBuffer {
         Ok(())
     }
 
+    pub fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, String> {
+        let bytes = self.get_slice(self.position, len)?.to_vec();
+        self.advance(len)?;
+        Ok(bytes)
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        for byte in bytes {
+            self.write_u8(*byte)?;
+        }
+        Ok(())
+    }
+
     pub fn get_slice(&self, start: usize, len: usize) -> Result<&[u8], String> {
-        if start + len >= BUFFER_SIZE {
+        if start + len > self.raw_buffer.len() {
             return Err("End of buffer!".into());
         }
         let end = start + len;
@@ -63,7 +98,7 @@ impl WrappedBuffer {
     }
 
     pub fn as_slice(&mut self) -> Result<&mut [u8], String> {
-        Ok(&mut self.raw_buffer[0..BUFFER_SIZE])
+        Ok(&mut self.raw_buffer[..])
     }
 
     pub fn advance(&mut self, num_steps: usize) -> Result<(), String> {
@@ -77,7 +112,7 @@ impl WrappedBuffer {
     }
 
     pub fn peek(&self, pos: usize) -> Result<u8, String> {
-        if pos >= BUFFER_SIZE {
+        if pos >= self.raw_buffer.len() {
             return Err("End of buffer!".into());
         }
         Ok(self.raw_buffer[pos])
@@ -86,11 +121,25 @@ impl WrappedBuffer {
     pub fn pos(&self) -> usize {
         self.position
     }
+
+    pub fn len(&self) -> usize {
+        self.raw_buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw_buffer.is_empty()
+    }
+}
+
+impl Default for WrappedBuffer {
+    fn default() -> Self {
+        WrappedBuffer::new()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{WrappedBuffer, BUFFER_SIZE};
+    use super::{WrappedBuffer, BUFFER_SIZE, MAX_MESSAGE_SIZE};
     use crate::parser::test_helpers::expect_error;
     use std::error::Error;
 
@@ -123,4 +172,42 @@ mod tests {
         )?;
         Ok(())
     }
+
+    #[test]
+    fn reading_fails_past_end_of_wrapped_bytes() -> Result<(), Box<dyn Error>> {
+        let mut buffer = WrappedBuffer::from_bytes(&[0xAB, 0xCD, 0xEF])?;
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.read_u16()?, 0xABCD);
+
+        expect_error(buffer.read_u16(), BUFFER_OVERRUN_MESSAGE)?;
+        expect_error(buffer.peek(3), BUFFER_OVERRUN_MESSAGE)?;
+        expect_error(buffer.get_slice(2, 2), BUFFER_OVERRUN_MESSAGE)?;
+        assert_eq!(buffer.get_slice(2, 1)?, &[0xEF]);
+        Ok(())
+    }
+
+    #[test]
+    fn writing_fails_past_end_of_sized_buffer() -> Result<(), Box<dyn Error>> {
+        let mut buffer = WrappedBuffer::with_size(3);
+        buffer.write_u16(0xABCD)?;
+        expect_error(buffer.write_u16(0xEF01), BUFFER_OVERRUN_MESSAGE)?;
+        Ok(())
+    }
+
+    #[test]
+    fn reads_and_writes_byte_runs() -> Result<(), Box<dyn Error>> {
+        let mut buffer = WrappedBuffer::with_size(4);
+        buffer.write_bytes(&[1, 2, 3])?;
+        expect_error(buffer.write_bytes(&[4, 5]), BUFFER_OVERRUN_MESSAGE)?;
+
+        buffer.seek(1)?;
+        assert_eq!(buffer.read_bytes(2)?, vec![2, 3]);
+        expect_error(buffer.read_bytes(2), BUFFER_OVERRUN_MESSAGE)?;
+        Ok(())
+    }
+
+    #[test]
+    fn wrapping_fails_for_oversized_message() {
+        assert!(WrappedBuffer::from_bytes(&[0; MAX_MESSAGE_SIZE + 1]).is_err());
+    }
 }
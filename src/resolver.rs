@@ -1,3 +1,20 @@
+mod cache;
 mod dns_resolver;
+mod https;
+mod json_api;
+mod recursive;
+mod selection;
+mod tcp;
+#[cfg(test)]
+mod test_servers;
+mod tls;
 mod wrapped_socket;
-pub use dns_resolver::DnsResolver;
+pub use cache::{Cache, CacheLimits};
+pub use dns_resolver::{
+    DnsResolver, DEFAULT_UPSTREAM, DEFAULT_UPSTREAM_RETRIES, DEFAULT_UPSTREAM_TIMEOUT,
+};
+pub use https::DNS_OVER_HTTPS_PORT;
+pub use recursive::{Recursor, ROOT_SERVERS};
+pub use selection::{OrderedFailover, Random, RoundRobin, SelectionStrategy, Strategy};
+pub use tcp::TcpLimits;
+pub use tls::DNS_OVER_TLS_PORT;
@@ -0,0 +1,745 @@
+use super::dns_resolver::lock;
+use crate::{
+    dnssec::RecordSource,
+    parser::{labels, DnsPacket, DnsPacketBuilder, DnsRecord, QueryType, RRSet, ResultCode},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    mem::size_of,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// How far to follow cached CNAMEs before asking upstream instead.
+const MAX_CNAME_CHAIN: usize = 8;
+const CLASS_IN: u16 = 1;
+
+/// Limits on what the cache holds, which the operator can change. TTLs are clamped to between `min_ttl` and
+/// `max_ttl` seconds (negative answers included), and once there are more than `max_entries` RRsets and negative
+/// answers or they take more than about `max_bytes`, the least recently used are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheLimits {
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+}
+
+impl Default for CacheLimits {
+    fn default() -> CacheLimits {
+        CacheLimits {
+            max_entries: 10_000,
+            max_bytes: 16 * 1024 * 1024,
+            min_ttl: 0,
+            max_ttl: 86_400,
+        }
+    }
+}
+
+// What an entry is for, by lowercased name and class. Negative answers (RFC 2308) are kept apart from RRsets:
+// NXDOMAIN says there's nothing at all at a name, while NODATA only rules out one type.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Records(String, QueryType, u16),
+    NoData(String, QueryType, u16),
+    NxDomain(String, u16),
+}
+
+impl Key {
+    fn records(name: &str, query_type: QueryType, class: u16) -> Key {
+        Key::Records(normalize(name), query_type, class)
+    }
+
+    fn no_data(name: &str, query_type: QueryType, class: u16) -> Key {
+        Key::NoData(normalize(name), query_type, class)
+    }
+
+    fn nx_domain(name: &str, class: u16) -> Key {
+        Key::NxDomain(normalize(name), class)
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Key::Records(name, ..) | Key::NoData(name, ..) | Key::NxDomain(name, ..) => name,
+        }
+    }
+}
+
+struct Entry {
+    // An RRset's records followed by the RRSIGs covering it (and, if it was expanded from a wildcard, the NSEC or
+    // NSEC3 records proving the name itself doesn't exist), or for a negative answer the SOA and any NSEC or NSEC3
+    // records proving it, with their signatures.
+    records: Vec<DnsRecord>,
+    expires: Instant,
+    last_used: u64,
+    size: usize,
+}
+
+/// RRsets and negative answers from upstream, kept until their TTLs run out. Every method takes the current time,
+/// so callers decide the clock.
+pub struct Cache {
+    limits: CacheLimits,
+    entries: HashMap<Key, Entry>,
+    // Keys by when they were last used, oldest first.
+    recency: BTreeMap<u64, Key>,
+    clock: u64,
+    bytes: usize,
+}
+
+impl Cache {
+    pub fn new(limits: CacheLimits) -> Cache {
+        Cache {
+            limits,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Stores every RRset in a response's answer section, along with its signatures and, for those expanded from a
+    // wildcard, the denial of existence records in the authority section. If the response says the
+    // name it ends up at (after any CNAMEs) doesn't exist or has no records of the type asked for, that's stored
+    // too, for as long as the SOA in the authority section allows.
+    pub fn insert_response(&mut self, response: &DnsPacket, now: Instant) {
+        let rescode = response.header.rescode;
+        if !matches!(rescode, ResultCode::NOERROR | ResultCode::NXDOMAIN)
+            || response.header.truncated_message
+        {
+            return;
+        }
+        let (signatures, records): (Vec<DnsRecord>, Vec<DnsRecord>) = response
+            .answers
+            .iter()
+            .filter(|record| !matches!(record, DnsRecord::OPT { .. }))
+            .cloned()
+            .partition(|record| matches!(record, DnsRecord::RRSIG { .. }));
+        for rrset in RRSet::group(records) {
+            let set_key = Key::records(rrset.name(), rrset.query_type(), rrset.class());
+            let mut covering: Vec<DnsRecord> = signatures
+                .iter()
+                .filter(|signature| match signature {
+                    DnsRecord::RRSIG { type_covered, .. } => {
+                        *type_covered == rrset.query_type()
+                            && Key::records(signature.domain(), *type_covered, rrset.class())
+                                == set_key
+                    }
+                    _ => false,
+                })
+                .cloned()
+                .collect();
+            // An RRSIG with fewer labels than its owner signed a wildcard (RFC 4035 section 5.3.4), and without the
+            // proof that the name itself doesn't exist, a validator can't accept the answer.
+            let owner_labels = labels(rrset.name()).count();
+            if covering.iter().any(|signature| {
+                matches!(signature, DnsRecord::RRSIG { labels: signed, .. } if (*signed as usize) < owner_labels)
+            }) {
+                covering.extend(denial_records(&response.authorities));
+            }
+            self.insert(rrset, covering, now);
+        }
+
+        let Some(question) = response.questions.first() else {
+            return;
+        };
+        if let Some(name) = unanswered(&question.name, question.query_type, &response.answers) {
+            let key = match rescode {
+                ResultCode::NXDOMAIN => Key::nx_domain(&name, CLASS_IN),
+                _ => Key::no_data(&name, question.query_type, CLASS_IN),
+            };
+            self.insert_negative(key, &response.authorities, now);
+        }
+    }
+
+    // Stores an RRset, replacing any already held for its name and type, unless its clamped TTL is zero or it's
+    // too big to ever fit. Any negative answer it contradicts is dropped.
+    pub fn insert(&mut self, rrset: RRSet, signatures: Vec<DnsRecord>, now: Instant) {
+        let (name, query_type, class) = (rrset.name(), rrset.query_type(), rrset.class());
+        self.remove(&Key::nx_domain(name, class));
+        self.remove(&Key::no_data(name, query_type, class));
+        let key = Key::records(name, query_type, class);
+        let ttl = rrset.ttl();
+        let mut records = rrset.into_records();
+        records.extend(signatures);
+        self.store(key, records, ttl, now);
+    }
+
+    // The records held for a name and type, with TTLs counting down the time they have left.
+    pub fn get(
+        &mut self,
+        name: &str,
+        query_type: QueryType,
+        class: u16,
+        now: Instant,
+    ) -> Option<Vec<DnsRecord>> {
+        self.lookup(Key::records(name, query_type, class), now)
+    }
+
+    // A response to a query made wholly from the cache, following cached CNAMEs to the records asked for or to a
+    // cached negative answer, which comes with its SOA in the authority section.
+    pub fn answer(&mut self, name: &str, query_type: QueryType, now: Instant) -> Option<DnsPacket> {
+        let response = DnsPacketBuilder::new()
+            .response(true)
+            .question(name, query_type);
+        let mut answers = Vec::new();
+        let mut proof = Vec::new();
+        let mut name = name.to_string();
+        for _ in 0..MAX_CNAME_CHAIN {
+            if let Some(records) = self.get(&name, query_type, CLASS_IN, now) {
+                let (records, denial) = split_proof(records, query_type);
+                answers.extend(records);
+                proof.extend(denial);
+                return Some(response.answers(answers).authorities(proof).build());
+            }
+            if let Some(authority) = self.lookup(Key::nx_domain(&name, CLASS_IN), now) {
+                return Some(
+                    response
+                        .rescode(ResultCode::NXDOMAIN)
+                        .answers(answers)
+                        .authorities(authority)
+                        .build(),
+                );
+            }
+            if let Some(authority) = self.lookup(Key::no_data(&name, query_type, CLASS_IN), now) {
+                return Some(response.answers(answers).authorities(authority).build());
+            }
+            if query_type == QueryType::CNAME {
+                return None;
+            }
+            let (cnames, denial) = split_proof(
+                self.get(&name, QueryType::CNAME, CLASS_IN, now)?,
+                QueryType::CNAME,
+            );
+            name = cnames.iter().find_map(|record| match record {
+                DnsRecord::CNAME { host, .. } => Some(host.clone()),
+                _ => None,
+            })?;
+            answers.extend(cnames);
+            proof.extend(denial);
+        }
+        None
+    }
+
+    // Negative answers last for the smaller of the SOA's TTL and its minimum field (RFC 2308 section 5), and
+    // without an SOA can't be cached at all.
+    fn insert_negative(&mut self, key: Key, authorities: &[DnsRecord], now: Instant) {
+        let Some(ttl) = authorities.iter().find_map(|record| match record {
+            DnsRecord::SOA { minimum, ttl, .. } => Some(*minimum.min(ttl)),
+            _ => None,
+        }) else {
+            return;
+        };
+        let proof = authorities
+            .iter()
+            .filter(|record| {
+                matches!(
+                    record.query_type(),
+                    QueryType::SOA | QueryType::NSEC | QueryType::NSEC3 | QueryType::RRSIG
+                )
+            })
+            .cloned()
+            .collect();
+        self.store(key, proof, ttl, now);
+    }
+
+    fn store(&mut self, key: Key, records: Vec<DnsRecord>, ttl: u32, now: Instant) {
+        let ttl = ttl.max(self.limits.min_ttl).min(self.limits.max_ttl);
+        let size = entry_size(&key, &records);
+        if ttl == 0 || self.limits.max_entries == 0 || size > self.limits.max_bytes {
+            return;
+        }
+
+        self.remove(&key);
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.bytes += size;
+        self.entries.insert(
+            key,
+            Entry {
+                records,
+                expires: now + Duration::from_secs(ttl as u64),
+                last_used: self.clock,
+                size,
+            },
+        );
+        self.enforce_limits(now);
+    }
+
+    fn lookup(&mut self, key: Key, now: Instant) -> Option<Vec<DnsRecord>> {
+        let entry = self.entries.get_mut(&key)?;
+        if entry.expires <= now {
+            self.remove(&key);
+            return None;
+        }
+
+        self.recency.remove(&entry.last_used);
+        self.clock += 1;
+        entry.last_used = self.clock;
+        self.recency.insert(self.clock, key);
+
+        // Rounded up, so a record read straight back has the TTL it came with.
+        let left = entry.expires - now;
+        let remaining = (left.as_secs() + u64::from(left.subsec_nanos() > 0)) as u32;
+        let mut records = entry.records.clone();
+        for record in records.iter_mut() {
+            record.set_ttl(remaining);
+        }
+        Some(records)
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.bytes -= entry.size;
+        }
+    }
+
+    // Drops whatever has expired, then the least recently used, until the cache is back within its limits.
+    fn enforce_limits(&mut self, now: Instant) {
+        if !self.over_limits() {
+            return;
+        }
+        let expired: Vec<Key> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+        while self.over_limits() {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.bytes -= entry.size;
+            }
+        }
+    }
+
+    fn over_limits(&self) -> bool {
+        self.entries.len() > self.limits.max_entries || self.bytes > self.limits.max_bytes
+    }
+}
+
+// The NSEC and NSEC3 records in an authority section, with their signatures.
+fn denial_records(authorities: &[DnsRecord]) -> Vec<DnsRecord> {
+    authorities
+        .iter()
+        .filter(|record| match record {
+            DnsRecord::RRSIG { type_covered, .. } => {
+                matches!(type_covered, QueryType::NSEC | QueryType::NSEC3)
+            }
+            _ => matches!(record.query_type(), QueryType::NSEC | QueryType::NSEC3),
+        })
+        .cloned()
+        .collect()
+}
+
+// Separates the records of an entry for `query_type` into the RRset with its signatures and the proof stored with
+// it for a wildcard expansion, which goes back in the authority section.
+fn split_proof(records: Vec<DnsRecord>, query_type: QueryType) -> (Vec<DnsRecord>, Vec<DnsRecord>) {
+    records.into_iter().partition(|record| match record {
+        DnsRecord::RRSIG { type_covered, .. } => *type_covered == query_type,
+        _ => record.query_type() == query_type,
+    })
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// The name a query for `name` ends up at by following the CNAMEs among `answers`, if there's nothing of the type
+// asked for there.
+fn unanswered(name: &str, query_type: QueryType, answers: &[DnsRecord]) -> Option<String> {
+    let mut name = normalize(name);
+    for _ in 0..=answers.len() {
+        let owned: Vec<&DnsRecord> = answers
+            .iter()
+            .filter(|record| normalize(record.domain()) == name)
+            .collect();
+        if owned.iter().any(|record| record.query_type() == query_type) {
+            return None;
+        }
+        match owned.iter().find_map(|record| match record {
+            DnsRecord::CNAME { host, .. } => Some(normalize(host)),
+            _ => None,
+        }) {
+            Some(host) => name = host,
+            None => return Some(name),
+        }
+    }
+    None
+}
+
+// A rough count of the memory an entry takes: the records themselves, plus what their names and data point to.
+fn entry_size(key: &Key, records: &[DnsRecord]) -> usize {
+    size_of::<Key>()
+        + size_of::<Entry>()
+        + key.name().len()
+        + records
+            .iter()
+            .map(|record| {
+                size_of::<DnsRecord>() + record.domain().len() + record.data_to_string().len()
+            })
+            .sum::<usize>()
+}
+
+// Answers queries from the cache where it can, and otherwise asks `source` and remembers what it says. The cache is
+// shared between workers, but only locked while it's read or written, never while `source` is asked.
+pub(super) struct Cached<'a> {
+    cache: &'a Mutex<Cache>,
+    source: &'a mut dyn RecordSource,
+}
+
+impl<'a> Cached<'a> {
+    pub(super) fn new(cache: &'a Mutex<Cache>, source: &'a mut dyn RecordSource) -> Cached<'a> {
+        Cached { cache, source }
+    }
+}
+
+impl RecordSource for Cached<'_> {
+    fn query(&mut self, name: &str, query_type: QueryType) -> Result<DnsPacket, Box<dyn Error>> {
+        let now = Instant::now();
+        if let Some(response) = lock(self.cache).answer(name, query_type, now) {
+            return Ok(response);
+        }
+        let response = self.source.query(name, query_type)?;
+        lock(self.cache).insert_response(&response, now);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lock, Cache, CacheLimits, Cached};
+    use crate::{
+        dnssec::{test_zones::example_hierarchy, RecordSource, Security, Validator},
+        parser::{DnsPacket, DnsPacketBuilder, DnsRecord, QueryType, RRSet, ResultCode},
+    };
+    use std::{
+        error::Error,
+        net::Ipv4Addr,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    const CLASS_IN: u16 = 1;
+
+    fn a_record(domain: &str, last_octet: u8, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            address: Ipv4Addr::new(192, 0, 2, last_octet),
+            ttl,
+        }
+    }
+
+    fn ttls(records: &[DnsRecord]) -> Vec<u32> {
+        records.iter().map(|record| record.ttl()).collect()
+    }
+
+    fn seconds(count: u64) -> Duration {
+        Duration::from_secs(count)
+    }
+
+    #[test]
+    fn counts_ttls_down_until_expiry() {
+        let mut cache = Cache::new(CacheLimits::default());
+        let now = Instant::now();
+        let mut rrset = RRSet::new(a_record("www.example.com", 1, 300));
+        rrset.add(a_record("www.example.com", 2, 300)).unwrap();
+        cache.insert(rrset, Vec::new(), now);
+
+        let records = cache.get(
+            "WWW.Example.com.",
+            QueryType::A,
+            CLASS_IN,
+            now + seconds(100),
+        );
+        assert_eq!(records.map(|records| ttls(&records)), Some(vec![200, 200]));
+        assert!(cache
+            .get("www.example.com", QueryType::AAAA, CLASS_IN, now)
+            .is_none());
+
+        assert!(cache
+            .get(
+                "www.example.com",
+                QueryType::A,
+                CLASS_IN,
+                now + seconds(300)
+            )
+            .is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn clamps_ttls() {
+        let mut cache = Cache::new(CacheLimits {
+            min_ttl: 60,
+            max_ttl: 3600,
+            ..CacheLimits::default()
+        });
+        let now = Instant::now();
+        cache.insert(RRSet::new(a_record("short.example", 1, 5)), Vec::new(), now);
+        cache.insert(
+            RRSet::new(a_record("long.example", 1, 86400)),
+            Vec::new(),
+            now,
+        );
+
+        let short = cache.get("short.example", QueryType::A, CLASS_IN, now);
+        assert_eq!(short.map(|records| ttls(&records)), Some(vec![60]));
+        let long = cache.get("long.example", QueryType::A, CLASS_IN, now);
+        assert_eq!(long.map(|records| ttls(&records)), Some(vec![3600]));
+
+        // Nothing is kept when the operator's maximum is zero.
+        let mut cache = Cache::new(CacheLimits {
+            max_ttl: 0,
+            ..CacheLimits::default()
+        });
+        cache.insert(RRSet::new(a_record("www.example", 1, 300)), Vec::new(), now);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = Cache::new(CacheLimits {
+            max_entries: 2,
+            ..CacheLimits::default()
+        });
+        let now = Instant::now();
+        cache.insert(RRSet::new(a_record("a.example", 1, 300)), Vec::new(), now);
+        cache.insert(RRSet::new(a_record("b.example", 2, 300)), Vec::new(), now);
+        assert!(cache
+            .get("a.example", QueryType::A, CLASS_IN, now)
+            .is_some());
+        cache.insert(RRSet::new(a_record("c.example", 3, 300)), Vec::new(), now);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache
+            .get("a.example", QueryType::A, CLASS_IN, now)
+            .is_some());
+        assert!(cache
+            .get("b.example", QueryType::A, CLASS_IN, now)
+            .is_none());
+        assert!(cache
+            .get("c.example", QueryType::A, CLASS_IN, now)
+            .is_some());
+    }
+
+    #[test]
+    fn keeps_within_memory_limit() {
+        let mut cache = Cache::new(CacheLimits {
+            max_bytes: 1024,
+            ..CacheLimits::default()
+        });
+        let now = Instant::now();
+        for index in 0..100 {
+            let name = format!("host{}.example", index);
+            cache.insert(RRSet::new(a_record(&name, index, 300)), Vec::new(), now);
+        }
+        assert!(!cache.is_empty());
+        assert!(cache.len() < 100);
+        assert!(cache.bytes <= 1024);
+        assert!(cache
+            .get("host99.example", QueryType::A, CLASS_IN, now)
+            .is_some());
+    }
+
+    // Answers each query with its packet, counting how often it's asked.
+    struct Counting {
+        packet: DnsPacket,
+        queries: usize,
+    }
+
+    impl RecordSource for Counting {
+        fn query(&mut self, _: &str, _: QueryType) -> Result<DnsPacket, Box<dyn Error>> {
+            self.queries += 1;
+            Ok(self.packet.clone())
+        }
+    }
+
+    #[test]
+    fn answers_repeated_queries_from_cache() -> Result<(), Box<dyn Error>> {
+        let mut source = Counting {
+            packet: DnsPacketBuilder::new()
+                .response(true)
+                .question("alias.example", QueryType::A)
+                .answer(DnsRecord::CNAME {
+                    domain: String::from("alias.example"),
+                    host: String::from("www.example"),
+                    ttl: 600,
+                })
+                .answer(a_record("www.example", 1, 300))
+                .build(),
+            queries: 0,
+        };
+        let cache = Mutex::new(Cache::new(CacheLimits::default()));
+
+        let first = Cached::new(&cache, &mut source).query("alias.example", QueryType::A)?;
+        let second = Cached::new(&cache, &mut source).query("alias.example", QueryType::A)?;
+        // The target on its own is in there too.
+        let target = Cached::new(&cache, &mut source).query("www.example", QueryType::A)?;
+
+        assert_eq!(source.queries, 1);
+        assert_eq!(second.answers, first.answers);
+        assert_eq!(target.answers, vec![a_record("www.example", 1, 300)]);
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_the_proof_for_wildcard_answers() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        let mut validator = Validator::new(hierarchy.trust_anchor());
+        let cache = Mutex::new(Cache::new(CacheLimits::default()));
+
+        for _ in 0..2 {
+            let mut cached = Cached::new(&cache, &mut hierarchy);
+            let response = cached.query("foo.secure.example", QueryType::A)?;
+            assert_eq!(validator.validate(&mut cached, &response), Security::Secure);
+        }
+        let cached = lock(&cache).answer("foo.secure.example", QueryType::A, Instant::now());
+        let cached = cached.expect("The expansion should be cached.");
+        assert!(cached
+            .authorities
+            .iter()
+            .any(|record| record.query_type() == QueryType::NSEC));
+        assert!(cached
+            .answers
+            .iter()
+            .all(|record| record.domain() == "foo.secure.example"));
+        Ok(())
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: String::from("example"),
+            primary_name_server: String::from("ns.example"),
+            mailbox: String::from("hostmaster.example"),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum,
+            ttl,
+        }
+    }
+
+    fn negative(name: &str, query_type: QueryType, rescode: ResultCode) -> DnsPacketBuilder {
+        DnsPacketBuilder::new()
+            .response(true)
+            .question(name, query_type)
+            .rescode(rescode)
+    }
+
+    #[test]
+    fn caches_nxdomain_for_every_type() {
+        let mut cache = Cache::new(CacheLimits::default());
+        let now = Instant::now();
+        let response = negative("missing.example", QueryType::A, ResultCode::NXDOMAIN)
+            .authority(soa(3600, 300))
+            .build();
+        cache.insert_response(&response, now);
+
+        let cached = cache.answer("Missing.example", QueryType::AAAA, now + seconds(100));
+        let cached = cached.expect("NXDOMAIN should be cached.");
+        assert_eq!(cached.header.rescode, ResultCode::NXDOMAIN);
+        assert!(cached.answers.is_empty());
+        // The SOA's minimum is lower than its TTL, so that's how long the answer lasts.
+        assert_eq!(ttls(&cached.authorities), vec![200]);
+        assert!(matches!(cached.authorities[0], DnsRecord::SOA { .. }));
+
+        assert!(cache
+            .answer("missing.example", QueryType::A, now + seconds(300))
+            .is_none());
+    }
+
+    #[test]
+    fn caches_nodata_for_one_type() {
+        let mut cache = Cache::new(CacheLimits::default());
+        let now = Instant::now();
+        let response = negative("www.example", QueryType::AAAA, ResultCode::NOERROR)
+            .authority(soa(60, 300))
+            .build();
+        cache.insert_response(&response, now);
+
+        let cached = cache.answer("www.example", QueryType::AAAA, now);
+        let cached = cached.expect("NODATA should be cached.");
+        assert_eq!(cached.header.rescode, ResultCode::NOERROR);
+        assert!(cached.answers.is_empty());
+        assert_eq!(ttls(&cached.authorities), vec![60]);
+        assert!(cache.answer("www.example", QueryType::A, now).is_none());
+
+        // Records turning up later replace it.
+        cache.insert(RRSet::new(a_record("www.example", 1, 300)), Vec::new(), now);
+        cache.insert(
+            RRSet::new(DnsRecord::AAAA {
+                domain: String::from("www.example"),
+                address: "2001:db8::1".parse().unwrap(),
+                ttl: 300,
+            }),
+            Vec::new(),
+            now,
+        );
+        let cached = cache.answer("www.example", QueryType::AAAA, now);
+        assert_eq!(cached.map(|cached| cached.answers.len()), Some(1));
+    }
+
+    #[test]
+    fn caches_negative_answers_at_end_of_cname_chain() {
+        let mut cache = Cache::new(CacheLimits::default());
+        let now = Instant::now();
+        let alias = DnsRecord::CNAME {
+            domain: String::from("alias.example"),
+            host: String::from("gone.example"),
+            ttl: 600,
+        };
+        let response = negative("alias.example", QueryType::A, ResultCode::NXDOMAIN)
+            .answer(alias.clone())
+            .authority(soa(3600, 300))
+            .build();
+        cache.insert_response(&response, now);
+
+        let cached = cache.answer("alias.example", QueryType::A, now);
+        let cached = cached.expect("The chain should be cached.");
+        assert_eq!(cached.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(cached.answers, vec![alias]);
+        // It's the target that doesn't exist, not the alias.
+        assert!(cache
+            .answer("alias.example", QueryType::CNAME, now)
+            .is_some());
+        assert_eq!(
+            cache
+                .answer("gone.example", QueryType::AAAA, now)
+                .map(|cached| cached.header.rescode),
+            Some(ResultCode::NXDOMAIN)
+        );
+    }
+
+    #[test]
+    fn needs_soa_to_cache_negative_answers() {
+        let mut cache = Cache::new(CacheLimits::default());
+        let now = Instant::now();
+        cache.insert_response(
+            &negative("missing.example", QueryType::A, ResultCode::NXDOMAIN).build(),
+            now,
+        );
+        cache.insert_response(
+            &negative("missing.example", QueryType::A, ResultCode::SERVFAIL)
+                .authority(soa(3600, 300))
+                .build(),
+            now,
+        );
+        assert!(cache.is_empty());
+    }
+}
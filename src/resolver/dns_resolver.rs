@@ -1,91 +1,647 @@
-use super::wrapped_socket::WrappedSocket;
-use crate::parser::{DnsPacket, DnsQuestion, QueryType, ResultCode};
-use std::{error::Error, net::Ipv4Addr};
+use super::{
+    cache::{Cache, CacheLimits, Cached},
+    https,
+    recursive::Recursor,
+    selection::{OrderedFailover, SelectionStrategy},
+    tcp::{self, Connections, Handler, TcpLimits, MAX_MESSAGE_SIZE},
+    tls,
+    wrapped_socket::{WrappedSocket, MAX_DATAGRAM_SIZE},
+};
+use crate::{
+    dnssec::{RecordSource, Security, TrustAnchor, Validator},
+    parser::{
+        compare_names, DnsPacket, DnsPacketBuilder, DnsRecord, QueryType, ResultCode,
+        DEFAULT_EDNS_PAYLOAD_SIZE,
+    },
+};
+use rustls::ServerConfig;
+use std::{
+    cmp::Ordering,
+    error::Error,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket},
+    num::NonZeroUsize,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
 
-const REMOTE_SOCKET_PORT: u16 = 53;
-const LOCAL_SOCKET_PORT: u16 = 4000;
-const REMOTE_SERVER_IP: Ipv4Addr = Ipv4Addr::new(8, 8, 8, 8);
+const UDP_MESSAGE_SIZE: usize = 512;
+// How long to wait for an upstream server on the first round of attempts, and how many more rounds to make.
+pub const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+pub const DEFAULT_UPSTREAM_RETRIES: u32 = 2;
+// Extended DNS Error info-codes (RFC 8914 section 4).
+const EDE_DNSSEC_BOGUS: u16 = 6;
+const EDE_NO_REACHABLE_AUTHORITY: u16 = 22;
+// Workers spend most of their time waiting on other servers, so there are several for each core.
+const WORKERS_PER_CORE: usize = 4;
+// Used until `with_upstreams` says otherwise.
+pub const DEFAULT_UPSTREAM: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53);
+// How far to follow CNAMEs through an answer before deciding they go round in a loop.
+const MAX_CNAME_CHAIN: usize = 16;
+// Tries at finding a port free for both UDP and TCP, when the OS is left to choose it.
+const BIND_ATTEMPTS: usize = 10;
+
+// How a query arrived, which decides how large its response can be.
+#[derive(Clone, Copy)]
+enum Transport {
+    Udp,
+    Stream,
+}
 
 pub struct DnsResolver {
-    socket: WrappedSocket,
+    socket: UdpSocket,
+    tcp_listener: TcpListener,
+    tcp_limits: TcpLimits,
+    tls: Option<(TcpListener, Arc<ServerConfig>)>,
+    https: Option<(TcpListener, Arc<ServerConfig>)>,
+    workers: usize,
+    upstreams: Vec<SocketAddr>,
+    strategy: Mutex<Box<dyn SelectionStrategy>>,
+    timeout: Duration,
+    retries: u32,
+    connections: Arc<Connections>,
+    recursor: Option<Recursor>,
+    cache: Mutex<Cache>,
+    trust_anchor: Option<TrustAnchor>,
+    // Validators not in use by a TCP query, kept so what they've learnt about zones isn't lost.
+    validators: Mutex<Vec<Validator>>,
 }
 
 impl DnsResolver {
     pub fn new(port: u16) -> Result<DnsResolver, Box<dyn Error>> {
-        let socket = WrappedSocket::new(port, (Ipv4Addr::UNSPECIFIED, port).into());
-        Ok(DnsResolver { socket })
+        let (socket, tcp_listener) =
+            bind(port).map_err(|error| format!("Failed to bind port {}: {}", port, error))?;
+        let cores = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        Ok(DnsResolver {
+            socket,
+            tcp_listener,
+            tcp_limits: TcpLimits::default(),
+            tls: None,
+            https: None,
+            workers: cores * WORKERS_PER_CORE,
+            upstreams: vec![DEFAULT_UPSTREAM],
+            strategy: Mutex::new(Box::new(OrderedFailover)),
+            timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            retries: DEFAULT_UPSTREAM_RETRIES,
+            connections: Arc::default(),
+            recursor: None,
+            cache: Mutex::new(Cache::new(CacheLimits::default())),
+            trust_anchor: None,
+            validators: Mutex::new(Vec::new()),
+        })
+    }
+
+    // How many queries can be worked on at once, each on its own thread.
+    pub fn with_workers(mut self, workers: usize) -> DnsResolver {
+        self.workers = workers.max(1);
+        self
+    }
+
+    // Bounds the connections the TCP listener takes on, and what each can ask of it.
+    pub fn with_tcp_limits(mut self, limits: TcpLimits) -> DnsResolver {
+        self.tcp_limits = limits;
+        self
+    }
+
+    // Also answers queries over TLS on `port`, presenting the certificate chain and private key in the PEM files at
+    // `certificate` and `key`. Connections are held to the same limits as plain TCP.
+    pub fn with_tls(
+        mut self,
+        port: u16,
+        certificate: &Path,
+        key: &Path,
+    ) -> Result<DnsResolver, Box<dyn Error>> {
+        let config = tls::server_config(certificate, key, tls::DOT_PROTOCOLS)?;
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
+            .map_err(|error| format!("Failed to bind port {} for TLS: {}", port, error))?;
+        self.tls = Some((listener, config));
+        Ok(self)
+    }
+
+    // Also answers queries over HTTPS on `port`, at /dns-query, with the same certificate and key files as `with_tls`.
+    pub fn with_https(
+        mut self,
+        port: u16,
+        certificate: &Path,
+        key: &Path,
+    ) -> Result<DnsResolver, Box<dyn Error>> {
+        let config = tls::server_config(certificate, key, https::HTTPS_PROTOCOLS)?;
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
+            .map_err(|error| format!("Failed to bind port {} for HTTPS: {}", port, error))?;
+        self.https = Some((listener, config));
+        Ok(self)
+    }
+
+    // Forwards queries to `upstreams`, IPv4 or IPv6, trying them in the order `strategy` picks for each query.
+    pub fn with_upstreams(
+        mut self,
+        upstreams: Vec<SocketAddr>,
+        strategy: Box<dyn SelectionStrategy>,
+    ) -> Result<DnsResolver, Box<dyn Error>> {
+        if upstreams.is_empty() {
+            return Err("At least one upstream server is needed.".into());
+        }
+        self.upstreams = upstreams;
+        self.strategy = Mutex::new(strategy);
+        Ok(self)
+    }
+
+    // Waits up to `timeout` for each upstream in turn, then goes round them all again up to `retries` more times,
+    // doubling the wait each round.
+    pub fn with_retries(mut self, timeout: Duration, retries: u32) -> DnsResolver {
+        self.timeout = timeout;
+        self.retries = retries;
+        self
+    }
+
+    // Resolves queries itself with `recursor`, starting from its root hints, instead of forwarding them upstream.
+    pub fn with_recursion(mut self, mut recursor: Recursor) -> DnsResolver {
+        recursor.dnssec = self.trust_anchor.is_some();
+        self.recursor = Some(recursor);
+        self
+    }
+
+    // Replaces the cache with an empty one bounded by `limits`.
+    pub fn with_cache(mut self, limits: CacheLimits) -> DnsResolver {
+        self.cache = Mutex::new(Cache::new(limits));
+        self
+    }
+
+    // Validates upstream answers against a chain of trust starting at `trust_anchor`.
+    pub fn with_dnssec_validation(mut self, trust_anchor: TrustAnchor) -> DnsResolver {
+        self.trust_anchor = Some(trust_anchor);
+        if let Some(recursor) = self.recursor.as_mut() {
+            recursor.dnssec = true;
+        }
+        self
+    }
+
+    // The address queries are received on, over both UDP and TCP. It has an OS-chosen port if the resolver was made
+    // with port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
     }
 
-    pub fn start_listening(&mut self) -> Result<(), Box<dyn Error>> {
+    // Where queries over TLS are received, if they are.
+    pub fn tls_local_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.tls.as_ref().map(|(listener, _)| listener.local_addr())
+    }
+
+    // Where queries over HTTPS are received, if they are.
+    pub fn https_local_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.https
+            .as_ref()
+            .map(|(listener, _)| listener.local_addr())
+    }
+
+    // Answers UDP queries on every worker thread, TCP and TLS connections on threads of their own, and HTTPS requests
+    // on a thread running an async runtime. This only returns if a worker stops, which takes a panic.
+    pub fn start_listening(self) -> Result<(), Box<dyn Error>> {
+        let resolver = Arc::new(self);
+        let mut workers = Vec::new();
+        for _ in 0..resolver.workers {
+            let socket = resolver.socket.try_clone()?;
+            let resolver = Arc::clone(&resolver);
+            workers.push(thread::spawn(move || resolver.serve(&socket)));
+        }
+        let handler: Handler = {
+            let resolver = Arc::clone(&resolver);
+            Arc::new(move |query, client| resolver.answer_stream_query(query, client))
+        };
+        let limits = resolver.tcp_limits;
+        {
+            let (resolver, handler) = (Arc::clone(&resolver), Arc::clone(&handler));
+            workers.push(thread::spawn(move || {
+                tcp::listen(
+                    &resolver.tcp_listener,
+                    limits.max_connections,
+                    move |stream| tcp::serve_stream(stream, limits, Arc::clone(&handler)),
+                )
+            }));
+        }
+        if let Some((listener, config)) = &resolver.https {
+            let (listener, config) = (listener.try_clone()?, Arc::clone(config));
+            let handler = Arc::clone(&handler);
+            workers.push(thread::spawn(move || {
+                if let Err(error) = https::listen(listener, config, limits, handler) {
+                    eprintln!("Stopped answering queries over HTTPS: {}", error);
+                }
+            }));
+        }
+        if resolver.tls.is_some() {
+            workers.push(thread::spawn(move || {
+                if let Some((listener, config)) = &resolver.tls {
+                    tcp::listen(listener, limits.max_connections, {
+                        let config = Arc::clone(config);
+                        move |stream| tls::serve(stream, &config, limits, Arc::clone(&handler))
+                    })
+                }
+            }));
+        }
+        for worker in workers {
+            worker.join().map_err(|_| "A worker thread panicked.")?;
+        }
+        Ok(())
+    }
+
+    // One worker's loop. Each has its own validator, and a query that goes wrong is logged and answered with
+    // SERVFAIL, leaving the worker free for the next.
+    fn serve(&self, socket: &UdpSocket) {
+        let mut validator = self.trust_anchor.clone().map(Validator::new);
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
         loop {
-            self.answer_query()?;
+            let (size, client) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(error) => {
+                    eprintln!("Failed to receive a query: {}", error);
+                    continue;
+                }
+            };
+            let query = &buffer[..size];
+            let Some(response) = self.respond(query, client, Transport::Udp, validator.as_mut())
+            else {
+                continue;
+            };
+            if let Err(error) = socket.send_to(&response, client) {
+                eprintln!("Failed to respond to {}: {}", client, error);
+            }
         }
     }
 
-    fn answer_query(&mut self) -> Result<(), Box<dyn Error>> {
-        let mut query = DnsPacket::read(&mut self.socket)?;
-        let mut response = DnsPacket::new();
-        response.header.id = query.header.id;
-        response.header.num_questions = 1;
-        response.header.recursion_desired = true;
-        response.header.recursion_available = true;
-        response.header.response = true;
+    // A TCP query, answered with a validator from the pool so several from one connection can be worked on at once.
+    fn answer_stream_query(&self, query: &[u8], client: SocketAddr) -> Option<Vec<u8>> {
+        let mut validator = self.trust_anchor.clone().map(|trust_anchor| {
+            lock(&self.validators)
+                .pop()
+                .unwrap_or_else(|| Validator::new(trust_anchor))
+        });
+        let response = self.respond(query, client, Transport::Stream, validator.as_mut());
+        if let Some(validator) = validator {
+            lock(&self.validators).push(validator);
+        }
+        response
+    }
+
+    // The response to send, which is SERVFAIL if anything goes wrong, or nothing if even that can't be made.
+    fn respond(
+        &self,
+        query: &[u8],
+        client: SocketAddr,
+        transport: Transport,
+        validator: Option<&mut Validator>,
+    ) -> Option<Vec<u8>> {
+        self.answer_query(query, transport, validator)
+            .or_else(|error| {
+                eprintln!("Failed to answer a query from {}: {}", client, error);
+                server_failure(query)
+            })
+            .map_err(|error| eprintln!("Failed to respond to {}: {}", client, error))
+            .ok()
+    }
 
-        match query.questions.pop() {
-            Some(question) => match self.query(question.name.as_str(), question.query_type) {
-                Ok(downstream_result) => {
-                    response.questions.push(question);
-                    response.header.rescode = downstream_result.header.rescode;
+    fn answer_query(
+        &self,
+        query: &[u8],
+        transport: Transport,
+        validator: Option<&mut Validator>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let query = DnsPacket::from_bytes(query)?;
+        let mut upstream = self.upstream();
+        let mut recursor = self.recursor.clone();
+        let source: &mut dyn RecordSource = match recursor.as_mut() {
+            Some(recursor) => recursor,
+            None => &mut upstream,
+        };
+        let mut response = resolve(&query, &mut Cached::new(&self.cache, source), validator);
 
-                    for answer in downstream_result.answers {
-                        response.answers.push(answer);
-                    }
-                    for record in downstream_result.authorities {
-                        response.authorities.push(record);
+        // Fit the response into what a UDP client can receive, leaving it to retry over TCP if need be.
+        let max_size = match transport {
+            Transport::Udp => udp_payload_size(&query),
+            Transport::Stream => MAX_MESSAGE_SIZE,
+        };
+        response.to_bytes_with_limit(max_size)
+    }
+
+    fn upstream(&self) -> Upstream {
+        Upstream {
+            servers: lock(&self.strategy).order(&self.upstreams),
+            dnssec: self.trust_anchor.is_some(),
+            timeout: self.timeout,
+            retries: self.retries,
+            connections: Arc::clone(&self.connections),
+        }
+    }
+}
+
+// Binds UDP and TCP to the same port. With port 0, UDP gets whatever the OS picks and TCP tries for the same one.
+fn bind(port: u16) -> io::Result<(UdpSocket, TcpListener)> {
+    for _ in 0..BIND_ATTEMPTS {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        match TcpListener::bind((Ipv4Addr::UNSPECIFIED, socket.local_addr()?.port())) {
+            Ok(tcp_listener) => return Ok((socket, tcp_listener)),
+            Err(error) if port != 0 => return Err(error),
+            Err(_) => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "No port was free for both UDP and TCP.",
+    ))
+}
+
+// A worker which panics while holding a lock leaves the data as it was, which is still fine to use.
+pub(super) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// SERVFAIL echoing as much of the query as can be read: its question if it parses, otherwise just its ID.
+fn server_failure(query: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let response = match DnsPacket::from_bytes(query) {
+        Ok(query) => DnsPacketBuilder::response_to(&query),
+        Err(_) if query.len() >= 2 => DnsPacketBuilder::new()
+            .response(true)
+            .id(u16::from_be_bytes([query[0], query[1]])),
+        Err(error) => return Err(error),
+    };
+    response.rescode(ResultCode::SERVFAIL).build().to_bytes()
+}
+
+// The upstream recursive servers, in the order to try them. When we validate, they're asked for DNSSEC records and
+// told not to validate themselves.
+struct Upstream {
+    servers: Vec<SocketAddr>,
+    dnssec: bool,
+    timeout: Duration,
+    retries: u32,
+    connections: Arc<Connections>,
+}
+
+impl Upstream {
+    fn query_server(
+        &self,
+        server: SocketAddr,
+        name: &str,
+        query_type: QueryType,
+        timeout: Duration,
+    ) -> Result<DnsPacket, Box<dyn Error>> {
+        // A random ID from a random port, as well as a socket of its own for each of the queries several workers
+        // may have in flight at once.
+        let mut builder = DnsPacketBuilder::query().question(name, query_type);
+        if self.dnssec {
+            builder = builder.dnssec_ok(true).checking_disabled(true);
+        }
+        let mut query = builder.build();
+        let response =
+            WrappedSocket::new(server)?.exchange(&mut query, Instant::now() + timeout)?;
+        if !response.header.truncated_message {
+            return Ok(response);
+        }
+        // Too big for UDP, so ask again over TCP where the whole answer fits (RFC 7766 section 5).
+        self.connections
+            .exchange(server, &mut query, Instant::now() + timeout)
+    }
+}
+
+impl RecordSource for Upstream {
+    fn query(&mut self, name: &str, query_type: QueryType) -> Result<DnsPacket, Box<dyn Error>> {
+        // An upstream that can't answer is as good as one that doesn't, so its failure is worth getting past too.
+        let mut failures = Vec::new();
+        let mut timeout = self.timeout;
+        for _ in 0..=self.retries {
+            for server in &self.servers {
+                match self.query_server(*server, name, query_type, timeout) {
+                    Ok(packet)
+                        if !matches!(
+                            packet.header.rescode,
+                            ResultCode::SERVFAIL | ResultCode::NOTIMP | ResultCode::REFUSED
+                        ) =>
+                    {
+                        return Ok(packet)
                     }
-                    for record in downstream_result.additional_records {
-                        response.additional_records.push(record);
+                    Ok(packet) => {
+                        failures.push(format!("{} answered {:?}.", server, packet.header.rescode))
                     }
+                    Err(error) => failures.push(error.to_string()),
                 }
-                // Got an error response from downstream.
-                _ => response.header.rescode = ResultCode::SERVFAIL,
-            },
-            // Incoming query packet is malformed (contains no question records).
-            _ => response.header.rescode = ResultCode::FORMERR,
-        };
-        response.write(&mut self.socket)?;
-        Ok(())
+            }
+            timeout = timeout.saturating_mul(2);
+        }
+        match failures.last() {
+            Some(last) => Err(format!(
+                "All {} attempts to resolve {} {:?} failed; the last: {}",
+                failures.len(),
+                name,
+                query_type,
+                last
+            )
+            .into()),
+            None => Err("No upstream servers configured.".into()),
+        }
     }
+}
 
-    fn query(&mut self, name: &str, query_type: QueryType) -> Result<DnsPacket, Box<dyn Error>> {
-        let remote_address = (REMOTE_SERVER_IP, REMOTE_SOCKET_PORT);
-        let mut socket = WrappedSocket::new(LOCAL_SOCKET_PORT, remote_address.into());
-
-        let mut packet = DnsPacket::new();
-        packet.header.id = 451;
-        packet.header.num_questions = 1;
-        packet.header.recursion_desired = true;
-        packet.questions.push(DnsQuestion {
-            name: name.to_string(),
-            query_type,
+// Answers a client's query from upstream (or by recursion), validating the answer first if there's a validator.
+fn resolve(
+    query: &DnsPacket,
+    upstream: &mut dyn RecordSource,
+    validator: Option<&mut Validator>,
+) -> DnsPacket {
+    let response = DnsPacketBuilder::response_to(query).recursion_available(true);
+    // Incoming query packet is malformed (contains no question records).
+    let Some(question) = query.questions.first() else {
+        return response.rescode(ResultCode::FORMERR).build();
+    };
+    let upstream_result = match upstream.query(question.name.as_str(), question.query_type) {
+        Ok(upstream_result) => upstream_result,
+        // Nobody upstream (or, recursing, no authoritative server) gave an answer we could use.
+        Err(error) => {
+            let reason = error.to_string();
+            return failure(query, response, EDE_NO_REACHABLE_AUTHORITY, &reason);
+        }
+    };
+
+    // A client setting CD will do its own validation, so gets the data whatever state it's in.
+    let security = match validator {
+        Some(validator) if !query.header.checking_disabled => {
+            validator.validate(upstream, &upstream_result)
+        }
+        _ => Security::Insecure,
+    };
+    if let Security::Bogus(reason) = &security {
+        return failure(query, response, EDE_DNSSEC_BOGUS, reason);
+    }
+
+    // AD only goes to clients which said they understand it, either with DO or by setting AD (RFC 6840 section 5.7).
+    let dnssec_ok = query.dnssec_ok();
+    let authentic_data = security == Security::Secure && (dnssec_ok || query.header.authentic_data);
+    let wanted = |record: &DnsRecord| {
+        !matches!(record, DnsRecord::OPT { .. })
+            && (dnssec_ok
+                || record.query_type() == question.query_type
+                || !matches!(
+                    record.query_type(),
+                    QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3
+                ))
+    };
+    let mut answers: Vec<DnsRecord> = upstream_result.answers.into_iter().filter(wanted).collect();
+    let rescode = match synthesize_cnames(&question.name, &mut answers) {
+        Ok(()) => upstream_result.header.rescode,
+        Err(rescode) => rescode,
+    };
+    let mut response = response
+        .rescode(rescode)
+        .authentic_data(authentic_data)
+        .answers(answers)
+        .authorities(upstream_result.authorities.into_iter().filter(wanted))
+        .additional_records(
+            upstream_result
+                .additional_records
+                .into_iter()
+                .filter(wanted),
+        );
+    // The upstream's OPT record describes its connection to us, so clients using EDNS get our own.
+    if query.edns_payload_size().is_some() {
+        response = response
+            .edns(DEFAULT_EDNS_PAYLOAD_SIZE)
+            .dnssec_ok(dnssec_ok);
+    }
+    response.build()
+}
+
+// SERVFAIL which, for clients using EDNS, carries an Extended DNS Error saying why.
+fn failure(
+    query: &DnsPacket,
+    response: DnsPacketBuilder,
+    info_code: u16,
+    reason: &str,
+) -> DnsPacket {
+    let mut response = response.rescode(ResultCode::SERVFAIL);
+    if query.edns_payload_size().is_some() {
+        response = response
+            .edns(DEFAULT_EDNS_PAYLOAD_SIZE)
+            .dnssec_ok(query.dnssec_ok())
+            .extended_error(info_code, reason);
+    }
+    response.build()
+}
+
+// Makes sure every DNAME on the way from `name` to the answer is followed by the CNAME it implies, for clients which
+// don't understand DNAME (RFC 6672 section 3.4). A CNAME that doesn't match its DNAME is replaced, and a DNAME that
+// would make the name too long gives YXDOMAIN.
+fn synthesize_cnames(name: &str, answers: &mut Vec<DnsRecord>) -> Result<(), ResultCode> {
+    let mut name = name.to_string();
+    for _ in 0..MAX_CNAME_CHAIN {
+        let dname = answers.iter().find_map(|record| {
+            let synthesized = record.synthesize_cname(&name).transpose()?;
+            Some((record.clone(), synthesized))
         });
+        let next = match dname {
+            Some((dname, synthesized)) => {
+                let cname = match synthesized {
+                    Ok(cname) => cname,
+                    Err(rescode) => {
+                        answers.retain(|record| !is_cname_at(record, &name));
+                        return Err(rescode);
+                    }
+                };
+                let DnsRecord::CNAME { host, .. } = &cname else {
+                    return Ok(());
+                };
+                let upstream_agrees = answers.iter().any(|record| match record {
+                    DnsRecord::CNAME {
+                        domain,
+                        host: upstream_host,
+                        ..
+                    } => {
+                        compare_names(domain, &name) == Ordering::Equal
+                            && compare_names(upstream_host, host) == Ordering::Equal
+                    }
+                    _ => false,
+                });
+                if !upstream_agrees {
+                    answers.retain(|record| !is_cname_at(record, &name));
+                    let position = answers.iter().position(|record| *record == dname);
+                    answers.insert(position.map_or(0, |position| position + 1), cname.clone());
+                }
+                host.clone()
+            }
+            None => {
+                let upstream_cname = answers.iter().find_map(|record| match record {
+                    DnsRecord::CNAME { domain, host, .. }
+                        if compare_names(domain, &name) == Ordering::Equal =>
+                    {
+                        Some(host.clone())
+                    }
+                    _ => None,
+                });
+                match upstream_cname {
+                    Some(host) => host,
+                    None => return Ok(()),
+                }
+            }
+        };
+        name = next;
+    }
+    Ok(())
+}
 
-        packet.write(&mut socket)?;
-        Ok(DnsPacket::read(&mut socket)?)
+// A CNAME owned by `name`, or a signature over one.
+fn is_cname_at(record: &DnsRecord, name: &str) -> bool {
+    match record {
+        DnsRecord::CNAME { domain, .. } => compare_names(domain, name) == Ordering::Equal,
+        DnsRecord::RRSIG {
+            domain,
+            type_covered: QueryType::CNAME,
+            ..
+        } => compare_names(domain, name) == Ordering::Equal,
+        _ => false,
     }
 }
 
+// The smaller of what the client says it can receive and what we're prepared to send (RFC 6891 section 6.2.5), so a
+// spoofed query can't have us send large fragmented datagrams to its victim.
+fn udp_payload_size(query: &DnsPacket) -> usize {
+    query.edns_payload_size().map_or(UDP_MESSAGE_SIZE, |size| {
+        (size as usize).clamp(UDP_MESSAGE_SIZE, DEFAULT_EDNS_PAYLOAD_SIZE as usize)
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use super::DnsResolver;
-    use crate::parser::{DnsPacket, DnsRecord, QueryType};
-    use std::error::Error;
+    use super::{resolve, udp_payload_size, DnsResolver, Upstream};
+    use crate::{
+        dnssec::{
+            test_zones::{a_record, example_hierarchy},
+            RecordSource, Validator,
+        },
+        parser::{
+            DnsPacket, DnsPacketBuilder, DnsRecord, QueryType, ResultCode,
+            DEFAULT_EDNS_PAYLOAD_SIZE,
+        },
+        resolver::{
+            tcp::{write_message, FrameReader, TcpLimits},
+            test_servers::{self, test_hierarchy, Behaviour, StandIns, Truncating},
+            Recursor,
+        },
+    };
+    use std::{
+        error::Error,
+        io::Write,
+        net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket},
+        sync::{atomic::Ordering, Arc},
+        thread,
+        time::{Duration, Instant},
+    };
 
     #[test]
     fn can_answer_dns_query() -> Result<(), Box<dyn Error>> {
-        let mut resolver = DnsResolver::new(8000)?;
+        let resolver = DnsResolver::new(8000)?;
         let expected_domain = "google.com";
-        let response: DnsPacket = resolver.query(expected_domain, QueryType::A)?;
+        let response: DnsPacket = resolver.upstream().query(expected_domain, QueryType::A)?;
         let answers = response.answers;
 
         match answers.first() {
@@ -100,4 +656,523 @@ mod tests {
 
         Ok(())
     }
+
+    // Answers every query with the same packet.
+    struct Canned(DnsPacket);
+
+    impl RecordSource for Canned {
+        fn query(&mut self, _: &str, _: QueryType) -> Result<DnsPacket, Box<dyn Error>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn dname(domain: &str, target: &str) -> DnsRecord {
+        DnsRecord::DNAME {
+            domain: domain.to_string(),
+            target: target.to_string(),
+            ttl: 300,
+        }
+    }
+
+    fn cname(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::CNAME {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 300,
+        }
+    }
+
+    fn has_signatures(packet: &DnsPacket) -> bool {
+        packet
+            .answers
+            .iter()
+            .any(|record| matches!(record, DnsRecord::RRSIG { .. }))
+    }
+
+    #[test]
+    fn validated_answers_have_ad_bit_for_dnssec_clients() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        let mut validator = Validator::new(hierarchy.trust_anchor());
+        let query = DnsPacketBuilder::query()
+            .question("www.example", QueryType::A)
+            .dnssec_ok(true)
+            .build();
+
+        let response = resolve(&query, &mut hierarchy, Some(&mut validator));
+
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(response.header.authentic_data);
+        assert!(response.dnssec_ok());
+        assert!(has_signatures(&response));
+        Ok(())
+    }
+
+    #[test]
+    fn dnssec_records_are_stripped_for_other_clients() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        let mut validator = Validator::new(hierarchy.trust_anchor());
+        let query = DnsPacketBuilder::query()
+            .question("www.example", QueryType::A)
+            .build();
+
+        let response = resolve(&query, &mut hierarchy, Some(&mut validator));
+
+        assert!(!response.header.authentic_data);
+        assert!(!has_signatures(&response));
+        assert_eq!(response.answers.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn insecure_answers_lack_ad_bit() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        let mut validator = Validator::new(hierarchy.trust_anchor());
+        let query = DnsPacketBuilder::query()
+            .question("www.insecure.example", QueryType::A)
+            .dnssec_ok(true)
+            .build();
+
+        let response = resolve(&query, &mut hierarchy, Some(&mut validator));
+
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(!response.header.authentic_data);
+        assert_eq!(response.answers.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn bogus_answers_are_servfail() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        hierarchy.tampered.push(String::from("www.example"));
+        let mut validator = Validator::new(hierarchy.trust_anchor());
+        let query = DnsPacketBuilder::query()
+            .question("www.example", QueryType::A)
+            .build();
+
+        let response = resolve(&query, &mut hierarchy, Some(&mut validator));
+
+        assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.extended_error(), None);
+
+        // Clients using EDNS are told why.
+        let query = DnsPacketBuilder::query()
+            .question("www.example", QueryType::A)
+            .edns(DEFAULT_EDNS_PAYLOAD_SIZE)
+            .build();
+        let response = resolve(&query, &mut hierarchy, Some(&mut validator));
+        assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+        assert!(matches!(response.extended_error(), Some((6, _))));
+        Ok(())
+    }
+
+    #[test]
+    fn checking_disabled_skips_validation() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        hierarchy.tampered.push(String::from("www.example"));
+        let mut validator = Validator::new(hierarchy.trust_anchor());
+        let query = DnsPacketBuilder::query()
+            .question("www.example", QueryType::A)
+            .checking_disabled(true)
+            .dnssec_ok(true)
+            .build();
+
+        let response = resolve(&query, &mut hierarchy, Some(&mut validator));
+
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(response.header.checking_disabled);
+        assert!(!response.header.authentic_data);
+        assert!(has_signatures(&response));
+        Ok(())
+    }
+
+    #[test]
+    fn validated_dname_answers_keep_synthesized_cname() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        let mut validator = Validator::new(hierarchy.trust_anchor());
+        let query = DnsPacketBuilder::query()
+            .question("www.old.example", QueryType::A)
+            .dnssec_ok(true)
+            .build();
+
+        let response = resolve(&query, &mut hierarchy, Some(&mut validator));
+
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(response.header.authentic_data);
+        assert!(response
+            .answers
+            .contains(&cname("www.old.example", "www.secure.example")));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_or_wrong_cnames_are_synthesized_from_dname() -> Result<(), Box<dyn Error>> {
+        let address = a_record("www.new.example", 1);
+        let query = DnsPacketBuilder::query()
+            .question("www.old.example", QueryType::A)
+            .build();
+        for upstream_cname in [None, Some(cname("www.old.example", "elsewhere.example"))] {
+            let upstream_answer = DnsPacketBuilder::response_to(&query)
+                .answer(dname("old.example", "new.example"))
+                .answers(upstream_cname)
+                .answer(address.clone())
+                .build();
+
+            let response = resolve(&query, &mut Canned(upstream_answer), None);
+
+            assert_eq!(response.header.rescode, ResultCode::NOERROR);
+            assert_eq!(
+                response.answers,
+                vec![
+                    dname("old.example", "new.example"),
+                    cname("www.old.example", "www.new.example"),
+                    address.clone()
+                ]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn overlong_dname_substitution_is_yxdomain() -> Result<(), Box<dyn Error>> {
+        // 253 bytes on the wire, which becomes 256 once "old" turns into "longer".
+        let name = format!(
+            "{0}.{0}.{0}.{1}.old.example",
+            "a".repeat(63),
+            "b".repeat(47)
+        );
+        let query = DnsPacketBuilder::query()
+            .question(&name, QueryType::A)
+            .build();
+        let upstream_answer = DnsPacketBuilder::response_to(&query)
+            .answer(dname("old.example", "longer.example"))
+            .build();
+
+        let response = resolve(&query, &mut Canned(upstream_answer), None);
+
+        assert_eq!(response.header.rescode, ResultCode::YXDOMAIN);
+        assert_eq!(
+            response.answers,
+            vec![dname("old.example", "longer.example")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn answers_pass_through_without_validator() -> Result<(), Box<dyn Error>> {
+        let mut hierarchy = example_hierarchy()?;
+        hierarchy.tampered.push(String::from("www.example"));
+        let query = DnsPacketBuilder::query()
+            .question("www.example", QueryType::A)
+            .build();
+
+        let response = resolve(&query, &mut hierarchy, None);
+
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn answers_clients_by_recursion() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        let mut recursor = Recursor::new(vec![servers.root()])?
+            .with_port(servers.port())
+            .with_timeout(Duration::from_millis(200));
+        let query = DnsPacketBuilder::query()
+            .question("alias.example", QueryType::A)
+            .build();
+
+        let response = resolve(&query, &mut recursor, None);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(response.header.recursion_available);
+        assert_eq!(response.answers.len(), 2);
+
+        // Lookups that can't finish are a server failure for the client.
+        let query = DnsPacketBuilder::query()
+            .question("www.loop", QueryType::A)
+            .build();
+        let response = resolve(&query, &mut recursor, None);
+        assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+        Ok(())
+    }
+
+    // Starts a resolver recursing through `servers` on its own threads, returning where to send it queries.
+    fn start(
+        servers: &StandIns,
+        workers: usize,
+        timeout: Duration,
+    ) -> Result<SocketAddr, Box<dyn Error>> {
+        run(recursing(servers, timeout)?.with_workers(workers))
+    }
+
+    fn recursing(servers: &StandIns, timeout: Duration) -> Result<DnsResolver, Box<dyn Error>> {
+        let recursor = Recursor::new(vec![servers.root()])?
+            .with_port(servers.port())
+            .with_timeout(timeout);
+        Ok(DnsResolver::new(0)?.with_recursion(recursor))
+    }
+
+    fn run(resolver: DnsResolver) -> Result<SocketAddr, Box<dyn Error>> {
+        let port = resolver.local_addr()?.port();
+        thread::spawn(move || {
+            resolver
+                .start_listening()
+                .map_err(|error| error.to_string())
+        });
+        Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+    }
+
+    fn client() -> Result<UdpSocket, Box<dyn Error>> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        Ok(socket)
+    }
+
+    fn send_query(
+        socket: &UdpSocket,
+        server: SocketAddr,
+        name: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut query = DnsPacketBuilder::query()
+            .question(name, QueryType::A)
+            .build();
+        socket.send_to(&query.to_bytes()?, server)?;
+        Ok(())
+    }
+
+    fn receive(socket: &UdpSocket) -> Result<DnsPacket, Box<dyn Error>> {
+        let mut buffer = [0; 4096];
+        let size = socket.recv(&mut buffer)?;
+        DnsPacket::from_bytes(&buffer[..size])
+    }
+
+    fn tcp_client(server: SocketAddr) -> Result<TcpStream, Box<dyn Error>> {
+        let stream = TcpStream::connect(server)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        Ok(stream)
+    }
+
+    fn receive_tcp(frames: &mut FrameReader<&TcpStream>) -> Result<DnsPacket, Box<dyn Error>> {
+        let message = frames.next_message()?.ok_or("Connection closed.")?;
+        DnsPacket::from_bytes(&message)
+    }
+
+    // Whether the server closes `stream` within `within`.
+    fn closed_within(stream: &TcpStream, within: Duration) -> Result<bool, Box<dyn Error>> {
+        stream.set_read_timeout(Some(within))?;
+        Ok(matches!(FrameReader::new(stream).next_message(), Ok(None)))
+    }
+
+    #[test]
+    fn keeps_serving_after_malformed_queries() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        let server = start(&servers, 1, Duration::from_millis(200))?;
+        let socket = client()?;
+
+        // A header promising a question that isn't there.
+        socket.send_to(&[0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0], server)?;
+        let response = receive(&socket)?;
+        assert_eq!(response.header.id, 0x1234);
+        assert!(response.header.response);
+        assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+
+        send_query(&socket, server, "www.example")?;
+        let response = receive(&socket)?;
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn slow_queries_dont_hold_up_others() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        // One of flaky's servers never answers, so looking up www.flaky waits out the timeout.
+        let server = start(&servers, 2, Duration::from_secs(2))?;
+        let (slow, fast) = (client()?, client()?);
+
+        send_query(&slow, server, "www.flaky")?;
+        thread::sleep(Duration::from_millis(50));
+        let started = Instant::now();
+        send_query(&fast, server, "www.example")?;
+        let response = receive(&fast)?;
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let response = receive(&slow)?;
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        Ok(())
+    }
+
+    // A silent server at 127.0.0.2, one that refuses at 127.0.0.3 and one serving example at 127.0.0.4.
+    fn upstreams() -> Result<(StandIns, [SocketAddr; 3]), Box<dyn Error>> {
+        let example = vec![
+            test_servers::soa("example"),
+            test_servers::a("www.example", Ipv4Addr::new(192, 0, 2, 1)),
+        ];
+        let servers = StandIns::start(vec![
+            (Ipv4Addr::new(127, 0, 0, 2), Behaviour::Silent),
+            (Ipv4Addr::new(127, 0, 0, 3), Behaviour::Refuses),
+            (
+                Ipv4Addr::new(127, 0, 0, 4),
+                Behaviour::Authoritative(example),
+            ),
+        ])?;
+        let address = |last_octet| SocketAddr::from(([127, 0, 0, last_octet], servers.port()));
+        let addresses = [address(2), address(3), address(4)];
+        Ok((servers, addresses))
+    }
+
+    fn upstream(servers: Vec<SocketAddr>, timeout: Duration, retries: u32) -> Upstream {
+        Upstream {
+            servers,
+            dnssec: false,
+            timeout,
+            retries,
+            connections: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn fails_over_to_next_upstream() -> Result<(), Box<dyn Error>> {
+        let (servers, [silent, refuses, works]) = upstreams()?;
+        let mut upstream = upstream(vec![silent, refuses, works], Duration::from_millis(100), 0);
+
+        let started = Instant::now();
+        let response = upstream.query("www.example", QueryType::A)?;
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(servers.queries(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn retries_with_backoff_before_giving_up() -> Result<(), Box<dyn Error>> {
+        let (servers, [silent, refuses, _]) = upstreams()?;
+        let mut upstream = upstream(vec![silent, refuses], Duration::from_millis(50), 2);
+
+        let started = Instant::now();
+        let error = upstream
+            .query("www.example", QueryType::A)
+            .err()
+            .ok_or("Expected the upstreams to fail.")?;
+        // Waits of 50, 100 and 200ms on the silent server, and a refusal each round.
+        assert!(started.elapsed() >= Duration::from_millis(350));
+        assert_eq!(servers.queries(), 6);
+        assert!(error.to_string().contains("All 6 attempts"));
+        Ok(())
+    }
+
+    #[test]
+    fn explains_failure_when_upstreams_are_exhausted() -> Result<(), Box<dyn Error>> {
+        let (_servers, [silent, refuses, _]) = upstreams()?;
+        let mut upstream = upstream(vec![silent, refuses], Duration::from_millis(50), 0);
+        let query = DnsPacketBuilder::query()
+            .question("www.example", QueryType::A)
+            .edns(DEFAULT_EDNS_PAYLOAD_SIZE)
+            .build();
+
+        let response = resolve(&query, &mut upstream, None);
+
+        assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+        let (info_code, reason) = response
+            .extended_error()
+            .ok_or("Expected an extended error.")?;
+        assert_eq!(info_code, 22);
+        assert!(reason.contains(&format!("{} answered REFUSED", refuses)));
+        Ok(())
+    }
+
+    #[test]
+    fn answers_pipelined_tcp_queries_as_they_are_ready() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        // One of flaky's servers never answers, so www.flaky takes longer than www.example.
+        let server = start(&servers, 1, Duration::from_millis(500))?;
+        let stream = tcp_client(server)?;
+
+        let mut both = Vec::new();
+        let mut ids = Vec::new();
+        for name in ["www.flaky", "www.example"] {
+            let mut query = DnsPacketBuilder::query()
+                .question(name, QueryType::A)
+                .build();
+            ids.push(query.header.id);
+            write_message(&mut both, &query.to_bytes()?)?;
+        }
+        (&stream).write_all(&both)?;
+
+        let mut frames = FrameReader::new(&stream);
+        let first = receive_tcp(&mut frames)?;
+        let second = receive_tcp(&mut frames)?;
+        assert_eq!(first.header.id, ids[1]);
+        assert_eq!(first.questions[0].name, "www.example");
+        assert_eq!(second.header.id, ids[0]);
+        for response in [first, second] {
+            assert_eq!(response.header.rescode, ResultCode::NOERROR);
+            assert_eq!(response.answers.len(), 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn limits_tcp_connections_and_closes_idle_ones() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        let limits = TcpLimits {
+            max_connections: 1,
+            idle_timeout: Duration::from_millis(300),
+            ..TcpLimits::default()
+        };
+        let server = run(recursing(&servers, Duration::from_millis(200))?.with_tcp_limits(limits))?;
+
+        let stream = tcp_client(server)?;
+        let over_limit = tcp_client(server)?;
+        assert!(closed_within(&over_limit, Duration::from_millis(200))?);
+
+        let mut query = DnsPacketBuilder::query()
+            .question("www.example", QueryType::A)
+            .build();
+        write_message(&mut &stream, &query.to_bytes()?)?;
+        let response = receive_tcp(&mut FrameReader::new(&stream))?;
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+
+        assert!(closed_within(&stream, Duration::from_secs(2))?);
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_to_tcp_for_truncated_answers() -> Result<(), Box<dyn Error>> {
+        let mut records = vec![test_servers::soa("example")];
+        records
+            .extend((1..=40).map(|i| test_servers::a("big.example", Ipv4Addr::new(192, 0, 2, i))));
+        let server = Truncating::start(records)?;
+        let mut upstream = upstream(vec![server.address], Duration::from_millis(500), 0);
+
+        for _ in 0..2 {
+            let response = upstream.query("big.example", QueryType::A)?;
+            assert_eq!(response.header.rescode, ResultCode::NOERROR);
+            assert!(!response.header.truncated_message);
+            assert_eq!(response.answers.len(), 40);
+        }
+        assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn sends_no_more_over_udp_than_either_side_allows() {
+        let query = |size: Option<u16>| {
+            let builder = DnsPacketBuilder::query().question("www.example", QueryType::A);
+            match size {
+                Some(size) => builder.edns(size).build(),
+                None => builder.build(),
+            }
+        };
+        assert_eq!(udp_payload_size(&query(None)), 512);
+        assert_eq!(udp_payload_size(&query(Some(100))), 512);
+        assert_eq!(udp_payload_size(&query(Some(1000))), 1000);
+        assert_eq!(
+            udp_payload_size(&query(Some(u16::MAX))),
+            DEFAULT_EDNS_PAYLOAD_SIZE as usize
+        );
+    }
 }
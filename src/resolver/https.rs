@@ -0,0 +1,604 @@
+// DNS over HTTPS (RFC 8484): DNS messages carried in requests to /dns-query, over HTTP/1.1 or HTTP/2 as the client
+// chooses during the TLS handshake. Requests to /resolve get the same answers as JSON.
+
+use super::{
+    json_api::{self, DNS_JSON},
+    tcp::{Handler, TcpLimits, MAX_MESSAGE_SIZE},
+};
+use crate::parser::{DnsPacket, DnsRecord, ResultCode};
+use data_encoding::BASE64URL_NOPAD;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto::Builder,
+};
+use rustls::ServerConfig;
+use std::{
+    convert::Infallible,
+    io,
+    net::{SocketAddr, TcpListener},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    runtime,
+    sync::Semaphore,
+    task, time,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+pub const DNS_OVER_HTTPS_PORT: u16 = 443;
+const READ_CHUNK_SIZE: usize = 4096;
+// What to offer during the handshake, in order of preference.
+pub(super) const HTTPS_PROTOCOLS: &[&[u8]] = &[b"h2", b"http/1.1"];
+const DNS_QUERY_PATH: &str = "/dns-query";
+const RESOLVE_PATH: &str = "/resolve";
+const DNS_MESSAGE: &str = "application/dns-message";
+
+// Serves requests until the listener fails, on an async runtime of its own. Queries are answered by `handler` on the
+// runtime's blocking threads, since resolving them waits on other servers. Connections count against the same
+// limits as over TCP, and those that go quiet for `idle_timeout` are closed.
+pub(super) fn listen(
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    limits: TcpLimits,
+    handler: Handler,
+) -> io::Result<()> {
+    let runtime = runtime::Builder::new_multi_thread()
+        .enable_io()
+        .enable_time()
+        .build()?;
+    runtime.block_on(accept(listener, config, limits, handler))
+}
+
+async fn accept(
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    limits: TcpLimits,
+    handler: Handler,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let acceptor = TlsAcceptor::from(config);
+    let connections = Arc::new(Semaphore::new(limits.max_connections));
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                eprintln!("Failed to accept an HTTPS connection: {}", error);
+                continue;
+            }
+        };
+        // Beyond the limit, connections are closed straight away as they are over TCP.
+        let Ok(permit) = Arc::clone(&connections).try_acquire_owned() else {
+            continue;
+        };
+        let (acceptor, handler) = (acceptor.clone(), Arc::clone(&handler));
+        tokio::spawn(async move {
+            let _permit = permit;
+            let stream = match time::timeout(limits.idle_timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(error)) => {
+                    eprintln!("TLS handshake with {} failed: {}", client, error);
+                    return;
+                }
+                Err(_) => return,
+            };
+            // Neither protocol's timers run until the client starts talking, so it has to do that in time too.
+            let mut stream = stream;
+            let mut first = vec![0; READ_CHUNK_SIZE];
+            match time::timeout(limits.idle_timeout, stream.read(&mut first)).await {
+                Ok(Ok(size)) if size > 0 => first.truncate(size),
+                _ => return,
+            }
+            let builder = builder(&stream, limits.idle_timeout);
+            let stream = Prefixed { first, stream };
+            let service = service_fn(move |request| respond(request, Arc::clone(&handler), client));
+            let served = builder
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+            if let Err(error) = served {
+                eprintln!("HTTPS connection with {} failed: {}", client, error);
+            }
+        });
+    }
+}
+
+// Speaks whichever protocol was agreed during the handshake, HTTP/1.1 if none was. Waiting on HTTP/1.1 clients for a
+// request is bounded by `idle_timeout`, and HTTP/2 clients have to answer a ping every `idle_timeout` to stay.
+fn builder(
+    stream: &TlsStream<tokio::net::TcpStream>,
+    idle_timeout: Duration,
+) -> Builder<TokioExecutor> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+        builder = builder.http2_only();
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .keep_alive_interval(idle_timeout)
+            .keep_alive_timeout(idle_timeout);
+    } else {
+        builder = builder.http1_only();
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(idle_timeout);
+    }
+    builder
+}
+
+// A stream with what was already read from it put back in front.
+struct Prefixed<S> {
+    first: Vec<u8>,
+    stream: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.first.is_empty() {
+            return Pin::new(&mut self.stream).poll_read(context, buffer);
+        }
+        let size = self.first.len().min(buffer.remaining());
+        buffer.put_slice(&self.first[..size]);
+        self.first.drain(..size);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(context, buffer)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(context)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(context)
+    }
+}
+
+async fn respond(
+    request: Request<Incoming>,
+    handler: Handler,
+    client: SocketAddr,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    Ok(match request.uri().path() {
+        DNS_QUERY_PATH => match dns_query(request).await {
+            Ok(query) => match resolve(query, handler, client).await {
+                Ok(response) => dns_message(response),
+                Err(status) => empty(status),
+            },
+            Err(status) => empty(status),
+        },
+        RESOLVE_PATH => resolve_json(request, handler, client).await,
+        _ => empty(StatusCode::NOT_FOUND),
+    })
+}
+
+async fn resolve(
+    query: Vec<u8>,
+    handler: Handler,
+    client: SocketAddr,
+) -> Result<Vec<u8>, StatusCode> {
+    match task::spawn_blocking(move || handler(&query, client)).await {
+        Ok(Some(response)) => Ok(response),
+        // Too short to even have an ID to answer to.
+        Ok(None) => Err(StatusCode::BAD_REQUEST),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// Answers a JSON API request the same way as the DNS message it stands for.
+async fn resolve_json(
+    request: Request<Incoming>,
+    handler: Handler,
+    client: SocketAddr,
+) -> Response<Full<Bytes>> {
+    if request.method() != Method::GET {
+        return empty(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let query = json_api::query(request.uri().query().unwrap_or_default())
+        .and_then(|mut query| query.to_bytes().map_err(|error| error.to_string()));
+    let query = match query {
+        Ok(query) => query,
+        Err(error) => return json(StatusCode::BAD_REQUEST, json_api::error(&error), None),
+    };
+    let response = resolve(query, handler, client)
+        .await
+        .and_then(|response| DnsPacket::from_bytes(&response).map_err(|_| StatusCode::BAD_GATEWAY));
+    match response {
+        Ok(response) => json(
+            StatusCode::OK,
+            json_api::to_json(&response),
+            max_age(&response),
+        ),
+        Err(status) => json(
+            status,
+            json_api::error("The query couldn't be answered."),
+            None,
+        ),
+    }
+}
+
+// The DNS query a request carries: base64url-encoded in the `dns` parameter of a GET, or the whole body of a POST.
+async fn dns_query(request: Request<Incoming>) -> Result<Vec<u8>, StatusCode> {
+    match *request.method() {
+        Method::GET => {
+            let encoded = request
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|parameter| parameter.strip_prefix("dns="))
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            // Padding should be left off (RFC 8484 section 4.1), but is easy enough to put up with.
+            BASE64URL_NOPAD
+                .decode(encoded.trim_end_matches('=').as_bytes())
+                .map_err(|_| StatusCode::BAD_REQUEST)
+        }
+        Method::POST => {
+            let content_type = request.headers().get(CONTENT_TYPE);
+            if content_type.is_none_or(|content_type| content_type != DNS_MESSAGE) {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            let body = Limited::new(request.into_body(), MAX_MESSAGE_SIZE)
+                .collect()
+                .await
+                .map_err(|error| {
+                    if error.is::<LengthLimitError>() {
+                        StatusCode::PAYLOAD_TOO_LARGE
+                    } else {
+                        StatusCode::BAD_REQUEST
+                    }
+                })?;
+            Ok(body.to_bytes().to_vec())
+        }
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+fn dns_message(message: Vec<u8>) -> Response<Full<Bytes>> {
+    let max_age = DnsPacket::from_bytes(&message)
+        .ok()
+        .as_ref()
+        .and_then(max_age);
+    with_body(StatusCode::OK, DNS_MESSAGE, message, max_age)
+}
+
+fn json(status: StatusCode, json: String, max_age: Option<u32>) -> Response<Full<Bytes>> {
+    with_body(status, DNS_JSON, json.into_bytes(), max_age)
+}
+
+fn with_body(
+    status: StatusCode,
+    content_type: &str,
+    body: Vec<u8>,
+    max_age: Option<u32>,
+) -> Response<Full<Bytes>> {
+    let mut response = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type);
+    if let Some(max_age) = max_age {
+        response = response.header(CACHE_CONTROL, format!("max-age={}", max_age));
+    }
+    response
+        .body(Full::from(body))
+        .unwrap_or_else(|_| empty(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+// How long an HTTP cache may keep `response`: until its first answer expires, or for a negative answer as long as
+// its SOA says (RFC 8484 section 5.1). Failures aren't cached at all.
+fn max_age(response: &DnsPacket) -> Option<u32> {
+    if !matches!(
+        response.header.rescode,
+        ResultCode::NOERROR | ResultCode::NXDOMAIN
+    ) {
+        return None;
+    }
+    response
+        .answers
+        .iter()
+        .map(DnsRecord::ttl)
+        .min()
+        .or_else(|| {
+            response.authorities.iter().find_map(|record| match record {
+                DnsRecord::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+                _ => None,
+            })
+        })
+}
+
+fn empty(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{listen, max_age, TcpLimits, HTTPS_PROTOCOLS};
+    use crate::{
+        parser::{
+            test_helpers::test_file_path, DnsPacket, DnsPacketBuilder, QueryType, ResultCode,
+        },
+        resolver::{
+            test_servers,
+            tls::{
+                server_config,
+                tests::{client_config, handler, CERTIFICATE, KEY},
+            },
+        },
+    };
+    use data_encoding::BASE64URL_NOPAD;
+    use http_body_util::{BodyExt, Full};
+    use hyper::{
+        body::Bytes,
+        client::conn::{http1, http2},
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        Method, Request, Response, StatusCode,
+    };
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use rustls::pki_types::ServerName;
+    use serde_json::Value;
+    use std::{
+        error::Error,
+        net::{Ipv4Addr, SocketAddr, TcpListener},
+        thread,
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt},
+        net::TcpStream,
+        runtime::Runtime,
+        time,
+    };
+    use tokio_rustls::TlsConnector;
+
+    fn start() -> Result<SocketAddr, Box<dyn Error>> {
+        start_with(TcpLimits {
+            max_connections: 8,
+            ..TcpLimits::default()
+        })
+    }
+
+    fn start_with(limits: TcpLimits) -> Result<SocketAddr, Box<dyn Error>> {
+        let config = server_config(
+            &test_file_path(CERTIFICATE),
+            &test_file_path(KEY),
+            HTTPS_PROTOCOLS,
+        )?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || listen(listener, config, limits, handler()));
+        Ok(address)
+    }
+
+    // Sends `requests` one after another on a connection using `protocol`, "h2" or "http/1.1".
+    fn exchange(
+        server: SocketAddr,
+        protocol: &[u8],
+        requests: Vec<Request<Full<Bytes>>>,
+    ) -> Result<Vec<Response<Bytes>>, Box<dyn Error>> {
+        let connector = TlsConnector::from(client_config(&[protocol])?);
+        Runtime::new()?.block_on(async {
+            let stream = TcpStream::connect(server).await?;
+            let stream = connector
+                .connect(ServerName::try_from("localhost")?, stream)
+                .await?;
+            let stream = TokioIo::new(stream);
+            let mut responses = Vec::new();
+            if protocol == b"h2" {
+                let (mut sender, connection) =
+                    http2::handshake(TokioExecutor::new(), stream).await?;
+                tokio::spawn(connection);
+                for request in requests {
+                    sender.ready().await?;
+                    responses.push(collect(sender.send_request(request).await?).await?);
+                }
+            } else {
+                let (mut sender, connection) = http1::handshake(stream).await?;
+                tokio::spawn(connection);
+                for request in requests {
+                    sender.ready().await?;
+                    responses.push(collect(sender.send_request(request).await?).await?);
+                }
+            }
+            Ok(responses)
+        })
+    }
+
+    // Whether the server hangs up within `wait` on a client which says nothing, once it has agreed on `protocol` if
+    // there is one.
+    fn closes_silent_connection(
+        server: SocketAddr,
+        protocol: Option<&[u8]>,
+        wait: Duration,
+    ) -> Result<bool, Box<dyn Error>> {
+        Runtime::new()?.block_on(async {
+            let stream = TcpStream::connect(server).await?;
+            let mut stream: Box<dyn AsyncRead + Unpin> = match protocol {
+                Some(protocol) => {
+                    let connector = TlsConnector::from(client_config(&[protocol])?);
+                    let server_name = ServerName::try_from("localhost")?;
+                    Box::new(connector.connect(server_name, stream).await?)
+                }
+                None => Box::new(stream),
+            };
+            // Whatever the server sends meanwhile, settings and pings included, goes unanswered.
+            let mut buffer = [0; 1024];
+            let closed = time::timeout(wait, async {
+                while let Ok(size) = stream.read(&mut buffer).await {
+                    if size == 0 {
+                        break;
+                    }
+                }
+            });
+            Ok(closed.await.is_ok())
+        })
+    }
+
+    async fn collect(
+        response: Response<hyper::body::Incoming>,
+    ) -> Result<Response<Bytes>, Box<dyn Error>> {
+        let (parts, body) = response.into_parts();
+        Ok(Response::from_parts(
+            parts,
+            body.collect().await?.to_bytes(),
+        ))
+    }
+
+    fn request(
+        method: Method,
+        path: &str,
+        body: Vec<u8>,
+    ) -> Result<Request<Full<Bytes>>, Box<dyn Error>> {
+        Ok(Request::builder()
+            .method(method)
+            .uri(format!("https://localhost{}", path))
+            .header(CONTENT_TYPE, "application/dns-message")
+            .body(Full::from(body))?)
+    }
+
+    fn query() -> Result<Vec<u8>, Box<dyn Error>> {
+        DnsPacketBuilder::new()
+            .recursion_desired(true)
+            .question("www.example", QueryType::A)
+            .build()
+            .to_bytes()
+    }
+
+    #[test]
+    fn answers_get_and_post_over_both_http_versions() -> Result<(), Box<dyn Error>> {
+        let server = start()?;
+        let get = format!("/dns-query?dns={}", BASE64URL_NOPAD.encode(&query()?));
+        for protocol in HTTPS_PROTOCOLS {
+            let responses = exchange(
+                server,
+                protocol,
+                vec![
+                    request(Method::GET, &get, Vec::new())?,
+                    request(Method::POST, "/dns-query", query()?)?,
+                ],
+            )?;
+            for response in responses {
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers()[CONTENT_TYPE], "application/dns-message");
+                assert_eq!(response.headers()[CACHE_CONTROL], "max-age=300");
+                let answer = DnsPacket::from_bytes(response.body())?;
+                assert_eq!(answer.questions[0].name, "www.example");
+                assert_eq!(answer.answers.len(), 1);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_requests_without_a_query() -> Result<(), Box<dyn Error>> {
+        let server = start()?;
+        let mut wrong_type = request(Method::POST, "/dns-query", query()?)?;
+        wrong_type
+            .headers_mut()
+            .insert(CONTENT_TYPE, "text/plain".parse()?);
+        let responses = exchange(
+            server,
+            b"h2",
+            vec![
+                request(Method::GET, "/elsewhere", Vec::new())?,
+                request(Method::GET, "/dns-query?dns=not*base64", Vec::new())?,
+                request(Method::GET, "/dns-query?name=www.example", Vec::new())?,
+                request(Method::PUT, "/dns-query", query()?)?,
+                wrong_type,
+            ],
+        )?;
+        let statuses: Vec<StatusCode> = responses.iter().map(Response::status).collect();
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::NOT_FOUND,
+                StatusCode::BAD_REQUEST,
+                StatusCode::BAD_REQUEST,
+                StatusCode::METHOD_NOT_ALLOWED,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn closes_connections_that_stay_silent() -> Result<(), Box<dyn Error>> {
+        let server = start_with(TcpLimits {
+            idle_timeout: Duration::from_millis(200),
+            ..TcpLimits::default()
+        })?;
+        let wait = Duration::from_secs(3);
+        assert!(closes_silent_connection(server, None, wait)?);
+        for protocol in HTTPS_PROTOCOLS {
+            assert!(closes_silent_connection(server, Some(protocol), wait)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn answers_json_api_requests() -> Result<(), Box<dyn Error>> {
+        let server = start()?;
+        let responses = exchange(
+            server,
+            b"h2",
+            vec![
+                request(Method::GET, "/resolve?name=www.example&type=A", Vec::new())?,
+                request(Method::GET, "/resolve?type=A", Vec::new())?,
+                request(Method::POST, "/resolve?name=www.example", Vec::new())?,
+            ],
+        )?;
+        assert_eq!(responses[0].status(), StatusCode::OK);
+        assert_eq!(responses[0].headers()[CONTENT_TYPE], "application/dns-json");
+        assert_eq!(responses[0].headers()[CACHE_CONTROL], "max-age=300");
+        let json: Value = serde_json::from_slice(responses[0].body())?;
+        assert_eq!(json["Status"], 0);
+        assert_eq!(json["Question"][0]["name"], "www.example.");
+        assert_eq!(json["Answer"][0]["data"], "192.0.2.1");
+
+        assert_eq!(responses[1].status(), StatusCode::BAD_REQUEST);
+        let json: Value = serde_json::from_slice(responses[1].body())?;
+        assert_eq!(json["error"], "A name to resolve is required.");
+        assert_eq!(responses[2].status(), StatusCode::METHOD_NOT_ALLOWED);
+        Ok(())
+    }
+
+    #[test]
+    fn negative_answers_are_cached_for_their_soa_minimum() {
+        let query = DnsPacketBuilder::query()
+            .question("missing.example", QueryType::A)
+            .build();
+        let nxdomain = DnsPacketBuilder::response_to(&query)
+            .rescode(ResultCode::NXDOMAIN)
+            .authority(test_servers::soa("example"))
+            .build();
+        assert_eq!(max_age(&nxdomain), Some(300));
+
+        let failure = DnsPacketBuilder::response_to(&query)
+            .rescode(ResultCode::SERVFAIL)
+            .build();
+        assert_eq!(max_age(&failure), None);
+    }
+}
@@ -0,0 +1,251 @@
+// The JSON API that Google and Cloudflare offer alongside DNS over HTTPS: a GET of /resolve?name=example.com&type=AAAA
+// is answered with the response as a JSON object, for clients that would rather not deal with DNS messages.
+
+use crate::parser::{
+    absolute_name, DnsPacket, DnsPacketBuilder, DnsQuestion, DnsRecord, QueryType,
+    DEFAULT_EDNS_PAYLOAD_SIZE,
+};
+use serde_json::{json, Value};
+
+pub(super) const DNS_JSON: &str = "application/dns-json";
+const MAX_NAME_LENGTH: usize = 253;
+// Mnemonics for common types the parser keeps as raw data, which `QueryType` only knows by number.
+const UNMODELLED_TYPES: [(&str, u16); 4] = [("PTR", 12), ("MX", 15), ("TXT", 16), ("SRV", 33)];
+
+// The query a request's parameters ask for: `name`, of the `type` given as a mnemonic or a number (A if left out),
+// with DNSSEC records if `do` is set and without validation if `cd` is.
+pub(super) fn query(parameters: &str) -> Result<DnsPacket, String> {
+    let (mut name, mut query_type) = (None, QueryType::A);
+    let (mut checking_disabled, mut dnssec_ok) = (false, false);
+    for parameter in parameters
+        .split('&')
+        .filter(|parameter| !parameter.is_empty())
+    {
+        let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+        let value = percent_decode(value)?;
+        match key {
+            "name" => name = Some(value),
+            "type" => {
+                let unmodelled = UNMODELLED_TYPES
+                    .iter()
+                    .find(|(mnemonic, _)| mnemonic.eq_ignore_ascii_case(&value));
+                query_type = match (value.parse(), unmodelled) {
+                    (Ok(number), _) | (_, Some(&(_, number))) => QueryType::from_u16(number),
+                    _ => value.parse()?,
+                }
+            }
+            "cd" => checking_disabled = flag(key, &value)?,
+            "do" => dnssec_ok = flag(key, &value)?,
+            // Others, like the padding some clients add, don't change the answer.
+            _ => {}
+        }
+    }
+    let name = name.ok_or("A name to resolve is required.")?;
+    let name = match name.trim_end_matches('.') {
+        "" => return Err(String::from("A name to resolve is required.")),
+        name if name.len() > MAX_NAME_LENGTH => return Err(format!("Name too long: {}", name)),
+        name => name,
+    };
+    Ok(DnsPacketBuilder::query()
+        .checking_disabled(checking_disabled)
+        .question(name, query_type)
+        // Always EDNS, so failures come with an explanation to pass on.
+        .edns(DEFAULT_EDNS_PAYLOAD_SIZE)
+        .dnssec_ok(dnssec_ok)
+        .build())
+}
+
+fn flag(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" => Ok(true),
+        "" | "0" | "false" => Ok(false),
+        _ => Err(format!("Bad value for {}: {}", key, value)),
+    }
+}
+
+fn percent_decode(value: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let decoded = rest
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| format!("Bad escape in {}", value))?;
+        bytes.push(decoded);
+        rest = &rest[2..];
+    }
+    String::from_utf8(bytes).map_err(|_| format!("Not UTF-8: {}", value))
+}
+
+// `response` in the shape the other services use, with record data in zone file format. The extended DNS error,
+// if any, becomes a comment.
+pub(super) fn to_json(response: &DnsPacket) -> String {
+    let header = &response.header;
+    let mut json = json!({
+        "Status": header.rescode as u8,
+        "TC": header.truncated_message,
+        "RD": header.recursion_desired,
+        "RA": header.recursion_available,
+        "AD": header.authentic_data,
+        "CD": header.checking_disabled,
+        "Question": response.questions.iter().map(question).collect::<Vec<_>>(),
+    });
+    for (key, records) in [
+        ("Answer", &response.answers),
+        ("Authority", &response.authorities),
+    ] {
+        if !records.is_empty() {
+            json[key] = records.iter().map(record).collect();
+        }
+    }
+    if let Some((info_code, text)) = response.extended_error() {
+        json["Comment"] = match text.as_str() {
+            "" => json!(format!("Extended DNS error {}", info_code)),
+            text => json!(format!("Extended DNS error {}: {}", info_code, text)),
+        };
+    }
+    json.to_string()
+}
+
+// A body explaining why a request couldn't be answered.
+pub(super) fn error(message: &str) -> String {
+    json!({ "error": message }).to_string()
+}
+
+fn question(question: &DnsQuestion) -> Value {
+    json!({
+        "name": absolute_name(&question.name),
+        "type": question.query_type.to_u16(),
+    })
+}
+
+fn record(record: &DnsRecord) -> Value {
+    json!({
+        "name": absolute_name(record.domain()),
+        "type": record.query_type().to_u16(),
+        "TTL": record.ttl(),
+        "data": record.data_to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{query, to_json};
+    use crate::parser::{DnsPacket, DnsPacketBuilder, DnsRecord, QueryType, ResultCode};
+    use serde_json::{json, Value};
+    use std::{error::Error, net::Ipv4Addr};
+
+    #[test]
+    fn reads_the_query_from_parameters() -> Result<(), Box<dyn Error>> {
+        let packet = query("name=www.example.&type=aaaa&cd=1&do=true")?;
+        assert_eq!(packet.questions[0].name, "www.example");
+        assert_eq!(packet.questions[0].query_type, QueryType::AAAA);
+        assert!(packet.header.recursion_desired);
+        assert!(packet.header.checking_disabled);
+        assert!(packet.dnssec_ok());
+
+        let packet = query("type=65&name=%77ww.example")?;
+        assert_eq!(packet.questions[0].name, "www.example");
+        assert_eq!(packet.questions[0].query_type, QueryType::HTTPS);
+        assert!(!packet.header.checking_disabled);
+        assert!(!packet.dnssec_ok());
+
+        assert_eq!(
+            query("name=www.example")?.questions[0].query_type,
+            QueryType::A
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_bad_parameters() {
+        for parameters in [
+            "",
+            "name=.",
+            "name=www.example&type=BOGUS",
+            "name=www.example&cd=maybe",
+            "name=www%2",
+        ] {
+            assert!(query(parameters).is_err(), "{}", parameters);
+        }
+    }
+
+    #[test]
+    fn describes_the_response() -> Result<(), Box<dyn Error>> {
+        let query = query("name=www.example")?;
+        let response = DnsPacketBuilder::response_to(&query)
+            .recursion_available(true)
+            .answer(DnsRecord::A {
+                domain: String::from("www.example"),
+                address: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 300,
+            })
+            .build();
+        let json: Value = serde_json::from_str(&to_json(&response))?;
+        assert_eq!(
+            json,
+            json!({
+                "Status": 0,
+                "TC": false,
+                "RD": true,
+                "RA": true,
+                "AD": false,
+                "CD": false,
+                "Question": [{ "name": "www.example.", "type": 1 }],
+                "Answer": [{ "name": "www.example.", "type": 1, "TTL": 300, "data": "192.0.2.1" }],
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn describes_records_of_types_without_a_variant() -> Result<(), Box<dyn Error>> {
+        let query = query("name=example&type=mx")?;
+        assert_eq!(query.questions[0].query_type, QueryType::UNKNOWN(15));
+        let mut response = DnsPacketBuilder::response_to(&query)
+            .answer(DnsRecord::UNKNOWN {
+                domain: String::from("example"),
+                query_type: 15,
+                data: b"\x00\x0a\x04mail\x07example\x00".to_vec(),
+                ttl: 300,
+            })
+            .build();
+        // As it arrives from upstream.
+        let response = DnsPacket::from_bytes(&response.to_bytes()?)?;
+        let json: Value = serde_json::from_str(&to_json(&response))?;
+        assert_eq!(
+            json["Answer"],
+            json!([{
+                "name": "example.",
+                "type": 15,
+                "TTL": 300,
+                "data": "\\# 16 000A046D61696C076578616D706C6500",
+            }])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn passes_on_why_resolution_failed() -> Result<(), Box<dyn Error>> {
+        let query = query("name=www.example")?;
+        let response = DnsPacketBuilder::response_to(&query)
+            .rescode(ResultCode::SERVFAIL)
+            .edns(1232)
+            .extended_error(22, "No upstream answered.")
+            .build();
+        let json: Value = serde_json::from_str(&to_json(&response))?;
+        assert_eq!(json["Status"], 2);
+        assert_eq!(
+            json["Comment"],
+            "Extended DNS error 22: No upstream answered."
+        );
+        assert!(json.get("Answer").is_none());
+        Ok(())
+    }
+}
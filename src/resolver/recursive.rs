@@ -0,0 +1,762 @@
+use super::{dns_resolver::lock, tcp::Connections, wrapped_socket::WrappedSocket};
+use crate::{
+    dnssec::RecordSource,
+    parser::{
+        compare_names, is_subdomain, DnsPacket, DnsPacketBuilder, DnsRecord, QueryType, ResultCode,
+    },
+};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    error::Error,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const DNS_PORT: u16 = 53;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+// Limits on the work one client query can cause, so delegation loops, endless CNAME chains and name servers whose
+// own addresses can't be found end in SERVFAIL rather than a flood of queries.
+const MAX_QUERIES: usize = 64;
+const MAX_REFERRALS: usize = 16;
+const MAX_CNAME_RESTARTS: usize = 8;
+// How deeply lookups of name servers that came without glue may nest.
+const MAX_GLUELESS_DEPTH: usize = 3;
+// Bounds on what's remembered of delegations and name server addresses between lookups.
+const MAX_LEARNT: usize = 4096;
+const MAX_LEARNT_TTL: u32 = 86400;
+
+/// The root servers, a.root-servers.net to m.root-servers.net, as published at
+/// https://www.iana.org/domains/root/servers.
+pub const ROOT_SERVERS: [IpAddr; 26] = [
+    IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)),
+    IpAddr::V4(Ipv4Addr::new(170, 247, 170, 2)),
+    IpAddr::V4(Ipv4Addr::new(192, 33, 4, 12)),
+    IpAddr::V4(Ipv4Addr::new(199, 7, 91, 13)),
+    IpAddr::V4(Ipv4Addr::new(192, 203, 230, 10)),
+    IpAddr::V4(Ipv4Addr::new(192, 5, 5, 241)),
+    IpAddr::V4(Ipv4Addr::new(192, 112, 36, 4)),
+    IpAddr::V4(Ipv4Addr::new(198, 97, 190, 53)),
+    IpAddr::V4(Ipv4Addr::new(192, 36, 148, 17)),
+    IpAddr::V4(Ipv4Addr::new(192, 58, 128, 30)),
+    IpAddr::V4(Ipv4Addr::new(193, 0, 14, 129)),
+    IpAddr::V4(Ipv4Addr::new(199, 7, 83, 42)),
+    IpAddr::V4(Ipv4Addr::new(202, 12, 27, 33)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30)),
+    IpAddr::V6(Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2d, 0, 0, 0, 0, 0xd)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0xa8, 0, 0, 0, 0, 0xe)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2f, 0, 0, 0, 0, 0xf)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x12, 0, 0, 0, 0, 0xd0d)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x1, 0, 0, 0, 0, 0x53)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xc27, 0, 0, 0, 0x2, 0x30)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fd, 0, 0, 0, 0, 0, 0x1)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x9f, 0, 0, 0, 0, 0x42)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0xdc3, 0, 0, 0, 0, 0, 0x35)),
+];
+
+/// Resolves names itself instead of forwarding them: starting from the root hints, it follows referrals down the
+/// delegation tree until it reaches a server authoritative for the name (RFC 1034 section 5.3.3). The delegations
+/// it learns on the way are remembered, so later lookups can skip the parts of the tree already walked.
+#[derive(Clone)]
+pub struct Recursor {
+    root_hints: Vec<SocketAddr>,
+    port: u16,
+    timeout: Duration,
+    retries: u32,
+    // Shared by every clone, so TCP connections to name servers outlive the lookup that opened them.
+    connections: Arc<Connections>,
+    // Also shared, so later lookups start from the closest zone whose servers are already known rather than the
+    // root, and don't look up the same glueless name servers again.
+    zones: Arc<Mutex<Learnt<Vec<NameServer>>>>,
+    hosts: Arc<Mutex<Learnt<Vec<SocketAddr>>>>,
+    // Whether to ask for DNSSEC records, which the resolver turns on when it validates.
+    pub(super) dnssec: bool,
+}
+
+// Where to send a query: an address from the root hints or glue, or the name of a server still to be looked up.
+#[derive(Clone)]
+enum NameServer {
+    Address(SocketAddr),
+    Name(String),
+}
+
+// What a useful response from a zone's server means for the lookup.
+enum Step {
+    Answer(DnsPacket),
+    Referral {
+        zone: String,
+        servers: Vec<NameServer>,
+        ttl: u32,
+    },
+}
+
+// What earlier lookups found out, by name, until its TTL runs out.
+struct Learnt<T> {
+    entries: HashMap<String, (T, Instant)>,
+}
+
+impl<T> Default for Learnt<T> {
+    fn default() -> Learnt<T> {
+        Learnt {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Clone> Learnt<T> {
+    fn get(&mut self, name: &str, now: Instant) -> Option<T> {
+        let key = key(name);
+        match self.entries.get(&key) {
+            Some((value, expires)) if *expires > now => Some(value.clone()),
+            Some(_) => {
+                self.entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // Once full, only makes room by dropping what has expired, so a flood of new names can't push out the rest.
+    fn insert(&mut self, name: &str, value: T, ttl: u32, now: Instant) {
+        let ttl = ttl.min(MAX_LEARNT_TTL);
+        if ttl == 0 {
+            return;
+        }
+        if self.entries.len() >= MAX_LEARNT {
+            self.entries.retain(|_, (_, expires)| *expires > now);
+            if self.entries.len() >= MAX_LEARNT {
+                return;
+            }
+        }
+        let expires = now + Duration::from_secs(ttl as u64);
+        self.entries.insert(key(name), (value, expires));
+    }
+
+    fn forget(&mut self, name: &str) {
+        self.entries.remove(&key(name));
+    }
+}
+
+// Counts the queries sent on behalf of one client query.
+#[derive(Default)]
+struct Work {
+    queries: usize,
+}
+
+impl Work {
+    fn spend(&mut self) -> Result<(), String> {
+        if self.queries >= MAX_QUERIES {
+            return Err(format!("Gave up after {} queries.", MAX_QUERIES));
+        }
+        self.queries += 1;
+        Ok(())
+    }
+}
+
+impl Recursor {
+    pub fn new(root_hints: Vec<SocketAddr>) -> Result<Recursor, Box<dyn Error>> {
+        if root_hints.is_empty() {
+            return Err("At least one root hint is needed.".into());
+        }
+        Ok(Recursor {
+            root_hints,
+            port: DNS_PORT,
+            timeout: DEFAULT_TIMEOUT,
+            retries: 0,
+            connections: Arc::default(),
+            zones: Arc::default(),
+            hosts: Arc::default(),
+            dnssec: false,
+        })
+    }
+
+    // The port to ask name servers found through referrals on, which is 53 anywhere but a test.
+    pub fn with_port(mut self, port: u16) -> Recursor {
+        self.port = port;
+        self
+    }
+
+    // How long to wait for each name server before trying the next.
+    pub fn with_timeout(mut self, timeout: Duration) -> Recursor {
+        self.timeout = timeout;
+        self
+    }
+
+    // How many more times to ask the servers for a zone which didn't respond at all, doubling the wait each round.
+    pub fn with_retries(mut self, retries: u32) -> Recursor {
+        self.retries = retries;
+        self
+    }
+
+    // Looks `name` up from the top, starting again at the target whenever a CNAME leads out of a response.
+    fn resolve(
+        &self,
+        name: &str,
+        query_type: QueryType,
+        work: &mut Work,
+        depth: usize,
+    ) -> Result<DnsPacket, String> {
+        let mut answers = Vec::new();
+        let mut current = name.to_string();
+        for _ in 0..MAX_CNAME_RESTARTS {
+            let response = self.descend(&current, query_type, work, depth)?;
+            let (chain, restart) = follow_chain(&current, query_type, &response.answers);
+            answers.extend(chain);
+            match restart {
+                Some(target) if response.header.rescode == ResultCode::NOERROR => current = target,
+                _ => {
+                    return Ok(DnsPacketBuilder::new()
+                        .response(true)
+                        .question(name, query_type)
+                        .rescode(response.header.rescode)
+                        .answers(answers)
+                        .authorities(response.authorities)
+                        .build())
+                }
+            }
+        }
+        Err(format!("Too many CNAMEs following {}.", name))
+    }
+
+    // Follows referrals to a server which answers for `name` with authority, from the closest zone already known.
+    fn descend(
+        &self,
+        name: &str,
+        query_type: QueryType,
+        work: &mut Work,
+        depth: usize,
+    ) -> Result<DnsPacket, String> {
+        if let Some((zone, servers)) = self.closest_zone(name, query_type) {
+            match self.descend_from(zone.clone(), servers, name, query_type, work, depth) {
+                Ok(response) => return Ok(response),
+                // Its servers may have moved since, so forget them and go back to the root.
+                Err(_) => lock(&self.zones).forget(&zone),
+            }
+        }
+        let servers = self
+            .root_hints
+            .iter()
+            .map(|address| NameServer::Address(*address))
+            .collect();
+        self.descend_from(String::new(), servers, name, query_type, work, depth)
+    }
+
+    fn descend_from(
+        &self,
+        mut zone: String,
+        mut servers: Vec<NameServer>,
+        name: &str,
+        query_type: QueryType,
+        work: &mut Work,
+        depth: usize,
+    ) -> Result<DnsPacket, String> {
+        for _ in 0..MAX_REFERRALS {
+            match self.ask_zone(&zone, &servers, name, query_type, work, depth)? {
+                Step::Answer(response) => return Ok(response),
+                Step::Referral {
+                    zone: child,
+                    servers: child_servers,
+                    ttl,
+                } => {
+                    lock(&self.zones).insert(&child, child_servers.clone(), ttl, Instant::now());
+                    zone = child;
+                    servers = child_servers;
+                }
+            }
+        }
+        Err(format!("Too many referrals looking up {}.", name))
+    }
+
+    // The closest zone enclosing `name` whose servers an earlier referral named. DS records are served by the
+    // parent zone, so for those `name`'s own zone doesn't count.
+    fn closest_zone(&self, name: &str, query_type: QueryType) -> Option<(String, Vec<NameServer>)> {
+        let mut zones = lock(&self.zones);
+        let now = Instant::now();
+        let mut zone = name.trim_end_matches('.');
+        if query_type == QueryType::DS {
+            zone = zone.split_once('.')?.1;
+        }
+        loop {
+            if let Some(servers) = zones.get(zone, now) {
+                return Some((zone.to_string(), servers));
+            }
+            zone = zone.split_once('.')?.1;
+        }
+    }
+
+    // Asks the servers for `zone` in turn until one is useful. Those that don't respond are skipped, as are lame
+    // ones, which neither answer with authority nor refer us further down. If none is, those that didn't respond
+    // are asked again, up to `retries` more times.
+    fn ask_zone(
+        &self,
+        zone: &str,
+        servers: &[NameServer],
+        name: &str,
+        query_type: QueryType,
+        work: &mut Work,
+        depth: usize,
+    ) -> Result<Step, String> {
+        let mut last_error = format!("No servers for {} answered.", display(zone));
+        let mut pending = servers.to_vec();
+        let mut timeout = self.timeout;
+        for _ in 0..=self.retries {
+            let mut silent = Vec::new();
+            for server in pending {
+                let addresses = match server {
+                    NameServer::Address(address) => vec![address],
+                    NameServer::Name(host) => match self.addresses(&host, work, depth) {
+                        Ok(addresses) => addresses,
+                        Err(error) => {
+                            last_error = error;
+                            continue;
+                        }
+                    },
+                };
+                for address in addresses {
+                    work.spend()?;
+                    match self.query_server(address, name, query_type, timeout) {
+                        Ok(response) => match self.classify(zone, name, response) {
+                            Some(step) => return Ok(step),
+                            None => {
+                                last_error = format!("{} is lame for {}.", address, display(zone))
+                            }
+                        },
+                        Err(error) => {
+                            last_error = format!("{}: {}", address, error);
+                            silent.push(NameServer::Address(address));
+                        }
+                    }
+                }
+            }
+            pending = silent;
+            timeout = timeout.saturating_mul(2);
+        }
+        Err(last_error)
+    }
+
+    // Looks up a name server that was delegated to without glue.
+    fn addresses(
+        &self,
+        host: &str,
+        work: &mut Work,
+        depth: usize,
+    ) -> Result<Vec<SocketAddr>, String> {
+        if let Some(addresses) = lock(&self.hosts).get(host, Instant::now()) {
+            return Ok(addresses);
+        }
+        if depth >= MAX_GLUELESS_DEPTH {
+            return Err(format!("Too many nested lookups to find {}.", host));
+        }
+        let response = self.resolve(host, QueryType::A, work, depth + 1)?;
+        let (addresses, ttls): (Vec<SocketAddr>, Vec<u32>) = response
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { address, ttl, .. } => {
+                    Some((SocketAddr::new((*address).into(), self.port), *ttl))
+                }
+                _ => None,
+            })
+            .unzip();
+        let Some(ttl) = ttls.into_iter().min() else {
+            return Err(format!("Name server {} has no address.", host));
+        };
+        lock(&self.hosts).insert(host, addresses.clone(), ttl, Instant::now());
+        Ok(addresses)
+    }
+
+    // What a response from one of `zone`'s servers means, or None if the server is lame for it.
+    fn classify(&self, zone: &str, name: &str, response: DnsPacket) -> Option<Step> {
+        match response.header.rescode {
+            ResultCode::NOERROR | ResultCode::NXDOMAIN => {}
+            _ => return None,
+        }
+        if response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
+            // A referral has to lead further down towards `name`, or we'd go round in circles.
+            let child = response.authorities.iter().find_map(|record| match record {
+                DnsRecord::NS { domain, .. }
+                    if is_subdomain(name, domain)
+                        && is_subdomain(domain, zone)
+                        && compare_names(domain, zone) != Ordering::Equal =>
+                {
+                    Some(domain.clone())
+                }
+                _ => None,
+            });
+            if let Some(child) = child {
+                let ttl = response
+                    .authorities
+                    .iter()
+                    .filter(|record| matches!(record, DnsRecord::NS { .. }))
+                    .filter(|record| compare_names(record.domain(), &child) == Ordering::Equal)
+                    .map(DnsRecord::ttl)
+                    .min()
+                    .unwrap_or(0);
+                return Some(Step::Referral {
+                    servers: self.name_servers(&child, zone, &response),
+                    zone: child,
+                    ttl,
+                });
+            }
+        }
+        if !response.header.authoritative_answer {
+            return None;
+        }
+        Some(Step::Answer(within_zone(zone, response)))
+    }
+
+    // The servers a referral to `child` names, those with glue first. Glue is only believed for names within the
+    // `zone` the referral came from, as its servers have no say over addresses anywhere else.
+    fn name_servers(&self, child: &str, zone: &str, referral: &DnsPacket) -> Vec<NameServer> {
+        let mut glued = Vec::new();
+        let mut glueless = Vec::new();
+        for record in &referral.authorities {
+            let DnsRecord::NS { domain, host, .. } = record else {
+                continue;
+            };
+            if compare_names(domain, child) != Ordering::Equal {
+                continue;
+            }
+            let glue: Vec<NameServer> = referral
+                .additional_records
+                .iter()
+                .filter(|_| is_subdomain(host, zone))
+                .filter(|record| compare_names(record.domain(), host) == Ordering::Equal)
+                .filter_map(|record| match record {
+                    DnsRecord::A { address, .. } => Some(IpAddr::from(*address)),
+                    DnsRecord::AAAA { address, .. } => Some(IpAddr::from(*address)),
+                    _ => None,
+                })
+                .map(|ip| NameServer::Address(SocketAddr::new(ip, self.port)))
+                .collect();
+            if glue.is_empty() {
+                glueless.push(NameServer::Name(host.clone()));
+            } else {
+                glued.extend(glue);
+            }
+        }
+        glued.extend(glueless);
+        glued
+    }
+
+    fn query_server(
+        &self,
+        server: SocketAddr,
+        name: &str,
+        query_type: QueryType,
+        timeout: Duration,
+    ) -> Result<DnsPacket, Box<dyn Error>> {
+        let mut builder = DnsPacketBuilder::query()
+            .recursion_desired(false)
+            .question(name, query_type);
+        if self.dnssec {
+            builder = builder.dnssec_ok(true);
+        }
+        let mut query = builder.build();
+        let response =
+            WrappedSocket::new(server)?.exchange(&mut query, Instant::now() + timeout)?;
+        if !response.header.truncated_message {
+            return Ok(response);
+        }
+        // Large answers, DNSKEY sets especially, have to come over TCP (RFC 7766 section 5).
+        self.connections
+            .exchange(server, &mut query, Instant::now() + timeout)
+    }
+}
+
+impl RecordSource for Recursor {
+    fn query(&mut self, name: &str, query_type: QueryType) -> Result<DnsPacket, Box<dyn Error>> {
+        Ok(self.resolve(name, query_type, &mut Work::default(), 0)?)
+    }
+}
+
+// `response` without the records owned by names outside `zone`. A server only has a say over its own zone, so
+// anything else it sends, such as the address a CNAME leads to elsewhere, is left for that name's own servers.
+fn within_zone(zone: &str, mut response: DnsPacket) -> DnsPacket {
+    let in_zone = |record: &DnsRecord| {
+        matches!(record, DnsRecord::OPT { .. }) || is_subdomain(record.domain(), zone)
+    };
+    response.answers.retain(in_zone);
+    response.authorities.retain(in_zone);
+    response.additional_records.retain(in_zone);
+    response
+}
+
+// The records from `records` on the way from `name` to its answer, following CNAMEs and DNAMEs as far as the
+// response goes, and the name to restart from if the chain leads out of it.
+fn follow_chain(
+    name: &str,
+    query_type: QueryType,
+    records: &[DnsRecord],
+) -> (Vec<DnsRecord>, Option<String>) {
+    let owned_by = |name: &str| {
+        records
+            .iter()
+            .filter(|record| compare_names(record.domain(), name) == Ordering::Equal)
+            .cloned()
+            .collect::<Vec<DnsRecord>>()
+    };
+    let dname_for = |name: &str| {
+        records
+            .iter()
+            .find_map(|record| match record.synthesize_cname(name) {
+                Ok(Some(DnsRecord::CNAME { host, .. })) => {
+                    Some((record.domain().to_string(), host))
+                }
+                _ => None,
+            })
+    };
+
+    let mut chain: Vec<DnsRecord> = Vec::new();
+    let mut name = name.to_string();
+    // Each step adds a record, so this is enough to reach the end of any chain without going round a loop.
+    for _ in 0..=records.len() {
+        let mut step = owned_by(&name);
+        let mut target = step.iter().find_map(|record| match record {
+            DnsRecord::CNAME { host, .. } => Some(host.clone()),
+            _ => None,
+        });
+        if let Some((owner, host)) = dname_for(&name) {
+            step.extend(owned_by(&owner).into_iter().filter(|record| {
+                matches!(
+                    record,
+                    DnsRecord::DNAME { .. }
+                        | DnsRecord::RRSIG {
+                            type_covered: QueryType::DNAME,
+                            ..
+                        }
+                )
+            }));
+            target.get_or_insert(host);
+        }
+        for record in step {
+            if !chain.contains(&record) {
+                chain.push(record);
+            }
+        }
+
+        match target {
+            Some(target) if query_type != QueryType::CNAME => {
+                if owned_by(&target).is_empty() && dname_for(&target).is_none() {
+                    return (chain, Some(target));
+                }
+                name = target;
+            }
+            _ => return (chain, None),
+        }
+    }
+    (chain, None)
+}
+
+fn key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn display(zone: &str) -> &str {
+    if zone.is_empty() {
+        "."
+    } else {
+        zone
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Recursor, MAX_QUERIES};
+    use crate::{
+        dnssec::RecordSource,
+        parser::{DnsRecord, QueryType, ResultCode},
+        resolver::{
+            cache::{Cache, CacheLimits, Cached},
+            dns_resolver::lock,
+            test_servers::{self, test_hierarchy, Behaviour, StandIns, Truncating},
+        },
+    };
+    use std::{
+        error::Error,
+        net::Ipv4Addr,
+        sync::{atomic::Ordering, Mutex},
+        time::{Duration, Instant},
+    };
+
+    fn recursor(servers: &StandIns) -> Result<Recursor, Box<dyn Error>> {
+        Ok(Recursor::new(vec![servers.root()])?
+            .with_port(servers.port())
+            .with_timeout(Duration::from_millis(200)))
+    }
+
+    fn addresses(records: &[DnsRecord]) -> Vec<Ipv4Addr> {
+        records
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { address, .. } => Some(*address),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn follows_referrals_using_glue() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        let response = recursor(&servers)?.query("www.example", QueryType::A)?;
+
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(
+            addresses(&response.answers),
+            vec![Ipv4Addr::new(192, 0, 2, 1)]
+        );
+        // The root, then example's server.
+        assert_eq!(servers.queries(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn looks_up_glueless_name_servers() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        let response = recursor(&servers)?.query("www.sub.example", QueryType::A)?;
+
+        assert_eq!(
+            addresses(&response.answers),
+            vec![Ipv4Addr::new(192, 0, 2, 2)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn remembers_delegations_between_lookups() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        let mut recursor = recursor(&servers)?;
+        recursor.query("www.sub.example", QueryType::A)?;
+        // The root, example's server, the root and other's server to find sub.example's server, then that.
+        assert_eq!(servers.queries(), 5);
+
+        // Straight to sub.example's server, which clones share too.
+        let response = recursor.clone().query("www.sub.example", QueryType::AAAA)?;
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(servers.queries(), 6);
+
+        // And straight to example's.
+        let response = recursor.query("www.example", QueryType::A)?;
+        assert_eq!(
+            addresses(&response.answers),
+            vec![Ipv4Addr::new(192, 0, 2, 1)]
+        );
+        assert_eq!(servers.queries(), 7);
+        Ok(())
+    }
+
+    #[test]
+    fn retries_servers_which_did_not_respond() -> Result<(), Box<dyn Error>> {
+        let servers = StandIns::start(vec![(Ipv4Addr::new(127, 0, 0, 2), Behaviour::Silent)])?;
+        let mut recursor = recursor(&servers)?.with_retries(2);
+
+        assert!(recursor.query("www.example", QueryType::A).is_err());
+        assert_eq!(servers.queries(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn restarts_at_cname_targets() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        let response = recursor(&servers)?.query("alias.example", QueryType::A)?;
+
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(matches!(
+            &response.answers[0],
+            DnsRecord::CNAME { host, .. } if host == "www.sub.example"
+        ));
+        assert_eq!(
+            addresses(&response.answers),
+            vec![Ipv4Addr::new(192, 0, 2, 2)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_records_from_outside_the_zone_asked() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        let mut recursor = recursor(&servers)?;
+        let cache = Mutex::new(Cache::new(CacheLimits::default()));
+
+        // example's server sends its own address for www.other along with the CNAME to it.
+        let response = Cached::new(&cache, &mut recursor).query("stray.example", QueryType::A)?;
+
+        assert_eq!(
+            addresses(&response.answers),
+            vec![Ipv4Addr::new(192, 0, 2, 4)]
+        );
+        let cached = lock(&cache).get("www.other", QueryType::A, 1, Instant::now());
+        assert_eq!(
+            addresses(&cached.unwrap_or_default()),
+            vec![Ipv4Addr::new(192, 0, 2, 4)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn passes_on_negative_answers() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        let mut recursor = recursor(&servers)?;
+
+        let response = recursor.query("missing.example", QueryType::A)?;
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+        assert!(response.answers.is_empty());
+        assert!(matches!(response.authorities[0], DnsRecord::SOA { .. }));
+
+        let response = recursor.query("www.example", QueryType::AAAA)?;
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(response.answers.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn skips_lame_and_silent_servers() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        let response = recursor(&servers)?.query("www.flaky", QueryType::A)?;
+
+        assert_eq!(
+            addresses(&response.answers),
+            vec![Ipv4Addr::new(192, 0, 2, 3)]
+        );
+        // The root, the server which refuses, the one which never answers and finally the working one.
+        assert_eq!(servers.queries(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_on_delegation_loops() -> Result<(), Box<dyn Error>> {
+        let servers = test_hierarchy()?;
+        assert!(recursor(&servers)?.query("www.loop", QueryType::A).is_err());
+        assert!(servers.queries() <= MAX_QUERIES);
+        Ok(())
+    }
+
+    #[test]
+    fn needs_root_hints() {
+        assert!(Recursor::new(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn asks_again_over_tcp_when_truncated() -> Result<(), Box<dyn Error>> {
+        let mut records = vec![test_servers::soa("")];
+        records
+            .extend((1..=40).map(|i| test_servers::a("big.example", Ipv4Addr::new(192, 0, 2, i))));
+        let server = Truncating::start(records)?;
+        let mut recursor = Recursor::new(vec![server.address])?
+            .with_port(server.address.port())
+            .with_timeout(Duration::from_millis(500));
+
+        for _ in 0..2 {
+            let response = recursor.query("big.example", QueryType::A)?;
+            assert!(!response.header.truncated_message);
+            assert_eq!(addresses(&response.answers).len(), 40);
+        }
+        // The second lookup reuses the first one's connection.
+        assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use rand::seq::SliceRandom;
+use std::{fmt::Display, net::SocketAddr, str::FromStr};
+
+/// Decides the order upstream servers are tried in for a query. The first is asked, and the others in turn only
+/// if the ones before them fail.
+pub trait SelectionStrategy: Send {
+    fn order(&mut self, upstreams: &[SocketAddr]) -> Vec<SocketAddr>;
+}
+
+// Always the configured order, so later servers are only fallbacks.
+pub struct OrderedFailover;
+
+impl SelectionStrategy for OrderedFailover {
+    fn order(&mut self, upstreams: &[SocketAddr]) -> Vec<SocketAddr> {
+        upstreams.to_vec()
+    }
+}
+
+// Each query starts one server further along the list, spreading the load evenly.
+#[derive(Default)]
+pub struct RoundRobin {
+    next: usize,
+}
+
+impl SelectionStrategy for RoundRobin {
+    fn order(&mut self, upstreams: &[SocketAddr]) -> Vec<SocketAddr> {
+        if upstreams.is_empty() {
+            return Vec::new();
+        }
+        let start = self.next % upstreams.len();
+        self.next = start + 1;
+        let mut order = upstreams.to_vec();
+        order.rotate_left(start);
+        order
+    }
+}
+
+// A fresh shuffle for every query.
+pub struct Random;
+
+impl SelectionStrategy for Random {
+    fn order(&mut self, upstreams: &[SocketAddr]) -> Vec<SocketAddr> {
+        let mut order = upstreams.to_vec();
+        order.shuffle(&mut rand::rng());
+        order
+    }
+}
+
+/// The built-in strategies, by the names used in the config file and on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    #[default]
+    OrderedFailover,
+    RoundRobin,
+    Random,
+}
+
+impl Strategy {
+    pub fn selector(self) -> Box<dyn SelectionStrategy> {
+        match self {
+            Strategy::OrderedFailover => Box::new(OrderedFailover),
+            Strategy::RoundRobin => Box::new(RoundRobin::default()),
+            Strategy::Random => Box::new(Random),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Strategy, String> {
+        match name {
+            "ordered" | "failover" | "ordered-failover" => Ok(Strategy::OrderedFailover),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "random" => Ok(Strategy::Random),
+            _ => Err(format!(
+                "Unknown upstream strategy {:?} (expected ordered-failover, round-robin or random).",
+                name
+            )),
+        }
+    }
+}
+
+impl Display for Strategy {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str(match self {
+            Strategy::OrderedFailover => "ordered-failover",
+            Strategy::RoundRobin => "round-robin",
+            Strategy::Random => "random",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Strategy;
+    use std::{collections::HashSet, net::SocketAddr};
+
+    fn upstreams() -> Vec<SocketAddr> {
+        ["192.0.2.1:53", "192.0.2.2:53", "[2001:db8::3]:5353"]
+            .iter()
+            .map(|address| address.parse().expect("Valid test address."))
+            .collect()
+    }
+
+    #[test]
+    fn ordered_failover_keeps_configured_order() {
+        let mut selector = Strategy::OrderedFailover.selector();
+        for _ in 0..3 {
+            assert_eq!(selector.order(&upstreams()), upstreams());
+        }
+    }
+
+    #[test]
+    fn round_robin_rotates_first_choice() {
+        let upstreams = upstreams();
+        let mut selector = Strategy::RoundRobin.selector();
+        let firsts: Vec<SocketAddr> = (0..4).map(|_| selector.order(&upstreams)[0]).collect();
+        assert_eq!(
+            firsts,
+            vec![upstreams[0], upstreams[1], upstreams[2], upstreams[0]]
+        );
+        assert_eq!(
+            selector.order(&upstreams),
+            vec![upstreams[1], upstreams[2], upstreams[0]]
+        );
+        assert!(selector.order(&[]).is_empty());
+    }
+
+    #[test]
+    fn random_tries_every_server_once() {
+        let upstreams = upstreams();
+        let mut selector = Strategy::Random.selector();
+        for _ in 0..10 {
+            let order = selector.order(&upstreams);
+            assert_eq!(order.len(), upstreams.len());
+            assert_eq!(
+                order.into_iter().collect::<HashSet<_>>(),
+                upstreams.iter().copied().collect()
+            );
+        }
+    }
+
+    #[test]
+    fn parses_strategy_names() {
+        for strategy in [
+            Strategy::OrderedFailover,
+            Strategy::RoundRobin,
+            Strategy::Random,
+        ] {
+            assert_eq!(strategy.to_string().parse(), Ok(strategy));
+        }
+        assert_eq!("failover".parse(), Ok(Strategy::OrderedFailover));
+        assert!("fastest".parse::<Strategy>().is_err());
+    }
+}
@@ -0,0 +1,415 @@
+// DNS over TCP (RFC 7766): each message is preceded by its length as two bytes, and a client may send several queries
+// on one connection without waiting, getting the responses back in whatever order they're ready.
+
+use super::{dns_resolver::lock, wrapped_socket::answers};
+use crate::parser::DnsPacket;
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Largest message a two-byte length can describe.
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+const READ_CHUNK_SIZE: usize = 4096;
+// Unused connections kept open to each server, and how long one is trusted to still be open.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+const MAX_IDLE_TIME: Duration = Duration::from_secs(5);
+
+// Answers one query's bytes from the given client, or gives nothing if there's nothing worth sending back.
+pub(super) type Handler = Arc<dyn Fn(&[u8], SocketAddr) -> Option<Vec<u8>> + Send + Sync>;
+
+/// How much the TCP listener takes on: open connections at once, queries in flight on each, and how long a
+/// connection with none in flight can sit unused before it's closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpLimits {
+    pub max_connections: usize,
+    pub max_pipelined: usize,
+    pub idle_timeout: Duration,
+}
+
+impl Default for TcpLimits {
+    fn default() -> TcpLimits {
+        TcpLimits {
+            max_connections: 256,
+            max_pipelined: 16,
+            // RFC 7766 section 6.2.3 suggests seconds rather than minutes.
+            idle_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+// Reads length-prefixed messages, holding on to part of one if a read times out so it can carry on afterwards.
+pub struct FrameReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    // How long to wait for each whole message, however many bytes of it trickle in meanwhile.
+    idle_timeout: Option<Duration>,
+    idle_since: Instant,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> FrameReader<R> {
+        FrameReader {
+            reader,
+            buffer: Vec::new(),
+            idle_timeout: None,
+            idle_since: Instant::now(),
+        }
+    }
+
+    // Fails reads with `TimedOut` once `timeout` has passed without a whole message. A read timeout on the stream
+    // underneath only notices silence, so a client sending a byte at a time could otherwise hold on forever.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> FrameReader<R> {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    // Starts the wait for the next message again.
+    pub fn reset_idle_timer(&mut self) {
+        self.idle_since = Instant::now();
+    }
+
+    // The next whole message, or `None` if the other end closed the connection between messages.
+    pub fn next_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            if let [high, low, ..] = self.buffer[..] {
+                let end = 2 + u16::from_be_bytes([high, low]) as usize;
+                if self.buffer.len() >= end {
+                    let message = self.buffer[2..end].to_vec();
+                    self.buffer.drain(..end);
+                    self.reset_idle_timer();
+                    return Ok(Some(message));
+                }
+            }
+            if let Some(timeout) = self.idle_timeout {
+                if self.idle_since.elapsed() >= timeout {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        "No whole message arrived in time.",
+                    ));
+                }
+            }
+            match self.reader.read(&mut chunk)? {
+                0 if self.buffer.is_empty() => return Ok(None),
+                0 => {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Connection closed partway through a message.",
+                    ))
+                }
+                size => self.buffer.extend_from_slice(&chunk[..size]),
+            }
+        }
+    }
+
+    // Whether everything read so far has been handed out as messages.
+    pub fn is_idle(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+// Writes `message` with its length in front, in one go so the two don't go out as separate segments.
+pub fn write_message<W: Write>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+    let length = u16::try_from(message.len())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Message too long for TCP."))?;
+    let mut framed = Vec::with_capacity(2 + message.len());
+    framed.extend_from_slice(&length.to_be_bytes());
+    framed.extend_from_slice(message);
+    writer.write_all(&framed)?;
+    writer.flush()
+}
+
+// Connections to servers for queries over TCP, kept open afterwards so later queries can reuse them (RFC 7766
+// section 6.2.1). Each is used by one query at a time.
+#[derive(Default)]
+pub struct Connections {
+    idle: Mutex<HashMap<SocketAddr, Vec<(TcpStream, Instant)>>>,
+}
+
+impl Connections {
+    // Sends `query` to `server` and waits until `deadline` for the response.
+    pub fn exchange(
+        &self,
+        server: SocketAddr,
+        query: &mut DnsPacket,
+        deadline: Instant,
+    ) -> Result<DnsPacket, Box<dyn Error>> {
+        let bytes = query.to_bytes()?;
+        // A server may close a connection while it sits unused, which only shows once it's used again, so a
+        // reused one that fails gets a second go on a new connection.
+        if let Some(stream) = self.take(server) {
+            if let Ok(response) = self.exchange_on(stream, server, query, &bytes, deadline) {
+                return Ok(response);
+            }
+        }
+        let stream = TcpStream::connect_timeout(&server, remaining(server, deadline)?)?;
+        stream.set_nodelay(true)?;
+        self.exchange_on(stream, server, query, &bytes, deadline)
+    }
+
+    fn exchange_on(
+        &self,
+        stream: TcpStream,
+        server: SocketAddr,
+        query: &DnsPacket,
+        bytes: &[u8],
+        deadline: Instant,
+    ) -> Result<DnsPacket, Box<dyn Error>> {
+        write_message(&mut &stream, bytes)?;
+        let mut frames = FrameReader::new(&stream);
+        let response = loop {
+            stream.set_read_timeout(Some(remaining(server, deadline)?))?;
+            let message = frames
+                .next_message()?
+                .ok_or_else(|| format!("{} closed the connection.", server))?;
+            let response = DnsPacket::from_bytes(&message)?;
+            if answers(query, &response) {
+                break response;
+            }
+        };
+        // Anything left over would muddle the next query's response, so the connection isn't worth keeping.
+        if frames.is_idle() {
+            self.put(server, stream);
+        }
+        Ok(response)
+    }
+
+    fn take(&self, server: SocketAddr) -> Option<TcpStream> {
+        let mut idle = lock(&self.idle);
+        let streams = idle.get_mut(&server)?;
+        streams.retain(|(_, since)| since.elapsed() < MAX_IDLE_TIME);
+        streams.pop().map(|(stream, _)| stream)
+    }
+
+    fn put(&self, server: SocketAddr, stream: TcpStream) {
+        let mut idle = lock(&self.idle);
+        let streams = idle.entry(server).or_default();
+        if streams.len() < MAX_IDLE_CONNECTIONS {
+            streams.push((stream, Instant::now()));
+        }
+    }
+}
+
+fn remaining(server: SocketAddr, deadline: Instant) -> io::Result<Duration> {
+    match deadline.saturating_duration_since(Instant::now()) {
+        remaining if remaining.is_zero() => Err(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("No response from {}.", server),
+        )),
+        remaining => Ok(remaining),
+    }
+}
+
+// Accepts connections for as long as the listener works, each served by `serve` on its own thread. Beyond
+// `max_connections`, new ones are closed straight away so the client can go elsewhere.
+pub(super) fn listen<S>(listener: &TcpListener, max_connections: usize, serve: S)
+where
+    S: Fn(TcpStream) -> io::Result<()> + Send + Sync + 'static,
+{
+    let serve = Arc::new(serve);
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                eprintln!("Failed to accept a TCP connection: {}", error);
+                continue;
+            }
+        };
+        if connections.fetch_add(1, Ordering::SeqCst) >= max_connections {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            continue;
+        }
+        let connections = Arc::clone(&connections);
+        let serve = Arc::clone(&serve);
+        thread::spawn(move || {
+            if let Err(error) = serve(stream) {
+                eprintln!("TCP connection failed: {}", error);
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+// Serves one plain TCP connection.
+pub(super) fn serve_stream(
+    stream: TcpStream,
+    limits: TcpLimits,
+    handler: Handler,
+) -> io::Result<()> {
+    let client = stream.peer_addr()?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(limits.idle_timeout))?;
+    // Otherwise a client that stops reading would leave responses blocked behind a full send buffer for good.
+    stream.set_write_timeout(Some(limits.idle_timeout))?;
+    let writer = stream.try_clone()?;
+    serve_connection(stream, writer, client, limits, handler);
+    Ok(())
+}
+
+// Reads queries off one connection and answers each on a thread of its own, writing responses as they're ready. The
+// connection is closed when the client closes its end, sends something that isn't a message, or goes too long
+// without sending a whole one; queries already read are still answered first. It's also closed once a response
+// can't be written, after which nothing more is sent.
+pub(super) fn serve_connection<R, W>(
+    reader: R,
+    writer: W,
+    client: SocketAddr,
+    limits: TcpLimits,
+    handler: Handler,
+) where
+    R: Read,
+    W: Write + Send + 'static,
+{
+    let mut frames = FrameReader::new(reader).with_idle_timeout(limits.idle_timeout);
+    let writer = Arc::new(Mutex::new(writer));
+    let in_flight = Arc::new((Mutex::new(0usize), Condvar::new()));
+    let write_failed = Arc::new(AtomicBool::new(false));
+    while !write_failed.load(Ordering::SeqCst) {
+        let query = match frames.next_message() {
+            Ok(Some(query)) => query,
+            // The idle timer only runs while the client isn't waiting on us (RFC 7766 section 6.2.3).
+            Err(error)
+                if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                    && *lock(&in_flight.0) > 0 =>
+            {
+                frames.reset_idle_timer();
+                continue;
+            }
+            Ok(None) | Err(_) => break,
+        };
+
+        let (count, finished) = &*in_flight;
+        let mut count_guard = lock(count);
+        while *count_guard >= limits.max_pipelined {
+            count_guard = finished
+                .wait(count_guard)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        *count_guard += 1;
+        drop(count_guard);
+
+        let (handler, writer, in_flight, write_failed) = (
+            Arc::clone(&handler),
+            Arc::clone(&writer),
+            Arc::clone(&in_flight),
+            Arc::clone(&write_failed),
+        );
+        thread::spawn(move || {
+            if let Some(response) = handler(&query, client) {
+                let mut writer = lock(&writer);
+                if !write_failed.load(Ordering::SeqCst) {
+                    if let Err(error) = write_message(&mut *writer, &response) {
+                        eprintln!("Failed to respond to {} over TCP: {}", client, error);
+                        write_failed.store(true, Ordering::SeqCst);
+                    }
+                }
+            }
+            let (count, finished) = &*in_flight;
+            *lock(count) -= 1;
+            finished.notify_all();
+        });
+    }
+
+    let (count, finished) = &*in_flight;
+    let mut count_guard = lock(count);
+    while *count_guard > 0 {
+        count_guard = finished
+            .wait(count_guard)
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve_connection, serve_stream, write_message, FrameReader, TcpLimits};
+    use std::{
+        error::Error,
+        io::{self, Cursor, Read},
+        net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+        sync::{mpsc, Arc},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    // A client sending the start of a huge message a byte at a time, never quite going quiet.
+    struct Trickle;
+
+    impl Read for Trickle {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(10));
+            buffer[0] = 0xff;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn reads_messages_split_and_joined_across_reads() -> Result<(), Box<dyn Error>> {
+        let mut stream = Vec::new();
+        write_message(&mut stream, b"first")?;
+        write_message(&mut stream, b"")?;
+        write_message(&mut stream, &[7; 300])?;
+
+        let mut frames = FrameReader::new(Cursor::new(stream));
+        assert_eq!(frames.next_message()?, Some(b"first".to_vec()));
+        assert_eq!(frames.next_message()?, Some(Vec::new()));
+        assert_eq!(frames.next_message()?, Some(vec![7; 300]));
+        assert_eq!(frames.next_message()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn closing_partway_through_a_message_is_an_error() {
+        let mut frames = FrameReader::new(Cursor::new(vec![0, 5, 1, 2]));
+        assert!(frames.next_message().is_err());
+    }
+
+    #[test]
+    fn closes_connections_that_trickle_in_slowly() {
+        let limits = TcpLimits {
+            idle_timeout: Duration::from_millis(100),
+            ..TcpLimits::default()
+        };
+        let client = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353);
+        let started = Instant::now();
+        serve_connection(Trickle, io::sink(), client, limits, Arc::new(|_, _| None));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn closes_connections_that_stop_reading() -> Result<(), Box<dyn Error>> {
+        let limits = TcpLimits {
+            idle_timeout: Duration::from_millis(200),
+            ..TcpLimits::default()
+        };
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let (stream, _) = listener.accept()?;
+
+        // Far more in responses than the socket buffers on either side can hold.
+        let (served, done) = mpsc::channel();
+        thread::spawn(move || {
+            let handler = Arc::new(|_: &[u8], _| Some(vec![0; 60_000]));
+            let _ = served.send(serve_stream(stream, limits, handler).is_ok());
+        });
+        for _ in 0..1000 {
+            write_message(&mut client, b"")?;
+        }
+        assert_eq!(done.recv_timeout(Duration::from_secs(10)), Ok(true));
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_frame_oversized_messages() {
+        assert!(write_message(&mut Vec::new(), &vec![0; 70000]).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+// Stand-in authoritative servers on loopback, so recursion can be tested without a network. Each one listens on its
+// own 127.0.0.x address, all of them on the same port, since referrals only carry addresses.
+
+use super::tcp::{write_message, FrameReader};
+use crate::parser::{
+    compare_names, is_subdomain, DnsPacket, DnsPacketBuilder, DnsRecord, QueryType, ResultCode,
+};
+use std::{
+    cmp::Ordering,
+    error::Error,
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+// How often serving threads check whether they should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const BIND_ATTEMPTS: usize = 10;
+
+pub enum Behaviour {
+    // Answers from these records, for every zone with an SOA among them.
+    Authoritative(Vec<DnsRecord>),
+    Refuses,
+    Silent,
+}
+
+// A set of running servers, stopped when dropped. The first one is the root.
+pub struct StandIns {
+    root: SocketAddr,
+    queries: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>,
+}
+
+impl StandIns {
+    pub fn start(servers: Vec<(Ipv4Addr, Behaviour)>) -> Result<StandIns, Box<dyn Error>> {
+        let sockets = bind(&servers)?;
+        let stand_ins = StandIns {
+            root: sockets[0].local_addr()?,
+            queries: Arc::new(AtomicUsize::new(0)),
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+        let behaviours = servers.into_iter().map(|(_, behaviour)| behaviour);
+        for (socket, behaviour) in sockets.into_iter().zip(behaviours) {
+            socket.set_read_timeout(Some(POLL_INTERVAL))?;
+            let queries = Arc::clone(&stand_ins.queries);
+            let stop = Arc::clone(&stand_ins.stop);
+            thread::spawn(move || serve(socket, &behaviour, &queries, &stop));
+        }
+        Ok(stand_ins)
+    }
+
+    pub fn root(&self) -> SocketAddr {
+        self.root
+    }
+
+    pub fn port(&self) -> u16 {
+        self.root.port()
+    }
+
+    // Queries received by all the servers so far.
+    pub fn queries(&self) -> usize {
+        self.queries.load(AtomicOrdering::SeqCst)
+    }
+}
+
+impl Drop for StandIns {
+    fn drop(&mut self) {
+        self.stop.store(true, AtomicOrdering::SeqCst);
+    }
+}
+
+// The first server gets whatever port is free, and the rest try to share it, starting again if one can't.
+fn bind(servers: &[(Ipv4Addr, Behaviour)]) -> Result<Vec<UdpSocket>, Box<dyn Error>> {
+    for _ in 0..BIND_ATTEMPTS {
+        let first = UdpSocket::bind((servers[0].0, 0))?;
+        let port = first.local_addr()?.port();
+        let rest: Result<Vec<UdpSocket>, _> = servers[1..]
+            .iter()
+            .map(|(address, _)| UdpSocket::bind((*address, port)))
+            .collect();
+        if let Ok(rest) = rest {
+            let mut sockets = vec![first];
+            sockets.extend(rest);
+            return Ok(sockets);
+        }
+    }
+    Err("Couldn't find a port free on every stand-in address.".into())
+}
+
+// A server on 127.0.0.1 answering from `records` in full over TCP, but over UDP only ever with TC set and nothing else.
+// It runs until the tests finish, and counts the TCP connections it accepts.
+pub struct Truncating {
+    pub address: SocketAddr,
+    pub connections: Arc<AtomicUsize>,
+}
+
+impl Truncating {
+    pub fn start(records: Vec<DnsRecord>) -> Result<Truncating, Box<dyn Error>> {
+        let (socket, listener) = bind_both()?;
+        let truncating = Truncating {
+            address: socket.local_addr()?,
+            connections: Arc::new(AtomicUsize::new(0)),
+        };
+        thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            while let Ok((size, client)) = socket.recv_from(&mut buffer) {
+                let Ok(query) = DnsPacket::from_bytes(&buffer[..size]) else {
+                    continue;
+                };
+                let mut response = DnsPacketBuilder::response_to(&query).build();
+                response.header.truncated_message = true;
+                if let Ok(bytes) = response.to_bytes() {
+                    let _ = socket.send_to(&bytes, client);
+                }
+            }
+        });
+        let records = Arc::new(records);
+        let connections = Arc::clone(&truncating.connections);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                connections.fetch_add(1, AtomicOrdering::SeqCst);
+                let records = Arc::clone(&records);
+                thread::spawn(move || serve_stream(&stream, &records));
+            }
+        });
+        Ok(truncating)
+    }
+}
+
+fn bind_both() -> Result<(UdpSocket, TcpListener), Box<dyn Error>> {
+    for _ in 0..BIND_ATTEMPTS {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        if let Ok(listener) = TcpListener::bind(socket.local_addr()?) {
+            return Ok((socket, listener));
+        }
+    }
+    Err("Couldn't find a port free for both UDP and TCP.".into())
+}
+
+fn serve_stream(stream: &TcpStream, records: &[DnsRecord]) {
+    let mut frames = FrameReader::new(stream);
+    while let Ok(Some(message)) = frames.next_message() {
+        let Ok(query) = DnsPacket::from_bytes(&message) else {
+            return;
+        };
+        let Ok(bytes) = answer(&query, records).to_bytes_with_limit(u16::MAX as usize) else {
+            return;
+        };
+        if write_message(&mut &*stream, &bytes).is_err() {
+            return;
+        }
+    }
+}
+
+fn serve(socket: UdpSocket, behaviour: &Behaviour, queries: &AtomicUsize, stop: &AtomicBool) {
+    let mut buffer = [0; 4096];
+    while !stop.load(AtomicOrdering::SeqCst) {
+        let Ok((size, client)) = socket.recv_from(&mut buffer) else {
+            continue;
+        };
+        queries.fetch_add(1, AtomicOrdering::SeqCst);
+        let Ok(query) = DnsPacket::from_bytes(&buffer[..size]) else {
+            continue;
+        };
+        let mut response = match behaviour {
+            Behaviour::Authoritative(records) => answer(&query, records),
+            Behaviour::Refuses => DnsPacketBuilder::response_to(&query)
+                .rescode(ResultCode::REFUSED)
+                .build(),
+            Behaviour::Silent => continue,
+        };
+        if let Ok(bytes) = response.to_bytes() {
+            let _ = socket.send_to(&bytes, client);
+        }
+    }
+}
+
+// What an authoritative server for the zones in `records` says: a referral at a zone cut, otherwise an answer.
+fn answer(query: &DnsPacket, records: &[DnsRecord]) -> DnsPacket {
+    let response = DnsPacketBuilder::response_to(query);
+    let Some(question) = query.questions.first() else {
+        return response.rescode(ResultCode::FORMERR).build();
+    };
+    let name = question.name.as_str();
+    let Some(soa) = deepest(records, name, |record| {
+        matches!(record, DnsRecord::SOA { .. })
+    }) else {
+        return response.rescode(ResultCode::REFUSED).build();
+    };
+    let zone = soa.domain();
+
+    // DS records at a cut are the parent's to answer for.
+    let cut = deepest(records, name, |record| match record {
+        DnsRecord::NS { domain, .. } if compare_names(domain, zone) != Ordering::Equal => {
+            question.query_type != QueryType::DS || compare_names(domain, name) != Ordering::Equal
+        }
+        _ => false,
+    });
+    if let Some(cut) = cut {
+        let delegation: Vec<DnsRecord> = owned(records, cut.domain(), QueryType::NS).collect();
+        let glue: Vec<DnsRecord> = delegation
+            .iter()
+            .flat_map(|ns| match ns {
+                DnsRecord::NS { host, .. } => owned(records, host, QueryType::A).collect(),
+                _ => Vec::new(),
+            })
+            .collect();
+        return response
+            .authorities(delegation)
+            .additional_records(glue)
+            .build();
+    }
+
+    let response = response.authoritative_answer(true);
+    let matching: Vec<DnsRecord> = owned(records, name, question.query_type).collect();
+    if !matching.is_empty() {
+        return response.answers(matching).build();
+    }
+    let cnames: Vec<DnsRecord> = owned(records, name, QueryType::CNAME).collect();
+    if !cnames.is_empty() {
+        // Whatever the server holds for the target goes along too, as far from its own zone as that may be.
+        let targets: Vec<DnsRecord> = cnames
+            .iter()
+            .flat_map(|cname| match cname {
+                DnsRecord::CNAME { host, .. } => {
+                    owned(records, host, question.query_type).collect()
+                }
+                _ => Vec::new(),
+            })
+            .collect();
+        return response.answers(cnames).answers(targets).build();
+    }
+    let exists = records
+        .iter()
+        .any(|record| is_subdomain(record.domain(), name));
+    response
+        .rescode(if exists {
+            ResultCode::NOERROR
+        } else {
+            ResultCode::NXDOMAIN
+        })
+        .authority(soa.clone())
+        .build()
+}
+
+// The record closest to `name` at or above it which `wanted` picks.
+fn deepest<'a>(
+    records: &'a [DnsRecord],
+    name: &str,
+    wanted: impl Fn(&DnsRecord) -> bool,
+) -> Option<&'a DnsRecord> {
+    records
+        .iter()
+        .filter(|record| wanted(record) && is_subdomain(name, record.domain()))
+        .max_by_key(|record| {
+            record
+                .domain()
+                .split('.')
+                .filter(|label| !label.is_empty())
+                .count()
+        })
+}
+
+fn owned<'a>(
+    records: &'a [DnsRecord],
+    name: &'a str,
+    query_type: QueryType,
+) -> impl Iterator<Item = DnsRecord> + 'a {
+    records
+        .iter()
+        .filter(move |record| {
+            compare_names(record.domain(), name) == Ordering::Equal
+                && record.query_type() == query_type
+        })
+        .cloned()
+}
+
+pub fn soa(zone: &str) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: zone.to_string(),
+        primary_name_server: format!("ns.{}", zone),
+        mailbox: format!("hostmaster.{}", zone),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 300,
+        ttl: 3600,
+    }
+}
+
+pub fn ns(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::NS {
+        domain: domain.to_string(),
+        host: host.to_string(),
+        ttl: 3600,
+    }
+}
+
+pub fn a(domain: &str, address: Ipv4Addr) -> DnsRecord {
+    DnsRecord::A {
+        domain: domain.to_string(),
+        address,
+        ttl: 300,
+    }
+}
+
+pub fn cname(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::CNAME {
+        domain: domain.to_string(),
+        host: host.to_string(),
+        ttl: 300,
+    }
+}
+
+fn loopback(last_octet: u8) -> Ipv4Addr {
+    Ipv4Addr::new(127, 0, 0, last_octet)
+}
+
+// A small tree of zones:
+//
+// - the root (127.0.0.2) delegates example, other and flaky with glue, and loop and circle without;
+// - example (127.0.0.3) has www and alias, a CNAME to www.sub.example, and delegates sub.example to sub-ns.other;
+// - example also has stray, a CNAME to www.other, and a wrong address for www.other which it sends along with it;
+// - other (127.0.0.4) holds www.other and sub-ns.other's address, 127.0.0.5, which serves sub.example;
+// - flaky's first server (127.0.0.6) refuses, its second (127.0.0.7) never answers and its third (127.0.0.8) works;
+// - loop and circle are each served by a name in the other, so neither can be reached.
+pub fn test_hierarchy() -> Result<StandIns, Box<dyn Error>> {
+    let root = vec![
+        soa(""),
+        ns("example", "ns.example"),
+        a("ns.example", loopback(3)),
+        ns("other", "ns.other"),
+        a("ns.other", loopback(4)),
+        ns("flaky", "ns1.flaky"),
+        ns("flaky", "ns2.flaky"),
+        ns("flaky", "ns3.flaky"),
+        a("ns1.flaky", loopback(6)),
+        a("ns2.flaky", loopback(7)),
+        a("ns3.flaky", loopback(8)),
+        ns("loop", "ns.circle"),
+        ns("circle", "ns.loop"),
+    ];
+    let example = vec![
+        soa("example"),
+        ns("example", "ns.example"),
+        a("ns.example", loopback(3)),
+        a("www.example", Ipv4Addr::new(192, 0, 2, 1)),
+        cname("alias.example", "www.sub.example"),
+        cname("stray.example", "www.other"),
+        a("www.other", Ipv4Addr::new(198, 51, 100, 1)),
+        ns("sub.example", "sub-ns.other"),
+    ];
+    let other = vec![
+        soa("other"),
+        ns("other", "ns.other"),
+        a("ns.other", loopback(4)),
+        a("sub-ns.other", loopback(5)),
+        a("www.other", Ipv4Addr::new(192, 0, 2, 4)),
+    ];
+    let sub = vec![
+        soa("sub.example"),
+        ns("sub.example", "sub-ns.other"),
+        a("www.sub.example", Ipv4Addr::new(192, 0, 2, 2)),
+    ];
+    let flaky = vec![soa("flaky"), a("www.flaky", Ipv4Addr::new(192, 0, 2, 3))];
+    StandIns::start(vec![
+        (loopback(2), Behaviour::Authoritative(root)),
+        (loopback(3), Behaviour::Authoritative(example)),
+        (loopback(4), Behaviour::Authoritative(other)),
+        (loopback(5), Behaviour::Authoritative(sub)),
+        (loopback(6), Behaviour::Refuses),
+        (loopback(7), Behaviour::Silent),
+        (loopback(8), Behaviour::Authoritative(flaky)),
+    ])
+}
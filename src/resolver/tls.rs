@@ -0,0 +1,268 @@
+// DNS over TLS (RFC 7858): the same length-prefixed messages as over TCP, inside a TLS session.
+
+use super::{
+    dns_resolver::lock,
+    tcp::{serve_connection, Handler, TcpLimits},
+};
+use rustls::{
+    crypto::ring::{default_provider, Ticketer},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig, ServerConnection,
+};
+use std::{
+    error::Error,
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+pub const DNS_OVER_TLS_PORT: u16 = 853;
+const READ_CHUNK_SIZE: usize = 4096;
+
+// Protocol names for negotiating DNS over TLS (RFC 7858 section 3.2) during the handshake.
+pub(super) const DOT_PROTOCOLS: &[&[u8]] = &[b"dot"];
+
+// Server settings for the certificate chain and private key in the PEM files at `certificate` and `key`, offering
+// `protocols` to clients that ask. Clients can resume sessions, from the server's cache or with a ticket, rather than
+// going through a full handshake each time.
+pub(super) fn server_config(
+    certificate: &Path,
+    key: &Path,
+    protocols: &[&[u8]],
+) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+    let chain = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| format!("{}: {}", certificate.display(), error))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|error| format!("{}: {}", key.display(), error))?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+    config.ticketer = Ticketer::new()?;
+    config.alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+// Serves one connection: the handshake happens as the first query is read, and the session is closed properly once
+// the connection is finished with.
+pub(super) fn serve(
+    stream: TcpStream,
+    config: &Arc<ServerConfig>,
+    limits: TcpLimits,
+    handler: Handler,
+) -> io::Result<()> {
+    let client = stream.peer_addr()?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(limits.idle_timeout))?;
+    // A client that stops reading would otherwise hold the session, and with it the reader, for good.
+    stream.set_write_timeout(Some(limits.idle_timeout))?;
+    let session = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+    let session = Arc::new(Mutex::new(session));
+    let reader = TlsReader {
+        session: Arc::clone(&session),
+        stream: stream.try_clone()?,
+    };
+    let writer = TlsWriter {
+        session: Arc::clone(&session),
+        stream: stream.try_clone()?,
+    };
+    serve_connection(reader, writer, client, limits, handler);
+
+    let mut session = lock(&session);
+    session.send_close_notify();
+    session.write_tls(&mut &stream)?;
+    Ok(())
+}
+
+// The reading half of a session shared with a `TlsWriter`, so responses can go out while the next query is awaited.
+// The session is only locked between reads from the socket, never during one.
+struct TlsReader {
+    session: Arc<Mutex<ServerConnection>>,
+    stream: TcpStream,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = [0; READ_CHUNK_SIZE];
+        loop {
+            match lock(&self.session).reader().read(buf) {
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                // Plenty of clients just close the connection, and messages are framed anyway.
+                Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+                result => return result,
+            }
+            let size = (&self.stream).read(&mut incoming)?;
+            let mut session = lock(&self.session);
+            session.read_tls(&mut &incoming[..size])?;
+            let processed = session.process_new_packets();
+            // Handshake messages and alerts go straight back.
+            while session.wants_write() {
+                session.write_tls(&mut &self.stream)?;
+            }
+            processed.map_err(|error| io::Error::new(ErrorKind::InvalidData, error))?;
+        }
+    }
+}
+
+struct TlsWriter {
+    session: Arc<Mutex<ServerConnection>>,
+    stream: TcpStream,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = lock(&self.session);
+        let size = session.writer().write(buf)?;
+        while session.wants_write() {
+            session.write_tls(&mut &self.stream)?;
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::{serve, server_config, DOT_PROTOCOLS};
+    use crate::{
+        parser::{test_helpers::test_file_path, DnsPacket, DnsPacketBuilder, DnsRecord, QueryType},
+        resolver::tcp::{listen, write_message, FrameReader, Handler, TcpLimits},
+    };
+    use rustls::{
+        crypto::ring::default_provider,
+        pki_types::{pem::PemObject, CertificateDer},
+        ClientConfig, ClientConnection, HandshakeKind, RootCertStore, StreamOwned,
+    };
+    use std::{
+        error::Error,
+        io::Write,
+        net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    // A self-signed certificate for localhost and 127.0.0.1.
+    pub const CERTIFICATE: &str = "tls_certificate.pem";
+    pub const KEY: &str = "tls_key.pem";
+
+    type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+    // Answers every query with an address, taking a while over any for "slow".
+    pub fn handler() -> Handler {
+        Arc::new(|query, _| {
+            let query = DnsPacket::from_bytes(query).ok()?;
+            let name = query.questions.first()?.name.clone();
+            if name == "slow" {
+                thread::sleep(Duration::from_millis(300));
+            }
+            let address = DnsRecord::A {
+                domain: name,
+                address: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 300,
+            };
+            DnsPacketBuilder::response_to(&query)
+                .answer(address)
+                .build()
+                .to_bytes()
+                .ok()
+        })
+    }
+
+    fn start() -> Result<SocketAddr, Box<dyn Error>> {
+        let config = server_config(
+            &test_file_path(CERTIFICATE),
+            &test_file_path(KEY),
+            DOT_PROTOCOLS,
+        )?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let address = listener.local_addr()?;
+        let handler = handler();
+        thread::spawn(move || {
+            listen(&listener, 8, move |stream| {
+                serve(stream, &config, TcpLimits::default(), Arc::clone(&handler))
+            })
+        });
+        Ok(address)
+    }
+
+    // Trusts the test certificate, and offers `protocols`.
+    pub fn client_config(protocols: &[&[u8]]) -> Result<Arc<ClientConfig>, Box<dyn Error>> {
+        let mut roots = RootCertStore::empty();
+        for certificate in CertificateDer::pem_file_iter(test_file_path(CERTIFICATE))? {
+            roots.add(certificate?)?;
+        }
+        let mut config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        Ok(Arc::new(config))
+    }
+
+    fn connect(
+        server: SocketAddr,
+        config: &Arc<ClientConfig>,
+    ) -> Result<TlsStream, Box<dyn Error>> {
+        let session = ClientConnection::new(Arc::clone(config), "localhost".try_into()?)?;
+        let stream = TcpStream::connect(server)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        Ok(StreamOwned::new(session, stream))
+    }
+
+    fn send_query(stream: &mut TlsStream, name: &str) -> Result<(), Box<dyn Error>> {
+        let mut query = DnsPacketBuilder::query()
+            .question(name, QueryType::A)
+            .build();
+        write_message(stream, &query.to_bytes()?)?;
+        Ok(())
+    }
+
+    fn receive(frames: &mut FrameReader<&mut TlsStream>) -> Result<DnsPacket, Box<dyn Error>> {
+        let message = frames.next_message()?.ok_or("Connection closed.")?;
+        DnsPacket::from_bytes(&message)
+    }
+
+    #[test]
+    fn answers_pipelined_queries_as_they_are_ready() -> Result<(), Box<dyn Error>> {
+        let server = start()?;
+        let mut stream = connect(server, &client_config(DOT_PROTOCOLS)?)?;
+
+        send_query(&mut stream, "slow")?;
+        send_query(&mut stream, "fast")?;
+        let mut frames = FrameReader::new(&mut stream);
+        let first = receive(&mut frames)?;
+        let second = receive(&mut frames)?;
+        assert_eq!(first.questions[0].name, "fast");
+        assert_eq!(second.questions[0].name, "slow");
+        assert_eq!(second.answers.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn resumes_sessions() -> Result<(), Box<dyn Error>> {
+        let server = start()?;
+        let config = client_config(DOT_PROTOCOLS)?;
+
+        let mut kinds = Vec::new();
+        for _ in 0..2 {
+            let mut stream = connect(server, &config)?;
+            send_query(&mut stream, "fast")?;
+            // Reading the response also takes in the session tickets sent after the handshake.
+            receive(&mut FrameReader::new(&mut stream))?;
+            kinds.push(stream.conn.handshake_kind());
+            stream.conn.send_close_notify();
+            stream.flush()?;
+        }
+        assert_eq!(
+            kinds,
+            [Some(HandshakeKind::Full), Some(HandshakeKind::Resumed)]
+        );
+        Ok(())
+    }
+}
@@ -1,49 +1,229 @@
+use crate::parser::{compare_names, DnsPacket};
+use rand::Rng;
 use std::{
-    io::{Read, Result, Write},
-    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    cmp::Ordering,
+    error::Error,
+    io::{self, ErrorKind, Result, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Instant,
 };
 
+// Largest payload a UDP datagram can carry.
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+// Random ports to try before leaving the choice to the OS.
+const BIND_ATTEMPTS: usize = 8;
+const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+// A socket for querying one server. It's bound to a port picked at random, so an attacker has to guess that as well
+// as the query's ID to forge a response (RFC 5452 section 9.2), and only hears from the server it was made for.
 pub struct WrappedSocket {
     raw_socket: UdpSocket,
     remote_addr: SocketAddr,
-    last_received_addr: Option<SocketAddr>,
 }
 
 impl WrappedSocket {
-    pub fn new(port: u16, remote_addr: SocketAddr) -> WrappedSocket {
-        WrappedSocket {
-            raw_socket: UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
-                .expect(format!("Failed to bind socket (port: {})", port).as_str()),
-
+    pub fn new(remote_addr: SocketAddr) -> Result<WrappedSocket> {
+        // Bind to the same address family as the remote end, so IPv6 servers can be reached.
+        let local_ip: IpAddr = match remote_addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let mut rng = rand::rng();
+        for _ in 0..BIND_ATTEMPTS {
+            let port = rng.random_range(FIRST_UNPRIVILEGED_PORT..=u16::MAX);
+            if let Ok(raw_socket) = UdpSocket::bind((local_ip, port)) {
+                return Ok(WrappedSocket {
+                    raw_socket,
+                    remote_addr,
+                });
+            }
+        }
+        // Most systems pick ephemeral ports at random too.
+        Ok(WrappedSocket {
+            raw_socket: UdpSocket::bind((local_ip, 0))?,
             remote_addr,
-            last_received_addr: None,
+        })
+    }
+
+    // Receives the next datagram from the remote server, ignoring any from elsewhere. Each datagram is exactly one
+    // DNS message, which a byte-stream Read can't express.
+    pub fn receive(&mut self, deadline: Instant) -> Result<Vec<u8>> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("No response from {}.", self.remote_addr),
+                ));
+            }
+            self.raw_socket.set_read_timeout(Some(remaining))?;
+            match self.raw_socket.recv_from(&mut buf) {
+                Ok((size, addr)) if addr == self.remote_addr => {
+                    buf.truncate(size);
+                    return Ok(buf);
+                }
+                Ok(_) => {}
+                // The deadline passing shows up as one of these, depending on the platform.
+                Err(error)
+                    if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(error) => return Err(error),
+            }
         }
     }
-}
 
-impl Read for WrappedSocket {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let (size, addr) = self.raw_socket.recv_from(buf)?;
-        self.last_received_addr = Some(addr);
-        Ok(size)
+    // Sends `query` and waits until `deadline` for its response. Anything else that arrives in the meantime, with
+    // the wrong ID or question or that doesn't parse, is dropped as a possible forgery.
+    pub fn exchange(
+        &mut self,
+        query: &mut DnsPacket,
+        deadline: Instant,
+    ) -> std::result::Result<DnsPacket, Box<dyn Error>> {
+        query.write(self)?;
+        loop {
+            let Ok(response) = DnsPacket::from_bytes(&self.receive(deadline)?) else {
+                continue;
+            };
+            if answers(query, &response) {
+                return Ok(response);
+            }
+        }
     }
 }
 
+// Whether `response` is a response to `query`: same ID and same question, ignoring case.
+pub(super) fn answers(query: &DnsPacket, response: &DnsPacket) -> bool {
+    response.header.response
+        && response.header.id == query.header.id
+        && response.questions.len() == query.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(&query.questions)
+            .all(|(answered, asked)| {
+                answered.query_type == asked.query_type
+                    && compare_names(&answered.name, &asked.name) == Ordering::Equal
+            })
+}
+
 impl Write for WrappedSocket {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let size = self.raw_socket.send_to(
-            buf,
-            if let Some(addr) = self.last_received_addr {
-                addr
-            } else {
-                self.remote_addr
-            },
-        )?;
-        self.last_received_addr = None;
-        Ok(size)
+        self.raw_socket.send_to(buf, self.remote_addr)
     }
 
+    // Each write goes out as a datagram straight away, so there's nothing to flush.
     fn flush(&mut self) -> std::io::Result<()> {
-        unimplemented!()
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WrappedSocket;
+    use crate::parser::{DnsPacket, DnsPacketBuilder, QueryType};
+    use std::{
+        error::Error,
+        io::Write,
+        net::{Ipv4Addr, UdpSocket},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    fn query() -> DnsPacket {
+        DnsPacketBuilder::query()
+            .question("www.example.com", QueryType::A)
+            .build()
+    }
+
+    #[test]
+    fn binds_random_ports() -> Result<(), Box<dyn Error>> {
+        let server = "192.0.2.1:53".parse()?;
+        let ports: Vec<u16> = (0..4)
+            .map(|_| Ok(WrappedSocket::new(server)?.raw_socket.local_addr()?.port()))
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        assert!(ports.iter().all(|port| *port >= 1024));
+        assert!(ports.windows(2).any(|pair| pair[0] != pair[1]));
+        Ok(())
+    }
+
+    #[test]
+    fn writes_datagrams_through_write() -> Result<(), Box<dyn Error>> {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let mut socket = WrappedSocket::new(server.local_addr()?)?;
+        socket.write_all(b"datagram")?;
+        socket.flush()?;
+
+        let mut buffer = [0; 16];
+        let size = server.recv(&mut buffer)?;
+        assert_eq!(&buffer[..size], b"datagram");
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_responses_which_dont_match() -> Result<(), Box<dyn Error>> {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let spoofer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let server_addr = server.local_addr()?;
+
+        let responder = thread::spawn(move || -> Result<(), String> {
+            let mut buffer = [0; 512];
+            let (size, client) = server.recv_from(&mut buffer).map_err(|e| e.to_string())?;
+            let query = DnsPacket::from_bytes(&buffer[..size]).map_err(|e| e.to_string())?;
+            let send = |from: &UdpSocket, mut response: DnsPacket| {
+                let bytes = response.to_bytes().map_err(|e| e.to_string())?;
+                from.send_to(&bytes, client).map_err(|e| e.to_string())
+            };
+            // Right ID and question, but from the wrong address.
+            send(&spoofer, DnsPacketBuilder::response_to(&query).build())?;
+            // Right address, but the wrong ID.
+            send(
+                &server,
+                DnsPacketBuilder::response_to(&query)
+                    .id(query.header.id ^ 1)
+                    .build(),
+            )?;
+            // Right address and ID, but another question.
+            send(
+                &server,
+                DnsPacketBuilder::new()
+                    .response(true)
+                    .id(query.header.id)
+                    .question("www.example.org", QueryType::A)
+                    .build(),
+            )?;
+            // A query rather than a response.
+            send(
+                &server,
+                DnsPacketBuilder::new()
+                    .id(query.header.id)
+                    .question("www.example.com", QueryType::A)
+                    .build(),
+            )?;
+            send(
+                &server,
+                DnsPacketBuilder::response_to(&query)
+                    .recursion_available(true)
+                    .build(),
+            )?;
+            Ok(())
+        });
+
+        let mut socket = WrappedSocket::new(server_addr)?;
+        let response = socket.exchange(&mut query(), Instant::now() + Duration::from_secs(5))?;
+        responder.join().map_err(|_| "Responder panicked.")??;
+        assert!(response.header.recursion_available);
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_at_deadline() -> Result<(), Box<dyn Error>> {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let mut socket = WrappedSocket::new(server.local_addr()?)?;
+        let started = Instant::now();
+        assert!(socket
+            .exchange(&mut query(), started + Duration::from_millis(100))
+            .is_err());
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        Ok(())
     }
 }